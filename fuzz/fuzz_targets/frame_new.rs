@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::frame::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    // The server hands Frame::new its whole read buffer along with the number
+    // of bytes actually read, mirror that with a fixed size buffer.
+    let mut buffer = [0u8; 1024];
+    let len = data.len().min(buffer.len());
+    buffer[..len].copy_from_slice(&data[..len]);
+    let _ = Frame::new(&buffer, len);
+});
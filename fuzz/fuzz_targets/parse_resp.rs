@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::resptype::parse_resp;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, cursor)) = parse_resp(data) {
+        assert!(
+            cursor <= data.len(),
+            "cursor {} past end of {} bytes",
+            cursor,
+            data.len()
+        );
+    }
+});
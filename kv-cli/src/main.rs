@@ -0,0 +1,214 @@
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Parser};
+use std::io::{self, BufRead, Read, Write};
+
+use redis_starter_rust::client::{self, Client};
+use redis_starter_rust::resptype::*;
+
+#[derive(Parser, Debug)]
+#[command(name = "kv-cli", version, about, long_about = None, disable_help_flag = true)]
+struct Args {
+    #[arg(short, long, default_value_t = String::from("127.0.0.1"))]
+    host: String,
+
+    #[arg(short, long, default_value_t = 6379)]
+    port: u16,
+
+    /// Transfer raw RESP commands read from stdin to the server.
+    #[arg(long)]
+    pipe: bool,
+
+    /// Write every key to stdout as RESP SET commands, ready for --pipe.
+    #[arg(long, conflicts_with = "pipe")]
+    export: bool,
+
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+
+    /// Command to run, starts the interactive prompt when omitted.
+    command: Vec<String>,
+}
+
+fn command_from_args(args: Vec<String>) -> Type {
+    Type::Array(args.into_iter().map(Type::BulkString).collect())
+}
+
+/// Splits a prompt line into arguments, honouring single and double quotes.
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => {
+                let escaped = chars.next().context("unbalanced quotes")?;
+                let escaped = match escaped {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    c => c,
+                };
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                }
+            }
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("unbalanced quotes");
+    }
+    if let Some(arg) = current.take() {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// Formats a reply the way redis-cli does in interactive mode.
+fn format_reply(reply: &Type, indent: usize) -> String {
+    match reply {
+        Type::SimpleString(s) => s.to_string(),
+        Type::SimpleError(s) => format!("(error) {}", s),
+        Type::BulkString(s) => format!("{:?}", s),
+        Type::RDBSyncString(_) => "(rdb payload)".to_string(),
+        Type::NullBulkString => "(nil)".to_string(),
+        Type::Integer(i) => format!("(integer) {}", i),
+        Type::Array(items) if items.is_empty() => "(empty array)".to_string(),
+        Type::Array(items) => {
+            let width = items.len().to_string().len();
+            items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| {
+                    let prefix = format!("{:>width$}) ", i + 1, width = width);
+                    let pad = if i == 0 { 0 } else { indent };
+                    format!(
+                        "{}{}{}",
+                        " ".repeat(pad),
+                        prefix,
+                        format_reply(item, indent + prefix.len())
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    }
+}
+
+async fn run_command(conn: &mut Client, args: Vec<String>) -> Result<()> {
+    conn.send(command_from_args(args)).await?;
+    let reply = conn.read_reply().await?;
+    println!("{}", format_reply(&reply, 0));
+    Ok(())
+}
+
+async fn run_pipe(conn: &mut Client) -> Result<()> {
+    let mut input = Vec::new();
+    io::stdin()
+        .read_to_end(&mut input)
+        .context("reading commands from stdin")?;
+
+    let mut cursor = 0;
+    let mut errors = 0;
+    let mut replies = 0;
+    while cursor < input.len() {
+        let (command, len) = parse_resp(&input[cursor..]).context("parsing commands from stdin")?;
+        cursor += len;
+
+        conn.send(command).await?;
+        if let Type::SimpleError(e) = conn.read_reply().await? {
+            eprintln!("{}", e);
+            errors += 1;
+        }
+        replies += 1;
+    }
+
+    println!("All data transferred.");
+    println!("errors: {}, replies: {}", errors, replies);
+    Ok(())
+}
+
+async fn run_export(conn: &mut Client) -> Result<()> {
+    let keys = match conn.request(&["KEYS", "*"]).await? {
+        Type::Array(keys) => keys,
+        reply => bail!("unexpected reply to KEYS: {}", reply),
+    };
+
+    let mut stdout = io::stdout().lock();
+    let mut exported = 0;
+    for key in keys {
+        let key: String = key.try_into()?;
+        // Keys that expired or were deleted since KEYS ran are skipped.
+        let Type::BulkString(value) = conn.request(&["GET", &key]).await? else {
+            continue;
+        };
+        let pttl: i64 = conn.request_as(&["PTTL", &key]).await?;
+        let px = pttl.to_string();
+        let mut args = vec!["SET", key.as_str(), value.as_str()];
+        match pttl {
+            -1 => {}
+            pttl if pttl > 0 => args.extend(["PX", px.as_str()]),
+            _ => continue,
+        }
+        stdout.write_all(&client::command(&args).serialize())?;
+        exported += 1;
+    }
+    stdout.flush()?;
+    eprintln!("Exported {} keys.", exported);
+    Ok(())
+}
+
+async fn run_repl(conn: &mut Client, prompt: &str) -> Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}> ", prompt);
+        io::stdout().flush()?;
+
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let args = match split_line(&line?) {
+            Ok(args) => args,
+            Err(e) => {
+                println!("Invalid argument(s): {}", e);
+                continue;
+            }
+        };
+        match args.first().map(|cmd| cmd.to_lowercase()) {
+            None => continue,
+            Some(cmd) if cmd == "quit" || cmd == "exit" => return Ok(()),
+            Some(_) => run_command(conn, args).await?,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut conn = Client::connect((args.host.as_str(), args.port))
+        .await
+        .with_context(|| format!("Could not connect to {}:{}", args.host, args.port))?;
+
+    if args.pipe {
+        run_pipe(&mut conn).await
+    } else if args.export {
+        run_export(&mut conn).await
+    } else if !args.command.is_empty() {
+        run_command(&mut conn, args.command).await
+    } else {
+        let prompt = format!("{}:{}", args.host, args.port);
+        run_repl(&mut conn, &prompt).await
+    }
+}
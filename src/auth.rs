@@ -0,0 +1,398 @@
+//! Password checks for `AUTH`. Where passwords come from is up to an
+//! [`AuthProvider`]: a file of hashed passwords, the environment, or a
+//! callback into the program embedding the store, plugged in with
+//! [`ServerBuilder::auth_provider`](crate::ServerBuilder::auth_provider).
+//!
+//! The server remembers passwords a provider accepted for a minute, so slow
+//! providers are not asked on every connection, and turns an address away
+//! for a minute after five failed attempts.
+//!
+//! Providers may also limit what a user can run with an [`Acl`].
+//!
+//! Connections this server makes to others, as a replica, a sentinel or for
+//! `MIGRATE`, log in with a [`MasterAuth`].
+use crate::client::*;
+use crate::clock::*;
+use crate::command::*;
+use crate::resptype::*;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::{Debug, Write as _};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// User `AUTH <password>` authenticates as.
+pub const DEFAULT_USER: &str = "default";
+
+/// How long an accepted password is trusted without asking the provider.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Failed attempts from one address before it is turned away.
+const MAX_FAILURES: u32 = 5;
+
+/// How long an address is turned away for.
+const LOCKOUT: Duration = Duration::from_secs(60);
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>>;
+
+pub type AclFuture<'a> = Pin<Box<dyn Future<Output = Result<Acl>> + Send + 'a>>;
+
+pub type SharedAuthProvider = Arc<dyn AuthProvider>;
+
+pub trait AuthProvider: Send + Sync + Debug {
+    /// Whether `password` is the one of `user`. Errors are for a source that
+    /// can't be read, not for a wrong password.
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> AuthFuture<'a>;
+
+    /// What `user` may run once logged in, asked after a successful check.
+    /// Everything by default.
+    fn acl<'a>(&'a self, user: &'a str) -> AclFuture<'a> {
+        Box::pin(async move { Ok(Acl::allow_all(user)) })
+    }
+}
+
+/// Commands a user may run. Rules follow the password hash in the file read
+/// by [`FileProvider`]: `-@write` takes away every write and `-<command>` a
+/// single command, everything else is allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    user: String,
+    no_writes: bool,
+    denied: Vec<Command>,
+}
+
+impl Acl {
+    pub fn allow_all(user: impl Into<String>) -> Self {
+        Self {
+            user: user.into(),
+            no_writes: false,
+            denied: Vec::new(),
+        }
+    }
+
+    pub fn parse(user: impl Into<String>, rules: &[&str]) -> Result<Self> {
+        let mut acl = Self::allow_all(user);
+        for rule in rules {
+            match rule.strip_prefix('-') {
+                Some(category) if category.eq_ignore_ascii_case("@write") => acl.no_writes = true,
+                Some(name) => {
+                    let command = Command::try_from(&Type::BulkString(name.to_string()))
+                        .with_context(|| format!("invalid rule {}", rule))?;
+                    acl.denied.push(command);
+                }
+                None => bail!(
+                    "invalid rule {}, only -@write and -<command> are supported",
+                    rule
+                ),
+            }
+        }
+        Ok(acl)
+    }
+
+    /// The error running `command` gets, `None` if it is allowed.
+    pub fn check(&self, command: &Command) -> Option<String> {
+        if (self.no_writes && command.is_write()) || self.denied.contains(command) {
+            return Some(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                self.user,
+                command.name().to_lowercase()
+            ));
+        }
+        None
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::allow_all(DEFAULT_USER)
+    }
+}
+
+/// What this server sends in `AUTH` when it connects to another one, like
+/// `masteruser` and `masterauth` in Redis.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MasterAuth {
+    /// `None` logs in as the default user.
+    pub user: Option<String>,
+    pub password: String,
+}
+
+impl MasterAuth {
+    /// Sends `AUTH` on `client`, a refused password is an error.
+    pub(crate) async fn login(&self, client: &mut Client) -> Result<()> {
+        let mut args = vec!["AUTH"];
+        args.extend(self.user.as_deref());
+        args.push(&self.password);
+        match client.request(&args).await? {
+            Type::SimpleError(e) => bail!("AUTH refused: {}", e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of `password`, as stored in the file read by
+/// [`FileProvider`].
+pub fn hash_password(password: &str) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(password.as_bytes()) {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Compares all of `a` and `b` whatever they start with, so how long it
+/// takes doesn't tell how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    a.len() == b.len() && std::hint::black_box(diff) == 0
+}
+
+/// Reads users from a file with one `<user> <sha256 of password> [rule...]`
+/// line each, see [`hash_password`] and [`Acl`]. Blank lines and lines
+/// starting with `#` are skipped. The file is read again on every check, so
+/// edits apply without a restart.
+#[derive(Debug, Clone)]
+pub struct FileProvider {
+    path: PathBuf,
+}
+
+impl FileProvider {
+    /// Uses `path`, which must already hold a valid file.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let provider = Self { path: path.into() };
+        let contents = std::fs::read_to_string(&provider.path)
+            .with_context(|| format!("reading {}", provider.path.display()))?;
+        parse_users(&contents)?;
+        Ok(provider)
+    }
+}
+
+fn parse_users(contents: &str) -> Result<HashMap<&str, (&str, Acl)>> {
+    let mut users = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [user, hash, rules @ ..] = fields.as_slice() else {
+            bail!("line {}: expected a user and a password hash", i + 1);
+        };
+        let acl = Acl::parse(*user, rules).with_context(|| format!("line {}", i + 1))?;
+        users.insert(*user, (*hash, acl));
+    }
+    Ok(users)
+}
+
+impl FileProvider {
+    async fn read(&self) -> Result<String> {
+        tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("reading {}", self.path.display()))
+    }
+}
+
+impl AuthProvider for FileProvider {
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let contents = self.read().await?;
+            let users = parse_users(&contents)
+                .with_context(|| format!("parsing {}", self.path.display()))?;
+            Ok(users.get(user).is_some_and(|(hash, _)| {
+                constant_time_eq(
+                    hash.to_ascii_lowercase().as_bytes(),
+                    hash_password(password).as_bytes(),
+                )
+            }))
+        })
+    }
+
+    fn acl<'a>(&'a self, user: &'a str) -> AclFuture<'a> {
+        Box::pin(async move {
+            let contents = self.read().await?;
+            let mut users = parse_users(&contents)
+                .with_context(|| format!("parsing {}", self.path.display()))?;
+            let (_, acl) = users.remove(user).context("no such user")?;
+            Ok(acl)
+        })
+    }
+}
+
+/// Reads the password of each user from the environment variable named
+/// after the prefix and the upper cased user, `KV_PASSWORD_DEFAULT` for the
+/// default user with a `KV_PASSWORD_` prefix. Users without one can't log
+/// in.
+#[derive(Debug, Clone)]
+pub struct EnvProvider {
+    prefix: String,
+}
+
+impl EnvProvider {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl AuthProvider for EnvProvider {
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let name = format!("{}{}", self.prefix, user.to_uppercase().replace('-', "_"));
+            Ok(std::env::var(name)
+                .is_ok_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes())))
+        })
+    }
+}
+
+/// Asks a function of the embedding program, which gets the user and the
+/// password.
+pub struct CallbackProvider<F> {
+    callback: F,
+}
+
+impl<F, Fut> CallbackProvider<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<bool>> + Send + 'static,
+{
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> Debug for CallbackProvider<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CallbackProvider")
+    }
+}
+
+impl<F, Fut> AuthProvider for CallbackProvider<F>
+where
+    F: Fn(String, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<bool>> + Send + 'static,
+{
+    fn check<'a>(&'a self, user: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin((self.callback)(user.to_string(), password.to_string()))
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Checks passwords with a provider, caching what it accepts and limiting
+/// how often each address may fail.
+#[derive(Debug)]
+pub(crate) struct Authenticator {
+    provider: SharedAuthProvider,
+    clock: SharedClock,
+    /// Salted hashes of accepted user and password pairs, so the passwords
+    /// themselves are not kept, when to stop trusting them and what the
+    /// user may run.
+    accepted: Mutex<HashMap<[u8; 32], (Instant, Acl)>>,
+    salt: [u8; 8],
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(provider: SharedAuthProvider, clock: SharedClock) -> Self {
+        Self {
+            provider,
+            clock,
+            accepted: Mutex::default(),
+            salt: RandomState::new().build_hasher().finish().to_le_bytes(),
+            failures: Mutex::default(),
+        }
+    }
+
+    /// What `user` may run if `password` is theirs, for a client connecting
+    /// from `addr`. Fails without asking the provider while `addr` is
+    /// turned away.
+    pub(crate) async fn authenticate(
+        &self,
+        addr: IpAddr,
+        user: &str,
+        password: &str,
+    ) -> Result<Option<Acl>> {
+        let now = self.clock.now();
+        if self.locked_out(addr, now) {
+            bail!("too many failed AUTH attempts, try again later");
+        }
+        let key: [u8; 32] = Sha256::new()
+            .chain_update(self.salt)
+            .chain_update((user.len() as u64).to_le_bytes())
+            .chain_update(user)
+            .chain_update(password)
+            .finalize()
+            .into();
+        let cached = match self.accepted.lock().unwrap().get(&key) {
+            Some((until, acl)) if now < *until => Some(acl.clone()),
+            _ => None,
+        };
+        if let Some(acl) = cached {
+            self.failures.lock().unwrap().remove(&addr);
+            return Ok(Some(acl));
+        }
+
+        // Count the attempt as failed while the provider is asked, so
+        // attempts made meanwhile can't get past the limit.
+        {
+            let mut failures = self.failures.lock().unwrap();
+            let failures = failures.entry(addr).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            // Failures from before the last lockout ended are forgiven.
+            if now >= failures.last + LOCKOUT {
+                failures.count = 0;
+            }
+            if failures.count >= MAX_FAILURES {
+                bail!("too many failed AUTH attempts, try again later");
+            }
+            failures.count += 1;
+            failures.last = now;
+        }
+        let acl = match self.provider.check(user, password).await {
+            Ok(true) => self.provider.acl(user).await.map(Some),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let now = self.clock.now();
+        let mut failures = self.failures.lock().unwrap();
+        match &acl {
+            Ok(Some(acl)) => {
+                let mut accepted = self.accepted.lock().unwrap();
+                accepted.retain(|_, (until, _)| now < *until);
+                accepted.insert(key, (now + CACHE_TTL, acl.clone()));
+                failures.remove(&addr);
+            }
+            Ok(None) => {}
+            // A provider that fails is not a wrong password.
+            Err(_) => {
+                if let Some(failures) = failures.get_mut(&addr) {
+                    failures.count = failures.count.saturating_sub(1);
+                }
+            }
+        }
+        failures.retain(|_, failures| failures.count > 0 && now < failures.last + LOCKOUT);
+        acl
+    }
+
+    fn locked_out(&self, addr: IpAddr, now: Instant) -> bool {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .is_some_and(|failures| failures.count >= MAX_FAILURES && now < failures.last + LOCKOUT)
+    }
+}
@@ -0,0 +1,147 @@
+//! External storage behind the in-memory database, which turns the server
+//! into a cache in front of it. Reads of keys that are not in memory are
+//! forwarded to the [`Backend`] and what it returns is kept, and every write
+//! and deletion is also sent to it once it has been applied.
+//!
+//! [`FileBackend`] keeps one file per key in a directory, other backends
+//! such as SQL or HTTP services can be plugged in with
+//! [`ServerBuilder::backend`](crate::ServerBuilder::backend).
+use anyhow::{ensure, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Write as _};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+pub type SharedBackend = Arc<dyn Backend>;
+
+/// A value as kept by a backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    pub value: String,
+    /// Unix time in milliseconds the value expires at, backends return
+    /// expired values and the server drops them.
+    pub expires_at: Option<u64>,
+}
+
+impl Stored {
+    /// A value that does not expire.
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            expires_at: None,
+        }
+    }
+}
+
+pub trait Backend: Send + Sync + Debug {
+    /// The value stored under `key`, `None` if there is none.
+    fn load<'a>(&'a self, key: &'a str) -> BackendFuture<'a, Option<Stored>>;
+
+    /// Stores `value` under `key`, replacing what was there.
+    fn store<'a>(&'a self, key: &'a str, value: &'a Stored) -> BackendFuture<'a, ()>;
+
+    /// Deletes `key`, if it is there.
+    fn remove<'a>(&'a self, key: &'a str) -> BackendFuture<'a, ()>;
+}
+
+/// Stores each key in its own file under a directory, named after the hex
+/// encoded SHA-256 of the key so that long keys fit in a file name. The
+/// first line of a file holds the unix time in milliseconds the value
+/// expires at, empty if it does not, the second one the length of the key,
+/// then come the key and the value.
+#[derive(Debug, Clone)]
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    /// Uses `dir`, creating it if needed.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("creating backend directory {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut name = String::with_capacity(64);
+        for byte in Sha256::digest(key.as_bytes()) {
+            let _ = write!(name, "{:02x}", byte);
+        }
+        self.dir.join(name)
+    }
+}
+
+fn parse_file(key: &str, contents: &str) -> Result<Stored> {
+    let (expires_at, rest) = contents.split_once('\n').context("no header")?;
+    let expires_at = match expires_at {
+        "" => None,
+        expires_at => Some(expires_at.parse().context("invalid expiry")?),
+    };
+    let (key_len, rest) = rest.split_once('\n').context("no key length")?;
+    let key_len: usize = key_len.parse().context("invalid key length")?;
+    let (stored_key, value) = (rest.get(..key_len), rest.get(key_len..));
+    ensure!(stored_key == Some(key), "the file holds another key");
+    Ok(Stored {
+        value: value.unwrap_or_default().to_string(),
+        expires_at,
+    })
+}
+
+impl Backend for FileBackend {
+    fn load<'a>(&'a self, key: &'a str) -> BackendFuture<'a, Option<Stored>> {
+        Box::pin(async move {
+            let contents = match tokio::fs::read_to_string(self.path(key)).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("reading {:?} from the backend", key))
+                }
+            };
+            parse_file(key, &contents)
+                .map(Some)
+                .with_context(|| format!("reading {:?} from the backend", key))
+        })
+    }
+
+    fn store<'a>(&'a self, key: &'a str, value: &'a Stored) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            // Write to a temporary file first so readers never see half a value.
+            static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+            let path = self.path(key);
+            let tmp =
+                path.with_extension(format!("tmp{}", NEXT_TMP.fetch_add(1, Ordering::Relaxed)));
+            let expires_at = value.expires_at.map(|at| at.to_string());
+            let contents = format!(
+                "{}\n{}\n{}{}",
+                expires_at.unwrap_or_default(),
+                key.len(),
+                key,
+                value.value
+            );
+            tokio::fs::write(&tmp, contents)
+                .await
+                .with_context(|| format!("writing {:?} to the backend", key))?;
+            tokio::fs::rename(&tmp, &path)
+                .await
+                .with_context(|| format!("writing {:?} to the backend", key))
+        })
+    }
+
+    fn remove<'a>(&'a self, key: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(key)).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("deleting {:?} from the backend", key))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+}
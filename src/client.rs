@@ -0,0 +1,134 @@
+use crate::resptype::*;
+use anyhow::{bail, ensure, Context, Result};
+use std::str;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+/// Builds a command as an array of bulk strings.
+pub fn command(args: &[&str]) -> Type {
+    Type::Array(
+        args.iter()
+            .map(|arg| Type::BulkString(arg.to_string()))
+            .collect(),
+    )
+}
+
+/// Async RESP client used for the replica link and by kv-cli.
+///
+/// Replies are read into an internal buffer until a complete value has
+/// arrived, anything past that value stays buffered for the next read.
+/// Values larger than [`MAX_FRAME_LEN`] are refused.
+#[derive(Debug)]
+pub struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    scanner: Scanner,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            scanner: Scanner::default(),
+        }
+    }
+
+    pub async fn send(&mut self, command: Type) -> Result<()> {
+        self.write_raw(&command.serialize()).await
+    }
+
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Sends `args` as a command and waits for its reply.
+    pub async fn request(&mut self, args: &[&str]) -> Result<Type> {
+        self.send(command(args)).await?;
+        self.read_reply().await
+    }
+
+    /// Like `request`, but turns error replies into `Err` and converts the
+    /// reply into `T`.
+    pub async fn request_as<T>(&mut self, args: &[&str]) -> Result<T>
+    where
+        T: TryFrom<Type, Error = anyhow::Error>,
+    {
+        match self.request(args).await? {
+            Type::SimpleError(e) => bail!("{}", e),
+            reply => reply.try_into(),
+        }
+    }
+
+    pub async fn read_reply(&mut self) -> Result<Type> {
+        let (reply, _) = self
+            .read_frame()
+            .await?
+            .context("connection closed before a reply arrived")?;
+        Ok(reply)
+    }
+
+    /// Waits for the next complete value on the connection and returns it
+    /// together with its raw encoding, or `None` once the peer has closed the
+    /// connection cleanly.
+    pub async fn read_frame(&mut self) -> Result<Option<(Type, Vec<u8>)>> {
+        loop {
+            if let Some(len) = self.scanner.scan(&self.buffer)? {
+                let (value, _) = parse_resp(&self.buffer[..len])?;
+                let raw = self.buffer.drain(..len).collect();
+                return Ok(Some((value, raw)));
+            }
+            ensure!(
+                self.buffer.len() <= MAX_FRAME_LEN,
+                "frame longer than {} bytes",
+                MAX_FRAME_LEN
+            );
+            if self.fill().await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                bail!("connection closed in the middle of a frame");
+            }
+        }
+    }
+
+    /// Reads the RDB payload a master sends after FULLRESYNC. It is framed
+    /// like a bulk string but without the trailing CRLF.
+    pub async fn read_rdb(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(i) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                if self.buffer[0] != b'$' {
+                    bail!("expected RDB payload, got {:?}", &self.buffer[..i]);
+                }
+                let len: usize = str::from_utf8(&self.buffer[1..i])?
+                    .parse()
+                    .context("parsing RDB payload length")?;
+                ensure!(len <= MAX_FRAME_LEN, "invalid RDB payload length {}", len);
+                let end = i + 2 + len;
+                if self.buffer.len() >= end {
+                    let rdb = self.buffer[i + 2..end].to_vec();
+                    self.buffer.drain(..end);
+                    return Ok(rdb);
+                }
+            }
+            if self.fill().await? == 0 {
+                bail!("connection closed while reading RDB payload");
+            }
+        }
+    }
+
+    async fn fill(&mut self) -> Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let len = self.stream.read(&mut chunk).await?;
+        self.buffer.extend_from_slice(&chunk[..len]);
+        Ok(len)
+    }
+}
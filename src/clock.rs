@@ -0,0 +1,117 @@
+//! Time source for key expiry, replication heartbeats and the slow log.
+//!
+//! Everything that reads the time goes through a [`Clock`], so tests can hand
+//! the server a [`MockClock`] and move time forward by hand instead of
+//! sleeping.
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub trait Clock: Send + Sync + Debug {
+    /// Monotonic time, used for expiry deadlines and measuring durations.
+    fn now(&self) -> Instant;
+
+    /// Wall clock time since the unix epoch.
+    fn unix_time(&self) -> Duration;
+
+    /// Resolves once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when [`MockClock::advance`] is called.
+///
+/// ```
+/// use redis_starter_rust::clock::{Clock, MockClock};
+/// use std::time::Duration;
+///
+/// let clock = MockClock::at(Duration::from_secs(1_700_000_000));
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(5));
+/// assert_eq!(clock.now() - start, Duration::from_secs(5));
+/// assert_eq!(clock.unix_time(), Duration::from_secs(1_700_000_005));
+/// ```
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    unix_start: Duration,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl MockClock {
+    /// Starts at the current wall clock time.
+    pub fn new() -> Self {
+        Self::at(SystemClock.unix_time())
+    }
+
+    /// Starts at `unix_time` since the epoch.
+    pub fn at(unix_time: Duration) -> Self {
+        let (elapsed, _) = watch::channel(Duration::ZERO);
+        Self {
+            start: Instant::now(),
+            unix_start: unix_time,
+            elapsed,
+        }
+    }
+
+    /// Moves the clock forward, waking any sleeps that are now due.
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += by);
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.borrow()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn unix_time(&self) -> Duration {
+        self.unix_start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut elapsed = self.elapsed.subscribe();
+        let deadline = *elapsed.borrow() + duration;
+        Box::pin(async move {
+            while *elapsed.borrow_and_update() < deadline {
+                if elapsed.changed().await.is_err() {
+                    // The clock is gone and will never reach the deadline.
+                    std::future::pending::<()>().await;
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,1184 @@
+//! Cluster mode: keys are spread over 16384 hash slots and each node only
+//! serves the keys whose slot it owns, redirecting clients to the node that
+//! owns the others.
+use crate::auth::*;
+use crate::client::Client;
+use crate::clock::*;
+use crate::command::*;
+use crate::frame::*;
+use crate::gossip::*;
+use crate::log::*;
+use crate::resptype::*;
+use crate::server::*;
+use anyhow::{bail, Context, Result};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const SLOTS: u16 = 16384;
+
+/// Redis' default `cluster-node-timeout`.
+pub const DEFAULT_NODE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Reply to keys whose slot no node serves.
+const SLOT_NOT_SERVED: &str = "CLUSTERDOWN Hash slot not served";
+/// Reply to commands and transactions whose keys are in several slots.
+const CROSSSLOT: &str = "CROSSSLOT Keys in request don't hash to the same slot";
+/// Reply to multi-key commands on a slot whose keys are halfway migrated.
+const TRYAGAIN: &str = "TRYAGAIN Multiple keys request during rehashing of slot";
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster hashes keys with.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Slot of `key`. Only the part inside the first non-empty `{...}` is hashed
+/// if there is one, so related keys can be kept on the same node.
+pub fn key_hash_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let hashed = match bytes.iter().position(|&b| b == b'{') {
+        Some(open) => match bytes[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &bytes[open + 1..open + 1 + len],
+            _ => bytes,
+        },
+        None => bytes,
+    };
+    crc16(hashed) % SLOTS
+}
+
+/// Parses a slot range such as `0-8191`, or a single slot.
+pub fn parse_slot_range(s: &str) -> Result<RangeInclusive<u16>> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|&n| n < SLOTS)
+            .with_context(|| format!("invalid slot {:?}, must be below {}", n, SLOTS))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start > end {
+        bail!("invalid slot range {:?}", s);
+    }
+    Ok(start..=end)
+}
+
+/// Random 40 character hex id, like the node ids Redis generates.
+pub(crate) fn random_node_id() -> String {
+    let mut id = String::new();
+    for i in 0..3 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(i);
+        hasher.write_u32(std::process::id());
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(40);
+    id
+}
+
+/// Whether a node answers on the cluster bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Online,
+    /// This node has not heard from it for longer than the node timeout.
+    PFail,
+    /// A majority of the masters agree it is unreachable.
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    /// Address clients are redirected to.
+    pub addr: SocketAddr,
+    /// Port of the cluster bus, on the same host as `addr`.
+    pub bus_port: u16,
+    /// Id of the master this node replicates, `None` for masters.
+    pub replica_of: Option<String>,
+    /// Epoch of the slot ownership this node claims, the higher one wins
+    /// when two nodes claim the same slot.
+    pub config_epoch: u64,
+    pub health: Health,
+}
+
+impl Node {
+    pub fn bus_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.ip(), self.bus_port)
+    }
+}
+
+#[derive(Debug)]
+struct ClusterState {
+    /// Index of this node in `nodes`.
+    myself: usize,
+    nodes: Vec<Node>,
+    /// Index into `nodes` of the owner of each slot.
+    slots: Vec<Option<usize>>,
+    /// Slots being moved away from this node, and the node they go to.
+    migrating: HashMap<u16, usize>,
+    /// Slots being moved to this node, and the node they come from.
+    importing: HashMap<u16, usize>,
+    /// Highest epoch seen anywhere in the cluster.
+    current_epoch: u64,
+    /// When each node was last heard from on the bus.
+    last_pong: HashMap<usize, Instant>,
+    /// Masters that reported a node as failing, and when they last did.
+    fail_reports: HashMap<usize, HashMap<usize, Instant>>,
+    /// Bus addresses to introduce this node to with `CLUSTER MEET`.
+    meets: Vec<SocketAddr>,
+    /// Client address of the master this node replicates, its id is only
+    /// known once they meet on the bus.
+    master_addr: Option<SocketAddr>,
+    /// When this replica may ask for votes to replace its failed master.
+    next_election: Option<Instant>,
+    /// Epoch this master last voted in, it votes at most once per epoch.
+    last_vote_epoch: u64,
+    /// Failed masters this node voted to replace, and when.
+    voted_for: HashMap<usize, Instant>,
+}
+
+impl ClusterState {
+    fn new(myself: usize, nodes: Vec<Node>, slots: Vec<Option<usize>>) -> Self {
+        let current_epoch = nodes.iter().map(|node| node.config_epoch).max();
+        Self {
+            myself,
+            nodes,
+            slots,
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            current_epoch: current_epoch.unwrap_or_default(),
+            last_pong: HashMap::new(),
+            fail_reports: HashMap::new(),
+            meets: Vec::new(),
+            master_addr: None,
+            next_election: None,
+            last_vote_epoch: 0,
+            voted_for: HashMap::new(),
+        }
+    }
+
+    /// A cluster of one node that owns no slots yet.
+    fn alone(addr: SocketAddr, bus_port: u16) -> Self {
+        let myself = Node {
+            id: random_node_id(),
+            addr,
+            bus_port,
+            replica_of: None,
+            config_epoch: 0,
+            health: Health::Online,
+        };
+        Self::new(0, vec![myself], vec![None; SLOTS as usize])
+    }
+
+    /// Contiguous runs of slots with the same owner, as `(start, end, node)`.
+    fn slot_ranges(&self) -> Vec<(u16, u16, usize)> {
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let Some(owner) = *owner else {
+                continue;
+            };
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, end, node)) if *node == owner && *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot, owner)),
+            }
+        }
+        ranges
+    }
+
+    /// Indexes of the nodes replicating `master`.
+    fn replicas_of(&self, master: usize) -> impl Iterator<Item = usize> + '_ {
+        let id = &self.nodes[master].id;
+        (0..self.nodes.len()).filter(move |&i| self.nodes[i].replica_of.as_ref() == Some(id))
+    }
+
+    /// A node as listed by `CLUSTER SLOTS`: ip, port and id.
+    fn slots_entry(&self, node: usize) -> Type {
+        let node = &self.nodes[node];
+        Type::Array(vec![
+            Type::BulkString(node.addr.ip().to_string()),
+            Type::Integer(node.addr.port().to_string()),
+            Type::BulkString(node.id.clone()),
+        ])
+    }
+
+    /// A node as listed by `CLUSTER SHARDS`, a map flattened into an array.
+    fn shards_entry(&self, node: usize) -> Type {
+        let node = &self.nodes[node];
+        let role = match node.replica_of {
+            Some(_) => "replica",
+            None => "master",
+        };
+        let health = match node.health {
+            Health::Fail => "failed",
+            Health::Online | Health::PFail => "online",
+        };
+        Type::Array(vec![
+            Type::BulkString("id".to_string()),
+            Type::BulkString(node.id.clone()),
+            Type::BulkString("port".to_string()),
+            Type::Integer(node.addr.port().to_string()),
+            Type::BulkString("ip".to_string()),
+            Type::BulkString(node.addr.ip().to_string()),
+            Type::BulkString("endpoint".to_string()),
+            Type::BulkString(node.addr.ip().to_string()),
+            Type::BulkString("role".to_string()),
+            Type::BulkString(role.to_string()),
+            Type::BulkString("replication-offset".to_string()),
+            Type::Integer("0".to_string()),
+            Type::BulkString("health".to_string()),
+            Type::BulkString(health.to_string()),
+        ])
+    }
+
+    /// A node as listed by `CLUSTER NODES`, in the nodes.conf format.
+    fn nodes_line(&self, i: usize, now: Instant, unix_now: Duration) -> String {
+        let node = &self.nodes[i];
+        let mut flags = Vec::new();
+        if i == self.myself {
+            flags.push("myself");
+        }
+        flags.push(match node.replica_of {
+            Some(_) => "slave",
+            None => "master",
+        });
+        match node.health {
+            Health::Online => {}
+            Health::PFail => flags.push("fail?"),
+            Health::Fail => flags.push("fail"),
+        }
+        let pong = match self.last_pong.get(&i) {
+            Some(&at) if i != self.myself => unix_now.saturating_sub(now - at).as_millis(),
+            _ => 0,
+        };
+        let link = match node.health {
+            Health::Online => "connected",
+            _ => "disconnected",
+        };
+        let mut line = format!(
+            "{} {}@{} {} {} 0 {} {} {}",
+            node.id,
+            node.addr,
+            node.bus_port,
+            flags.join(","),
+            node.replica_of.as_deref().unwrap_or("-"),
+            pong,
+            node.config_epoch,
+            link
+        );
+        for (start, end, _) in self.slot_ranges().into_iter().filter(|r| r.2 == i) {
+            if start == end {
+                line.push_str(&format!(" {}", start));
+            } else {
+                line.push_str(&format!(" {}-{}", start, end));
+            }
+        }
+        if i == self.myself {
+            let mut migrating: Vec<_> = self.migrating.iter().collect();
+            migrating.sort();
+            for (slot, &to) in migrating {
+                line.push_str(&format!(" [{}->-{}]", slot, self.nodes[to].id));
+            }
+            let mut importing: Vec<_> = self.importing.iter().collect();
+            importing.sort();
+            for (slot, &from) in importing {
+                line.push_str(&format!(" [{}-<-{}]", slot, self.nodes[from].id));
+            }
+        }
+        line
+    }
+
+    /// Makes node `i` the master of this node if it is the one `--replicaof`
+    /// pointed at.
+    fn adopt_master(&mut self, i: usize) {
+        let myself = self.myself;
+        if i != myself
+            && self.nodes[myself].replica_of.is_none()
+            && self.master_addr == Some(self.nodes[i].addr)
+        {
+            self.nodes[myself].replica_of = Some(self.nodes[i].id.clone());
+        }
+    }
+
+    /// The failed master this replica should take over from, if any.
+    fn failed_master(&self) -> Option<usize> {
+        let master = self
+            .node_index(self.nodes[self.myself].replica_of.as_ref()?)
+            .ok()?;
+        (self.nodes[master].health == Health::Fail).then_some(master)
+    }
+
+    fn is_master(&self, node: usize) -> bool {
+        self.nodes[node].replica_of.is_none()
+    }
+
+    /// Masters owning at least one slot, which is what failure votes are
+    /// counted against.
+    fn cluster_size(&self) -> usize {
+        let mut masters: Vec<usize> = self.slots.iter().flatten().copied().collect();
+        masters.sort_unstable();
+        masters.dedup();
+        masters.len()
+    }
+
+    /// Whether this node votes for replica `candidate` to replace its failed
+    /// master, recording the vote if it does. Only masters owning slots vote,
+    /// once per epoch and once per failed master within `window`. `behind`
+    /// is set if the request's epoch is older than ours.
+    fn grant_vote(
+        &mut self,
+        candidate: usize,
+        behind: bool,
+        now: Instant,
+        window: Duration,
+    ) -> bool {
+        let Some(master) = self.nodes[candidate]
+            .replica_of
+            .as_ref()
+            .and_then(|id| self.node_index(id).ok())
+        else {
+            return false;
+        };
+        let voted_recently = self
+            .voted_for
+            .get(&master)
+            .is_some_and(|&at| now - at < window);
+        if behind
+            || !self.slots.contains(&Some(self.myself))
+            || self.nodes[master].health != Health::Fail
+            || self.last_vote_epoch >= self.current_epoch
+            || voted_recently
+        {
+            return false;
+        }
+        self.last_vote_epoch = self.current_epoch;
+        self.voted_for.insert(master, now);
+        true
+    }
+
+    /// Gives this node a new epoch higher than any other, so the slots it
+    /// claims win over older claims.
+    fn bump_epoch(&mut self) {
+        self.current_epoch += 1;
+        let myself = self.myself;
+        self.nodes[myself].config_epoch = self.current_epoch;
+    }
+
+    fn node_index(&self, id: &str) -> Result<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.id == id)
+            .with_context(|| format!("Unknown node {}", id))
+    }
+
+    /// Adds or replaces `node`, returning its index.
+    fn add_node(&mut self, node: Node) -> usize {
+        match self.nodes.iter().position(|known| known.id == node.id) {
+            Some(i) => {
+                self.nodes[i] = node;
+                i
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+}
+
+/// Parses the topology from `CLUSTER NODES` style lines, as found in a Redis
+/// nodes.conf: `<id> <ip:port[@cport]> <flags> <master> <ping-sent>
+/// <pong-recv> <epoch> <link-state> <slot>...`.
+fn parse_nodes(contents: &str) -> Result<ClusterState> {
+    let mut nodes = Vec::new();
+    let mut myself = None;
+    let mut owned = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("vars ") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            bail!("line {}: expected at least 8 fields", n + 1);
+        }
+        let mut addr_fields = fields[1].split(',').next().unwrap_or_default().split('@');
+        let addr: SocketAddr = addr_fields
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .with_context(|| format!("line {}: invalid address {:?}", n + 1, fields[1]))?;
+        let bus_port = match addr_fields.next() {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("line {}: invalid bus port {:?}", n + 1, port))?,
+            None => default_bus_port(addr.port())?,
+        };
+        let config_epoch = fields[6]
+            .parse()
+            .with_context(|| format!("line {}: invalid epoch {:?}", n + 1, fields[6]))?;
+        let health = match fields[2].split(',').find(|flag| flag.starts_with("fail")) {
+            Some("fail") => Health::Fail,
+            Some(_) => Health::PFail,
+            None => Health::Online,
+        };
+        if fields[2].split(',').any(|flag| flag == "myself") {
+            if myself.is_some() {
+                bail!("line {}: more than one node is flagged myself", n + 1);
+            }
+            myself = Some(nodes.len());
+        }
+        nodes.push(Node {
+            id: fields[0].to_string(),
+            addr,
+            bus_port,
+            replica_of: (fields[3] != "-").then(|| fields[3].to_string()),
+            config_epoch,
+            health,
+        });
+        // Migration markers like `[42->-id]` are runtime state, not ownership.
+        for range in fields[8..].iter().filter(|range| !range.starts_with('[')) {
+            let range = parse_slot_range(range).with_context(|| format!("line {}", n + 1))?;
+            owned.push((range, nodes.len() - 1));
+        }
+    }
+
+    let mut slots = vec![None; SLOTS as usize];
+    for (range, node) in owned {
+        for slot in range {
+            slots[slot as usize] = Some(node);
+        }
+    }
+    let myself = myself.context("no node is flagged myself")?;
+    Ok(ClusterState::new(myself, nodes, slots))
+}
+
+/// Redis puts the cluster bus 10000 ports above the client port by default.
+pub fn default_bus_port(port: u16) -> Result<u16> {
+    port.checked_add(10000)
+        .with_context(|| format!("no default cluster bus port for port {}", port))
+}
+
+/// Cluster state shared by every connection of a node and its cluster bus.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    state: Arc<Mutex<ClusterState>>,
+    clock: SharedClock,
+    node_timeout: Duration,
+}
+
+impl Cluster {
+    /// A cluster with only this node in it, owning `slots`.
+    pub fn new(addr: SocketAddr, bus_port: u16, slots: RangeInclusive<u16>) -> Self {
+        let mut state = ClusterState::alone(addr, bus_port);
+        for slot in slots {
+            state.slots[slot as usize] = Some(0);
+        }
+        Self::from_state(state)
+    }
+
+    /// A cluster with only this node in it, replicating the node that
+    /// listens for clients at `master_addr` once it shows up on the bus.
+    pub fn replica(addr: SocketAddr, bus_port: u16, master_addr: SocketAddr) -> Self {
+        let mut state = ClusterState::alone(addr, bus_port);
+        state.master_addr = Some(master_addr);
+        Self::from_state(state)
+    }
+
+    /// Loads the topology from a nodes.conf style file. The node flagged
+    /// `myself` is this one, listening at `addr` and `bus_port`.
+    pub fn load(path: &Path, addr: SocketAddr, bus_port: u16) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading cluster config file {}", path.display()))?;
+        let mut state = parse_nodes(&contents)
+            .with_context(|| format!("parsing cluster config file {}", path.display()))?;
+        let myself = state.myself;
+        state.nodes[myself].addr = addr;
+        state.nodes[myself].bus_port = bus_port;
+        Ok(Self::from_state(state))
+    }
+
+    fn from_state(state: ClusterState) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            clock: Arc::new(SystemClock),
+            node_timeout: DEFAULT_NODE_TIMEOUT,
+        }
+    }
+
+    /// Time source and timeout for failure detection, a node is flagged as
+    /// failing once it has not answered for `node_timeout`.
+    pub fn with_node_timeout(mut self, clock: SharedClock, node_timeout: Duration) -> Self {
+        self.clock = clock;
+        self.node_timeout = node_timeout;
+        self
+    }
+
+    pub fn node_timeout(&self) -> Duration {
+        self.node_timeout
+    }
+
+    pub(crate) fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    pub fn myself(&self) -> String {
+        let state = self.state.lock().unwrap();
+        state.nodes[state.myself].id.clone()
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.state.lock().unwrap().nodes.clone()
+    }
+
+    /// Adds `node` to the routing table, or updates it if its id is known.
+    pub fn add_node(&self, node: Node) {
+        self.state.lock().unwrap().add_node(node);
+    }
+
+    /// Records `node_id` as the owner of `slots`.
+    pub fn assign_slots(&self, slots: RangeInclusive<u16>, node_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let node = state.node_index(node_id)?;
+        for slot in slots {
+            state.slots[slot as usize] = Some(node);
+        }
+        Ok(())
+    }
+
+    /// Starts moving `slot` to `node_id`, keys missing here are redirected
+    /// there with `-ASK`.
+    pub fn set_migrating(&self, slot: u16, node_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let node = state.node_index(node_id)?;
+        state.migrating.insert(slot, node);
+        Ok(())
+    }
+
+    /// Starts taking over `slot` from `node_id`, commands sent after `ASKING`
+    /// are served for it.
+    pub fn set_importing(&self, slot: u16, node_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let node = state.node_index(node_id)?;
+        state.importing.insert(slot, node);
+        Ok(())
+    }
+
+    /// A message about this node and what it knows of the others.
+    pub(crate) fn message(&self, kind: Kind) -> Message {
+        let state = self.state.lock().unwrap();
+        let slots = state
+            .slot_ranges()
+            .into_iter()
+            .filter(|&(_, _, owner)| owner == state.myself)
+            .map(|(start, end, _)| start..=end)
+            .collect();
+        let gossip = (0..state.nodes.len())
+            .filter(|&i| i != state.myself)
+            .map(|i| state.nodes[i].clone())
+            .collect();
+        Message {
+            kind,
+            sender: state.nodes[state.myself].clone(),
+            current_epoch: state.current_epoch,
+            slots,
+            gossip,
+        }
+    }
+
+    /// Ids and bus addresses of the other nodes.
+    pub(crate) fn peers(&self) -> Vec<(String, SocketAddr)> {
+        let state = self.state.lock().unwrap();
+        (0..state.nodes.len())
+            .filter(|&i| i != state.myself)
+            .map(|i| (state.nodes[i].id.clone(), state.nodes[i].bus_addr()))
+            .collect()
+    }
+
+    /// Bus addresses given to `CLUSTER MEET` since the last call.
+    pub(crate) fn take_meets(&self) -> Vec<SocketAddr> {
+        mem::take(&mut self.state.lock().unwrap().meets)
+    }
+
+    /// Applies what `message` tells about its sender and the rest of the
+    /// cluster, returning the reply to send back if it needs one.
+    pub(crate) fn receive(&self, message: Message) -> Option<Message> {
+        let now = self.clock.now();
+        let kind = message.kind;
+        let voted = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            if message.sender.id == state.nodes[state.myself].id {
+                return None;
+            }
+            // Hearing from a node at all proves it is reachable again.
+            let sender = Node {
+                health: Health::Online,
+                ..message.sender
+            };
+            let sender_index = state.add_node(sender.clone());
+            state.adopt_master(sender_index);
+            state.last_pong.insert(sender_index, now);
+            state.fail_reports.remove(&sender_index);
+            let behind = message.current_epoch < state.current_epoch;
+            state.current_epoch = state
+                .current_epoch
+                .max(message.current_epoch)
+                .max(sender.config_epoch);
+
+            if sender.replica_of.is_none() {
+                let mut lost = false;
+                for slot in message.slots.into_iter().flatten() {
+                    if state.importing.contains_key(&slot) {
+                        continue;
+                    }
+                    let stale = match state.slots[slot as usize] {
+                        Some(owner) => {
+                            owner != sender_index
+                                && state.nodes[owner].config_epoch < sender.config_epoch
+                        }
+                        None => true,
+                    };
+                    if stale {
+                        lost |= state.slots[slot as usize] == Some(state.myself);
+                        state.slots[slot as usize] = Some(sender_index);
+                        state.migrating.remove(&slot);
+                    }
+                }
+                // A master that comes back after being replaced finds all its
+                // slots taken and follows the node that took them.
+                let myself = state.myself;
+                if lost && !state.slots.contains(&Some(myself)) {
+                    state.nodes[myself].replica_of = Some(sender.id.clone());
+                }
+            }
+
+            for node in message.gossip {
+                if node.id == state.nodes[state.myself].id {
+                    continue;
+                }
+                let Ok(i) = state.node_index(&node.id) else {
+                    // Meet nodes others know about, unless they are down.
+                    if node.health != Health::Fail {
+                        let i = state.add_node(Node {
+                            health: Health::Online,
+                            ..node
+                        });
+                        state.adopt_master(i);
+                        state.last_pong.insert(i, now);
+                    }
+                    continue;
+                };
+                if kind == Kind::Fail {
+                    state.nodes[i].health = Health::Fail;
+                    continue;
+                }
+                // Only masters get a say in whether a node failed.
+                if !state.is_master(sender_index) {
+                    continue;
+                }
+                let reports = state.fail_reports.entry(i).or_default();
+                match node.health {
+                    Health::PFail | Health::Fail => reports.insert(sender_index, now),
+                    Health::Online => reports.remove(&sender_index),
+                };
+            }
+
+            kind == Kind::AuthRequest
+                && state.grant_vote(sender_index, behind, now, self.node_timeout * 2)
+        };
+        match kind {
+            Kind::AuthRequest if voted => Some(self.message(Kind::AuthAck)),
+            Kind::Meet | Kind::Ping | Kind::AuthRequest => Some(self.message(Kind::Pong)),
+            Kind::Pong | Kind::Fail | Kind::AuthAck => None,
+        }
+    }
+
+    /// Flags nodes that have not answered within the node timeout as
+    /// `PFail`, and those a majority of masters agree on as `Fail`. Returns
+    /// the ids of the nodes that just failed, for the caller to announce.
+    pub(crate) fn detect_failures(&self) -> Vec<String> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let needed = state.cluster_size() / 2 + 1;
+        let mut failed = Vec::new();
+        for i in (0..state.nodes.len()).filter(|&i| i != state.myself) {
+            let last_pong = *state.last_pong.entry(i).or_insert(now);
+            if now - last_pong > self.node_timeout && state.nodes[i].health == Health::Online {
+                state.nodes[i].health = Health::PFail;
+            }
+            let reports = state.fail_reports.entry(i).or_default();
+            reports.retain(|_, at| now - *at <= self.node_timeout * 2);
+            if state.nodes[i].health != Health::PFail {
+                continue;
+            }
+            let mut votes = reports.len();
+            if state.nodes[state.myself].replica_of.is_none() {
+                votes += 1;
+            }
+            if votes >= needed {
+                state.nodes[i].health = Health::Fail;
+                failed.push(state.nodes[i].id.clone());
+            }
+        }
+        failed
+    }
+
+    /// Starts an election if this is a replica whose master failed and its
+    /// turn has come, returning the vote request to send to every node.
+    /// Replicas of the same master go one after another, ordered by id, so
+    /// they don't split the vote.
+    pub(crate) fn election(&self) -> Option<Message> {
+        let now = self.clock.now();
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(master) = state.failed_master() else {
+                state.next_election = None;
+                return None;
+            };
+            let myself = state.nodes[state.myself].id.clone();
+            let rank = state
+                .replicas_of(master)
+                .filter(|&i| state.nodes[i].id < myself)
+                .count() as u32;
+            let start = *state
+                .next_election
+                .get_or_insert(now + self.node_timeout / 2 * rank);
+            if now < start {
+                return None;
+            }
+            // Try again with a new epoch if this round is not won.
+            state.next_election = Some(now + self.node_timeout * 2);
+            state.current_epoch += 1;
+        }
+        Some(self.message(Kind::AuthRequest))
+    }
+
+    /// Takes over the slots of the failed master once `votes` masters
+    /// granted the vote asked for with `epoch`. Returns whether it did.
+    pub(crate) fn promote_if_elected(&self, epoch: u64, votes: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(master) = state.failed_master() else {
+            return false;
+        };
+        if votes < state.cluster_size() / 2 + 1 {
+            return false;
+        }
+        let myself = state.myself;
+        for owner in state
+            .slots
+            .iter_mut()
+            .filter(|owner| **owner == Some(master))
+        {
+            *owner = Some(myself);
+        }
+        state.nodes[myself].replica_of = None;
+        state.nodes[myself].config_epoch = epoch;
+        state.current_epoch = state.current_epoch.max(epoch);
+        state.next_election = None;
+        true
+    }
+
+    /// Tells the others that the nodes in `failed` are down.
+    pub(crate) fn fail_message(&self, failed: &[String]) -> Message {
+        let mut message = self.message(Kind::Fail);
+        message.slots.clear();
+        message.gossip.retain(|node| failed.contains(&node.id));
+        message
+    }
+
+    /// The error to reply with if `command` on `keys` is not served here: a
+    /// `-CROSSSLOT` if the keys are in different slots, a `-MOVED` to the
+    /// slot's owner, an `-ASK` for keys that already left a migrating slot,
+    /// or `-CLUSTERDOWN` for slots nobody owns. `asking` is set after an
+    /// `ASKING` command and `exists` tells whether a key is stored here.
+    pub fn route(
+        &self,
+        command: &Command,
+        keys: &[&str],
+        asking: bool,
+        exists: impl Fn(&str) -> bool,
+    ) -> Option<String> {
+        let slot = key_hash_slot(keys.first()?);
+        if keys.iter().any(|key| key_hash_slot(key) != slot) {
+            return Some(CROSSSLOT.to_string());
+        }
+        let state = self.state.lock().unwrap();
+        // Like Redis, MIGRATE always runs locally while its slot is open, so
+        // keys can be moved freely during a migration.
+        let open = state.migrating.contains_key(&slot) || state.importing.contains_key(&slot);
+        if *command == Command::Migrate && open {
+            return None;
+        }
+        // Keys split between the two nodes of a migration can't be served by
+        // either, the client has to retry once they are all moved.
+        let missing = keys.iter().filter(|key| !exists(key)).count();
+        match state.slots[slot as usize] {
+            Some(owner) if owner == state.myself => match state.migrating.get(&slot) {
+                Some(&target) if missing == keys.len() => {
+                    Some(format!("ASK {} {}", slot, state.nodes[target].addr))
+                }
+                Some(_) if missing > 0 => Some(TRYAGAIN.to_string()),
+                _ => None,
+            },
+            _ if asking && state.importing.contains_key(&slot) => {
+                (keys.len() > 1 && missing > 0).then(|| TRYAGAIN.to_string())
+            }
+            Some(owner) => Some(format!("MOVED {} {}", slot, state.nodes[owner].addr)),
+            None => Some(SLOT_NOT_SERVED.to_string()),
+        }
+    }
+
+    /// Handles `ASKING` and the `CLUSTER` subcommands `INFO`, `NODES`,
+    /// `MEET`, `MYID`, `KEYSLOT`, `SLOTS`, `SHARDS`, `SETSLOT`,
+    /// `GETKEYSINSLOT` and `COUNTKEYSINSLOT`.
+    pub fn handle(&self, frame: Frame, db: &Db) -> Result<Vec<u8>> {
+        let ok = Type::SimpleString("OK".to_string()).serialize();
+        if frame.command() == Command::Asking {
+            return Ok(ok);
+        }
+        let args = frame.args().unwrap_or_default();
+        let (subcommand, args) = args.split_first().context("getting cluster subcommand")?;
+        let mut state = self.state.lock().unwrap();
+        match (subcommand.to_lowercase().as_str(), args) {
+            ("info", []) => {
+                let owners = state.slots.iter().flatten().map(|&i| state.nodes[i].health);
+                let (mut assigned, mut pfail, mut fail) = (0, 0, 0);
+                for health in owners {
+                    assigned += 1;
+                    match health {
+                        Health::Online => {}
+                        Health::PFail => pfail += 1,
+                        Health::Fail => fail += 1,
+                    }
+                }
+                let cluster_state = if assigned == SLOTS as usize && fail == 0 {
+                    "ok"
+                } else {
+                    "fail"
+                };
+                let info = [
+                    format!("cluster_state:{}", cluster_state),
+                    format!("cluster_slots_assigned:{}", assigned),
+                    format!("cluster_slots_ok:{}", assigned - pfail - fail),
+                    format!("cluster_slots_pfail:{}", pfail),
+                    format!("cluster_slots_fail:{}", fail),
+                    format!("cluster_known_nodes:{}", state.nodes.len()),
+                    format!("cluster_size:{}", state.cluster_size()),
+                    format!("cluster_current_epoch:{}", state.current_epoch),
+                    format!(
+                        "cluster_my_epoch:{}",
+                        state.nodes[state.myself].config_epoch
+                    ),
+                ];
+                Ok(Type::BulkString(info.join("\r\n") + "\r\n").serialize())
+            }
+            ("nodes", []) => {
+                let (now, unix_now) = (self.clock.now(), self.clock.unix_time());
+                let lines: String = (0..state.nodes.len())
+                    .map(|i| state.nodes_line(i, now, unix_now) + "\n")
+                    .collect();
+                Ok(Type::BulkString(lines).serialize())
+            }
+            ("meet", [ip, port, bus_port @ ..]) => {
+                let ip: IpAddr = ip.parse().context("Invalid node address specified")?;
+                let port: u16 = port.parse().context("Invalid base port specified")?;
+                let bus_port = match bus_port {
+                    [] => default_bus_port(port)?,
+                    [bus_port] => bus_port.parse().context("Invalid bus port specified")?,
+                    _ => bail!("wrong number of arguments for CLUSTER MEET"),
+                };
+                state.meets.push(SocketAddr::new(ip, bus_port));
+                Ok(ok)
+            }
+            ("myid", []) => Ok(Type::BulkString(state.nodes[state.myself].id.clone()).serialize()),
+            ("slots", []) => {
+                let rv = state
+                    .slot_ranges()
+                    .into_iter()
+                    .map(|(start, end, owner)| {
+                        let mut entry = vec![
+                            Type::Integer(start.to_string()),
+                            Type::Integer(end.to_string()),
+                            state.slots_entry(owner),
+                        ];
+                        entry.extend(state.replicas_of(owner).map(|i| state.slots_entry(i)));
+                        Type::Array(entry)
+                    })
+                    .collect();
+                Ok(Type::Array(rv).serialize())
+            }
+            ("shards", []) => {
+                let ranges = state.slot_ranges();
+                let masters =
+                    (0..state.nodes.len()).filter(|&i| state.nodes[i].replica_of.is_none());
+                let rv = masters
+                    .map(|master| {
+                        let slots = ranges
+                            .iter()
+                            .filter(|(_, _, owner)| *owner == master)
+                            .flat_map(|(start, end, _)| [start, end])
+                            .map(|slot| Type::Integer(slot.to_string()))
+                            .collect();
+                        let nodes = std::iter::once(master)
+                            .chain(state.replicas_of(master))
+                            .map(|i| state.shards_entry(i))
+                            .collect();
+                        Type::Array(vec![
+                            Type::BulkString("slots".to_string()),
+                            Type::Array(slots),
+                            Type::BulkString("nodes".to_string()),
+                            Type::Array(nodes),
+                        ])
+                    })
+                    .collect();
+                Ok(Type::Array(rv).serialize())
+            }
+            ("setslot", [slot, action, node @ ..]) => {
+                let slot = parse_slot(slot)?;
+                let owned = state.slots[slot as usize] == Some(state.myself);
+                let node = match node {
+                    [] => None,
+                    [id] => Some(state.node_index(id)?),
+                    _ => bail!("wrong number of arguments for CLUSTER SETSLOT"),
+                };
+                match (action.to_lowercase().as_str(), node) {
+                    ("migrating", Some(node)) => {
+                        if !owned {
+                            bail!("I'm not the owner of hash slot {}", slot);
+                        }
+                        state.migrating.insert(slot, node);
+                    }
+                    ("importing", Some(node)) => {
+                        if owned {
+                            bail!("I'm already the owner of hash slot {}", slot);
+                        }
+                        state.importing.insert(slot, node);
+                    }
+                    ("stable", None) => {
+                        state.migrating.remove(&slot);
+                        state.importing.remove(&slot);
+                    }
+                    ("node", Some(node)) => {
+                        if owned && node != state.myself && !keys_in_slot(db, slot).is_empty() {
+                            bail!(
+                                "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                                slot
+                            );
+                        }
+                        // Finishing an import claims the slot with a new epoch, so
+                        // the rest of the cluster takes the word of this node
+                        // over the old owner's.
+                        if node == state.myself && state.importing.contains_key(&slot) {
+                            state.bump_epoch();
+                        }
+                        state.slots[slot as usize] = Some(node);
+                        state.migrating.remove(&slot);
+                        state.importing.remove(&slot);
+                    }
+                    _ => bail!("Invalid CLUSTER SETSLOT action or number of arguments"),
+                }
+                Ok(ok)
+            }
+            ("getkeysinslot", [slot, count]) => {
+                let count: usize = count.parse().context("Invalid number of keys")?;
+                let keys = keys_in_slot(db, parse_slot(slot)?)
+                    .into_iter()
+                    .take(count)
+                    .map(Type::BulkString)
+                    .collect();
+                Ok(Type::Array(keys).serialize())
+            }
+            ("countkeysinslot", [slot]) => {
+                let count = keys_in_slot(db, parse_slot(slot)?).len();
+                Ok(Type::Integer(count.to_string()).serialize())
+            }
+            ("keyslot", [key]) => Ok(Type::Integer(key_hash_slot(key).to_string()).serialize()),
+            _ => bail!("unknown subcommand or wrong number of arguments for CLUSTER"),
+        }
+    }
+}
+
+fn parse_slot(s: &str) -> Result<u16> {
+    s.parse::<u16>()
+        .ok()
+        .filter(|&slot| slot < SLOTS)
+        .context("Invalid or out of range slot")
+}
+
+/// Live keys stored here that hash to `slot`, sorted.
+fn keys_in_slot(db: &Db, slot: u16) -> Vec<String> {
+    let db = db.lock().unwrap();
+    let now = db.now();
+    let mut keys: Vec<String> = db
+        .iter()
+        .filter(|(key, entry)| !entry.is_expired(now) && key_hash_slot(key) == slot)
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Handles `MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [KEYS key...]`. Keys are written to the target with `SET`, preceded by
+/// `ASKING` in cluster mode so it accepts them while it is importing their
+/// slot, after logging in with `auth` if given. Returns the reply and, unless
+/// `COPY` is given, the keys the target now holds, which the caller deletes
+/// here. When a key fails the ones before it have still moved.
+pub async fn migrate(
+    frame: Frame,
+    db: &Db,
+    client: &str,
+    cluster: bool,
+    auth: Option<&MasterAuth>,
+) -> Result<(Vec<u8>, Vec<String>)> {
+    let args = frame.args().unwrap_or_default();
+    let [host, port, key, destination_db, timeout, options @ ..] = args.as_slice() else {
+        bail!("wrong number of arguments for MIGRATE");
+    };
+    let port: u16 = port.parse().context("invalid port")?;
+    if destination_db != "0" {
+        bail!("only database 0 exists");
+    }
+    // Like Redis, a timeout of 0 means the default of one second.
+    let timeout = match timeout.parse::<u64>().context("invalid timeout")? {
+        0 => Duration::from_secs(1),
+        ms => Duration::from_millis(ms),
+    };
+    let (mut copy, mut replace, mut keys) = (false, false, Vec::new());
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_lowercase().as_str() {
+            "copy" => copy = true,
+            "replace" => replace = true,
+            "keys" if key.is_empty() => keys.extend(options.by_ref().cloned()),
+            _ => bail!("syntax error"),
+        }
+    }
+    if !key.is_empty() {
+        keys.push(key.clone());
+    }
+
+    // Copy the entries out, the lock can't be held while talking to the target.
+    let mut entries: Vec<(String, String, Option<Duration>)> = Vec::new();
+    {
+        let mut db = db.lock().unwrap();
+        let now = db.now();
+        for key in &keys {
+            if let Some(entry) = db.get(key)?.filter(|entry| !entry.is_expired(now)) {
+                entries.push((key.clone(), entry.value(), entry.ttl(now)));
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Ok((
+            Type::SimpleString("NOKEY".to_string()).serialize(),
+            Vec::new(),
+        ));
+    }
+
+    let mut moved = Vec::new();
+    let transfer = transfer(
+        (host.as_str(), port),
+        &entries,
+        replace,
+        cluster,
+        auth,
+        &mut moved,
+    );
+    let reply = match tokio::time::timeout(timeout, transfer).await {
+        Err(_) => Type::SimpleError("IOERR error or timeout writing to target instance".into()),
+        Ok(Err(e)) => Type::SimpleError(format!(
+            "IOERR error or timeout writing to target instance: {:#}",
+            e
+        )),
+        Ok(Ok(Err(reply))) => Type::SimpleError(reply),
+        Ok(Ok(Ok(()))) => Type::SimpleString("OK".to_string()),
+    };
+    for key in &moved {
+        audit(client, "MIGRATE", key);
+    }
+    if copy {
+        moved.clear();
+    }
+    Ok((reply.serialize(), moved))
+}
+
+/// Writes `entries` to the node at `addr`, adding each key to `moved` once
+/// the target has it. I/O failures are errors, replies that refuse a key are
+/// returned as the error to send back.
+async fn transfer(
+    addr: (&str, u16),
+    entries: &[(String, String, Option<Duration>)],
+    replace: bool,
+    cluster: bool,
+    auth: Option<&MasterAuth>,
+    moved: &mut Vec<String>,
+) -> Result<Result<(), String>> {
+    let mut target = Client::connect(addr).await?;
+    if let Some(auth) = auth {
+        auth.login(&mut target).await?;
+    }
+    for (key, value, ttl) in entries {
+        if !replace {
+            match target_request(&mut target, &["PTTL", key], cluster).await? {
+                Err(e) => return Ok(Err(e)),
+                Ok(Type::Integer(pttl)) if pttl == "-2" => {}
+                Ok(_) => return Ok(Err("BUSYKEY Target key name already exists.".to_string())),
+            }
+        }
+        // Keys about to expire still need a positive PX to stay valid.
+        let px = ttl.map(|ttl| ttl.as_millis().max(1).to_string());
+        let mut set = vec!["SET", key.as_str(), value.as_str()];
+        if let Some(px) = &px {
+            set.extend(["PX", px.as_str()]);
+        }
+        if let Err(e) = target_request(&mut target, &set, cluster).await? {
+            return Ok(Err(e));
+        }
+        moved.push(key.clone());
+    }
+    Ok(Ok(()))
+}
+
+/// Sends `args` to the target, after `ASKING` in cluster mode.
+async fn target_request(
+    target: &mut Client,
+    args: &[&str],
+    cluster: bool,
+) -> Result<Result<Type, String>> {
+    let mut replies = Vec::new();
+    if cluster {
+        replies.push(target.request(&["ASKING"]).await?);
+    }
+    replies.push(target.request(args).await?);
+    for reply in &replies {
+        if let Type::SimpleError(e) = reply {
+            return Ok(Err(format!(
+                "ERR Target instance replied with error: {}",
+                e
+            )));
+        }
+    }
+    Ok(Ok(replies.pop().unwrap()))
+}
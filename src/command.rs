@@ -1,7 +1,7 @@
 use crate::resptype::*;
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     Ping,
     Echo,
@@ -10,6 +10,27 @@ pub enum Command {
     Info,
     ReplConf,
     PSync,
+    Keys,
+    PTtl,
+    Time,
+    SlowLog,
+    Config,
+    Cluster,
+    Asking,
+    Migrate,
+    MGet,
+    MSet,
+    Multi,
+    Exec,
+    Discard,
+    ReplicaOf,
+    GetVer,
+    Client,
+    Del,
+    DelIfEq,
+    Append,
+    Object,
+    Auth,
 }
 
 impl TryFrom<&Type> for Command {
@@ -33,11 +54,170 @@ impl TryFrom<&Type> for Command {
                     Ok(Command::ReplConf)
                 } else if s == "psync" {
                     Ok(Command::PSync)
+                } else if s == "keys" {
+                    Ok(Command::Keys)
+                } else if s == "pttl" {
+                    Ok(Command::PTtl)
+                } else if s == "time" {
+                    Ok(Command::Time)
+                } else if s == "slowlog" {
+                    Ok(Command::SlowLog)
+                } else if s == "config" {
+                    Ok(Command::Config)
+                } else if s == "cluster" {
+                    Ok(Command::Cluster)
+                } else if s == "asking" {
+                    Ok(Command::Asking)
+                } else if s == "migrate" {
+                    Ok(Command::Migrate)
+                } else if s == "mget" {
+                    Ok(Command::MGet)
+                } else if s == "mset" {
+                    Ok(Command::MSet)
+                } else if s == "multi" {
+                    Ok(Command::Multi)
+                } else if s == "exec" {
+                    Ok(Command::Exec)
+                } else if s == "discard" {
+                    Ok(Command::Discard)
+                } else if s == "replicaof" || s == "slaveof" {
+                    Ok(Command::ReplicaOf)
+                } else if s == "getver" {
+                    Ok(Command::GetVer)
+                } else if s == "client" {
+                    Ok(Command::Client)
+                } else if s == "del" {
+                    Ok(Command::Del)
+                } else if s == "delifeq" {
+                    Ok(Command::DelIfEq)
+                } else if s == "append" {
+                    Ok(Command::Append)
+                } else if s == "object" {
+                    Ok(Command::Object)
+                } else if s == "auth" {
+                    Ok(Command::Auth)
                 } else {
                     bail!("Command not supported: {}", s)
                 }
             }
-            _ => bail!("Command parse error: {}", value.to_string()),
+            _ => bail!("Command parse error: {}", value),
         }
     }
 }
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "PING",
+            Command::Echo => "ECHO",
+            Command::Get => "GET",
+            Command::Set => "SET",
+            Command::Info => "INFO",
+            Command::ReplConf => "REPLCONF",
+            Command::PSync => "PSYNC",
+            Command::Keys => "KEYS",
+            Command::PTtl => "PTTL",
+            Command::Time => "TIME",
+            Command::SlowLog => "SLOWLOG",
+            Command::Config => "CONFIG",
+            Command::Cluster => "CLUSTER",
+            Command::Asking => "ASKING",
+            Command::Migrate => "MIGRATE",
+            Command::MGet => "MGET",
+            Command::MSet => "MSET",
+            Command::Multi => "MULTI",
+            Command::Exec => "EXEC",
+            Command::Discard => "DISCARD",
+            Command::ReplicaOf => "REPLICAOF",
+            Command::GetVer => "GETVER",
+            Command::Client => "CLIENT",
+            Command::Del => "DEL",
+            Command::DelIfEq => "DELIFEQ",
+            Command::Append => "APPEND",
+            Command::Object => "OBJECT",
+            Command::Auth => "AUTH",
+        }
+    }
+
+    /// Commands that modify keys, they are replicated and audited.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set
+                | Command::MSet
+                | Command::Del
+                | Command::DelIfEq
+                | Command::Append
+                | Command::Object
+        )
+    }
+
+    /// Writes that may take up more memory, they are refused over
+    /// `maxmemory`.
+    pub fn may_grow(&self) -> bool {
+        matches!(self, Command::Set | Command::MSet | Command::Append)
+    }
+
+    /// Commands that read keys, only the leader answers them in Raft mode.
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Command::Get | Command::MGet | Command::PTtl | Command::Keys | Command::GetVer
+        )
+    }
+
+    /// Commands whose result depends on what their keys already hold, a
+    /// backend loads those keys before they run.
+    pub fn touches_existing(&self) -> bool {
+        self.is_read() || matches!(self, Command::Append | Command::Del | Command::DelIfEq)
+    }
+
+    /// Where the keys are in the arguments, as the index of the first key,
+    /// of the last one (negative counts from the end) and the step between
+    /// them, like in the Redis command table.
+    fn key_positions(&self) -> Option<(usize, isize, usize)> {
+        match self {
+            Command::Get
+            | Command::Set
+            | Command::PTtl
+            | Command::GetVer
+            | Command::DelIfEq
+            | Command::Append => Some((0, 0, 1)),
+            Command::Object => Some((1, 1, 1)),
+            Command::MGet | Command::Del => Some((0, -1, 1)),
+            Command::MSet => Some((0, -1, 2)),
+            _ => None,
+        }
+    }
+
+    /// The arguments that are keys, which decide the slot in cluster mode.
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a str> {
+        // MIGRATE host port key|"" db timeout [COPY] [REPLACE] [KEYS key...]
+        if *self == Command::Migrate {
+            let keys = match args.get(2) {
+                Some(key) if !key.is_empty() => &args[2..3],
+                Some(_) => match args.iter().position(|arg| arg.eq_ignore_ascii_case("keys")) {
+                    Some(i) => &args[i + 1..],
+                    None => &[],
+                },
+                None => &[],
+            };
+            return keys.iter().map(String::as_str).collect();
+        }
+        let Some((first, last, step)) = self.key_positions() else {
+            return Vec::new();
+        };
+        let last = if last < 0 {
+            args.len() as isize + last
+        } else {
+            last
+        };
+        args.iter()
+            .enumerate()
+            .skip(first)
+            .step_by(step)
+            .take_while(|&(i, _)| i as isize <= last)
+            .map(|(_, key)| key.as_str())
+            .collect()
+    }
+}
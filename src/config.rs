@@ -0,0 +1,408 @@
+//! Runtime configuration, read and changed with `CONFIG GET` / `CONFIG SET`
+//! or from a redis.conf style config file that is re-read on `SIGHUP`.
+use crate::frame::*;
+use crate::glob::*;
+use crate::log::*;
+use crate::resptype::*;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Every parameter `CONFIG GET` knows about, in the order it lists them.
+const PARAMS: [&str; 9] = [
+    "loglevel",
+    "logfile",
+    "audit-logfile",
+    "log-max-size",
+    "log-max-files",
+    "log-compress",
+    "maxmemory",
+    "timeout",
+    "save",
+];
+
+/// Parameters that are only read at startup. They may appear in the config
+/// file, but changing them there needs a restart.
+pub const STARTUP_PARAMS: [&str; 25] = [
+    "bind",
+    "port",
+    "replicaof",
+    "memcached-port",
+    "daemonize",
+    "pidfile",
+    "supervised",
+    "shutdown-timeout",
+    "cluster-enabled",
+    "cluster-slots",
+    "cluster-config-file",
+    "cluster-port",
+    "cluster-node-timeout",
+    "raft-port",
+    "raft-peer",
+    "raft-election-timeout",
+    "raft-snapshot-entries",
+    "raft-dir",
+    "cold-storage-file",
+    "cold-storage-idle",
+    "backend-dir",
+    "auth-file",
+    "auth-env",
+    "masterauth",
+    "masteruser",
+];
+
+pub fn is_startup_param(name: &str) -> bool {
+    STARTUP_PARAMS.contains(&name.to_lowercase().as_str())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub loglevel: Level,
+    /// Server log path, empty logs to stdout.
+    pub logfile: String,
+    /// Audit log path, empty disables it.
+    pub audit_logfile: String,
+    pub log_max_size: u64,
+    pub log_max_files: usize,
+    pub log_compress: bool,
+    /// Writes are refused once keys and values take up this many bytes, `0`
+    /// means no limit.
+    pub maxmemory: u64,
+    /// Seconds before an idle client is disconnected, `0` never does.
+    pub timeout: u64,
+    /// `(seconds, changes)` snapshot rules. Nothing is persisted yet, they are
+    /// only kept so existing redis.conf files load.
+    pub save: Vec<(u64, u64)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            loglevel: Level::Notice,
+            logfile: String::new(),
+            audit_logfile: String::new(),
+            log_max_size: 100 * 1024 * 1024,
+            log_max_files: 10,
+            log_compress: true,
+            maxmemory: 0,
+            timeout: 0,
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+        }
+    }
+}
+
+impl Config {
+    pub fn rotation(&self) -> Rotation {
+        Rotation {
+            max_size: self.log_max_size,
+            max_files: self.log_max_files,
+            compress: self.log_compress,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name.to_lowercase().as_str() {
+            "loglevel" => self.loglevel.to_string(),
+            "logfile" => self.logfile.clone(),
+            "audit-logfile" => self.audit_logfile.clone(),
+            "log-max-size" => self.log_max_size.to_string(),
+            "log-max-files" => self.log_max_files.to_string(),
+            "log-compress" => yes_no(self.log_compress),
+            "maxmemory" => self.maxmemory.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "save" => self
+                .save
+                .iter()
+                .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name.to_lowercase().as_str() {
+            "loglevel" => self.loglevel = value.parse()?,
+            "logfile" => self.logfile = value.to_string(),
+            "audit-logfile" => self.audit_logfile = value.to_string(),
+            "log-max-size" => self.log_max_size = parse_memory(value)?,
+            "log-max-files" => {
+                self.log_max_files = value
+                    .parse()
+                    .with_context(|| format!("invalid number of files: {:?}", value))?
+            }
+            "log-compress" => self.log_compress = parse_yes_no(value)?,
+            "maxmemory" => self.maxmemory = parse_memory(value)?,
+            "timeout" => {
+                self.timeout = value
+                    .parse()
+                    .with_context(|| format!("invalid timeout: {:?}", value))?
+            }
+            "save" => self.save = parse_save(value)?,
+            name if is_startup_param(name) => bail!("can't set immutable config '{}'", name),
+            _ => bail!(
+                "Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ),
+        }
+        Ok(())
+    }
+
+    /// Idle client timeout, `None` if clients are never disconnected.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.timeout > 0).then(|| Duration::from_secs(self.timeout))
+    }
+
+    /// Whether `used` bytes exceed `maxmemory`, so writes should be refused.
+    pub fn over_maxmemory(&self, used: u64) -> bool {
+        self.maxmemory > 0 && used >= self.maxmemory
+    }
+
+    /// Name/value pairs of every parameter matching the glob `pattern`.
+    pub fn matching(&self, pattern: &str) -> Vec<(String, String)> {
+        let pattern = pattern.to_lowercase();
+        PARAMS
+            .iter()
+            .filter(|name| glob_match(&pattern, name))
+            .filter_map(|name| Some((name.to_string(), self.get(name)?)))
+            .collect()
+    }
+}
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.to_string()
+}
+
+fn parse_yes_no(s: &str) -> Result<bool> {
+    match s.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => bail!("argument must be 'yes' or 'no'"),
+    }
+}
+
+/// Parses `save` rules, pairs of seconds and changes. An empty value disables
+/// snapshotting.
+fn parse_save(s: &str) -> Result<Vec<(u64, u64)>> {
+    let numbers = s
+        .split_whitespace()
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid save rules: {:?}", s))?;
+    if numbers.len() % 2 != 0 {
+        bail!("save rules must be pairs of seconds and changes: {:?}", s);
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+/// Parses a byte count with an optional Redis style unit, e.g. `64mb` or `1g`.
+pub fn parse_memory(s: &str) -> Result<u64> {
+    let lower = s.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        unit => bail!("invalid memory unit {:?} in {:?}", unit, s),
+    };
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid memory amount: {:?}", s))?;
+    n.checked_mul(multiplier)
+        .with_context(|| format!("memory amount out of range: {:?}", s))
+}
+
+/// `config` with the parameter and value pairs of a `CONFIG SET` applied,
+/// on a copy so a bad value changes nothing.
+pub(crate) fn config_set(config: &Config, pairs: &[String]) -> Result<Config> {
+    let chunks = pairs.chunks_exact(2);
+    if pairs.is_empty() || !chunks.remainder().is_empty() {
+        bail!("unknown subcommand or wrong number of arguments for CONFIG");
+    }
+    let mut updated = config.clone();
+    for pair in chunks {
+        updated.set(&pair[0], &pair[1])?;
+    }
+    Ok(updated)
+}
+
+/// Handles `CONFIG GET pattern` and `CONFIG SET name value [name value ...]`.
+pub fn handle_config(frame: Frame, config: &Mutex<Config>, client: &str) -> Result<Vec<u8>> {
+    let args = frame.args().unwrap_or_default();
+    let (subcommand, args) = args.split_first().context("getting config subcommand")?;
+    match subcommand.to_lowercase().as_str() {
+        "get" => {
+            let config = config.lock().unwrap();
+            let mut rv = Vec::new();
+            for pattern in args {
+                for (name, value) in config.matching(pattern) {
+                    rv.push(Type::BulkString(name));
+                    rv.push(Type::BulkString(value));
+                }
+            }
+            Ok(Type::Array(rv).serialize())
+        }
+        "set" if !args.is_empty() && args.len() % 2 == 0 => {
+            let mut config = config.lock().unwrap();
+            let updated = config_set(&config, args)?;
+            configure(&updated)?;
+            *config = updated;
+            for pair in args.chunks(2) {
+                audit(client, "CONFIG SET", &pair[0]);
+            }
+            Ok(Type::SimpleString("OK".to_string()).serialize())
+        }
+        _ => bail!("unknown subcommand or wrong number of arguments for CONFIG"),
+    }
+}
+
+/// A redis.conf style config file, as it was last read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    path: PathBuf,
+    entries: BTreeMap<String, String>,
+}
+
+impl ConfigFile {
+    pub fn read(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        let entries = parse_config(&contents)
+            .with_context(|| format!("parsing config file {}", path.display()))?;
+        Ok(Self { path, entries })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(String::as_str)
+    }
+
+    /// Every directive in the file, with names lowercased and arguments
+    /// joined by single spaces.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Parses `name arg...` lines. Blank lines and `#` comments are skipped,
+/// arguments may be quoted, and repeated `save` lines add up like in Redis.
+pub fn parse_config(contents: &str) -> Result<BTreeMap<String, String>> {
+    let mut entries = BTreeMap::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tokens = split_config_line(line).with_context(|| format!("line {}", n + 1))?;
+        let Some((name, args)) = tokens.split_first() else {
+            continue;
+        };
+        let name = match name.to_lowercase().as_str() {
+            "slaveof" => "replicaof".to_string(),
+            name => name.to_string(),
+        };
+        if !PARAMS.contains(&name.as_str()) && !is_startup_param(&name) {
+            bail!("line {}: unknown directive '{}'", n + 1, name);
+        }
+        let value = args.join(" ");
+        if name == "save" {
+            let rules: &mut String = entries.entry(name).or_default();
+            if value.is_empty() {
+                rules.clear();
+            } else {
+                if !rules.is_empty() {
+                    rules.push(' ');
+                }
+                rules.push_str(&value);
+            }
+        } else {
+            entries.insert(name, value);
+        }
+    }
+    Ok(entries)
+}
+
+/// Splits a config line on whitespace, keeping quoted arguments together.
+fn split_config_line(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("unbalanced quotes");
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// What a config file reload changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reload {
+    /// Parameters that changed in the file and now have the new value.
+    pub applied: Vec<String>,
+    /// Startup parameters that changed in the file, they keep their old value
+    /// until the server is restarted.
+    pub restart_required: Vec<String>,
+}
+
+/// Re-reads `file` and applies every parameter whose value changed since it
+/// was last read, so values set with `CONFIG SET` or on the command line stay
+/// put unless the file changes them. Nothing is applied if any value is
+/// invalid.
+pub fn reload_config_file(file: &Mutex<ConfigFile>, config: &Mutex<Config>) -> Result<Reload> {
+    let mut file = file.lock().unwrap();
+    let mut reread = ConfigFile::read(file.path())?;
+    let mut config = config.lock().unwrap();
+    let mut updated = config.clone();
+    let mut reload = Reload::default();
+    for (name, value) in reread.entries() {
+        if file.get(name) == Some(value) {
+            continue;
+        }
+        if is_startup_param(name) {
+            reload.restart_required.push(name.to_string());
+        } else {
+            updated
+                .set(name, value)
+                .with_context(|| format!("setting {} to {:?}", name, value))?;
+            reload.applied.push(name.to_string());
+        }
+    }
+    configure(&updated)?;
+    *config = updated;
+    // Remember the values startup parameters are actually running with, so
+    // they are reported again on the next reload.
+    for name in &reload.restart_required {
+        match file.entries.get(name) {
+            Some(value) => reread.entries.insert(name.clone(), value.clone()),
+            None => reread.entries.remove(name),
+        };
+    }
+    *file = reread;
+    Ok(reload)
+}
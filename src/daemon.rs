@@ -0,0 +1,90 @@
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::fs;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+use crate::flags::Supervised;
+
+/// Set in the daemonized child, whose config file may still ask for
+/// `daemonize yes`.
+const DAEMONIZED_ENV: &str = "KV_STORE_DAEMONIZED";
+
+/// Re-executes the server in the background and exits the foreground
+/// process. Only returns in the daemonized child.
+pub fn daemonize() -> Result<()> {
+    if env::var_os(DAEMONIZED_ENV).is_some() {
+        env::remove_var(DAEMONIZED_ENV);
+        return Ok(());
+    }
+    let exe = env::current_exe().context("locating server executable")?;
+    let args = env::args_os()
+        .skip(1)
+        .filter(|arg| arg != "--daemonize")
+        .collect::<Vec<_>>();
+
+    let child = Command::new(exe)
+        .args(args)
+        .env(DAEMONIZED_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // A fresh process group keeps terminal job control signals away
+        // from the daemon.
+        .process_group(0)
+        .spawn()
+        .context("spawning daemon process")?;
+
+    println!("Daemonized with pid {}", child.id());
+    process::exit(0);
+}
+
+/// Holds the pidfile for as long as the server runs and removes it on drop.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        fs::write(path, format!("{}\n", process::id()))
+            .with_context(|| format!("writing pidfile {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Sends `state` to the service manager when running under a systemd
+/// `Type=notify` unit, does nothing otherwise.
+pub fn notify(supervised: Supervised, state: &str) -> Result<()> {
+    let socket = match (supervised, env::var_os("NOTIFY_SOCKET")) {
+        (Supervised::No, _) => return Ok(()),
+        (Supervised::Auto, None) => return Ok(()),
+        (Supervised::Systemd, None) => {
+            bail!("--supervised systemd requested but NOTIFY_SOCKET is not set")
+        }
+        (_, Some(socket)) => socket,
+    };
+
+    let socket = socket.to_string_lossy();
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(socket.as_ref()),
+    }
+    .context("parsing NOTIFY_SOCKET")?;
+
+    let msg = format!("{}\nMAINPID={}", state, process::id());
+    UnixDatagram::unbound()?
+        .send_to_addr(msg.as_bytes(), &addr)
+        .context("notifying service manager")?;
+    Ok(())
+}
@@ -1,18 +1,234 @@
-use anyhow::{bail, Context, Result};
-use itertools::Itertools;
-use clap::Parser;
+use anyhow::{bail, Result};
+use clap::{ArgAction, Parser, ValueEnum};
+use std::path::PathBuf;
+
+use redis_starter_rust::config::ConfigFile;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 pub struct Args {
+    /// redis.conf style config file, re-read on SIGHUP. Options given on the
+    /// command line take precedence over it.
+    pub config_file: Option<PathBuf>,
 
     #[arg(short, long, default_value_t = String::from("127.0.0.1"))]
     pub addr: String,
 
-    #[arg(short, long, default_value_t = String::from("6379"))]
-    pub port: String,
+    #[arg(short, long, default_value_t = 6379)]
+    pub port: u16,
 
     #[arg(required = false, short, long, num_args = 2)]
     pub replicaof: Option<Vec<String>>,
 
+    /// Also serve the memcached text protocol on this port.
+    #[arg(long)]
+    pub memcached_port: Option<u16>,
+
+    /// One of debug, verbose, notice or warning.
+    #[arg(long)]
+    pub loglevel: Option<String>,
+
+    /// Write the server log here instead of stdout, see CONFIG GET log-*.
+    #[arg(long)]
+    pub logfile: Option<String>,
+
+    /// Record writes and CONFIG SET calls to this file.
+    #[arg(long)]
+    pub audit_logfile: Option<String>,
+
+    /// Only serve keys in the hash slots given by --cluster-slots.
+    #[arg(long)]
+    pub cluster_enabled: bool,
+
+    /// Hash slots this node owns in cluster mode, e.g. 0-8191.
+    #[arg(long, requires = "cluster_enabled", default_value = "0-16383")]
+    pub cluster_slots: String,
+
+    /// Cluster topology in the format of a Redis nodes.conf, instead of
+    /// --cluster-slots.
+    #[arg(long, requires = "cluster_enabled")]
+    pub cluster_config_file: Option<PathBuf>,
+
+    /// Port of the cluster bus, the client port plus 10000 by default.
+    #[arg(long, requires = "cluster_enabled")]
+    pub cluster_port: Option<u16>,
+
+    /// Milliseconds a node may go without answering on the cluster bus
+    /// before it is flagged as failing.
+    #[arg(long, requires = "cluster_enabled", default_value_t = 15000)]
+    pub cluster_node_timeout: u64,
+
+    /// Monitor masters and fail them over instead of storing keys.
+    #[arg(long)]
+    pub sentinel: bool,
+
+    /// Master to monitor in sentinel mode, along with how many sentinels
+    /// must agree it is down. Can be given several times.
+    #[arg(
+        long,
+        requires = "sentinel",
+        num_args = 4,
+        value_names = ["NAME", "HOST", "PORT", "QUORUM"],
+        action = ArgAction::Append
+    )]
+    pub sentinel_monitor: Vec<String>,
+
+    /// Another sentinel to share state with. Can be given several times.
+    #[arg(
+        long,
+        requires = "sentinel",
+        num_args = 2,
+        value_names = ["HOST", "PORT"],
+        action = ArgAction::Append
+    )]
+    pub sentinel_peer: Vec<String>,
+
+    /// Milliseconds an instance may go without answering before a sentinel
+    /// considers it down.
+    #[arg(long, requires = "sentinel", default_value_t = 30000)]
+    pub sentinel_down_after: u64,
+
+    /// Milliseconds before a sentinel tries to fail the same master over
+    /// again.
+    #[arg(long, requires = "sentinel", default_value_t = 180000)]
+    pub sentinel_failover_timeout: u64,
+
+    /// Commit writes through a Raft log shared with the --raft-peer nodes,
+    /// which talk to each other on this port. Experimental.
+    #[arg(long)]
+    pub raft_port: Option<u16>,
+
+    /// Raft port of another node in the group. Can be given several times.
+    #[arg(
+        long,
+        requires = "raft_port",
+        num_args = 2,
+        value_names = ["HOST", "PORT"],
+        action = ArgAction::Append
+    )]
+    pub raft_peer: Vec<String>,
+
+    /// Milliseconds a Raft follower waits to hear from the leader before
+    /// starting an election.
+    #[arg(long, requires = "raft_port", default_value_t = 1000)]
+    pub raft_election_timeout: u64,
+
+    /// Applied entries the Raft log keeps before they are compacted into a
+    /// snapshot of the data.
+    #[arg(long, requires = "raft_port", default_value_t = 10000)]
+    pub raft_snapshot_entries: u64,
+
+    /// Directory the Raft term, vote, snapshot and log are kept in, in files
+    /// named after the Raft port.
+    #[arg(long, requires = "raft_port", default_value = ".")]
+    pub raft_dir: PathBuf,
+
+    /// Move values that go unused for a while to this file, to hold more
+    /// data than fits in memory. The file is truncated on startup.
+    #[arg(long)]
+    pub cold_storage_file: Option<PathBuf>,
+
+    /// Milliseconds a value must go unused before it is moved to the cold
+    /// storage file.
+    #[arg(long, requires = "cold_storage_file", default_value_t = 60000)]
+    pub cold_storage_idle: u64,
+
+    /// Cache the keys stored as files in this directory, loading missing
+    /// keys from it and writing every change through to it.
+    #[arg(long)]
+    pub backend_dir: Option<PathBuf>,
+
+    /// Compare two RDB snapshots instead of serving anything, listing the
+    /// keys added, removed or changed in the second one.
+    #[arg(long, num_args = 2, value_names = ["A", "B"])]
+    pub diff_rdb: Option<Vec<PathBuf>>,
+
+    /// Only compare keys matching this glob-style pattern.
+    #[arg(long, requires = "diff_rdb")]
+    pub diff_pattern: Option<String>,
+
+    /// Make clients AUTH with a password listed in this file, one
+    /// `<user> <sha256 of password>` pair per line.
+    #[arg(long)]
+    pub auth_file: Option<PathBuf>,
+
+    /// Make clients AUTH with the password in the environment variable named
+    /// after this prefix and the upper cased user, e.g. KV_PASSWORD_DEFAULT.
+    #[arg(long, conflicts_with = "auth_file")]
+    pub auth_env: Option<String>,
+
+    /// Password to AUTH with on the master, on MIGRATE targets and, in
+    /// sentinel mode, on monitored instances.
+    #[arg(long)]
+    pub masterauth: Option<String>,
+
+    /// User to log in as with --masterauth instead of the default user.
+    #[arg(long, requires = "masterauth")]
+    pub masteruser: Option<String>,
+
+    /// Seconds to wait for open connections to finish on SIGTERM/SIGINT.
+    #[arg(long, default_value_t = 10)]
+    pub shutdown_timeout: u64,
+
+    /// Run in the background, detached from the terminal.
+    #[arg(long)]
+    pub daemonize: bool,
+
+    /// Write the server pid to this file while running.
+    #[arg(long)]
+    pub pidfile: Option<PathBuf>,
+
+    /// Report readiness to a service manager.
+    #[arg(long, value_enum, default_value_t = Supervised::No)]
+    pub supervised: Supervised,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Supervised {
+    No,
+    /// Use systemd if NOTIFY_SOCKET is set.
+    Auto,
+    Systemd,
+}
+
+/// Turns the startup parameters in `file` into command line options, the
+/// rest is applied by the server itself.
+pub fn config_file_args(file: &ConfigFile) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    for (name, value) in file.entries() {
+        let (flag, values) = match name {
+            // Redis accepts several addresses, only the first one is used.
+            "bind" => ("addr", value.split_whitespace().take(1).collect()),
+            "replicaof" | "raft-peer" => (name, value.split_whitespace().collect()),
+            "daemonize" | "cluster-enabled" => match value.to_lowercase().as_str() {
+                "yes" => (name, vec![]),
+                "no" => continue,
+                _ => bail!("{} must be 'yes' or 'no'", name),
+            },
+            "port"
+            | "memcached-port"
+            | "pidfile"
+            | "supervised"
+            | "shutdown-timeout"
+            | "cluster-slots"
+            | "cluster-config-file"
+            | "cluster-port"
+            | "cluster-node-timeout"
+            | "raft-port"
+            | "raft-election-timeout"
+            | "raft-snapshot-entries"
+            | "raft-dir"
+            | "cold-storage-file"
+            | "cold-storage-idle"
+            | "backend-dir"
+            | "auth-file"
+            | "auth-env"
+            | "masterauth"
+            | "masteruser" => (name, vec![value]),
+            _ => continue,
+        };
+        args.push(format!("--{}", flag));
+        args.extend(values.into_iter().map(String::from));
+    }
+    Ok(args)
 }
@@ -2,9 +2,6 @@ use crate::command::*;
 use crate::resptype::*;
 use anyhow::{bail, Context, Result};
 use itertools::Itertools;
-use std::str;
-
-pub type Cursor = usize;
 
 #[derive(Debug, Clone)]
 pub struct Frame {
@@ -15,13 +12,10 @@ pub struct Frame {
 
 impl Frame {
     pub fn new(buffer: &[u8], len: usize) -> Result<Self> {
-        let mut bytes_vec: Vec<u8> = Vec::new();
-        for val in &buffer[..len] {
-            let val_c = val;
-            bytes_vec.push(*val_c);
-        }
+        let buffer = buffer.get(..len).context("frame length exceeds buffer")?;
+        let bytes_vec: Vec<u8> = buffer.to_vec();
 
-        let (resp, _) = parse_resp(buffer);
+        let (resp, _) = parse_resp(buffer)?;
 
         let Type::Array(tokens) = resp else {
             bail!("unable to parse tokens from array")
@@ -29,11 +23,13 @@ impl Frame {
         let cmd = tokens.first().context("parsing first token for command")?;
         let cmd: Command = cmd.try_into().context("parsing command string")?;
         match cmd {
-            Command::Ping => Ok(Self {
-                command: cmd,
-                args: None,
-                bytes_vec,
-            }),
+            Command::Ping | Command::Time | Command::Asking | Command::Multi | Command::Discard => {
+                Ok(Self {
+                    command: cmd,
+                    args: None,
+                    bytes_vec,
+                })
+            }
             Command::Echo => {
                 let (_, arg) = tokens
                     .into_iter()
@@ -60,38 +56,121 @@ impl Frame {
                     bytes_vec,
                 })
             }
-            Command::Set => {
-                if tokens.len() == 3 {
-                    let (_, key, val) = tokens
-                        .into_iter()
-                        .collect_tuple()
-                        .context("parsing argument for set command")?;
-                    let key = key.try_into().context("parsing key from Type")?;
-                    let val = val.try_into().context("parsing val from Type")?;
+            Command::Keys | Command::PTtl | Command::GetVer => {
+                let (_, arg) = tokens.into_iter().collect_tuple().with_context(|| {
+                    format!("parsing argument for {} command", cmd.name().to_lowercase())
+                })?;
+                let arg = arg.try_into().context("parsing arg from Type")?;
 
-                    Ok(Self {
-                        command: cmd,
-                        args: Some(vec![key, val]),
-                        bytes_vec,
-                    })
-                } else if tokens.len() == 5 {
-                    let (_, key, val, px, dur) = tokens
-                        .into_iter()
-                        .collect_tuple()
-                        .context("parsing argument for set command")?;
-                    let key = key.try_into().context("parsing key from Type")?;
-                    let val = val.try_into().context("parsing val from Type")?;
-                    let px = px.try_into().context("parsing px from Type")?;
-                    let dur = dur.try_into().context("parsing duration from Type")?;
+                Ok(Self {
+                    command: cmd,
+                    args: Some(vec![arg]),
+                    bytes_vec,
+                })
+            }
+            Command::SlowLog => {
+                if tokens.len() != 2 && tokens.len() != 3 {
+                    bail!("SlowLog command can only handle 1 or 2 arguments currently");
+                }
+                let args = tokens
+                    .into_iter()
+                    .skip(1)
+                    .map(|arg| arg.try_into().context("parsing arg from Type"))
+                    .collect::<Result<Vec<String>>>()?;
 
-                    Ok(Self {
-                        command: cmd,
-                        args: Some(vec![key, val, px, dur]),
-                        bytes_vec,
-                    })
-                } else {
-                    bail!("Set command can only handle 2 or 4 arguments currently");
+                Ok(Self {
+                    command: cmd,
+                    args: Some(args),
+                    bytes_vec,
+                })
+            }
+            Command::MSet if tokens.len() < 3 || tokens.len() % 2 == 0 => {
+                bail!("MSet command needs key value pairs");
+            }
+            Command::MGet if tokens.len() < 2 => {
+                bail!("MGet command needs at least one key");
+            }
+            Command::Migrate if tokens.len() < 6 => {
+                bail!("Migrate command needs host, port, key, destination-db and timeout");
+            }
+            Command::Del if tokens.len() < 2 => {
+                bail!("Del command needs at least one key");
+            }
+            Command::DelIfEq if tokens.len() != 3 => {
+                bail!("DelIfEq command needs a key and a value");
+            }
+            Command::Append if tokens.len() != 3 => {
+                bail!("Append command needs a key and a value");
+            }
+            Command::Object if tokens.len() != 3 => {
+                bail!("Object command needs a subcommand and a key");
+            }
+            Command::Auth if tokens.len() != 2 && tokens.len() != 3 => {
+                bail!("Auth command needs a password, or a user and a password");
+            }
+            Command::ReplicaOf if tokens.len() != 3 => {
+                bail!("ReplicaOf command needs host and port, or NO ONE");
+            }
+            Command::Config
+            | Command::Cluster
+            | Command::Client
+            | Command::Migrate
+            | Command::MGet
+            | Command::MSet
+            | Command::Del
+            | Command::DelIfEq
+            | Command::Append
+            | Command::Object
+            | Command::Auth
+            | Command::ReplicaOf => {
+                if tokens.len() < 2 {
+                    bail!("{} command needs a subcommand", cmd.name());
+                }
+                let args = tokens
+                    .into_iter()
+                    .skip(1)
+                    .map(|arg| arg.try_into().context("parsing arg from Type"))
+                    .collect::<Result<Vec<String>>>()?;
+
+                Ok(Self {
+                    command: cmd,
+                    args: Some(args),
+                    bytes_vec,
+                })
+            }
+            Command::Exec => {
+                // EXEC [DRYRUN]
+                let args = tokens
+                    .into_iter()
+                    .skip(1)
+                    .map(|arg| arg.try_into().context("parsing arg from Type"))
+                    .collect::<Result<Vec<String>>>()?;
+                if args.len() > 1 || !args.iter().all(|arg| arg.eq_ignore_ascii_case("dryrun")) {
+                    bail!("Exec command only takes a DRYRUN option");
                 }
+
+                Ok(Self {
+                    command: cmd,
+                    args: (!args.is_empty()).then_some(args),
+                    bytes_vec,
+                })
+            }
+            Command::Set => {
+                // SET key value [PX milliseconds] [IFVERSION version]
+                if tokens.len() < 3 || tokens.len() > 7 || tokens.len() % 2 == 0 {
+                    bail!("Set command needs a key, a value and PX or IFVERSION options");
+                }
+                let args = tokens
+                    .into_iter()
+                    .skip(1)
+                    .map(|arg| arg.try_into().context("parsing arg from Type"))
+                    .collect::<Result<Vec<String>>>()?;
+
+                Ok(Self {
+                    command: cmd,
+                    args: Some(args),
+                    bytes_vec,
+                })
             }
             Command::Info => {
                 if tokens.len() == 1 {
@@ -167,66 +246,13 @@ impl Frame {
         self.bytes_vec.clone()
     }
 
-    pub fn serialize(&self) -> Option<Vec<u8>> {
-        None
-    }
-}
-
-fn parse_integer(buffer: &[u8]) -> (Type, Cursor) {
-    let (val, cursor) = parse_crlf(buffer);
-    let val = str::from_utf8(&val).unwrap();
-    return (Type::Integer(val.to_string()), cursor);
-}
-
-fn parse_simple_string(buffer: &[u8]) -> (Type, Cursor) {
-    let (val, cursor) = parse_crlf(buffer);
-    return (
-        Type::SimpleString(str::from_utf8(&val).unwrap().to_string()),
-        cursor,
-    );
-}
-
-fn parse_bulk_string(buffer: &[u8]) -> (Type, Cursor) {
-    let (len_raw, cursor) = parse_crlf(buffer);
-    let len = parse_usize(len_raw);
-    let val = &buffer[cursor..(cursor + len)];
-    return (
-        Type::BulkString(str::from_utf8(&val).unwrap().to_string()),
-        cursor + len + 2,
-    );
-}
-
-fn parse_array(buffer: &[u8]) -> (Type, Cursor) {
-    let (num_elems_raw, mut cursor) = parse_crlf(buffer);
-    let num_elems = parse_usize(num_elems_raw);
-    let mut rv = Vec::<Type>::with_capacity(num_elems);
-    for _ in 0..num_elems {
-        let (elem, cursor_new) = parse_resp(&buffer[cursor..]);
-        cursor += cursor_new + 1;
-        rv.push(elem);
-    }
-    return (Type::Array(rv), cursor);
-}
-
-fn parse_crlf(buffer: &[u8]) -> (&[u8], Cursor) {
-    let mut i: usize = 0;
-    while i < buffer.len() && buffer[i] != b'\r' {
-        i += 1;
+    /// Re-encodes the frame as a RESP array, with the command name in its
+    /// canonical upper case form.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut tokens = vec![Type::BulkString(self.command.name().to_string())];
+        if let Some(args) = &self.args {
+            tokens.extend(args.iter().cloned().map(Type::BulkString));
+        }
+        Type::Array(tokens).serialize()
     }
-    return (&buffer[..i], (i + 2).min(buffer.len()));
-}
-
-fn parse_usize(buffer: &[u8]) -> usize {
-    let num_elems_str = str::from_utf8(buffer).expect("parse usize: from utf8");
-    num_elems_str.parse::<usize>().expect("parse usize: rv")
-}
-
-fn parse_resp(buffer: &[u8]) -> (Type, Cursor) {
-    match buffer[0] {
-        b'+' => return parse_simple_string(&buffer[1..]),
-        b'$' => return parse_bulk_string(&buffer[1..]),
-        b':' => return parse_integer(&buffer[1..]),
-        b'*' => return parse_array(&buffer[1..]),
-        x => panic!("Invalid RESP Type: {:?}", x),
-    };
 }
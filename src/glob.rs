@@ -0,0 +1,82 @@
+//! Redis style glob matching, as used by `KEYS`.
+//!
+//! Supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape the next
+//! character. An unterminated `[` matches itself.
+
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    let (mut pi, mut si) = (0, 0);
+    // Where to resume after the last `*` when the rest fails to match.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if let Some(next) = match_one(&pattern, pi, s[si]) {
+            pi = next;
+            si += 1;
+            continue;
+        }
+        if pattern.get(pi) == Some(&'*') {
+            backtrack = Some((pi + 1, si));
+            pi += 1;
+            continue;
+        }
+        match backtrack {
+            Some((star_pi, star_si)) => {
+                pi = star_pi;
+                si = star_si + 1;
+                backtrack = Some((star_pi, si));
+            }
+            None => return false,
+        }
+    }
+    pattern[pi..].iter().all(|c| *c == '*')
+}
+
+/// Matches `c` against the pattern token at `pi`, returning the index of the
+/// next token on success. `*` never matches here, the caller handles it.
+fn match_one(pattern: &[char], pi: usize, c: char) -> Option<usize> {
+    match pattern.get(pi)? {
+        '*' => None,
+        '?' => Some(pi + 1),
+        '\\' if pi + 1 < pattern.len() => (pattern[pi + 1] == c).then_some(pi + 2),
+        '[' => match match_class(pattern, pi, c) {
+            Some((true, next)) => Some(next),
+            Some((false, _)) => None,
+            None => (c == '[').then_some(pi + 1),
+        },
+        p => (*p == c).then_some(pi + 1),
+    }
+}
+
+/// Matches `c` against the class starting at `pattern[start] == '['`,
+/// returning whether it matched and the index after the closing `]`, or `None`
+/// if the class is never closed.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match *pattern.get(i)? {
+            ']' => return Some((matched != negate, i + 1)),
+            '\\' if i + 1 < pattern.len() => {
+                matched |= pattern[i + 1] == c;
+                i += 2;
+            }
+            lo if pattern.get(i + 1) == Some(&'-') && i + 2 < pattern.len() => {
+                let hi = pattern[i + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= lo <= c && c <= hi;
+                i += 3;
+            }
+            p => {
+                matched |= p == c;
+                i += 1;
+            }
+        }
+    }
+}
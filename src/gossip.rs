@@ -0,0 +1,360 @@
+//! Cluster bus: every node pings the others on a second port and they reply
+//! with what they know about the cluster, so slot ownership spreads and
+//! nodes that stop answering are noticed.
+//!
+//! Messages are RESP arrays of `[kind, header, nodes]`. The header describes
+//! the sender: `[id, ip:port, bus-port, master-id|"-", config-epoch,
+//! current-epoch, slots]`, with its slots as space separated ranges. `nodes`
+//! lists other nodes as `[id, ip:port, bus-port, master-id|"-",
+//! config-epoch, health]`: everything the sender knows for `PING`, `PONG` and
+//! `MEET`, the nodes that just failed for `FAIL`. A replica asks for votes
+//! with `AUTH_REQUEST`, masters answer `AUTH_ACK` to grant theirs and `PONG`
+//! otherwise.
+use crate::client::Client;
+use crate::cluster::*;
+use crate::log::*;
+use crate::resptype::*;
+use crate::server::*;
+use crate::server_log;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// A ping to a node that does not know the sender yet.
+    Meet,
+    Ping,
+    Pong,
+    /// Announces that a majority agreed the listed nodes are down.
+    Fail,
+    /// A replica asking masters to vote for it to replace its failed master.
+    AuthRequest,
+    /// A vote for the replica that sent `AuthRequest`.
+    AuthAck,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Message {
+    pub kind: Kind,
+    pub sender: Node,
+    pub current_epoch: u64,
+    /// Slots the sender owns.
+    pub slots: Vec<RangeInclusive<u16>>,
+    pub gossip: Vec<Node>,
+}
+
+impl Message {
+    fn encode(self) -> Type {
+        let kind = match self.kind {
+            Kind::Meet => "MEET",
+            Kind::Ping => "PING",
+            Kind::Pong => "PONG",
+            Kind::Fail => "FAIL",
+            Kind::AuthRequest => "AUTH_REQUEST",
+            Kind::AuthAck => "AUTH_ACK",
+        };
+        let slots: Vec<String> = self
+            .slots
+            .iter()
+            .map(|range| format!("{}-{}", range.start(), range.end()))
+            .collect();
+        let mut header = node_fields(self.sender);
+        header.pop();
+        header.push(Type::Integer(self.current_epoch.to_string()));
+        header.push(Type::BulkString(slots.join(" ")));
+        let gossip = self
+            .gossip
+            .into_iter()
+            .map(|node| Type::Array(node_fields(node)))
+            .collect();
+        Type::Array(vec![
+            Type::BulkString(kind.to_string()),
+            Type::Array(header),
+            Type::Array(gossip),
+        ])
+    }
+
+    fn decode(value: Type) -> Result<Self> {
+        let Type::Array(parts) = value else {
+            bail!("cluster bus message must be an array");
+        };
+        let [kind, Type::Array(header), Type::Array(gossip)] = <[Type; 3]>::try_from(parts)
+            .map_err(|_| anyhow!("cluster bus message must have 3 parts"))?
+        else {
+            bail!("malformed cluster bus message");
+        };
+        let kind = match String::try_from(kind)?.as_str() {
+            "MEET" => Kind::Meet,
+            "PING" => Kind::Ping,
+            "PONG" => Kind::Pong,
+            "FAIL" => Kind::Fail,
+            "AUTH_REQUEST" => Kind::AuthRequest,
+            "AUTH_ACK" => Kind::AuthAck,
+            kind => bail!("unknown cluster bus message {:?}", kind),
+        };
+        let [id, addr, bus_port, master, config_epoch, current_epoch, slots] =
+            <[Type; 7]>::try_from(header)
+                .map_err(|_| anyhow!("cluster bus header must have 7 fields"))?;
+        let sender = parse_node([id, addr, bus_port, master, config_epoch], "ok")?;
+        let slots = String::try_from(slots)?
+            .split_whitespace()
+            .map(parse_slot_range)
+            .collect::<Result<_>>()?;
+        let gossip = gossip
+            .into_iter()
+            .map(|entry| {
+                let Type::Array(fields) = entry else {
+                    bail!("gossip entry must be an array");
+                };
+                let [id, addr, bus_port, master, config_epoch, health] =
+                    <[Type; 6]>::try_from(fields)
+                        .map_err(|_| anyhow!("gossip entry must have 6 fields"))?;
+                parse_node(
+                    [id, addr, bus_port, master, config_epoch],
+                    &String::try_from(health)?,
+                )
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            kind,
+            sender,
+            current_epoch: integer(current_epoch)?,
+            slots,
+            gossip,
+        })
+    }
+}
+
+fn node_fields(node: Node) -> Vec<Type> {
+    let health = match node.health {
+        Health::Online => "ok",
+        Health::PFail => "pfail",
+        Health::Fail => "fail",
+    };
+    vec![
+        Type::BulkString(node.id),
+        Type::BulkString(node.addr.to_string()),
+        Type::Integer(node.bus_port.to_string()),
+        Type::BulkString(node.replica_of.unwrap_or_else(|| "-".to_string())),
+        Type::Integer(node.config_epoch.to_string()),
+        Type::BulkString(health.to_string()),
+    ]
+}
+
+fn parse_node(fields: [Type; 5], health: &str) -> Result<Node> {
+    let [id, addr, bus_port, master, config_epoch] = fields;
+    let master = String::try_from(master)?;
+    Ok(Node {
+        id: String::try_from(id)?,
+        addr: String::try_from(addr)?
+            .parse()
+            .context("invalid node address")?,
+        bus_port: integer(bus_port)?,
+        replica_of: (master != "-").then_some(master),
+        config_epoch: integer(config_epoch)?,
+        health: match health {
+            "ok" => Health::Online,
+            "pfail" => Health::PFail,
+            "fail" => Health::Fail,
+            _ => bail!("unknown node health {:?}", health),
+        },
+    })
+}
+
+fn integer<T: TryFrom<i64>>(value: Type) -> Result<T> {
+    T::try_from(i64::try_from(value)?)
+        .ok()
+        .context("integer out of range")
+}
+
+/// How often every other node is pinged, often enough to hear back several
+/// times within the node timeout.
+fn ping_period(node_timeout: Duration) -> Duration {
+    (node_timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))
+}
+
+/// Answers messages from other nodes until `shutdown` fires.
+pub async fn serve(
+    listener: TcpListener,
+    cluster: Cluster,
+    notify_shutdown: broadcast::Sender<()>,
+) {
+    let mut shutdown = notify_shutdown.subscribe();
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    let cluster = cluster.clone();
+                    let shutdown = notify_shutdown.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(e) = link_handler(stream, cluster, shutdown).await {
+                            server_log!(Level::Verbose, "cluster bus link error: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    server_log!(Level::Warning, "cluster bus error: {}", e);
+                }
+            },
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+async fn link_handler(
+    stream: TcpStream,
+    cluster: Cluster,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+    let mut link = Client::new(stream);
+    loop {
+        let frame = tokio::select! {
+            frame = link.read_frame() => frame?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+        let Some((message, _)) = frame else {
+            return Ok(());
+        };
+        if let Some(reply) = cluster.receive(Message::decode(message)?) {
+            link.send(reply.encode()).await?;
+        }
+    }
+}
+
+/// Pings every known node once per period, keeping a link open to each, and
+/// flags the ones that stop answering. Failures a majority agrees on are
+/// announced to everyone with `FAIL`, and a replica whose master failed runs
+/// for election to replace it.
+pub async fn ping_loop(server: Server, mut shutdown: broadcast::Receiver<()>) {
+    let Some(cluster) = server.cluster().cloned() else {
+        return;
+    };
+    let period = ping_period(cluster.node_timeout());
+    let mut links: HashMap<String, Client> = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = cluster.clock().sleep(period) => {}
+            _ = shutdown.recv() => return,
+        }
+
+        let meets = cluster.take_meets().into_iter().map(|addr| (None, addr));
+        let meet = cluster.message(Kind::Meet);
+        for reply in broadcast(&mut links, meets.collect(), meet, period).await {
+            cluster.receive(reply);
+        }
+        let ping = cluster.message(Kind::Ping);
+        for reply in broadcast(&mut links, peers(&cluster), ping, period).await {
+            cluster.receive(reply);
+        }
+
+        let failed = cluster.detect_failures();
+        if !failed.is_empty() {
+            server_log!(
+                Level::Notice,
+                "Marking nodes as failing: {}",
+                failed.join(", ")
+            );
+            let fail = cluster.fail_message(&failed).encode();
+            for link in links.values_mut() {
+                let _ = link.send(fail.clone()).await;
+            }
+        }
+
+        let Some(request) = cluster.election() else {
+            continue;
+        };
+        let epoch = request.current_epoch;
+        let replies = broadcast(&mut links, peers(&cluster), request, period).await;
+        let votes = replies
+            .iter()
+            .filter(|reply| reply.kind == Kind::AuthAck)
+            .count();
+        for reply in replies {
+            cluster.receive(reply);
+        }
+        if cluster.promote_if_elected(epoch, votes) {
+            server_log!(
+                Level::Notice,
+                "Failover election won for epoch {}, serving the failed master's slots",
+                epoch
+            );
+            server.promote();
+            // Let everyone know about the new owner right away.
+            let pong = cluster.message(Kind::Pong).encode();
+            for link in links.values_mut() {
+                let _ = link.send(pong.clone()).await;
+            }
+        }
+    }
+}
+
+fn peers(cluster: &Cluster) -> Vec<(Option<String>, SocketAddr)> {
+    cluster
+        .peers()
+        .into_iter()
+        .map(|(id, addr)| (Some(id), addr))
+        .collect()
+}
+
+/// Sends `message` to every target at once and collects the replies that
+/// arrive within `timeout`. Targets are `(id, bus address)`, with no id for
+/// nodes that have not been met yet.
+async fn broadcast(
+    links: &mut HashMap<String, Client>,
+    targets: Vec<(Option<String>, SocketAddr)>,
+    message: Message,
+    timeout: Duration,
+) -> Vec<Message> {
+    let mut exchanges = JoinSet::new();
+    for (id, addr) in targets {
+        let link = id.as_ref().and_then(|id| links.remove(id));
+        exchanges.spawn(exchange(id, addr, link, message.clone(), timeout));
+    }
+    let mut replies = Vec::new();
+    while let Some(res) = exchanges.join_next().await {
+        let Ok((id, res)) = res else {
+            continue;
+        };
+        match res {
+            Ok((link, reply)) => {
+                links.insert(id.unwrap_or_else(|| reply.sender.id.clone()), link);
+                replies.push(reply);
+            }
+            Err(e) => {
+                server_log!(Level::Debug, "cluster bus exchange failed: {:#}", e);
+            }
+        }
+    }
+    replies
+}
+
+/// Sends `message` over `link`, connecting to `addr` first if there is no
+/// link yet, and waits up to `timeout` for the reply.
+async fn exchange(
+    id: Option<String>,
+    addr: SocketAddr,
+    link: Option<Client>,
+    message: Message,
+    timeout: Duration,
+) -> (Option<String>, Result<(Client, Message)>) {
+    let exchange = async move {
+        let mut link = match link {
+            Some(link) => link,
+            None => Client::connect(addr).await?,
+        };
+        link.send(message.encode()).await?;
+        let reply = Message::decode(link.read_reply().await?)?;
+        Ok((link, reply))
+    };
+    let res = match tokio::time::timeout(timeout, exchange).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow!("no reply from {} within {:?}", addr, timeout)),
+    };
+    (id, res)
+}
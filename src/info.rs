@@ -1,11 +1,10 @@
-use crate::flags::*;
 use crate::frame::*;
+use crate::log::*;
 use crate::resptype::*;
 use crate::server::*;
+use crate::server_log;
 use anyhow::{bail, Context, Result};
-use itertools::Itertools;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
 
 const MASTER_DEFAULTS: [(&str, &str); 5] = [
     ("role", "master"),
@@ -45,12 +44,10 @@ const REPLICATION_ARGS: [&str; 7] = [
     "master_repl_offset",
 ];
 
-pub type Db = Arc<Mutex<Database>>;
-
-pub fn init_info_db(info_db: &Db, args: &Args) -> Result<()> {
-    let defaults: Vec<(&str, &str)> = match args.replicaof {
-        Some(_) => SLAVE_DEFAULTS.to_vec(),
-        None => MASTER_DEFAULTS.to_vec(),
+pub fn init_info_db(info_db: &Db, addr: &SocketAddr, role: &Role) -> Result<()> {
+    let defaults: Vec<(&str, &str)> = match role {
+        Role::Slave(_) => SLAVE_DEFAULTS.to_vec(),
+        Role::Master => MASTER_DEFAULTS.to_vec(),
     };
     let mut info_db = info_db.lock().unwrap();
 
@@ -58,27 +55,39 @@ pub fn init_info_db(info_db: &Db, args: &Args) -> Result<()> {
         let db_entry: DbEntry = DbEntry::new(v.to_owned(), None);
         info_db.insert(k.to_owned(), db_entry);
     }
-    if let Some(tokens) = &args.replicaof {
-        let (host, port) = tokens
-            .into_iter()
-            .collect_tuple()
-            .context("parsing arguments for --replicaof flag")?;
-
-        let host: String = host.try_into().context("parsing host from &str")?;
-        let db_entry: DbEntry = DbEntry::new(host.to_owned(), None);
+    if let Role::Slave(master_addr) = role {
+        let db_entry: DbEntry = DbEntry::new(master_addr.ip().to_string(), None);
         info_db.insert("master_host".to_owned(), db_entry);
 
-        let port: String = port.try_into().context("parsing port from &str")?;
-        let db_entry: DbEntry = DbEntry::new(port.to_owned(), None);
+        let db_entry: DbEntry = DbEntry::new(master_addr.port().to_string(), None);
         info_db.insert("master_port".to_owned(), db_entry);
     }
 
-    let db_entry: DbEntry = DbEntry::new(args.port.to_owned(), None);
+    let db_entry: DbEntry = DbEntry::new(addr.port().to_string(), None);
     info_db.insert("tcp_port".to_owned(), db_entry);
 
     Ok(())
 }
 
+fn info_entry(info_db: &Database, key: &str) -> String {
+    match info_db.peek(key) {
+        Some(entry) => entry.value(),
+        None => "(nil)".to_string(),
+    }
+}
+
+/// The `slaveN` lines describing connected replicas.
+fn replica_lines(info_db: &Database) -> Vec<String> {
+    let count: usize = info_entry(info_db, "connected_slaves")
+        .parse()
+        .unwrap_or_default();
+    (0..count)
+        .map(|i| format!("slave{}", i))
+        .filter(|k| info_db.peek(k).is_some())
+        .map(|k| format!("{}:{}\n", k, info_entry(info_db, &k)))
+        .collect()
+}
+
 enum InfoQuery {
     Replication,
     All,
@@ -122,9 +131,8 @@ fn info_query(query: InfoQuery, info_db: &Db) -> Result<Vec<u8>> {
 
             let rv = rv
                 .iter()
-                .map(|k| {
-                    k.to_owned() + ":" + info_db.get(k.to_owned()).unwrap().value().as_str() + "\n"
-                })
+                .map(|k| k.to_owned() + ":" + info_entry(&info_db, k).as_str() + "\n")
+                .chain(replica_lines(&info_db))
                 .collect::<Vec<String>>();
 
             let rv = rv
@@ -145,9 +153,8 @@ fn info_query(query: InfoQuery, info_db: &Db) -> Result<Vec<u8>> {
 
             let rv = rv
                 .iter()
-                .map(|k| {
-                    k.to_owned() + ":" + info_db.get(k.to_owned()).unwrap().value().as_str() + "\n"
-                })
+                .map(|k| k.to_owned() + ":" + info_entry(&info_db, k).as_str() + "\n")
+                .chain(replica_lines(&info_db))
                 .collect::<Vec<String>>();
 
             let rv = rv
@@ -175,29 +182,26 @@ fn info_query(query: InfoQuery, info_db: &Db) -> Result<Vec<u8>> {
 }
 
 pub fn handle_info(frame: Frame, info_db: &Db) -> Result<Vec<u8>> {
-    println!("handling info command");
+    server_log!(Level::Debug, "handling info command");
     // let mut info_db = info_db.lock().unwrap();
     if let Some(mut args) = frame.args() {
         if args.len() == 1 {
-            let query = args.pop().context("parsing argument for info command")?;
-            match query.to_lowercase().as_str() {
-                "replication" => {
-                    return info_query(query.try_into()?, info_db);
-                }
-                "all" => {
-                    return info_query(query.try_into()?, info_db);
-                }
-                "test" => {
-                    return info_query(query.try_into()?, info_db);
-                }
+            let query = args
+                .pop()
+                .context("parsing argument for info command")?
+                .to_lowercase();
+            match query.as_str() {
+                "replication" => info_query(query.try_into()?, info_db),
+                "all" => info_query(query.try_into()?, info_db),
+                "test" => info_query(query.try_into()?, info_db),
                 _ => {
                     bail!("can only support replication as arg for info");
                 }
             }
         } else {
-            return info_query("all".try_into()?, info_db);
+            info_query("all".try_into()?, info_db)
         }
     } else {
-        return info_query("all".try_into()?, info_db);
+        info_query("all".try_into()?, info_db)
     }
 }
@@ -0,0 +1,63 @@
+//! Per-key locks held by commands while their effects travel past the
+//! in-memory database, so that a backend sees writes to a key in the order
+//! they were applied.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+type KeyLock = Arc<tokio::sync::Mutex<()>>;
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct KeyLocks {
+    locks: Arc<Mutex<HashMap<String, KeyLock>>>,
+}
+
+impl KeyLocks {
+    /// Waits until no other command holds any of `keys` and takes them.
+    /// Keys are taken in order, so commands sharing several keys can't
+    /// deadlock.
+    pub(crate) async fn lock(&self, keys: &[&str]) -> KeyGuard {
+        let mut keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let locks: Vec<KeyLock> = {
+            let mut map = self.locks.lock().unwrap();
+            keys.iter()
+                .map(|key| map.entry(key.clone()).or_default().clone())
+                .collect()
+        };
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in locks {
+            guards.push(lock.lock_owned().await);
+        }
+        KeyGuard {
+            guards,
+            keys,
+            locks: self.locks.clone(),
+        }
+    }
+}
+
+/// Keys taken with [`KeyLocks::lock`], released on drop.
+pub(crate) struct KeyGuard {
+    guards: Vec<OwnedMutexGuard<()>>,
+    keys: Vec<String>,
+    locks: Arc<Mutex<HashMap<String, KeyLock>>>,
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        self.guards.clear();
+        // Forget locks nobody else is holding or waiting for, the map would
+        // otherwise keep every key ever written.
+        let mut map = self.locks.lock().unwrap();
+        for key in &self.keys {
+            if map
+                .get(key)
+                .is_some_and(|lock| Arc::strong_count(lock) == 1)
+            {
+                map.remove(key);
+            }
+        }
+    }
+}
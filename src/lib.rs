@@ -0,0 +1,32 @@
+//! An embeddable Redis compatible key-value store.
+//!
+//! The binary in `main.rs` is a thin wrapper around [`ServerBuilder`], the
+//! same API can be used to run the store inside other programs or to start
+//! in-process instances from integration tests.
+
+pub mod auth;
+pub mod backend;
+pub mod client;
+pub mod clock;
+pub mod cluster;
+pub mod command;
+pub mod config;
+pub mod frame;
+mod glob;
+mod gossip;
+mod info;
+mod keylock;
+pub mod log;
+mod memcache;
+pub mod raft;
+pub mod rdb;
+mod replication;
+mod response;
+pub mod resptype;
+pub mod sentinel;
+pub mod server;
+mod slowlog;
+mod tier;
+
+pub use resptype::Type;
+pub use server::{Database, Db, DbEntry, Role, Server, ServerBuilder, ServerHandle};
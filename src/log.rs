@@ -0,0 +1,333 @@
+//! Server and audit logging.
+//!
+//! Without a `logfile` messages go to stdout as plain text. With one they are
+//! written as JSON lines to a file that is rotated once it reaches
+//! `log-max-size`, keeping `log-max-files` old segments (`server.log.1`,
+//! `server.log.2`, ...) and gzipping them when `log-compress` is set. The
+//! audit log records every write and `CONFIG SET` the same way.
+//!
+//! Lines logged while a command runs carry the trace id its client set with
+//! `CLIENT TRACEID`, see [`traced`].
+use crate::clock::*;
+use crate::config::*;
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Logs `format!` style arguments at a [`Level`](crate::log::Level).
+#[macro_export]
+macro_rules! server_log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::log($level, format_args!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+            Level::Notice => "notice",
+            Level::Warning => "warning",
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "debug" => Ok(Level::Debug),
+            "verbose" => Ok(Level::Verbose),
+            "notice" => Ok(Level::Notice),
+            "warning" => Ok(Level::Warning),
+            _ => bail!("invalid log level: {:?}", s),
+        }
+    }
+}
+
+/// When and how a [`RotatingFile`] rotates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate once the file would grow past this many bytes, `0` never
+    /// rotates.
+    pub max_size: u64,
+    /// Old segments to keep, the oldest is deleted beyond this.
+    pub max_files: usize,
+    /// Gzip segments as they are rotated out.
+    pub compress: bool,
+}
+
+/// An append-only file that moves itself aside once it gets too big.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: Rotation,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> Result<Self> {
+        let path = path.into();
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            rotation,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.rotation = rotation;
+    }
+
+    /// Appends `line` and a newline, rotating first if it would not fit.
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.rotation.max_size > 0 && self.size > 0 && self.size + len > self.rotation.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn segment(&self, n: usize, gz: bool) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        if gz {
+            name.push(".gz");
+        }
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let max_files = self.rotation.max_files;
+        // Shift the old segments up by one, dropping the oldest.
+        for gz in [false, true] {
+            if max_files > 0 {
+                remove_if_exists(&self.segment(max_files, gz))?;
+            }
+            for n in (1..max_files).rev() {
+                let from = self.segment(n, gz);
+                if from.exists() {
+                    fs::rename(&from, self.segment(n + 1, gz))
+                        .with_context(|| format!("rotating {}", from.display()))?;
+                }
+            }
+        }
+
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else if self.rotation.compress {
+            gzip(&self.path, &self.segment(1, true))?;
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, self.segment(1, false))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening log file {}", path.display()))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn gzip(from: &Path, to: &Path) -> Result<()> {
+    let mut input = File::open(from)?;
+    let mut output = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut input, &mut output)?;
+    output.finish()?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Logger {
+    level: Level,
+    server: Option<RotatingFile>,
+    audit: Option<RotatingFile>,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: Level::Notice,
+    server: None,
+    audit: None,
+});
+
+/// Applies the logging settings from `config`, (re)opening files whose path
+/// changed. Nothing changes if one of them can't be opened.
+pub fn configure(config: &Config) -> Result<()> {
+    let rotation = config.rotation();
+    let mut logger = LOGGER.lock().unwrap();
+    let server = reopen(&logger.server, &config.logfile, rotation)?;
+    let audit = reopen(&logger.audit, &config.audit_logfile, rotation)?;
+
+    if let Some(server) = server {
+        logger.server = server;
+    }
+    if let Some(audit) = audit {
+        logger.audit = audit;
+    }
+    let logger = &mut *logger;
+    for file in [&mut logger.server, &mut logger.audit]
+        .into_iter()
+        .flatten()
+    {
+        file.set_rotation(rotation);
+    }
+    logger.level = config.loglevel;
+    Ok(())
+}
+
+/// Opens `path` unless `current` already points at it, an empty path closes
+/// the file.
+fn reopen(
+    current: &Option<RotatingFile>,
+    path: &str,
+    rotation: Rotation,
+) -> Result<Option<Option<RotatingFile>>> {
+    let unchanged = match current {
+        Some(file) => file.path() == Path::new(path),
+        None => path.is_empty(),
+    };
+    if unchanged {
+        return Ok(None);
+    }
+    if path.is_empty() {
+        return Ok(Some(None));
+    }
+    Ok(Some(Some(RotatingFile::open(path, rotation)?)))
+}
+
+tokio::task_local! {
+    static TRACE_ID: String;
+}
+
+/// Runs `f` with `trace_id` attached to everything it logs.
+pub async fn traced<F: Future>(trace_id: Option<String>, f: F) -> F::Output {
+    match trace_id {
+        Some(trace_id) => TRACE_ID.scope(trace_id, f).await,
+        None => f.await,
+    }
+}
+
+/// Trace id of the command running on the current task, if its client set
+/// one.
+pub fn trace_id() -> Option<String> {
+    TRACE_ID.try_with(String::clone).ok()
+}
+
+/// The `"trace_id"` field of a JSON log line, empty without a trace id.
+fn trace_field() -> String {
+    match trace_id() {
+        Some(trace_id) => format!(",\"trace_id\":{}", json_string(&trace_id)),
+        None => String::new(),
+    }
+}
+
+pub fn log(level: Level, args: fmt::Arguments) {
+    let mut logger = LOGGER.lock().unwrap();
+    if level < logger.level {
+        return;
+    }
+    let Some(file) = logger.server.as_mut() else {
+        match trace_id() {
+            Some(trace_id) => println!("[{}] {}", trace_id, args),
+            None => println!("{}", args),
+        }
+        return;
+    };
+    let line = format!(
+        "{{\"ts\":{},\"pid\":{},\"level\":\"{}\"{},\"msg\":{}}}",
+        timestamp(),
+        std::process::id(),
+        level,
+        trace_field(),
+        json_string(&args.to_string())
+    );
+    if let Err(e) = file.write_line(&line) {
+        eprintln!("writing log file: {:#}", e);
+    }
+}
+
+/// Records that `client` ran `command` on `key`, if an audit log is set.
+pub fn audit(client: &str, command: &str, key: &str) {
+    let mut logger = LOGGER.lock().unwrap();
+    let Some(file) = logger.audit.as_mut() else {
+        return;
+    };
+    let line = format!(
+        "{{\"ts\":{},\"client\":{}{},\"command\":{},\"key\":{}}}",
+        timestamp(),
+        json_string(client),
+        trace_field(),
+        json_string(command),
+        json_string(key)
+    );
+    if let Err(e) = file.write_line(&line) {
+        eprintln!("writing audit log: {:#}", e);
+    }
+}
+
+fn timestamp() -> String {
+    let now = SystemClock.unix_time();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+fn json_string(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len() + 2);
+    rv.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(rv, "\\u{:04x}", c as u32);
+            }
+            c => rv.push(c),
+        }
+    }
+    rv.push('"');
+    rv
+}
@@ -1,60 +1,266 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use clap::Parser;
 use itertools::Itertools;
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-};
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 
-use std::{thread, time};
+use redis_starter_rust::auth::{EnvProvider, FileProvider};
+use redis_starter_rust::backend::FileBackend;
+use redis_starter_rust::cluster::parse_slot_range;
+use redis_starter_rust::config::ConfigFile;
+use redis_starter_rust::log::Level;
+use redis_starter_rust::rdb::{self, Snapshot};
+use redis_starter_rust::sentinel::{Sentinel, SentinelBuilder};
+use redis_starter_rust::{server_log, Server};
 
-mod command;
+mod daemon;
 mod flags;
-mod frame;
-mod info;
-mod replication;
-mod response;
-mod resptype;
-mod server;
-
-use command::*;
+
+use daemon::*;
 use flags::*;
-use frame::*;
-use info::*;
-use replication::*;
-use response::*;
-use server::*;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
+    let mut args = Args::parse();
+    if let Some(paths) = &args.diff_rdb {
+        return diff_rdb(paths, args.diff_pattern.as_deref());
+    }
     println!("Logs from your program will appear here!");
 
-    let args = Args::parse();
-    let bind_addr: SocketAddr = format!("{}:{}", args.addr, args.port).parse().unwrap();
-
-    let db = Arc::new(Mutex::new(Database::default()));
-    let info_db = Arc::new(Mutex::new(Database::default()));
-    let _: () = init_info_db(&info_db, &args).unwrap();
-
-    // let listener = TcpListener::bind(&bind_addr).await.unwrap();
-    println!("Listening at {}", &bind_addr);
-
-    match &args.replicaof {
-        Some(tokens) => {
-            let (host, port) = tokens
-                .into_iter()
-                .collect_tuple()
-                .context("parsing arguments for --replicaof flag")
-                .unwrap();
-            let master_addr: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
-            let server = Server::new(bind_addr, Role::Slave(master_addr));
+    let config_file = match &args.config_file {
+        Some(path) => Some(ConfigFile::read(path)?),
+        None => None,
+    };
+    if let Some(file) = &config_file {
+        // Options from the file go first so the command line overrides them.
+        let argv = env::args_os()
+            .take(1)
+            .chain(config_file_args(file)?.into_iter().map(OsString::from))
+            .chain(env::args_os().skip(1));
+        args = Args::parse_from(argv);
+    }
+    if args.daemonize {
+        daemonize()?;
+    }
+
+    let mut builder = Server::builder().addr(&args.addr).port(args.port);
+    if let Some(tokens) = &args.replicaof {
+        let (host, port) = tokens
+            .iter()
+            .collect_tuple()
+            .context("parsing arguments for --replicaof flag")?;
+        let port: u16 = port.parse().context("parsing port for --replicaof flag")?;
+        builder = builder.replicaof(host, port);
+    }
+    if let Some(file) = config_file {
+        builder = builder.config_file(file);
+    }
+    if args.cluster_enabled {
+        let slots = parse_slot_range(&args.cluster_slots).context("parsing --cluster-slots")?;
+        builder = builder.cluster_slots(slots);
+    }
+    if let Some(path) = &args.cluster_config_file {
+        builder = builder.cluster_config_file(path);
+    }
+    if let Some(port) = args.cluster_port {
+        builder = builder.cluster_port(port);
+    }
+    builder = builder.cluster_node_timeout(Duration::from_millis(args.cluster_node_timeout));
+    if let Some(port) = args.raft_port {
+        builder = builder
+            .raft_port(port)
+            .raft_election_timeout(Duration::from_millis(args.raft_election_timeout))
+            .raft_snapshot_entries(args.raft_snapshot_entries)
+            .raft_dir(&args.raft_dir);
+    }
+    for (host, port) in args.raft_peer.iter().tuples() {
+        let port: u16 = port.parse().context("parsing port for --raft-peer")?;
+        builder = builder.raft_peer(host, port);
+    }
+    if let Some(path) = &args.cold_storage_file {
+        builder = builder.cold_storage(path, Duration::from_millis(args.cold_storage_idle));
+    }
+    if let Some(dir) = &args.backend_dir {
+        builder = builder.backend(Arc::new(FileBackend::open(dir)?));
+    }
+    if let Some(path) = &args.auth_file {
+        builder = builder.auth_provider(Arc::new(FileProvider::open(path)?));
+    }
+    if let Some(prefix) = &args.auth_env {
+        builder = builder.auth_provider(Arc::new(EnvProvider::new(prefix)));
+    }
+    if let Some(password) = &args.masterauth {
+        builder = builder.masterauth(password);
+    }
+    if let Some(user) = &args.masteruser {
+        builder = builder.masteruser(user);
+    }
+    if let Some(port) = args.memcached_port {
+        builder = builder.memcached_port(port);
+    }
+    if let Some(level) = &args.loglevel {
+        builder = builder.config("loglevel", level);
+    }
+    if let Some(path) = &args.logfile {
+        builder = builder.config("logfile", path);
+    }
+    if let Some(path) = &args.audit_logfile {
+        builder = builder.config("audit-logfile", path);
+    }
+
+    // Install the handlers before anything can report readiness, otherwise
+    // an early SIGTERM would still kill the process outright.
+    let mut interrupt = signal(SignalKind::interrupt()).context("installing SIGINT handler")?;
+    let mut terminate = signal(SignalKind::terminate()).context("installing SIGTERM handler")?;
+    let mut hangup = signal(SignalKind::hangup()).context("installing SIGHUP handler")?;
+
+    if args.sentinel {
+        let sentinel = sentinel_builder(&args)?.spawn().await?;
+        server_log!(
+            Level::Notice,
+            "Sentinel {} listening at {}",
+            sentinel.sentinel().myid(),
+            sentinel.local_addr()
+        );
+        let pidfile = match &args.pidfile {
+            Some(path) => Some(PidFile::create(path)?),
+            None => None,
+        };
+        notify(args.supervised, "READY=1")?;
+        let rv = sentinel
+            .run_until(shutdown_signal(&mut interrupt, &mut terminate))
+            .await;
+        drop(pidfile);
+        return rv;
+    }
+
+    let server = builder.spawn().await?;
+    server_log!(Level::Notice, "Listening at {}", server.local_addr());
+    if let Some(addr) = server.memcached_addr() {
+        server_log!(Level::Notice, "Listening for memcached clients at {}", addr);
+    }
+
+    let pidfile = match &args.pidfile {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+    notify(args.supervised, "READY=1")?;
+
+    let reloader = server.server().clone();
+    let signal = async {
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => reload_config(&reloader),
+                _ = shutdown_signal(&mut interrupt, &mut terminate) => break,
+            }
         }
-        None => {
-            let server = Server::new(bind_addr, Role::Master);
+        let _ = notify(args.supervised, "STOPPING=1");
+    };
+    let drain_timeout = Duration::from_secs(args.shutdown_timeout);
+    let rv = server.run_until(signal, drain_timeout).await;
+    drop(pidfile);
+    rv?;
+    server_log!(Level::Notice, "Shutdown complete");
+    Ok(())
+}
+
+/// Prints the keys added (`+`), removed (`-`) or changed (`~`) from the first
+/// snapshot to the second, and exits with 1 if there are any, like diff(1).
+fn diff_rdb(paths: &[PathBuf], pattern: Option<&str>) -> Result<()> {
+    let (a, b) = paths
+        .iter()
+        .map(Snapshot::read)
+        .collect_tuple()
+        .context("--diff-rdb needs two files")?;
+    let diff = rdb::diff(&a?, &b?, pattern);
+    for (mark, keys) in [
+        ("+", &diff.added),
+        ("-", &diff.removed),
+        ("~", &diff.changed),
+    ] {
+        for key in keys {
+            println!("{} {}", mark, key);
         }
     }
-    let _ = handshake(host, port, &args.port).await.unwrap();
+    println!(
+        "{} added, {} removed, {} changed",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn shutdown_signal(interrupt: &mut Signal, terminate: &mut Signal) {
+    tokio::select! {
+        _ = interrupt.recv() => server_log!(Level::Notice, "Received SIGINT, shutting down"),
+        _ = terminate.recv() => server_log!(Level::Notice, "Received SIGTERM, shutting down"),
+    }
+}
+
+fn sentinel_builder(args: &Args) -> Result<SentinelBuilder> {
+    let mut builder = Sentinel::builder()
+        .addr(&args.addr)
+        .port(args.port)
+        .down_after(Duration::from_millis(args.sentinel_down_after))
+        .failover_timeout(Duration::from_millis(args.sentinel_failover_timeout));
+    for (name, host, port, quorum) in args.sentinel_monitor.iter().tuples() {
+        let port: u16 = port
+            .parse()
+            .context("parsing port for --sentinel-monitor")?;
+        let quorum: usize = quorum
+            .parse()
+            .context("parsing quorum for --sentinel-monitor")?;
+        builder = builder.monitor(name, host, port, quorum);
+    }
+    for (host, port) in args.sentinel_peer.iter().tuples() {
+        let port: u16 = port.parse().context("parsing port for --sentinel-peer")?;
+        builder = builder.peer(host, port);
+    }
+    if let Some(password) = &args.masterauth {
+        builder = builder.masterauth(password);
+    }
+    if let Some(user) = &args.masteruser {
+        builder = builder.masteruser(user);
+    }
+    Ok(builder)
+}
+
+fn reload_config(server: &Server) {
+    server_log!(Level::Notice, "Received SIGHUP, reloading config file");
+    let reload = match server.reload_config() {
+        Ok(reload) => reload,
+        Err(e) => {
+            server_log!(
+                Level::Warning,
+                "Config reload failed, nothing changed: {:#}",
+                e
+            );
+            return;
+        }
+    };
+    if reload.applied.is_empty() && reload.restart_required.is_empty() {
+        server_log!(Level::Notice, "Config file unchanged");
+    }
+    if !reload.applied.is_empty() {
+        server_log!(
+            Level::Notice,
+            "Config applied: {}",
+            reload.applied.join(", ")
+        );
+    }
+    if !reload.restart_required.is_empty() {
+        server_log!(
+            Level::Warning,
+            "Config changed but requires a restart: {}",
+            reload.restart_required.join(", ")
+        );
+    }
 }
@@ -0,0 +1,319 @@
+//! Memcached text protocol listener.
+//!
+//! Serves `get`/`gets`, `set`, `delete`, `incr`/`decr`, `version` and `quit`
+//! on top of the same `Database` the RESP listener uses, so a key set by a
+//! memcached client can be read with `GET` and vice versa. Writes made here
+//! are not propagated to replicas.
+use crate::config::*;
+use crate::log::*;
+use crate::server::*;
+use crate::server_log;
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, RwLock},
+};
+
+/// Longest command line accepted, memcached itself allows 2048 bytes.
+const MAX_LINE: u64 = 2048;
+/// Largest value accepted by `set`, memcached's default item size limit.
+const MAX_VALUE: usize = 1024 * 1024;
+/// Exptimes above this many seconds are absolute unix timestamps.
+const RELATIVE_EXPTIME_LIMIT: i64 = 60 * 60 * 24 * 30;
+
+/// Accepts memcached connections until `notify_shutdown` fires, following the
+/// same shutdown protocol as the RESP accept loop.
+pub fn serve(
+    listener: TcpListener,
+    db: Db,
+    config: Arc<Mutex<Config>>,
+    exclusive: Arc<RwLock<()>>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete: mpsc::Sender<()>,
+) -> impl Future<Output = Result<()>> {
+    let mut shutdown = notify_shutdown.subscribe();
+    async move {
+        loop {
+            tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => {
+                        let db = db.clone();
+                        let config = config.clone();
+                        let exclusive = exclusive.clone();
+                        let shutdown = notify_shutdown.subscribe();
+                        let done = shutdown_complete.clone();
+                        tokio::spawn(async move {
+                            let rv =
+                                connection_handler(stream, db, config, exclusive, shutdown).await;
+                            drop(done);
+                            rv
+                        });
+                    }
+                    Err(e) => {
+                        server_log!(Level::Warning, "memcached error: {}", e);
+                    }
+                },
+                _ = shutdown.recv() => return Ok(()),
+            }
+        }
+    }
+}
+
+async fn connection_handler(
+    stream: TcpStream,
+    db: Db,
+    config: Arc<Mutex<Config>>,
+    exclusive: Arc<RwLock<()>>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+    let client = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_LINE);
+        let len = tokio::select! {
+            len = limited.read_until(b'\n', &mut line) => {
+                len.context("reading from stream")?
+            }
+            _ = shutdown.recv() => return Ok(()),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") {
+            writer.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+            return Ok(());
+        }
+
+        let Ok(line) = str::from_utf8(&line) else {
+            writer
+                .write_all(b"CLIENT_ERROR bad command line format\r\n")
+                .await?;
+            continue;
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let Some((&cmd, args)) = tokens.split_first() else {
+            writer.write_all(b"ERROR\r\n").await?;
+            continue;
+        };
+
+        // Commands take `exclusive` shared, so none runs in the middle of
+        // an EXEC, but only once a value has been read from the client.
+        let (reply, noreply) = match cmd {
+            "get" | "gets" => {
+                let _shared = exclusive.read().await;
+                (handle_get(args, &db), false)
+            }
+            "set" => {
+                let Some(header) = SetHeader::parse(args) else {
+                    writer
+                        .write_all(b"CLIENT_ERROR bad command line format\r\n")
+                        .await?;
+                    continue;
+                };
+                if header.bytes > MAX_VALUE {
+                    writer
+                        .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                        .await?;
+                    return Ok(());
+                }
+                let mut data = vec![0; header.bytes + 2];
+                reader
+                    .read_exact(&mut data)
+                    .await
+                    .context("reading value")?;
+                let noreply = header.noreply;
+                let _shared = exclusive.read().await;
+                let used = db.lock().unwrap().used_memory();
+                if config.lock().unwrap().over_maxmemory(used) {
+                    (
+                        b"SERVER_ERROR out of memory storing object\r\n".to_vec(),
+                        noreply,
+                    )
+                } else {
+                    (handle_set(header, data, &db), noreply)
+                }
+            }
+            "delete" => {
+                let _shared = exclusive.read().await;
+                match args {
+                    [key] => (handle_delete(key, &db), false),
+                    [key, "noreply"] => (handle_delete(key, &db), true),
+                    _ => (client_error("bad command line format"), false),
+                }
+            }
+            "incr" | "decr" => {
+                let _shared = exclusive.read().await;
+                match args {
+                    [key, delta] => (handle_incr(cmd == "incr", key, delta, &db), false),
+                    [key, delta, "noreply"] => (handle_incr(cmd == "incr", key, delta, &db), true),
+                    _ => (client_error("bad command line format"), false),
+                }
+            }
+            "version" => (
+                format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+                false,
+            ),
+            "quit" => return Ok(()),
+            _ => (b"ERROR\r\n".to_vec(), false),
+        };
+        if let ("set" | "delete" | "incr" | "decr", Some(key)) = (cmd, args.first()) {
+            audit(&client, cmd, key);
+        }
+        if !noreply {
+            writer.write_all(&reply).await?;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SetHeader {
+    key: String,
+    flags: u32,
+    exptime: i64,
+    bytes: usize,
+    noreply: bool,
+}
+
+impl SetHeader {
+    fn parse(args: &[&str]) -> Option<Self> {
+        let (key, flags, exptime, bytes, noreply) = match args {
+            [key, flags, exptime, bytes] => (key, flags, exptime, bytes, false),
+            [key, flags, exptime, bytes, "noreply"] => (key, flags, exptime, bytes, true),
+            _ => return None,
+        };
+        Some(Self {
+            key: key.to_string(),
+            flags: flags.parse().ok()?,
+            exptime: exptime.parse().ok()?,
+            bytes: bytes.parse().ok()?,
+            noreply,
+        })
+    }
+}
+
+fn client_error(msg: &str) -> Vec<u8> {
+    format!("CLIENT_ERROR {}\r\n", msg).into_bytes()
+}
+
+fn server_error(e: anyhow::Error) -> Vec<u8> {
+    format!("SERVER_ERROR {:#}\r\n", e).into_bytes()
+}
+
+/// Maps a memcached exptime onto a time to live. `None` means the item never
+/// expires and `Some(Duration::ZERO)` that it is already expired.
+fn ttl(exptime: i64, unix_now: Duration) -> Option<Duration> {
+    if exptime == 0 {
+        return None;
+    }
+    if exptime < 0 {
+        return Some(Duration::ZERO);
+    }
+    if exptime <= RELATIVE_EXPTIME_LIMIT {
+        return Some(Duration::from_secs(exptime as u64));
+    }
+    Some(Duration::from_secs(exptime as u64).saturating_sub(unix_now))
+}
+
+fn handle_get(keys: &[&str], db: &Db) -> Vec<u8> {
+    if keys.is_empty() {
+        return b"ERROR\r\n".to_vec();
+    }
+    let mut db = db.lock().unwrap();
+    let now = db.now();
+    let mut rv = Vec::new();
+    for key in keys {
+        let entry = match db.get(key) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => continue,
+            Err(e) => return server_error(e),
+        };
+        if entry.is_expired(now) {
+            continue;
+        }
+        let value = entry.value();
+        rv.extend(format!("VALUE {} {} {}\r\n", key, entry.flags(), value.len()).into_bytes());
+        rv.extend(value.into_bytes());
+        rv.extend(b"\r\n");
+    }
+    rv.extend(b"END\r\n");
+    rv
+}
+
+fn handle_set(header: SetHeader, mut data: Vec<u8>, db: &Db) -> Vec<u8> {
+    if !data.ends_with(b"\r\n") {
+        return client_error("bad data chunk");
+    }
+    data.truncate(header.bytes);
+    let Ok(value) = String::from_utf8(data) else {
+        return client_error("values must be valid utf8");
+    };
+
+    let mut db = db.lock().unwrap();
+    if db.is_append_only(&header.key) {
+        return client_error("key is append-only");
+    }
+    match ttl(header.exptime, db.clock().unix_time()) {
+        Some(ttl) if ttl.is_zero() => {
+            db.remove(&header.key);
+        }
+        ttl => {
+            let expiry = ttl.map(|ttl| db.now() + ttl);
+            let entry = DbEntry::new(value, expiry).with_flags(header.flags);
+            db.insert(header.key, entry);
+        }
+    }
+    b"STORED\r\n".to_vec()
+}
+
+fn handle_delete(key: &str, db: &Db) -> Vec<u8> {
+    let mut db = db.lock().unwrap();
+    if db.is_append_only(key) {
+        return client_error("key is append-only");
+    }
+    let now = db.now();
+    match db.remove(key) {
+        Some(entry) if !entry.is_expired(now) => b"DELETED\r\n".to_vec(),
+        _ => b"NOT_FOUND\r\n".to_vec(),
+    }
+}
+
+fn handle_incr(incr: bool, key: &str, delta: &str, db: &Db) -> Vec<u8> {
+    let Ok(delta) = delta.parse::<u64>() else {
+        return client_error("invalid numeric delta argument");
+    };
+    let mut db = db.lock().unwrap();
+    if db.is_append_only(key) {
+        return client_error("key is append-only");
+    }
+    let now = db.now();
+    let entry = match db.get(key) {
+        Ok(entry) => entry.filter(|entry| !entry.is_expired(now)).cloned(),
+        Err(e) => return server_error(e),
+    };
+    let Some(mut entry) = entry else {
+        return b"NOT_FOUND\r\n".to_vec();
+    };
+    let Ok(current) = entry.value().parse::<u64>() else {
+        return client_error("cannot increment or decrement non-numeric value");
+    };
+    // Like memcached, incr wraps around at 64 bits and decr stops at zero.
+    let value = if incr {
+        current.wrapping_add(delta)
+    } else {
+        current.saturating_sub(delta)
+    };
+    entry.set_value(value.to_string());
+    db.insert(key.to_string(), entry);
+    format!("{}\r\n", value).into_bytes()
+}
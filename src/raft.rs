@@ -0,0 +1,1162 @@
+//! Experimental strongly consistent mode: writes go through a Raft log
+//! replicated to a fixed set of nodes, and every node applies them once a
+//! majority has stored them. Reads are only served by the leader, after a
+//! majority has confirmed it still is the leader, so a client never reads
+//! a value older than one it already wrote or read.
+//!
+//! Nodes talk over their own port with RESP arrays. `[VOTE, term,
+//! candidate, last-log-index, last-log-term]` is answered with `[term,
+//! granted]`. `[APPEND, term, leader, leader-client-addr, prev-log-index,
+//! prev-log-term, leader-commit, entries]` is answered with `[term, success,
+//! match-index]`, where `entries` are `[term, command]` pairs holding the
+//! RESP encoding of each write. Nodes are known by their Raft address.
+//!
+//! Once enough entries were applied, a node replaces them with a snapshot
+//! of its database, an RDB file. A follower that needs entries the leader
+//! no longer has gets `[SNAPSHOT, term, leader, leader-client-addr,
+//! last-index, last-term, rdb]` instead, with the RDB file in hex, and
+//! answers it like `APPEND`.
+//!
+//! Each node writes its term, vote, snapshot and log to files in its Raft
+//! directory before answering a message that depends on them, or asking for
+//! votes, so a node that restarts neither votes twice in a term nor forgets
+//! entries it acknowledged. The database is loaded from the snapshot on
+//! startup, and the entries after it are applied again as the leader tells
+//! the node what is committed.
+use crate::client::*;
+use crate::clock::*;
+use crate::frame::*;
+use crate::log::*;
+use crate::rdb::{self, Snapshot};
+use crate::response::*;
+use crate::resptype::*;
+use crate::server::*;
+use crate::server_log;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, oneshot, Notify};
+use tokio::task::JoinSet;
+
+pub const DEFAULT_ELECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Applied entries a node keeps before compacting them into a snapshot.
+pub const DEFAULT_SNAPSHOT_ENTRIES: u64 = 10_000;
+
+/// Most entries sent to a follower in one `APPEND`.
+const MAX_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    term: u64,
+    /// The write as sent by the client, empty for the entry a new leader
+    /// appends to commit what earlier leaders left behind.
+    command: String,
+}
+
+#[derive(Debug)]
+struct State {
+    id: SocketAddr,
+    client_addr: SocketAddr,
+    peers: Vec<SocketAddr>,
+    role: RaftRole,
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    /// Index and term of the last entry the snapshot covers, entry `i` of
+    /// the log is `log[i - snapshot_index - 1]`.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// The database as of `snapshot_index`, as an RDB file.
+    snapshot: Vec<u8>,
+    log: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+    /// Raft and client address of the current leader.
+    leader: Option<(SocketAddr, SocketAddr)>,
+    next_index: HashMap<SocketAddr, u64>,
+    match_index: HashMap<SocketAddr, u64>,
+    election_deadline: Instant,
+    /// Writes waiting for their entry to be applied, by index.
+    proposals: HashMap<u64, oneshot::Sender<Response>>,
+    /// Reads waiting for a majority to confirm this is still the leader.
+    reads: Vec<oneshot::Sender<()>>,
+    /// `None` keeps everything in memory only.
+    storage: Option<Storage>,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_log(&self) -> (u64, u64) {
+        (self.last_index(), self.term_at(self.last_index()))
+    }
+
+    /// The entry at `index`, `None` past the end of the log or if it is
+    /// covered by the snapshot.
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.snapshot_index + 1)?;
+        self.log.get(offset as usize)
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        if index == self.snapshot_index {
+            return self.snapshot_term;
+        }
+        self.entry(index).map_or(0, |entry| entry.term)
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    /// Follows whoever has a term at least as high as ours. Clients waiting
+    /// on this node as the leader are let go.
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        if self.role == RaftRole::Leader {
+            server_log!(Level::Notice, "No longer the raft leader in term {}", term);
+        }
+        self.role = RaftRole::Follower;
+        self.proposals.clear();
+        self.reads.clear();
+    }
+
+    /// Drops the entries after `index`, they are dropped from disk on the
+    /// next [`State::persist`].
+    fn truncate_log(&mut self, index: u64) {
+        let len = index.saturating_sub(self.snapshot_index) as usize;
+        self.log.truncate(len);
+        if let Some(storage) = &mut self.storage {
+            storage.synced = storage.synced.min(len);
+        }
+    }
+
+    /// Writes the term, vote and log to disk where they changed. Nothing
+    /// may be acknowledged or asked for before this succeeds.
+    fn persist(&mut self) -> Result<()> {
+        match &mut self.storage {
+            Some(storage) => storage.save(self.term, self.voted_for, &self.log),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes `snapshot` cover the entries up to `index`, keeping the `kept`
+    /// entries of the log that follow it. Nothing changes unless the
+    /// snapshot and the log were written to disk first.
+    fn install_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        snapshot: Vec<u8>,
+        kept: usize,
+    ) -> Result<()> {
+        let log = self.log.split_off(self.log.len() - kept);
+        if let Some(storage) = &mut self.storage {
+            if let Err(e) = storage.compact(index, term, &snapshot, &log) {
+                self.log.extend(log);
+                return Err(e);
+            }
+        }
+        self.log = log;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot = snapshot;
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied = self.last_applied.max(index);
+        Ok(())
+    }
+
+    /// The error for a client that has to go to the leader instead.
+    fn not_leader(&self) -> anyhow::Error {
+        match self.leader {
+            Some((_, client_addr)) => anyhow!("NOTLEADER {}", client_addr),
+            None => anyhow!("TRYAGAIN No raft leader elected yet"),
+        }
+    }
+}
+
+/// This node's view of the Raft group, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Raft {
+    state: Arc<Mutex<State>>,
+    db: Db,
+    info_db: Db,
+    clock: SharedClock,
+    election_timeout: Duration,
+    snapshot_entries: u64,
+    /// Wakes the leader to replicate new entries without waiting for the
+    /// next heartbeat.
+    wake: Arc<Notify>,
+}
+
+impl Raft {
+    /// `id` is this node's Raft address and `peers` those of the others.
+    /// State is kept in `dir` if given, and read back from it. The log is
+    /// compacted into a snapshot every `snapshot_entries` applied entries.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        id: SocketAddr,
+        client_addr: SocketAddr,
+        peers: Vec<SocketAddr>,
+        dir: Option<&Path>,
+        db: Db,
+        info_db: Db,
+        clock: SharedClock,
+        election_timeout: Duration,
+        snapshot_entries: u64,
+    ) -> Result<Self> {
+        let (storage, saved) = match dir {
+            Some(dir) => {
+                let (storage, saved) = Storage::open(dir, id.port())?;
+                (Some(storage), saved)
+            }
+            None => (None, Saved::default()),
+        };
+        if saved.snapshot_index > 0 {
+            let snapshot = Snapshot::parse(&saved.snapshot).context("loading raft snapshot")?;
+            db.lock().unwrap().restore(&snapshot);
+        }
+        let election_deadline = clock.now() + randomized(election_timeout);
+        Ok(Self {
+            state: Arc::new(Mutex::new(State {
+                id,
+                client_addr,
+                peers: peers.into_iter().filter(|&peer| peer != id).collect(),
+                role: RaftRole::Follower,
+                term: saved.term,
+                voted_for: saved.voted_for,
+                snapshot_index: saved.snapshot_index,
+                snapshot_term: saved.snapshot_term,
+                snapshot: saved.snapshot,
+                log: saved.log,
+                commit_index: saved.snapshot_index,
+                last_applied: saved.snapshot_index,
+                leader: None,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                election_deadline,
+                proposals: HashMap::new(),
+                reads: Vec::new(),
+                storage,
+            })),
+            db,
+            info_db,
+            clock,
+            election_timeout,
+            snapshot_entries: snapshot_entries.max(1),
+            wake: Arc::default(),
+        })
+    }
+
+    pub fn role(&self) -> RaftRole {
+        self.state.lock().unwrap().role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.lock().unwrap().term
+    }
+
+    /// Client address of the leader this node follows.
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.state
+            .lock()
+            .unwrap()
+            .leader
+            .map(|(_, client_addr)| client_addr)
+    }
+
+    /// Index of the last entry applied to the database.
+    pub fn last_applied(&self) -> u64 {
+        self.state.lock().unwrap().last_applied
+    }
+
+    /// Runs a command from a client: writes are committed through the log,
+    /// reads wait for the leader to confirm its leadership.
+    pub(crate) async fn execute(&self, frame: Frame) -> Result<Response> {
+        if frame.command().is_write() {
+            return self.propose(frame).await;
+        }
+        self.confirm_leadership().await?;
+        create_response(frame, &self.db, &self.info_db)
+    }
+
+    async fn propose(&self, frame: Frame) -> Result<Response> {
+        let (tx, rx) = oneshot::channel();
+        let raft = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut state = raft.state.lock().unwrap();
+            if state.role != RaftRole::Leader {
+                return Err(state.not_leader());
+            }
+            let command = String::from_utf8(frame.serialize()).context("command is not UTF-8")?;
+            let term = state.term;
+            state.log.push(Entry { term, command });
+            let index = state.last_index();
+            // The leader counts itself as storing the entry.
+            if let Err(e) = state.persist() {
+                state.truncate_log(index - 1);
+                bail!("ERR Storing the raft entry failed: {:#}", e);
+            }
+            state.proposals.insert(index, tx);
+            Ok(())
+        })
+        .await??;
+        self.wake.notify_one();
+        let timeout = self.election_timeout * 2;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => bail!("ERR Lost raft leadership, the write may still be applied"),
+            Err(_) => bail!(
+                "ERR Write not committed within {:?}, it may still be applied",
+                timeout
+            ),
+        }
+    }
+
+    async fn confirm_leadership(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.role != RaftRole::Leader {
+                return Err(state.not_leader());
+            }
+            // Until an entry of its own term commits, a new leader may not
+            // know about everything that was committed before it.
+            if state.term_at(state.commit_index) != state.term {
+                bail!("TRYAGAIN The raft leader is still catching up");
+            }
+            state.reads.push(tx);
+        }
+        self.wake.notify_one();
+        match tokio::time::timeout(self.election_timeout, rx).await {
+            Ok(Ok(())) => Ok(()),
+            _ => Err(self.state.lock().unwrap().not_leader()),
+        }
+    }
+
+    /// Applies committed entries to the database, handing the replies to
+    /// the clients that proposed them, then compacts the log if it grew
+    /// long enough.
+    fn apply(&self, state: &mut State) {
+        while state.last_applied < state.commit_index {
+            state.last_applied += 1;
+            let index = state.last_applied;
+            let Some(Entry { command, .. }) = state.entry(index) else {
+                continue;
+            };
+            if command.is_empty() {
+                continue;
+            }
+            let response = match Frame::new(command.as_bytes(), command.len()) {
+                Ok(frame) => create_response(frame, &self.db, &self.info_db).unwrap_or_else(|e| {
+                    vec![Type::SimpleError(format!("ERR {:#}", e)).serialize()]
+                }),
+                Err(e) => {
+                    server_log!(Level::Warning, "Skipping raft entry {}: {:#}", index, e);
+                    Vec::new()
+                }
+            };
+            if let Some(tx) = state.proposals.remove(&index) {
+                let _ = tx.send(response);
+            }
+        }
+        if state.last_applied - state.snapshot_index >= self.snapshot_entries {
+            if let Err(e) = self.compact(state) {
+                server_log!(Level::Warning, "Compacting the raft log failed: {:#}", e);
+            }
+        }
+    }
+
+    /// Replaces the applied entries of the log with a snapshot of the
+    /// database.
+    fn compact(&self, state: &mut State) -> Result<()> {
+        let index = state.last_applied;
+        let term = state.term_at(index);
+        let snapshot = self.db.lock().unwrap().snapshot()?.serialize();
+        let kept = (state.last_index() - index) as usize;
+        state.install_snapshot(index, term, snapshot, kept)?;
+        server_log!(
+            Level::Verbose,
+            "Compacted the raft log up to entry {}",
+            index
+        );
+        Ok(())
+    }
+
+    /// Answers a message from another node, once what the answer depends
+    /// on is on disk.
+    fn receive(&self, message: Type) -> Result<Type> {
+        let mut state = self.state.lock().unwrap();
+        let reply = self.answer(&mut state, message)?;
+        state.persist()?;
+        Ok(reply)
+    }
+
+    fn answer(&self, state: &mut State, message: Type) -> Result<Type> {
+        let Type::Array(parts) = message else {
+            bail!("raft message must be an array");
+        };
+        let mut parts = parts.into_iter();
+        let kind = String::try_from(parts.next().context("empty raft message")?)?;
+        let mut next = || parts.next().context("raft message too short");
+        let now = self.clock.now();
+        match kind.as_str() {
+            "VOTE" => {
+                let term = integer(next()?)?;
+                let candidate = address(next()?)?;
+                let last_log = (integer(next()?)?, integer(next()?)?);
+                if term > state.term {
+                    state.step_down(term);
+                }
+                let (index, last_term) = state.last_log();
+                let up_to_date = (last_log.1, last_log.0) >= (last_term, index);
+                let granted = term == state.term
+                    && state.voted_for.unwrap_or(candidate) == candidate
+                    && up_to_date;
+                if granted {
+                    state.voted_for = Some(candidate);
+                    state.election_deadline = now + randomized(self.election_timeout);
+                }
+                Ok(Type::Array(vec![
+                    Type::Integer(state.term.to_string()),
+                    Type::Integer(u8::from(granted).to_string()),
+                ]))
+            }
+            "APPEND" => {
+                let term = integer(next()?)?;
+                let leader = address(next()?)?;
+                let leader_client = address(next()?)?;
+                let prev_index = integer(next()?)?;
+                let prev_term = integer(next()?)?;
+                let leader_commit = integer(next()?)?;
+                let Type::Array(entries) = next()? else {
+                    bail!("raft entries must be an array");
+                };
+                if term < state.term {
+                    return Ok(append_reply(state.term, false, 0));
+                }
+                self.follow(state, term, leader, leader_client, now);
+
+                // Entries up to the snapshot are committed, so they match.
+                let last = state.last_index();
+                if prev_index > last
+                    || (prev_index >= state.snapshot_index
+                        && state.term_at(prev_index) != prev_term)
+                {
+                    // Tell the leader where to look next.
+                    return Ok(append_reply(
+                        term,
+                        false,
+                        last.min(prev_index.saturating_sub(1)),
+                    ));
+                }
+                let count = entries.len() as u64;
+                for (i, entry) in entries.into_iter().enumerate() {
+                    let Type::Array(entry) = entry else {
+                        bail!("raft entry must be an array");
+                    };
+                    let [entry_term, command] = <[Type; 2]>::try_from(entry)
+                        .map_err(|_| anyhow!("raft entry must have 2 fields"))?;
+                    let index = prev_index + 1 + i as u64;
+                    let entry_term = integer(entry_term)?;
+                    if index <= state.snapshot_index {
+                        continue;
+                    }
+                    if index <= state.last_index() {
+                        if state.term_at(index) == entry_term {
+                            continue;
+                        }
+                        state.truncate_log(index - 1);
+                    }
+                    let command = String::try_from(command)?;
+                    state.log.push(Entry {
+                        term: entry_term,
+                        command,
+                    });
+                }
+                let matched = prev_index + count;
+                if leader_commit > state.commit_index {
+                    state.commit_index = leader_commit.min(matched).max(state.commit_index);
+                    self.apply(state);
+                }
+                Ok(append_reply(term, true, matched))
+            }
+            "SNAPSHOT" => {
+                let term = integer(next()?)?;
+                let leader = address(next()?)?;
+                let leader_client = address(next()?)?;
+                let last_index = integer(next()?)?;
+                let last_term = integer(next()?)?;
+                let rdb = rdb::from_hex(&String::try_from(next()?)?)?;
+                if term < state.term {
+                    return Ok(append_reply(state.term, false, 0));
+                }
+                self.follow(state, term, leader, leader_client, now);
+                if last_index <= state.commit_index {
+                    return Ok(append_reply(term, true, last_index));
+                }
+
+                let snapshot = Snapshot::parse(&rdb).context("parsing raft snapshot")?;
+                // Entries past the snapshot stay if the log agrees with it.
+                let kept =
+                    if last_index < state.last_index() && state.term_at(last_index) == last_term {
+                        (state.last_index() - last_index) as usize
+                    } else {
+                        0
+                    };
+                state.install_snapshot(last_index, last_term, rdb, kept)?;
+                self.db.lock().unwrap().restore(&snapshot);
+                server_log!(
+                    Level::Notice,
+                    "Loaded raft snapshot up to entry {} from {}",
+                    last_index,
+                    leader
+                );
+                Ok(append_reply(term, true, last_index))
+            }
+            kind => bail!("unknown raft message {:?}", kind),
+        }
+    }
+
+    /// Takes `leader` as the leader of `term`, which is at least ours, and
+    /// waits for it again before starting an election.
+    fn follow(
+        &self,
+        state: &mut State,
+        term: u64,
+        leader: SocketAddr,
+        leader_client: SocketAddr,
+        now: Instant,
+    ) {
+        if term > state.term || state.role != RaftRole::Follower {
+            state.step_down(term);
+        }
+        if state.leader.map(|(id, _)| id) != Some(leader) {
+            server_log!(
+                Level::Notice,
+                "Following raft leader {} in term {}",
+                leader,
+                term
+            );
+        }
+        state.leader = Some((leader, leader_client));
+        state.election_deadline = now + randomized(self.election_timeout);
+    }
+
+    /// Asks every other node for its vote in a new term.
+    async fn campaign(
+        &self,
+        links: &mut HashMap<SocketAddr, Client>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let raft = self.clone();
+        let (term, messages) = tokio::task::spawn_blocking(move || raft.start_election()).await??;
+        server_log!(Level::Verbose, "Starting raft election for term {}", term);
+        let replies = exchange_all(links, messages, timeout).await;
+
+        let raft = self.clone();
+        tokio::task::spawn_blocking(move || raft.count_votes(term, replies)).await?
+    }
+
+    /// Moves to a new term voting for this node, and returns the term and
+    /// the vote requests to send.
+    fn start_election(&self) -> Result<(u64, Vec<(SocketAddr, Type)>)> {
+        let mut state = self.state.lock().unwrap();
+        state.role = RaftRole::Candidate;
+        state.term += 1;
+        state.voted_for = Some(state.id);
+        state.leader = None;
+        state.election_deadline = self.clock.now() + randomized(self.election_timeout);
+        state.persist()?;
+        let (index, last_term) = state.last_log();
+        let vote = Type::Array(vec![
+            Type::BulkString("VOTE".to_string()),
+            Type::Integer(state.term.to_string()),
+            Type::BulkString(state.id.to_string()),
+            Type::Integer(index.to_string()),
+            Type::Integer(last_term.to_string()),
+        ]);
+        let messages = state.peers.iter().map(|&peer| (peer, vote.clone()));
+        Ok((state.term, messages.collect()))
+    }
+
+    /// Becomes the leader of `term` if a majority voted for this node.
+    fn count_votes(&self, term: u64, replies: Vec<(SocketAddr, Type)>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.role != RaftRole::Candidate || state.term != term {
+            return Ok(());
+        }
+        let mut votes = 1;
+        for (_, reply) in replies {
+            let Ok([reply_term, granted]) = integers::<2>(reply) else {
+                continue;
+            };
+            if reply_term > state.term {
+                state.step_down(reply_term);
+                return state.persist();
+            }
+            votes += usize::from(granted == 1);
+        }
+        if votes < state.majority() {
+            return Ok(());
+        }
+        let next = state.last_index() + 1;
+        state.log.push(Entry {
+            term,
+            command: String::new(),
+        });
+        if let Err(e) = state.persist() {
+            state.truncate_log(next - 1);
+            return Err(e.context("storing the entry of a new leader"));
+        }
+        server_log!(Level::Notice, "Elected raft leader for term {}", term);
+        state.role = RaftRole::Leader;
+        state.leader = Some((state.id, state.client_addr));
+        let peers = state.peers.clone();
+        state.next_index = peers.iter().map(|&peer| (peer, next)).collect();
+        state.match_index = peers.iter().map(|&peer| (peer, 0)).collect();
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Sends every follower the entries it is missing, the snapshot if the
+    /// leader no longer has them, or an empty `APPEND` as a heartbeat, then
+    /// commits what a majority has stored.
+    async fn replicate(
+        &self,
+        links: &mut HashMap<SocketAddr, Client>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let (term, reads, messages) = {
+            let mut state = self.state.lock().unwrap();
+            let reads = mem::take(&mut state.reads);
+            let messages: Vec<(SocketAddr, Type)> = state
+                .peers
+                .iter()
+                .map(|&peer| {
+                    let next = state.next_index.get(&peer).copied().unwrap_or(1);
+                    if next <= state.snapshot_index {
+                        return (peer, snapshot_message(&state));
+                    }
+                    let prev = next - 1;
+                    let skip = (prev - state.snapshot_index) as usize;
+                    let entries = state.log.iter().skip(skip).take(MAX_ENTRIES).map(|entry| {
+                        Type::Array(vec![
+                            Type::Integer(entry.term.to_string()),
+                            Type::BulkString(entry.command.clone()),
+                        ])
+                    });
+                    let message = Type::Array(vec![
+                        Type::BulkString("APPEND".to_string()),
+                        Type::Integer(state.term.to_string()),
+                        Type::BulkString(state.id.to_string()),
+                        Type::BulkString(state.client_addr.to_string()),
+                        Type::Integer(prev.to_string()),
+                        Type::Integer(state.term_at(prev).to_string()),
+                        Type::Integer(state.commit_index.to_string()),
+                        Type::Array(entries.collect()),
+                    ]);
+                    (peer, message)
+                })
+                .collect();
+            (state.term, reads, messages)
+        };
+        let replies = exchange_all(links, messages, timeout).await;
+
+        let raft = self.clone();
+        tokio::task::spawn_blocking(move || raft.record_replies(term, reads, replies)).await?
+    }
+
+    /// Moves each follower's next entry along by its reply to `APPEND`,
+    /// lets reads through if a majority answered and commits what a
+    /// majority has stored.
+    fn record_replies(
+        &self,
+        term: u64,
+        reads: Vec<oneshot::Sender<()>>,
+        replies: Vec<(SocketAddr, Type)>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.role != RaftRole::Leader || state.term != term {
+            return Ok(());
+        }
+        let mut acks = 1;
+        for (peer, reply) in replies {
+            let Ok([reply_term, success, index]) = integers::<3>(reply) else {
+                continue;
+            };
+            if reply_term > state.term {
+                state.step_down(reply_term);
+                return state.persist();
+            }
+            acks += 1;
+            if success == 1 {
+                state.match_index.insert(peer, index);
+                state.next_index.insert(peer, index + 1);
+            } else {
+                let next = state.next_index.get(&peer).copied().unwrap_or(1);
+                state
+                    .next_index
+                    .insert(peer, (index + 1).min(next - 1).max(1));
+            }
+        }
+        if acks >= state.majority() {
+            for read in reads {
+                let _ = read.send(());
+            }
+        }
+
+        let majority = state.majority();
+        for index in (state.commit_index + 1..=state.last_index()).rev() {
+            let stored = 1 + state.match_index.values().filter(|&&m| m >= index).count();
+            if state.term_at(index) == term && stored >= majority {
+                state.commit_index = index;
+                break;
+            }
+        }
+        self.apply(&mut state);
+        Ok(())
+    }
+}
+
+/// The snapshot for a follower that needs entries compacted into it.
+fn snapshot_message(state: &State) -> Type {
+    Type::Array(vec![
+        Type::BulkString("SNAPSHOT".to_string()),
+        Type::Integer(state.term.to_string()),
+        Type::BulkString(state.id.to_string()),
+        Type::BulkString(state.client_addr.to_string()),
+        Type::Integer(state.snapshot_index.to_string()),
+        Type::Integer(state.snapshot_term.to_string()),
+        Type::BulkString(rdb::to_hex(&state.snapshot)),
+    ])
+}
+
+/// `timeout` plus up to as much again at random, so nodes don't all start
+/// elections at the same time.
+fn randomized(timeout: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    timeout + timeout.mul_f64((random % 1000) as f64 / 1000.0)
+}
+
+/// How often the leader sends heartbeats, several times per election
+/// timeout.
+fn heartbeat_period(election_timeout: Duration) -> Duration {
+    (election_timeout / 5).clamp(Duration::from_millis(10), Duration::from_secs(1))
+}
+
+/// The answer to `APPEND` and `SNAPSHOT`.
+fn append_reply(term: u64, success: bool, index: u64) -> Type {
+    Type::Array(vec![
+        Type::Integer(term.to_string()),
+        Type::Integer(u8::from(success).to_string()),
+        Type::Integer(index.to_string()),
+    ])
+}
+
+fn integer(value: Type) -> Result<u64> {
+    u64::try_from(i64::try_from(value)?).context("negative integer in raft message")
+}
+
+fn integers<const N: usize>(reply: Type) -> Result<[u64; N]> {
+    let Type::Array(parts) = reply else {
+        bail!("raft reply must be an array");
+    };
+    let parts = parts.into_iter().map(integer).collect::<Result<Vec<_>>>()?;
+    <[u64; N]>::try_from(parts).map_err(|_| anyhow!("raft reply must have {} fields", N))
+}
+
+fn address(value: Type) -> Result<SocketAddr> {
+    String::try_from(value)?
+        .parse()
+        .context("invalid address in raft message")
+}
+
+/// Answers messages from other nodes until `shutdown` fires.
+pub async fn serve(listener: TcpListener, raft: Raft, notify_shutdown: broadcast::Sender<()>) {
+    let mut shutdown = notify_shutdown.subscribe();
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    let raft = raft.clone();
+                    let shutdown = notify_shutdown.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(e) = link_handler(stream, raft, shutdown).await {
+                            server_log!(Level::Verbose, "raft link error: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    server_log!(Level::Warning, "raft listener error: {}", e);
+                }
+            },
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+async fn link_handler(
+    stream: TcpStream,
+    raft: Raft,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+    let mut link = Client::new(stream);
+    loop {
+        let frame = tokio::select! {
+            frame = link.read_frame() => frame?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+        let Some((message, _)) = frame else {
+            return Ok(());
+        };
+        let raft = raft.clone();
+        let reply = tokio::task::spawn_blocking(move || raft.receive(message)).await??;
+        link.send(reply).await?;
+    }
+}
+
+/// Sends heartbeats while leader, and starts an election when no leader
+/// has been heard from within the election timeout.
+pub async fn run(raft: Raft, mut shutdown: broadcast::Receiver<()>) {
+    let period = heartbeat_period(raft.election_timeout);
+    let mut links: HashMap<SocketAddr, Client> = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = raft.clock.sleep(period) => {}
+            _ = raft.wake.notified() => {}
+            _ = shutdown.recv() => return,
+        }
+        let (role, deadline) = {
+            let state = raft.state.lock().unwrap();
+            (state.role, state.election_deadline)
+        };
+        if role == RaftRole::Leader {
+            if let Err(e) = raft.replicate(&mut links, period).await {
+                server_log!(Level::Warning, "Raft replication failed: {:#}", e);
+            }
+        } else if raft.clock.now() >= deadline {
+            if let Err(e) = raft.campaign(&mut links, period).await {
+                server_log!(Level::Warning, "Raft election failed: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Sends each message to its node at once and collects the replies that
+/// arrive within `timeout`, keeping a link open to each node.
+async fn exchange_all(
+    links: &mut HashMap<SocketAddr, Client>,
+    messages: Vec<(SocketAddr, Type)>,
+    timeout: Duration,
+) -> Vec<(SocketAddr, Type)> {
+    let mut exchanges = JoinSet::new();
+    for (addr, message) in messages {
+        let link = links.remove(&addr);
+        exchanges.spawn(async move {
+            let exchange = async move {
+                let mut link = match link {
+                    Some(link) => link,
+                    None => Client::connect(addr).await?,
+                };
+                link.send(message).await?;
+                let reply = link.read_reply().await?;
+                Ok::<_, anyhow::Error>((link, reply))
+            };
+            let res = match tokio::time::timeout(timeout, exchange).await {
+                Ok(res) => res,
+                Err(_) => Err(anyhow!("no reply from {} within {:?}", addr, timeout)),
+            };
+            (addr, res)
+        });
+    }
+    let mut replies = Vec::new();
+    while let Some(res) = exchanges.join_next().await {
+        let Ok((addr, res)) = res else {
+            continue;
+        };
+        match res {
+            Ok((link, reply)) => {
+                links.insert(addr, link);
+                replies.push((addr, reply));
+            }
+            Err(e) => server_log!(Level::Debug, "raft exchange failed: {:#}", e),
+        }
+    }
+    replies
+}
+
+/// What a node had on disk when it started.
+#[derive(Debug, Default)]
+struct Saved {
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot: Vec<u8>,
+    /// The entries after the snapshot.
+    log: Vec<Entry>,
+}
+
+/// The files a node keeps its term, vote, snapshot and log in.
+#[derive(Debug)]
+struct Storage {
+    dir: PathBuf,
+    /// Holds the term and vote, replaced as a whole.
+    meta: PathBuf,
+    /// Holds `<last-index> <last-term>\n` and the RDB file of the snapshot,
+    /// replaced as a whole.
+    snapshot: PathBuf,
+    log_path: PathBuf,
+    /// Holds the index of the entry before the first one on a line, then
+    /// `<term> <length>\n<command>` for each entry, in order.
+    log: File,
+    /// Where each entry starts in `log`.
+    offsets: Vec<u64>,
+    end: u64,
+    /// Term and vote as on disk.
+    saved: (u64, Option<SocketAddr>),
+    /// How many leading entries of the log in memory are on disk.
+    synced: usize,
+}
+
+impl Storage {
+    /// Opens the files of the node with Raft port `port` in `dir`, and
+    /// returns what they hold.
+    fn open(dir: &Path, port: u16) -> Result<(Self, Saved)> {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let meta = dir.join(format!("raft-{}.meta", port));
+        let (term, voted_for) = match fs::read_to_string(&meta) {
+            Ok(contents) => {
+                parse_meta(&contents).with_context(|| format!("parsing {}", meta.display()))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", meta.display())),
+        };
+
+        let snapshot_path = dir.join(format!("raft-{}.snapshot", port));
+        let (snapshot_index, snapshot_term, snapshot) = match fs::read(&snapshot_path) {
+            Ok(bytes) => parse_snapshot(bytes)
+                .with_context(|| format!("parsing {}", snapshot_path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, 0, Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", snapshot_path.display()))
+            }
+        };
+
+        let log_path = dir.join(format!("raft-{}.log", port));
+        let mut log = open_log(&log_path)?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)
+            .with_context(|| format!("reading {}", log_path.display()))?;
+        let parse = || {
+            let Some((base, start)) = parse_log_header(&bytes)? else {
+                return Ok((snapshot_index, (Vec::new(), Vec::new(), 0)));
+            };
+            Ok::<_, anyhow::Error>((base, parse_log(&bytes, start)?))
+        };
+        let (base, (entries, offsets, end)) =
+            parse().with_context(|| format!("parsing {}", log_path.display()))?;
+        ensure!(
+            base <= snapshot_index,
+            "{} starts after entry {} but the snapshot ends at {}",
+            log_path.display(),
+            base,
+            snapshot_index
+        );
+        // An entry cut short by a crash was never acknowledged.
+        log.set_len(end)?;
+
+        let mut storage = Self {
+            dir: dir.to_path_buf(),
+            meta,
+            snapshot: snapshot_path,
+            log_path,
+            log,
+            offsets,
+            end,
+            saved: (term, voted_for),
+            synced: entries.len(),
+        };
+        // The node stopped between writing a snapshot and compacting the log.
+        let covered = ((snapshot_index - base) as usize).min(entries.len());
+        let log = entries[covered..].to_vec();
+        if base != snapshot_index || end == 0 {
+            storage.rewrite_log(snapshot_index, &log)?;
+        }
+        let saved = Saved {
+            term,
+            voted_for,
+            snapshot_index,
+            snapshot_term,
+            snapshot,
+            log,
+        };
+        Ok((storage, saved))
+    }
+
+    fn save(&mut self, term: u64, voted_for: Option<SocketAddr>, log: &[Entry]) -> Result<()> {
+        if self.saved != (term, voted_for) {
+            let vote = voted_for.map(|addr| addr.to_string()).unwrap_or_default();
+            self.replace(&self.meta, format!("{}\n{}\n", term, vote).as_bytes())?;
+            self.saved = (term, voted_for);
+        }
+
+        if self.synced == log.len() && self.offsets.len() == log.len() {
+            return Ok(());
+        }
+        // Entries past `synced` were replaced, cut them off before writing
+        // the new ones so they can't come back after a crash.
+        let mut end = self.offsets.get(self.synced).copied().unwrap_or(self.end);
+        self.offsets.truncate(self.synced);
+        self.log.set_len(end)?;
+        let mut bytes = Vec::new();
+        for entry in &log[self.synced..] {
+            self.offsets.push(end + bytes.len() as u64);
+            encode_entry(&mut bytes, entry);
+        }
+        self.log.write_all_at(&bytes, end)?;
+        self.log.sync_data()?;
+        end += bytes.len() as u64;
+        self.end = end;
+        self.synced = log.len();
+        Ok(())
+    }
+
+    /// Writes a snapshot up to entry `index`, then replaces the log with
+    /// the entries in `log` that follow it.
+    fn compact(&mut self, index: u64, term: u64, snapshot: &[u8], log: &[Entry]) -> Result<()> {
+        let mut bytes = format!("{} {}\n", index, term).into_bytes();
+        bytes.extend_from_slice(snapshot);
+        self.replace(&self.snapshot, &bytes)?;
+        self.rewrite_log(index, log)
+    }
+
+    /// Replaces the log file with one holding `log`, which follows entry
+    /// `base`.
+    fn rewrite_log(&mut self, base: u64, log: &[Entry]) -> Result<()> {
+        let mut bytes = format!("{}\n", base).into_bytes();
+        let mut offsets = Vec::with_capacity(log.len());
+        for entry in log {
+            offsets.push(bytes.len() as u64);
+            encode_entry(&mut bytes, entry);
+        }
+        self.replace(&self.log_path, &bytes)?;
+        self.log = open_log(&self.log_path)?;
+        self.offsets = offsets;
+        self.end = bytes.len() as u64;
+        self.synced = log.len();
+        Ok(())
+    }
+
+    /// Replaces the file at `path` with `bytes`, so a crash leaves either
+    /// the old or the new contents.
+    fn replace(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))
+}
+
+fn encode_entry(bytes: &mut Vec<u8>, entry: &Entry) {
+    bytes.extend(format!("{} {}\n", entry.term, entry.command.len()).into_bytes());
+    bytes.extend(entry.command.as_bytes());
+}
+
+fn parse_meta(contents: &str) -> Result<(u64, Option<SocketAddr>)> {
+    let mut lines = contents.lines();
+    let term = lines.next().context("missing term")?.parse()?;
+    let voted_for = match lines.next().context("missing vote")? {
+        "" => None,
+        addr => Some(addr.parse().context("invalid vote")?),
+    };
+    Ok((term, voted_for))
+}
+
+/// The last index and term a snapshot covers, and its RDB file.
+fn parse_snapshot(mut bytes: Vec<u8>) -> Result<(u64, u64, Vec<u8>)> {
+    let newline = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .context("missing snapshot header")?;
+    let header = std::str::from_utf8(&bytes[..newline])?;
+    let (index, term) = header
+        .split_once(' ')
+        .with_context(|| format!("invalid snapshot header {:?}", header))?;
+    let (index, term) = (index.parse()?, term.parse()?);
+    let rdb = bytes.split_off(newline + 1);
+    Ok((index, term, rdb))
+}
+
+/// The index the log follows and where its first entry starts, `None` for
+/// a new file.
+fn parse_log_header(bytes: &[u8]) -> Result<Option<(u64, usize)>> {
+    let Some(newline) = bytes.iter().position(|&b| b == b'\n') else {
+        return Ok(None);
+    };
+    let base = std::str::from_utf8(&bytes[..newline])?
+        .parse()
+        .context("invalid log header")?;
+    Ok(Some((base, newline + 1)))
+}
+
+/// The entries in `bytes` from `start` on, where each starts and where the
+/// last complete one ends.
+fn parse_log(bytes: &[u8], start: usize) -> Result<(Vec<Entry>, Vec<u64>, u64)> {
+    let (mut entries, mut offsets, mut pos) = (Vec::new(), Vec::new(), start);
+    while let Some(newline) = bytes[pos..].iter().position(|&b| b == b'\n') {
+        let header = std::str::from_utf8(&bytes[pos..pos + newline])?;
+        let (term, len) = header
+            .split_once(' ')
+            .with_context(|| format!("invalid entry header {:?}", header))?;
+        let (term, len): (u64, usize) = (term.parse()?, len.parse()?);
+        let start = pos + newline + 1;
+        let Some(command) = bytes.get(start..start + len) else {
+            break;
+        };
+        let command = String::from_utf8(command.to_vec()).context("entry is not UTF-8")?;
+        entries.push(Entry { term, command });
+        offsets.push(pos as u64);
+        pos = start + len;
+    }
+    Ok((entries, offsets, pos as u64))
+}
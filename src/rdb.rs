@@ -0,0 +1,333 @@
+//! Reader and writer for RDB snapshots as written by Redis, and a diff
+//! between two of them, used by `--diff-rdb` to check that a replica or a
+//! backup holds the same keys as its source.
+//!
+//! Only string values in database 0 are supported, like the store itself.
+//! Checksums are verified when the file has one.
+use crate::glob::*;
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const EOF: u8 = 0xff;
+const SELECTDB: u8 = 0xfe;
+const EXPIRETIME: u8 = 0xfd;
+const EXPIRETIME_MS: u8 = 0xfc;
+const RESIZEDB: u8 = 0xfb;
+const AUX: u8 = 0xfa;
+const FREQ: u8 = 0xf9;
+const IDLE: u8 = 0xf8;
+const STRING: u8 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RdbEntry {
+    pub value: Vec<u8>,
+    /// Unix time in milliseconds.
+    pub expiry: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    entries: BTreeMap<String, RdbEntry>,
+}
+
+impl Snapshot {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let magic = reader.take(9).context("reading header")?;
+        ensure!(magic.starts_with(b"REDIS"), "not an RDB file");
+        let version: u32 = std::str::from_utf8(&magic[5..])
+            .ok()
+            .and_then(|version| version.parse().ok())
+            .context("parsing RDB version")?;
+
+        let mut entries = BTreeMap::new();
+        let mut expiry = None;
+        loop {
+            match reader.byte()? {
+                EOF => break,
+                SELECTDB => {
+                    let db = reader.length()?;
+                    ensure!(db == 0, "only database 0 is supported, found {}", db);
+                }
+                RESIZEDB => {
+                    reader.length()?;
+                    reader.length()?;
+                }
+                AUX => {
+                    reader.string()?;
+                    reader.string()?;
+                }
+                IDLE => {
+                    reader.length()?;
+                }
+                FREQ => {
+                    reader.byte()?;
+                }
+                EXPIRETIME => {
+                    let secs = u32::from_le_bytes(reader.array()?);
+                    expiry = Some(u64::from(secs) * 1000);
+                }
+                EXPIRETIME_MS => expiry = Some(u64::from_le_bytes(reader.array()?)),
+                STRING => {
+                    let key = String::from_utf8_lossy(&reader.string()?).to_string();
+                    let value = reader.string()?;
+                    let expiry = expiry.take();
+                    entries.insert(key, RdbEntry { value, expiry });
+                }
+                kind => bail!("unsupported value type {}", kind),
+            }
+        }
+
+        // Files from before version 5 have no checksum, a zero one was
+        // turned off when saving.
+        if version >= 5 {
+            let end = reader.pos;
+            let checksum = u64::from_le_bytes(reader.array().context("reading checksum")?);
+            if checksum != 0 && checksum != crc64(&bytes[..end]) {
+                bail!("checksum mismatch");
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn entries(&self) -> &BTreeMap<String, RdbEntry> {
+        &self.entries
+    }
+
+    pub fn insert(&mut self, key: String, entry: RdbEntry) {
+        self.entries.insert(key, entry);
+    }
+
+    /// Encodes the snapshot as an RDB file with a checksum, which
+    /// [`Snapshot::parse`] and Redis read back.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = b"REDIS0011".to_vec();
+        bytes.push(SELECTDB);
+        write_length(&mut bytes, 0);
+        for (key, entry) in &self.entries {
+            if let Some(expiry) = entry.expiry {
+                bytes.push(EXPIRETIME_MS);
+                bytes.extend(expiry.to_le_bytes());
+            }
+            bytes.push(STRING);
+            write_string(&mut bytes, key.as_bytes());
+            write_string(&mut bytes, &entry.value);
+        }
+        bytes.push(EOF);
+        let checksum = crc64(&bytes);
+        bytes.extend(checksum.to_le_bytes());
+        bytes
+    }
+}
+
+/// The hex digits of `bytes`, how RDB payloads travel inside RESP values.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair)
+                .ok()
+                .filter(|digits| digits.len() == 2)
+                .context("invalid hex digits")?;
+            u8::from_str_radix(digits, 16).context("invalid hex digits")
+        })
+        .collect()
+}
+
+/// Keys that differ between two snapshots, in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Only in the second snapshot.
+    pub added: Vec<String>,
+    /// Only in the first snapshot.
+    pub removed: Vec<String>,
+    /// In both but with another value or expiry.
+    pub changed: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares the keys of `a` and `b`, only those matching the glob-style
+/// `pattern` if there is one.
+pub fn diff(a: &Snapshot, b: &Snapshot, pattern: Option<&str>) -> Diff {
+    let selected = |key: &String| match pattern {
+        Some(pattern) => glob_match(pattern, key),
+        None => true,
+    };
+    let mut diff = Diff::default();
+    for (key, old) in a.entries.iter().filter(|(key, _)| selected(key)) {
+        match b.entries.get(key) {
+            None => diff.removed.push(key.clone()),
+            Some(new) if new != old => diff.changed.push(key.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.added = b
+        .entries
+        .keys()
+        .filter(|key| selected(key) && !a.entries.contains_key(*key))
+        .cloned()
+        .collect();
+    diff
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .context("unexpected end of file")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => bail!("expected a length, found a string encoding"),
+        }
+    }
+
+    /// The two top bits of the first byte tell how the length is stored,
+    /// `11` marks a string stored in a special encoding instead.
+    fn length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Plain(u64::from(first & 0x3f)),
+            1 => Length::Plain(u64::from(first & 0x3f) << 8 | u64::from(self.byte()?)),
+            2 if first == 0x80 => Length::Plain(u64::from(u32::from_be_bytes(self.array()?))),
+            2 if first == 0x81 => Length::Plain(u64::from_be_bytes(self.array()?)),
+            2 => bail!("invalid length encoding {:#x}", first),
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let len = match self.length_or_encoding()? {
+            Length::Plain(len) => len,
+            Length::Encoded(0) => return Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => {
+                return Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(2) => {
+                return Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(3) => {
+                let compressed = self.length()?;
+                let len = self.length()?;
+                let data = self.take(usize::try_from(compressed)?)?;
+                return lzf_decompress(data, usize::try_from(len)?);
+            }
+            Length::Encoded(encoding) => bail!("unknown string encoding {}", encoding),
+        };
+        Ok(self.take(usize::try_from(len)?)?.to_vec())
+    }
+}
+
+/// The shortest of the plain length encodings read by
+/// [`Reader::length_or_encoding`].
+fn write_length(bytes: &mut Vec<u8>, len: u64) {
+    match len {
+        0..=0x3f => bytes.push(len as u8),
+        0x40..=0x3fff => bytes.extend([0x40 | (len >> 8) as u8, len as u8]),
+        0x4000..=0xffff_ffff => {
+            bytes.push(0x80);
+            bytes.extend((len as u32).to_be_bytes());
+        }
+        _ => {
+            bytes.push(0x81);
+            bytes.extend(len.to_be_bytes());
+        }
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, value: &[u8]) {
+    write_length(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+/// Inflates LZF data, which alternates runs of literal bytes and
+/// back-references into what was already inflated.
+fn lzf_decompress(data: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() {
+        let ctrl = usize::from(data[i]);
+        i += 1;
+        if ctrl < 32 {
+            let literal = data.get(i..i + ctrl + 1).context("truncated LZF literal")?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+            continue;
+        }
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += usize::from(*data.get(i).context("truncated LZF reference")?);
+            i += 1;
+        }
+        let low = usize::from(*data.get(i).context("truncated LZF reference")?);
+        i += 1;
+        let back = ((ctrl & 0x1f) << 8) + low + 1;
+        let start = out
+            .len()
+            .checked_sub(back)
+            .context("LZF reference before start")?;
+        // The source may overlap what is being written, copy byte by byte.
+        for k in start..start + run + 2 {
+            out.push(out[k]);
+        }
+    }
+    ensure!(out.len() == len, "LZF data inflated to the wrong length");
+    Ok(out)
+}
+
+/// CRC-64/Jones as used by Redis, reflected and without a final xor.
+fn crc64(bytes: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+    let mut crc = 0u64;
+    for &byte in bytes {
+        crc ^= u64::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -1,114 +1,152 @@
+use crate::auth::*;
+use crate::client::*;
+use crate::clock::*;
 use crate::frame::*;
-use crate::info::*;
+use crate::log::*;
+use crate::rdb::Snapshot;
 use crate::response::*;
 use crate::resptype::*;
-use anyhow::{bail, Result};
-use std::str;
-use std::{thread, time};
-use tokio::{io, io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
-
-type WriteHalf = io::WriteHalf<TcpStream>;
-type ReadHalf = io::ReadHalf<TcpStream>;
+use crate::server::*;
+use crate::server_log;
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::time;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+
+/// A replica connected to this master.
+#[derive(Debug)]
+pub struct Replica {
+    pub stream: TcpStream,
+    /// Where the replica serves clients, from `REPLCONF listening-port`.
+    pub addr: SocketAddr,
+}
 
 pub async fn replicate(frame: Frame, streams: &StreamVec) {
-    let mut streams = streams.lock().unwrap();
+    let mut streams = streams.lock().await;
     let msg = frame.bytes_vec();
-    println!("Replicatiing: {:?}", msg);
-    for stream in streams.iter_mut() {
-        let _ = &stream.write_all(&msg).await.unwrap();
-        let _ = &stream.flush().await.unwrap();
+    server_log!(Level::Debug, "Replicatiing: {:?}", msg);
+    for replica in streams.iter_mut() {
+        let _ = replica.stream.write_all(&msg).await;
+        let _ = replica.stream.flush().await;
     }
 }
 
-fn sync_replica_db(/* info_db: &InfoDb, db: &Db */) -> Result<()> {
-    // For now we just add an arbitrary wait to simulate syncing the replica
-    // with the rdb file
-    let ten_millis = time::Duration::from_millis(3000);
-    thread::sleep(ten_millis);
-    Ok(())
-}
-
-async fn send_and_receive(msg: Vec<u8>, rd: &mut ReadHalf, wr: &mut WriteHalf) -> Result<()> {
-    if let Ok(_) = wr.write_all(&msg[..]).await {
-        let mut buffer: [u8; 1024] = [0; 1024];
-
-        let len = rd.read(&mut buffer).await?;
-
-        if len == 0 {
-            bail!("Nothing read from read buffer loop")
-        }
-
-        println!(
-            "Handshake: {:?} Received",
-            // str::from_utf8(&buffer[..len]).unwrap()
-            &buffer[..len]
+/// Lists `replicas` in INFO as `connected_slaves` and one `slaveN` line
+/// each, which is how sentinels find them.
+pub fn record_replicas(info_db: &Db, replicas: &[Replica]) {
+    let mut info_db = info_db.lock().unwrap();
+    let before: usize = info_db
+        .peek("connected_slaves")
+        .and_then(|entry| entry.value().parse().ok())
+        .unwrap_or_default();
+    for i in 0..before {
+        info_db.remove(&format!("slave{}", i));
+    }
+    info_db.insert(
+        "connected_slaves".to_owned(),
+        DbEntry::new(replicas.len().to_string(), None),
+    );
+    for (i, replica) in replicas.iter().enumerate() {
+        let line = format!(
+            "ip={},port={},state=online,offset=0,lag=0",
+            replica.addr.ip(),
+            replica.addr.port()
         );
+        info_db.insert(format!("slave{}", i), DbEntry::new(line, None));
     }
-    Ok(())
 }
 
-pub async fn handshake(host_addr: &str, host_port: &str, local_port: &str) -> Result<()> {
-    let bind_addr: String = host_addr.to_string() + ":" + host_port;
+/// How often the master pings its replicas, `repl-ping-replica-period` in
+/// Redis.
+pub const REPL_PING_PERIOD: Duration = Duration::from_secs(10);
+
+/// Pings every connected replica each `REPL_PING_PERIOD` on `clock`, so they
+/// can tell a quiet master from a dead link. Replicas whose link is gone are
+/// dropped.
+pub async fn heartbeat(
+    replicas: StreamVec,
+    info_db: Db,
+    clock: SharedClock,
+    mut shutdown: broadcast::Receiver<()>,
+) {
+    let ping = command(&["PING"]).serialize();
     loop {
-        let Ok(stream) = TcpStream::connect(&bind_addr).await else {
-            continue;
-        };
-        let (mut rd, mut wr) = io::split(stream);
-
-        let mut handshake_args: Vec<Vec<u8>> = Vec::new();
-        handshake_args.push(Type::Array(vec![Type::BulkString("ping".to_string())]).serialize());
-
-        handshake_args.push(
-            Type::Array(vec![
-                Type::BulkString("replconf".to_string()),
-                Type::BulkString("listening-port".to_string()),
-                Type::BulkString(local_port.to_string()),
-            ])
-            .serialize(),
-        );
-
-        handshake_args.push(
-            Type::Array(vec![
-                Type::BulkString("replconf".to_string()),
-                Type::BulkString("capa".to_string()),
-                Type::BulkString("psync".to_string()),
-            ])
-            .serialize(),
-        );
-
-        handshake_args.push(
-            Type::Array(vec![
-                Type::BulkString("psync".to_string()),
-                Type::BulkString("?".to_string()),
-                Type::BulkString("-1".to_string()),
-            ])
-            .serialize(),
-        );
-
-        for arg in handshake_args.into_iter() {
-            let _ = send_and_receive(arg.clone(), &mut rd, &mut wr).await;
+        tokio::select! {
+            _ = clock.sleep(REPL_PING_PERIOD) => {}
+            _ = shutdown.recv() => return,
         }
+        let mut streams = replicas.lock().await;
+        let mut alive = Vec::with_capacity(streams.len());
+        for mut replica in streams.drain(..) {
+            if replica.stream.write_all(&ping).await.is_ok() {
+                alive.push(replica);
+            } else {
+                server_log!(Level::Notice, "Lost replica {}", replica.addr);
+            }
+        }
+        *streams = alive;
+        record_replicas(&info_db, &streams);
+    }
+}
 
-        // Here we're waiting for RBD file after receiving the FULLRESYNC from
-        // the master instance.
-
-        let mut buffer: [u8; 1024] = [0; 1024];
-
-        let len = rd.read(&mut buffer).await?;
-
-        if len == 0 {
-            println!("Nothing read from read buffer");
-            return Ok(());
+/// Connects to the master, logs in with `auth` if given, runs the PSYNC
+/// handshake and then applies every command the master propagates to the
+/// local database.
+pub async fn handshake(
+    master_addr: SocketAddr,
+    local_port: u16,
+    auth: Option<MasterAuth>,
+    db: Db,
+    info_db: Db,
+) -> Result<()> {
+    let mut client = loop {
+        match Client::connect(master_addr).await {
+            Ok(client) => break client,
+            Err(_) => tokio::time::sleep(time::Duration::from_millis(100)).await,
         }
+    };
 
-        // let _ = sync_replica_db();
+    if let Some(auth) = &auth {
+        auth.login(&mut client).await?;
+    }
 
-        println!(
-            "Handshake Post: {:?} Received",
-            // str::from_utf8(&buffer[..len]).unwrap()
-            &buffer[..len]
-        );
+    let local_port = local_port.to_string();
+    let handshake_args: [&[&str]; 4] = [
+        &["ping"],
+        &["replconf", "listening-port", &local_port],
+        &["replconf", "capa", "psync"],
+        &["psync", "?", "-1"],
+    ];
+    for args in handshake_args {
+        let reply = client.request(args).await?;
+        server_log!(Level::Notice, "Handshake: {:?} Received", reply);
+        if let Type::SimpleError(e) = reply {
+            bail!("master rejected {:?}: {}", args, e);
+        }
+    }
 
-        return Ok(());
+    // The master's keys follow the FULLRESYNC as an RDB file, and replace
+    // ours.
+    let rdb = client.read_rdb().await?;
+    server_log!(
+        Level::Notice,
+        "Handshake Post: {} byte RDB Received",
+        rdb.len()
+    );
+    let snapshot = Snapshot::parse(&rdb).context("loading the master's RDB")?;
+    db.lock().unwrap().restore(&snapshot);
+
+    while let Some((_, raw)) = client.read_frame().await? {
+        match Frame::new(&raw, raw.len()) {
+            Ok(frame) => {
+                let _ = create_response(frame, &db, &info_db);
+            }
+            Err(e) => server_log!(Level::Warning, "Skipping propagated command: {:#}", e),
+        }
     }
+    server_log!(Level::Notice, "Master closed the replication link");
+    Ok(())
 }
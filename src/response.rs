@@ -1,18 +1,20 @@
 use crate::command::*;
 use crate::frame::*;
+use crate::glob::*;
 use crate::info::*;
+use crate::log::*;
+use crate::rdb;
+use crate::replication::*;
 use crate::resptype::*;
 use crate::server::*;
+use crate::server_log;
 use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
-use std::collections::HashMap;
-use std::num::ParseIntError;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use tokio::net::TcpStream;
-
-pub type Db = Arc<Mutex<Database>>;
-pub type StreamVec = Arc<Mutex<Vec<TcpStream>>>;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+pub type StreamVec = Arc<Mutex<Vec<Replica>>>;
 pub type Response = Vec<Vec<u8>>;
 
 // #[derive(Debug, Clone)]
@@ -22,7 +24,7 @@ pub type Response = Vec<Vec<u8>>;
 // }
 
 fn handle_get(frame: Frame, db: &Db) -> Result<Vec<u8>> {
-    let db = db.lock().unwrap();
+    let mut db = db.lock().unwrap();
     let Some(args) = frame.args() else {
         bail!("Could not get frame args as Vec<Type>");
     };
@@ -34,54 +36,246 @@ fn handle_get(frame: Frame, db: &Db) -> Result<Vec<u8>> {
     }
 
     let key = args.first().context("getting get key")?;
-    let Some(val) = db.get(key) else {
+    let now = db.now();
+    let Some(val) = db.get(key)? else {
         return Ok(Type::NullBulkString.serialize());
     };
 
-    match val.expiry {
-        Some(expiry) => {
-            if expiry <= Instant::now() {
-                return Ok(Type::NullBulkString.serialize());
-            } else {
-                return Ok(Type::BulkString(val.value.to_string()).serialize());
+    if val.is_expired(now) {
+        Ok(Type::NullBulkString.serialize())
+    } else {
+        Ok(Type::BulkString(val.value()).serialize())
+    }
+}
+
+fn handle_mget(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let mut db = db.lock().unwrap();
+    let Some(keys) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let now = db.now();
+    let values = keys
+        .iter()
+        .map(|key| {
+            Ok(match db.get(key)? {
+                Some(entry) if !entry.is_expired(now) => Type::BulkString(entry.value()),
+                _ => Type::NullBulkString,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Type::Array(values).serialize())
+}
+
+fn handle_mset(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let mut db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    for (key, val) in args.into_iter().tuples() {
+        db.insert(key, DbEntry::new(val, None));
+    }
+    Ok(Type::SimpleString("OK".to_string()).serialize())
+}
+
+/// Options of `SET key value [PX milliseconds] [IFVERSION version]`.
+#[derive(Debug, Default)]
+pub(crate) struct SetOptions {
+    pub px: Option<u64>,
+    pub if_version: Option<u64>,
+}
+
+/// Parses the options after the key and the value of a `SET`.
+pub(crate) fn parse_set_options(options: &[String]) -> Result<SetOptions> {
+    let mut parsed = SetOptions::default();
+    for (option, arg) in options.iter().tuples() {
+        match option.to_lowercase().as_str() {
+            "px" => {
+                parsed.px = Some(arg.parse::<u64>().context("parsing u64 from string")?);
             }
-        }
-        None => {
-            return Ok(Type::BulkString(val.value.to_string()).serialize());
+            "ifversion" => {
+                parsed.if_version =
+                    Some(arg.parse::<u64>().context("parsing version from string")?);
+            }
+            _ => bail!("can only support px or ifversion as extra command for set"),
         }
     }
+    Ok(parsed)
 }
 
+/// `SET key value [PX milliseconds] [IFVERSION version]`, with `IFVERSION`
+/// only writing if the key is still at that version. Replies with a null
+/// bulk string when it is not.
 fn handle_set(frame: Frame, db: &Db) -> Result<Vec<u8>> {
-    println!("handling set command");
+    server_log!(Level::Debug, "handling set command");
     let mut db = db.lock().unwrap();
-    let Some(args) = frame.args() else {
+    let Some(mut args) = frame.args() else {
         return Err(anyhow!("Could not get frame args as Vec<Type>"));
     };
-    if args.len() == 2 {
-        let (key, val) = args
-            .into_iter()
-            .collect_tuple()
-            .context("parsing argument for set command")?;
-        let set_val = DbEntry::new(val);
-        db.insert(key, set_val);
-    } else if args.len() == 4 {
-        let (key, val, px, dur) = args
-            .into_iter()
-            .collect_tuple()
-            .context("parsing argument for set command")?;
-        if px.to_lowercase().to_string() != "px" {
-            bail!("can only support px as extra command for set");
+    let options = parse_set_options(&args[2..])?;
+    args.truncate(2);
+    let (key, val) = args
+        .into_iter()
+        .collect_tuple()
+        .context("parsing argument for set command")?;
+    let expiry = options.px.map(|px| db.now() + Duration::from_millis(px));
+    if options
+        .if_version
+        .is_some_and(|version| version != key_version(&db, &key))
+    {
+        return Ok(Type::NullBulkString.serialize());
+    }
+    db.insert(key, DbEntry::new(val, expiry));
+    Ok(Type::SimpleString("OK".to_string()).serialize())
+}
+
+/// Deletes the keys, replies with how many of them existed.
+fn handle_del(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let mut db = db.lock().unwrap();
+    let Some(keys) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let now = db.now();
+    let deleted = keys
+        .iter()
+        .filter_map(|key| db.remove(key))
+        .filter(|entry| !entry.is_expired(now))
+        .count();
+    Ok(Type::Integer(deleted.to_string()).serialize())
+}
+
+/// `DELIFEQ key value` deletes `key` only if it holds `value`, replies with
+/// `1` if it did and `0` otherwise. Releases a lock only its holder knows
+/// the token of.
+fn handle_delifeq(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let mut db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let (key, value) = args
+        .iter()
+        .collect_tuple()
+        .context("parsing arguments for delifeq command")?;
+    let now = db.now();
+    let matches = db
+        .get(key)?
+        .is_some_and(|entry| !entry.is_expired(now) && entry.value() == *value);
+    if matches {
+        db.remove(key);
+    }
+    Ok(Type::Integer(u8::from(matches).to_string()).serialize())
+}
+
+/// Appends to the value stored under `key`, creating it if needed, and
+/// replies with the new length. The only write allowed on frozen keys.
+fn handle_append(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let mut db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let (key, suffix) = args
+        .into_iter()
+        .collect_tuple()
+        .context("parsing arguments for append command")?;
+    let now = db.now();
+    let entry = match db.get(&key)?.filter(|entry| !entry.is_expired(now)) {
+        Some(entry) => {
+            let mut entry = entry.clone();
+            entry.set_value(entry.value() + &suffix);
+            entry
         }
-        let dur = dur.parse::<u64>().context("parsing u64 from string")?;
-        let set_val = DbEntry::new_with_expiry(val, Duration::from_millis(dur));
-        db.insert(key, set_val);
-    } else {
-        println!("incorrect arg count");
+        None => DbEntry::new(suffix, None),
+    };
+    let len = entry.value().len();
+    db.insert(key, entry);
+    Ok(Type::Integer(len.to_string()).serialize())
+}
+
+/// `OBJECT FREEZE key` makes `key` append-only, `OBJECT THAW key` lifts it.
+fn handle_object(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let mut db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let (subcommand, key) = args
+        .iter()
+        .collect_tuple()
+        .context("parsing arguments for object command")?;
+    let append_only = parse_object_subcommand(subcommand)?;
+    if !db.set_append_only(key, append_only) {
+        bail!("no such key");
     }
     Ok(Type::SimpleString("OK".to_string()).serialize())
 }
 
+/// Whether an `OBJECT` subcommand freezes a key, it thaws it otherwise.
+pub(crate) fn parse_object_subcommand(subcommand: &str) -> Result<bool> {
+    match subcommand.to_lowercase().as_str() {
+        "freeze" => Ok(true),
+        "thaw" => Ok(false),
+        _ => bail!("unknown subcommand '{}' for OBJECT", subcommand),
+    }
+}
+
+/// Version of the value stored under `key`, `0` if there is none.
+fn key_version(db: &Database, key: &str) -> u64 {
+    match db.peek(key) {
+        Some(entry) if !entry.is_expired(db.now()) => entry.version(),
+        _ => 0,
+    }
+}
+
+fn handle_getver(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let key = args.first().context("getting getver key")?;
+    Ok(Type::Integer(key_version(&db, key).to_string()).serialize())
+}
+
+fn handle_keys(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let pattern = args.first().context("getting keys pattern")?;
+    let now = db.now();
+    let keys = db
+        .iter()
+        .filter(|(key, entry)| !entry.is_expired(now) && glob_match(pattern, key))
+        .map(|(key, _)| Type::BulkString(key.to_string()))
+        .collect();
+    Ok(Type::Array(keys).serialize())
+}
+
+/// Milliseconds left to live, `-1` without an expiry and `-2` for missing keys.
+fn handle_pttl(frame: Frame, db: &Db) -> Result<Vec<u8>> {
+    let db = db.lock().unwrap();
+    let Some(args) = frame.args() else {
+        bail!("Could not get frame args as Vec<Type>");
+    };
+    let key = args.first().context("getting pttl key")?;
+    let now = db.now();
+    let pttl = match db.peek(key) {
+        Some(entry) if !entry.is_expired(now) => match entry.ttl(now) {
+            Some(ttl) => ttl.as_millis() as i64,
+            None => -1,
+        },
+        _ => -2,
+    };
+    Ok(Type::Integer(pttl.to_string()).serialize())
+}
+
+/// Replies with the unix time as seconds and microseconds.
+fn handle_time(db: &Db) -> Result<Vec<u8>> {
+    let now = db.lock().unwrap().clock().unix_time();
+    Ok(Type::Array(vec![
+        Type::BulkString(now.as_secs().to_string()),
+        Type::BulkString(now.subsec_micros().to_string()),
+    ])
+    .serialize())
+}
+
 fn handle_replconf(frame: Frame, info_db: &Db) -> Result<Vec<u8>> {
     let mut info_db = info_db.lock().unwrap();
     let Some(args) = frame.args() else {
@@ -92,18 +286,16 @@ fn handle_replconf(frame: Frame, info_db: &Db) -> Result<Vec<u8>> {
             .into_iter()
             .collect_tuple()
             .context("parsing arguments for replconf command")?;
-        if key.to_lowercase().to_string() != "listening-port"
-            && key.to_lowercase().to_string() != "capa"
-        {
+        if key.to_lowercase() != "listening-port" && key.to_lowercase() != "capa" {
             bail!(
                 "can only support listening-port or capa as extra command for replconf: key: {:?}",
                 key
             );
         }
-        info_db.insert(key.clone(), val);
+        info_db.insert(key.clone(), DbEntry::new(val, None));
         // println!("GETTING HERE IN REPLCONF: {:?}", info_db.get(&key).unwrap());
     } else {
-        println!("incorrect arg count");
+        server_log!(Level::Verbose, "incorrect arg count");
     }
     Ok(Type::SimpleString("OK".to_string()).serialize())
 }
@@ -118,70 +310,142 @@ fn handle_psync(frame: Frame, info_db: &Db) -> Result<Vec<u8>> {
             .into_iter()
             .collect_tuple()
             .context("parsing arguments for replconf command")?;
-        if id.to_lowercase().to_string() != "?" && offset.to_lowercase().to_string() != "-1" {
+        if id.to_lowercase() != "?" && offset.to_lowercase() != "-1" {
             bail!(
                 "can only support '?' or '-1' as args for psync: arg1: {:?} arg2: {:?}",
                 id,
                 offset,
             );
         }
-        let rv_id: &str = info_db.get("master_replid").unwrap();
-        let rv_offset: &str = info_db.get("master_repl_offset").unwrap();
+        let rv_id = info_db
+            .peek("master_replid")
+            .context("getting master_replid")?
+            .value();
+        let rv_offset = info_db
+            .peek("master_repl_offset")
+            .context("getting master_repl_offset")?
+            .value();
         // println!("GETTING HERE IN REPLCONF: {:?}", rv_id);
         return Ok(
-            Type::SimpleString("FULLRESYNC ".to_string() + rv_id + " " + rv_offset).serialize(),
+            Type::SimpleString("FULLRESYNC ".to_string() + &rv_id + " " + &rv_offset).serialize(),
         );
     } else {
-        println!("incorrect arg count");
+        server_log!(Level::Verbose, "incorrect arg count");
     }
     Ok(Type::SimpleString("OK".to_string()).serialize())
 }
 
 pub fn create_response(frame: Frame, db: &Db, info_db: &Db) -> Result<Response> {
     match frame.command() {
-        Command::Ping => {
-            return Ok(vec![Type::SimpleString("PONG".to_string()).serialize()]);
-        }
+        Command::Ping => Ok(vec![Type::SimpleString("PONG".to_string()).serialize()]),
 
         Command::Echo => {
             let Some(args) = frame.args() else {
                 bail!("Could not get frame args as Vec<Type>");
             };
             if args.len() > 1 {
-                return Ok(vec![Type::BulkString(
+                Ok(vec![Type::BulkString(
                     "(error) Incorrect number of arguments for echo".to_string(),
                 )
-                .serialize()]);
+                .serialize()])
             } else {
                 let arg = args.first().context("getting echo arg")?;
-                return Ok(vec![Type::BulkString(arg.to_string()).serialize()]);
+                Ok(vec![Type::BulkString(arg.to_string()).serialize()])
             }
         }
 
         Command::Get => {
             let rv = handle_get(frame, db)?;
-            return Ok(vec![rv]);
+            Ok(vec![rv])
         }
 
         Command::Set => {
             let rv = handle_set(frame, db)?;
-            return Ok(vec![rv]);
+            Ok(vec![rv])
+        }
+
+        Command::MGet => {
+            let rv = handle_mget(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::MSet => {
+            let rv = handle_mset(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::Keys => {
+            let rv = handle_keys(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::PTtl => {
+            let rv = handle_pttl(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::GetVer => {
+            let rv = handle_getver(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::Del => {
+            let rv = handle_del(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::DelIfEq => {
+            let rv = handle_delifeq(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::Append => {
+            let rv = handle_append(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::Object => {
+            let rv = handle_object(frame, db)?;
+            Ok(vec![rv])
+        }
+
+        Command::Time => {
+            let rv = handle_time(db)?;
+            Ok(vec![rv])
+        }
+
+        Command::SlowLog
+        | Command::Config
+        | Command::Cluster
+        | Command::Asking
+        | Command::Migrate
+        | Command::Multi
+        | Command::Exec
+        | Command::Discard
+        | Command::ReplicaOf
+        | Command::Client
+        | Command::Auth => {
+            bail!(
+                "{} is only available on client connections",
+                frame.command().name()
+            );
         }
 
         Command::Info => {
             let rv = handle_info(frame, info_db)?;
-            return Ok(vec![rv]);
+            Ok(vec![rv])
         }
 
         Command::ReplConf => {
             let rv = handle_replconf(frame, info_db)?;
-            return Ok(vec![rv]);
+            Ok(vec![rv])
         }
 
         Command::PSync => {
             let rv = handle_psync(frame, info_db)?;
-            let rdb = Type::RDBSyncString("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2".to_string()).serialize();
-            return Ok(vec![rv, rdb]);
+            let snapshot = db.lock().unwrap().snapshot()?;
+            let rdb = Type::RDBSyncString(rdb::to_hex(&snapshot.serialize())).serialize();
+            Ok(vec![rv, rdb])
         }
     }
 }
@@ -1,10 +1,28 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Context, Result};
 use std::fmt::{Display, Formatter};
 use std::num::ParseIntError;
+use std::str;
 
-#[derive(Debug, Clone)]
+pub type Cursor = usize;
+
+/// How deep arrays may nest, deeper input is a protocol error rather than
+/// a stack overflow.
+pub const MAX_DEPTH: usize = 128;
+
+/// Largest bulk string, and frame read by a [`Client`](crate::client::Client),
+/// like `proto-max-bulk-len` in Redis.
+pub const MAX_FRAME_LEN: usize = 512 * 1024 * 1024;
+
+/// Returned (inside an `anyhow::Error`) when the buffer ends before the value
+/// does, i.e. more bytes need to be read from the connection.
+#[derive(Debug, thiserror::Error)]
+#[error("incomplete frame: {0}")]
+pub struct Incomplete(&'static str);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     SimpleString(String),
+    SimpleError(String),
     BulkString(String),
     RDBSyncString(String),
     NullBulkString,
@@ -20,6 +38,7 @@ impl Display for Type {
                 f.write_fmt(format_args!("*{}\r\n{}", items.len(), elements))
             }
             Type::SimpleString(s) => f.write_fmt(format_args!("+{}\r\n", s)),
+            Type::SimpleError(s) => f.write_fmt(format_args!("-{}\r\n", s)),
             Type::BulkString(s) => f.write_fmt(format_args!("${}\r\n{}\r\n", s.len(), s)),
             Type::RDBSyncString(s) => f.write_fmt(format_args!("${}\r\n{}", s.len(), s)),
             Type::NullBulkString => f.write_fmt(format_args!("$-1\r\n")),
@@ -32,15 +51,19 @@ impl TryFrom<Type> for String {
     type Error = anyhow::Error;
     fn try_from(value: Type) -> Result<Self> {
         match value {
-            Type::BulkString(s) => {
-                let s = s.to_lowercase();
-                Ok(String::from(s))
-            }
-            Type::SimpleString(s) => {
-                let s = s.to_lowercase();
-                Ok(String::from(s))
-            }
-            _ => bail!("Command parse error: {}", value.to_string()),
+            Type::BulkString(s) => Ok(s),
+            Type::SimpleString(s) => Ok(s),
+            _ => bail!("Command parse error: {}", value),
+        }
+    }
+}
+
+impl TryFrom<Type> for i64 {
+    type Error = anyhow::Error;
+    fn try_from(value: Type) -> Result<Self> {
+        match value {
+            Type::Integer(i) => i.parse::<i64>().context("parsing integer reply"),
+            _ => bail!("Expected integer reply: {}", value),
         }
     }
 }
@@ -49,6 +72,7 @@ impl Type {
     pub fn serialize(self) -> Vec<u8> {
         match self {
             Type::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            Type::SimpleError(s) => format!("-{}\r\n", s).into_bytes(),
             Type::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
             Type::RDBSyncString(rdb) => {
                 let hex: Result<Vec<u8>, ParseIntError> = (0..rdb.len())
@@ -58,9 +82,9 @@ impl Type {
                 let mut hex = hex.unwrap();
                 let mut prefix: Vec<u8> = format!("${}\r\n", hex.len()).into_bytes();
                 prefix.append(&mut hex);
-                return prefix;
+                prefix
             }
-            Type::NullBulkString => format!("$-1\r\n").into_bytes(),
+            Type::NullBulkString => b"$-1\r\n".to_vec(),
             Type::Integer(s) => format!(":{}\r\n", s).into_bytes(),
             Type::Array(elems) => {
                 let mut prefix = format!("*{}\r\n", elems.len()).into_bytes();
@@ -71,3 +95,179 @@ impl Type {
         }
     }
 }
+
+fn parse_integer(buffer: &[u8]) -> Result<(Type, Cursor)> {
+    let (val, cursor) = parse_crlf(buffer)?;
+    let val = str::from_utf8(val).context("parsing integer as utf8")?;
+    if val.parse::<i64>().is_err() {
+        bail!("invalid integer: {:?}", val);
+    }
+    Ok((Type::Integer(val.to_string()), cursor))
+}
+
+fn parse_simple_string(buffer: &[u8]) -> Result<(Type, Cursor)> {
+    let (val, cursor) = parse_crlf(buffer)?;
+    let val = str::from_utf8(val).context("parsing simple string as utf8")?;
+    Ok((Type::SimpleString(val.to_string()), cursor))
+}
+
+fn parse_simple_error(buffer: &[u8]) -> Result<(Type, Cursor)> {
+    let (val, cursor) = parse_crlf(buffer)?;
+    let val = str::from_utf8(val).context("parsing simple error as utf8")?;
+    Ok((Type::SimpleError(val.to_string()), cursor))
+}
+
+fn parse_bulk_string(buffer: &[u8]) -> Result<(Type, Cursor)> {
+    let (len_raw, cursor) = parse_crlf(buffer)?;
+    if len_raw == b"-1" {
+        return Ok((Type::NullBulkString, cursor));
+    }
+    let len = parse_usize(len_raw)?;
+    ensure!(len <= MAX_FRAME_LEN, "invalid bulk length {}", len);
+    let end = cursor
+        .checked_add(len)
+        .context("bulk string length overflows")?;
+    if buffer.len() < end + 2 {
+        return Err(Incomplete("bulk string shorter than its length").into());
+    }
+    let val = &buffer[cursor..end];
+    if &buffer[end..end + 2] != b"\r\n" {
+        bail!("bulk string is not terminated by CRLF");
+    }
+    let val = str::from_utf8(val).context("parsing bulk string as utf8")?;
+    Ok((Type::BulkString(val.to_string()), end + 2))
+}
+
+fn parse_array(buffer: &[u8], depth: usize) -> Result<(Type, Cursor)> {
+    ensure!(depth < MAX_DEPTH, "arrays nested deeper than {}", MAX_DEPTH);
+    let (num_elems_raw, mut cursor) = parse_crlf(buffer)?;
+    let num_elems = parse_usize(num_elems_raw)?;
+    // Every element takes at least three bytes, so don't trust the header
+    // with a bigger allocation than the buffer could possibly fill.
+    let mut rv = Vec::<Type>::with_capacity(num_elems.min(buffer.len() / 3));
+    for _ in 0..num_elems {
+        let (elem, cursor_new) = parse_value(&buffer[cursor..], depth + 1)?;
+        cursor += cursor_new + 1;
+        rv.push(elem);
+    }
+    Ok((Type::Array(rv), cursor))
+}
+
+fn parse_crlf(buffer: &[u8]) -> Result<(&[u8], Cursor)> {
+    let Some(i) = buffer.windows(2).position(|w| w == b"\r\n") else {
+        return Err(Incomplete("missing CRLF").into());
+    };
+    Ok((&buffer[..i], i + 2))
+}
+
+fn parse_usize(buffer: &[u8]) -> Result<usize> {
+    let num_elems_str = str::from_utf8(buffer).context("parse usize: from utf8")?;
+    num_elems_str
+        .parse::<usize>()
+        .with_context(|| format!("parse usize: {:?}", num_elems_str))
+}
+
+/// Parses a single RESP value from the start of `buffer`, returning it along
+/// with the number of bytes it occupied.
+pub fn parse_resp(buffer: &[u8]) -> Result<(Type, Cursor)> {
+    let (value, cursor) = parse_value(buffer, 0)?;
+    Ok((value, cursor + 1))
+}
+
+/// Parses a value inside `depth` arrays, returning the bytes it occupied
+/// after its type byte.
+fn parse_value(buffer: &[u8], depth: usize) -> Result<(Type, Cursor)> {
+    let Some(first) = buffer.first() else {
+        return Err(Incomplete("empty buffer").into());
+    };
+    match first {
+        b'+' => parse_simple_string(&buffer[1..]),
+        b'-' => parse_simple_error(&buffer[1..]),
+        b'$' => parse_bulk_string(&buffer[1..]),
+        b':' => parse_integer(&buffer[1..]),
+        b'*' => parse_array(&buffer[1..], depth),
+        x => bail!("Invalid RESP Type: {:?}", x),
+    }
+}
+
+/// Finds where the value at the start of a buffer ends without building it,
+/// picking up where it stopped when more bytes arrive, so a value read in
+/// many pieces is only scanned once.
+#[derive(Debug, Default)]
+pub(crate) struct Scanner {
+    /// Where the next value or line starts.
+    pos: usize,
+    /// How far the current line was searched for its CRLF.
+    searched: usize,
+    /// Elements still to come in each array being read, innermost last.
+    remaining: Vec<usize>,
+}
+
+impl Scanner {
+    /// The length of the complete value at the start of `buffer`, `None`
+    /// while more bytes are needed. `buffer` must only grow between calls
+    /// until a length is returned, which starts the next value afresh.
+    pub(crate) fn scan(&mut self, buffer: &[u8]) -> Result<Option<usize>> {
+        loop {
+            let rest = &buffer[self.pos..];
+            let from = self.searched.saturating_sub(self.pos + 1);
+            let Some(line) = rest[from.min(rest.len())..]
+                .windows(2)
+                .position(|w| w == b"\r\n")
+                .map(|i| from + i)
+            else {
+                self.searched = buffer.len();
+                return Ok(None);
+            };
+            ensure!(line > 0, "Invalid RESP Type: {:?}", rest[0]);
+            let header = &rest[1..line];
+            let mut len = line + 2;
+            match rest[0] {
+                b'+' | b'-' | b':' => {}
+                b'$' if header == b"-1" => {}
+                b'$' => {
+                    let bulk = parse_usize(header)?;
+                    ensure!(bulk <= MAX_FRAME_LEN, "invalid bulk length {}", bulk);
+                    len += bulk + 2;
+                    if rest.len() < len {
+                        return Ok(None);
+                    }
+                }
+                b'*' => {
+                    ensure!(
+                        self.remaining.len() < MAX_DEPTH,
+                        "arrays nested deeper than {}",
+                        MAX_DEPTH
+                    );
+                    let count = parse_usize(header)?;
+                    if count > 0 {
+                        self.pos += len;
+                        self.searched = self.pos;
+                        self.remaining.push(count);
+                        continue;
+                    }
+                }
+                x => bail!("Invalid RESP Type: {:?}", x),
+            }
+            self.pos += len;
+            self.searched = self.pos;
+            // The value may be the last one of the arrays around it.
+            loop {
+                match self.remaining.last_mut() {
+                    None => {
+                        let end = self.pos;
+                        *self = Self::default();
+                        return Ok(Some(end));
+                    }
+                    Some(1) => {
+                        self.remaining.pop();
+                    }
+                    Some(count) => {
+                        *count -= 1;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1173 @@
+//! Sentinel mode: instead of storing keys the process watches masters and
+//! their replicas, and replaces a master that stops answering.
+//!
+//! Every sentinel sends `INFO replication` to the masters it monitors and to
+//! their replicas, which it learns from the master's reply. A master that
+//! does not answer for `down-after` is subjectively down (`+sdown`). The
+//! other sentinels are then asked with `SENTINEL IS-MASTER-DOWN-BY-ADDR`, and
+//! once `quorum` of them agree it is objectively down (`+odown`). The sentinel
+//! that notices asks for votes in a new epoch and, elected by a majority,
+//! promotes a replica with `REPLICAOF NO ONE`. The remaining replicas are
+//! pointed at the new master with `REPLICAOF` as they are checked.
+//!
+//! Sentinels share their configuration and the sentinels they know of with
+//! `SENTINEL HELLO`, the highest config epoch wins. A sentinel that is not
+//! given with `--sentinel-peer` is only trusted once it answers
+//! `SENTINEL GET-MASTER-ADDR-BY-NAME` with the same master, and a master
+//! announced in a `HELLO` is only switched to once its `INFO` reports
+//! `role:master`. Clients learn about a new master by subscribing to
+//! `+switch-master` on any sentinel.
+use crate::auth::*;
+use crate::client::*;
+use crate::clock::*;
+use crate::cluster::random_node_id;
+use crate::glob::*;
+use crate::log::*;
+use crate::resptype::*;
+use crate::server_log;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Redis' default `down-after-milliseconds`.
+pub const DEFAULT_DOWN_AFTER: Duration = Duration::from_secs(30);
+
+/// Redis' default `failover-timeout`.
+pub const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Most sentinels waiting to be checked at once, so `HELLO`s can't grow the
+/// list without bound.
+const MAX_CANDIDATES: usize = 16;
+
+/// A master or replica being watched.
+#[derive(Debug, Clone)]
+struct Instance {
+    addr: SocketAddr,
+    last_ok: Instant,
+    /// Subjectively down, it has not answered for `down_after`.
+    sdown: bool,
+    /// Its last `INFO replication` reply.
+    info: HashMap<String, String>,
+}
+
+impl Instance {
+    fn new(addr: SocketAddr, now: Instant) -> Self {
+        Self {
+            addr,
+            last_ok: now,
+            sdown: false,
+            info: HashMap::new(),
+        }
+    }
+
+    /// The master it reported replicating from.
+    fn reported_master(&self) -> Option<SocketAddr> {
+        let host = self.info.get("master_host")?;
+        let port = self.info.get("master_port")?;
+        format!("{}:{}", host, port).parse().ok()
+    }
+
+    fn describe(&self, kind: &str) -> String {
+        format!("{} {} {}", kind, self.addr.ip(), self.addr.port())
+    }
+}
+
+#[derive(Debug)]
+struct Monitored {
+    name: String,
+    quorum: usize,
+    master: Instance,
+    replicas: Vec<Instance>,
+    /// Since when the master is objectively down, `quorum` sentinels agree
+    /// it is down.
+    odown: Option<Instant>,
+    config_epoch: u64,
+    /// The sentinel this one voted for to fail the master over, and in which
+    /// epoch.
+    leader: Option<String>,
+    leader_epoch: u64,
+    /// When this sentinel last tried a failover or voted for another one to
+    /// do it, it waits for the failover timeout before trying again.
+    failover_start: Option<Instant>,
+    /// A new master announced by another sentinel, and its config epoch. It
+    /// is switched to once it reports `role:master`.
+    proposed: Option<(SocketAddr, u64)>,
+}
+
+impl Monitored {
+    fn describe(&self) -> String {
+        let addr = self.master.addr;
+        format!("{} {} {}", self.name, addr.ip(), addr.port())
+    }
+
+    /// Makes `addr` the master under `epoch`, the old master becomes one of
+    /// its replicas.
+    fn switch_master(&mut self, addr: SocketAddr, epoch: u64, now: Instant) {
+        let old = std::mem::replace(&mut self.master, Instance::new(addr, now));
+        self.replicas.retain(|replica| replica.addr != addr);
+        self.replicas.push(Instance::new(old.addr, now));
+        self.odown = None;
+        self.config_epoch = epoch;
+        self.failover_start = None;
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    myid: String,
+    addr: SocketAddr,
+    current_epoch: u64,
+    masters: Vec<Monitored>,
+    /// Other sentinels, by the address they serve clients on.
+    peers: Vec<SocketAddr>,
+    /// Sentinels that announced themselves but were not checked yet.
+    candidates: Vec<SocketAddr>,
+}
+
+impl State {
+    fn master(&mut self, name: &str) -> Result<&mut Monitored> {
+        self.masters
+            .iter_mut()
+            .find(|m| m.name == name)
+            .with_context(|| format!("No such master with that name {}", name))
+    }
+
+    fn add_peer(&mut self, addr: SocketAddr) {
+        if addr != self.addr && !self.peers.contains(&addr) {
+            server_log!(Level::Notice, "+sentinel {}", addr);
+            self.peers.push(addr);
+        }
+    }
+
+    /// Remembers a sentinel to check before trusting it as a peer.
+    fn add_candidate(&mut self, addr: SocketAddr) {
+        if addr != self.addr
+            && !self.peers.contains(&addr)
+            && !self.candidates.contains(&addr)
+            && self.candidates.len() < MAX_CANDIDATES
+        {
+            self.candidates.push(addr);
+        }
+    }
+}
+
+/// Shared state of a sentinel, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct Sentinel {
+    state: Arc<Mutex<State>>,
+    clock: SharedClock,
+    down_after: Duration,
+    failover_timeout: Duration,
+    /// Sent in `AUTH` to monitored instances, not to other sentinels.
+    masterauth: Option<MasterAuth>,
+    events: broadcast::Sender<(String, String)>,
+}
+
+impl Sentinel {
+    pub fn builder() -> SentinelBuilder {
+        SentinelBuilder::default()
+    }
+
+    pub fn myid(&self) -> String {
+        self.state.lock().unwrap().myid.clone()
+    }
+
+    /// The current master of `name`, as `SENTINEL GET-MASTER-ADDR-BY-NAME`.
+    pub fn master_addr(&self, name: &str) -> Option<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        state.master(name).ok().map(|m| m.master.addr)
+    }
+
+    /// Sends `payload` to clients subscribed to `channel`.
+    fn publish(&self, channel: &str, payload: String) {
+        server_log!(Level::Notice, "{} {}", channel, payload);
+        let _ = self.events.send((channel.to_string(), payload));
+    }
+
+    /// Every instance to check, masters first, then replicas and masters
+    /// announced by other sentinels.
+    fn instances(&self) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        let masters = state.masters.iter().map(|m| m.master.addr);
+        let replicas = state
+            .masters
+            .iter()
+            .flat_map(|m| m.replicas.iter().map(|replica| replica.addr));
+        let proposed = state
+            .masters
+            .iter()
+            .filter_map(|m| m.proposed.map(|(addr, _)| addr));
+        let mut instances = Vec::new();
+        for addr in masters.chain(replicas).chain(proposed) {
+            if !instances.contains(&addr) {
+                instances.push(addr);
+            }
+        }
+        instances
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.state.lock().unwrap().peers.clone()
+    }
+
+    fn names(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.masters.iter().map(|m| m.name.clone()).collect()
+    }
+
+    /// Takes the sentinels waiting to be checked.
+    fn candidates(&self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.state.lock().unwrap().candidates)
+    }
+
+    /// Trusts the candidates that report the same master for `name`, in
+    /// replies to `SENTINEL GET-MASTER-ADDR-BY-NAME`.
+    fn verify_peers(&self, name: &str, replies: HashMap<SocketAddr, Type>) {
+        let mut state = self.state.lock().unwrap();
+        let Ok(m) = state.master(name) else {
+            return;
+        };
+        let expected = Type::Array(vec![
+            Type::BulkString(m.master.addr.ip().to_string()),
+            Type::BulkString(m.master.addr.port().to_string()),
+        ]);
+        for (addr, reply) in replies {
+            if reply == expected {
+                state.add_peer(addr);
+            }
+        }
+    }
+
+    /// Records the reply of `instance` to a check, and flags it down once it
+    /// has not answered for too long.
+    fn observe(
+        &self,
+        instance: &mut Instance,
+        replies: &HashMap<SocketAddr, Type>,
+        kind: &str,
+        now: Instant,
+    ) {
+        match replies.get(&instance.addr) {
+            Some(reply) => {
+                instance.last_ok = now;
+                instance.info = parse_info(reply);
+                if instance.sdown {
+                    instance.sdown = false;
+                    self.publish("-sdown", instance.describe(kind));
+                }
+            }
+            None if !instance.sdown && now - instance.last_ok > self.down_after => {
+                instance.sdown = true;
+                self.publish("+sdown", instance.describe(kind));
+            }
+            None => {}
+        }
+    }
+
+    /// Applies the `INFO` replies from one round of checks: learns replicas,
+    /// flags instances that did not answer for too long, and returns the
+    /// replicas to point at their master with `REPLICAOF`.
+    fn record_info(&self, replies: HashMap<SocketAddr, Type>) -> Vec<(SocketAddr, SocketAddr)> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let mut reconfigure = Vec::new();
+        for m in state.masters.iter_mut() {
+            if let Some((addr, epoch)) = m.proposed {
+                // Keep waiting while it doesn't answer, drop it if it is not
+                // a master after all.
+                if let Some(reply) = replies.get(&addr) {
+                    m.proposed = None;
+                    let role = parse_info(reply).get("role").cloned();
+                    if role.as_deref() == Some("master") && epoch > m.config_epoch {
+                        let old = m.master.addr;
+                        m.switch_master(addr, epoch, now);
+                        let payload = format!(
+                            "{} {} {} {} {}",
+                            m.name,
+                            old.ip(),
+                            old.port(),
+                            addr.ip(),
+                            addr.port()
+                        );
+                        self.publish("+switch-master", payload);
+                    }
+                }
+            }
+            self.observe(&mut m.master, &replies, "master", now);
+            for replica in m.replicas.iter_mut() {
+                self.observe(replica, &replies, "slave", now);
+            }
+
+            for addr in info_replicas(&m.master.info) {
+                if addr != m.master.addr && m.replicas.iter().all(|r| r.addr != addr) {
+                    m.replicas.push(Instance::new(addr, now));
+                    let payload = format!("slave {} @ {}", addr, m.describe());
+                    self.publish("+slave", payload);
+                }
+            }
+            if !m.master.sdown && m.odown.is_some() {
+                m.odown = None;
+                self.publish("-odown", format!("master {}", m.describe()));
+            }
+
+            // Only correct replicas while the master is up, otherwise this
+            // could undo a failover another sentinel has not told us about.
+            if m.master.sdown || m.master.info.get("role").map(String::as_str) != Some("master") {
+                continue;
+            }
+            for replica in m.replicas.iter().filter(|r| !r.sdown && !r.info.is_empty()) {
+                if replica.reported_master() != Some(m.master.addr) {
+                    reconfigure.push((replica.addr, m.master.addr));
+                    let payload = format!("slave {} @ {}", replica.addr, m.describe());
+                    self.publish("+slave-reconf-sent", payload);
+                }
+            }
+        }
+        reconfigure
+    }
+
+    /// Masters this sentinel considers down.
+    fn sdown_masters(&self) -> Vec<(String, SocketAddr)> {
+        let state = self.state.lock().unwrap();
+        state
+            .masters
+            .iter()
+            .filter(|m| m.master.sdown)
+            .map(|m| (m.name.clone(), m.master.addr))
+            .collect()
+    }
+
+    /// Flags the master `name` as objectively down once `agree` sentinels,
+    /// this one included, think it is. Starts a failover if it is and none
+    /// was tried recently, returning the epoch to ask for votes in.
+    ///
+    /// Sentinels take turns ordered by address, two check periods apart, so
+    /// they don't all ask for votes at once and split them.
+    fn odown(&self, name: &str, agree: usize) -> Option<u64> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let myid = state.myid.clone();
+        let epoch = state.current_epoch + 1;
+        let rank = state
+            .peers
+            .iter()
+            .filter(|&&peer| peer < state.addr)
+            .count() as u32;
+        let turn = check_period(self.down_after) * 2 * rank;
+        let m = state.master(name).ok()?;
+        if agree >= m.quorum && m.odown.is_none() {
+            m.odown = Some(now);
+            let payload = format!("master {} #quorum {}/{}", m.describe(), agree, m.quorum);
+            self.publish("+odown", payload);
+        }
+        let since = m.odown?;
+        let recent = m
+            .failover_start
+            .is_some_and(|start| now - start < self.failover_timeout);
+        if now - since < turn || recent || m.leader_epoch >= epoch {
+            return None;
+        }
+        m.failover_start = Some(now);
+        m.leader = Some(myid);
+        m.leader_epoch = epoch;
+        let payload = format!("master {}", m.describe());
+        state.current_epoch = epoch;
+        self.publish("+new-epoch", epoch.to_string());
+        self.publish("+try-failover", payload);
+        Some(epoch)
+    }
+
+    /// With `votes` from a majority of the sentinels, picks the replica of
+    /// `name` to promote.
+    fn elected(&self, name: &str, votes: usize) -> Option<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        let sentinels = state.peers.len() + 1;
+        let m = state.master(name).ok()?;
+        if votes < m.quorum.max(sentinels / 2 + 1) {
+            return None;
+        }
+        let payload = format!("master {}", m.describe());
+        self.publish("+elected-leader", payload.clone());
+        let candidate = m
+            .replicas
+            .iter()
+            .filter(|r| !r.sdown && r.info.get("role").map(String::as_str) == Some("slave"))
+            .map(|r| r.addr)
+            .min();
+        match candidate {
+            Some(addr) => {
+                let payload = format!("slave {} @ {}", addr, m.describe());
+                self.publish("+selected-slave", payload);
+            }
+            None => self.publish("-failover-abort-no-good-slave", payload),
+        }
+        candidate
+    }
+
+    /// Records that `addr` was promoted to replace the master of `name`.
+    fn promoted(&self, name: &str, addr: SocketAddr, epoch: u64) {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        let Ok(m) = state.master(name) else {
+            return;
+        };
+        let old = m.master.addr;
+        m.switch_master(addr, epoch, now);
+        self.publish(
+            "+failover-end",
+            format!("master {} {} {}", name, old.ip(), old.port()),
+        );
+        let payload = format!(
+            "{} {} {} {} {}",
+            name,
+            old.ip(),
+            old.port(),
+            addr.ip(),
+            addr.port()
+        );
+        self.publish("+switch-master", payload);
+    }
+
+    /// The `SENTINEL HELLO` to send to the other sentinels for each master.
+    fn hellos(&self) -> Vec<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        state
+            .masters
+            .iter()
+            .map(|m| {
+                vec![
+                    "SENTINEL".to_string(),
+                    "HELLO".to_string(),
+                    state.addr.ip().to_string(),
+                    state.addr.port().to_string(),
+                    state.myid.clone(),
+                    state.current_epoch.to_string(),
+                    m.name.clone(),
+                    m.master.addr.ip().to_string(),
+                    m.master.addr.port().to_string(),
+                    m.config_epoch.to_string(),
+                ]
+            })
+            .collect()
+    }
+
+    /// Learns the sentinels listed in a reply to `SENTINEL HELLO`, to check
+    /// before trusting them.
+    fn learn_peers(&self, reply: Type) {
+        let Type::Array(addrs) = reply else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        for addr in addrs {
+            if let Some(addr) = String::try_from(addr).ok().and_then(|a| a.parse().ok()) {
+                state.add_candidate(addr);
+            }
+        }
+    }
+
+    /// Answers a command from a client or another sentinel connected from
+    /// `from`.
+    fn handle(&self, args: &[String], from: IpAddr) -> Result<Type> {
+        let command = args.first().context("empty command")?.to_lowercase();
+        match command.as_str() {
+            "ping" => Ok(Type::SimpleString("PONG".to_string())),
+            "sentinel" => self.handle_sentinel(&args[1..], from),
+            _ => bail!("unknown command '{}' in sentinel mode", command),
+        }
+    }
+
+    fn handle_sentinel(&self, args: &[String], from: IpAddr) -> Result<Type> {
+        let sub = args.first().context("SENTINEL needs a subcommand")?;
+        let mut state = self.state.lock().unwrap();
+        let name = || args.get(1).context("missing master name");
+        match sub.to_lowercase().as_str() {
+            "myid" => Ok(Type::BulkString(state.myid.clone())),
+            "masters" => {
+                let peers = state.peers.len();
+                let masters = state.masters.iter().map(|m| master_fields(m, peers));
+                Ok(Type::Array(masters.collect()))
+            }
+            "master" => {
+                let peers = state.peers.len();
+                Ok(master_fields(state.master(name()?)?, peers))
+            }
+            "get-master-addr-by-name" => {
+                let Ok(m) = state.master(name()?) else {
+                    return Ok(Type::NullBulkString);
+                };
+                Ok(Type::Array(vec![
+                    Type::BulkString(m.master.addr.ip().to_string()),
+                    Type::BulkString(m.master.addr.port().to_string()),
+                ]))
+            }
+            "replicas" | "slaves" => {
+                let m = state.master(name()?)?;
+                let replicas = m.replicas.iter().map(|r| {
+                    let flags = if r.sdown { "slave,s_down" } else { "slave" };
+                    fields(&[
+                        ("name", r.addr.to_string()),
+                        ("ip", r.addr.ip().to_string()),
+                        ("port", r.addr.port().to_string()),
+                        ("flags", flags.to_string()),
+                    ])
+                });
+                Ok(Type::Array(replicas.collect()))
+            }
+            "sentinels" => {
+                state.master(name()?)?;
+                let peers = state.peers.iter().map(|addr| {
+                    fields(&[
+                        ("name", addr.to_string()),
+                        ("ip", addr.ip().to_string()),
+                        ("port", addr.port().to_string()),
+                    ])
+                });
+                Ok(Type::Array(peers.collect()))
+            }
+            "is-master-down-by-addr" => {
+                // ip port current-epoch runid, runid is `*` when only asking.
+                let [ip, port, epoch, runid] = &args[1..] else {
+                    bail!("IS-MASTER-DOWN-BY-ADDR needs ip, port, epoch and runid");
+                };
+                let addr: SocketAddr = format!("{}:{}", ip, port).parse()?;
+                let epoch: u64 = epoch.parse().context("invalid epoch")?;
+                state.current_epoch = state.current_epoch.max(epoch);
+                let m = state
+                    .masters
+                    .iter_mut()
+                    .find(|m| m.master.addr == addr)
+                    .context("No such master")?;
+                if runid != "*" && epoch > m.leader_epoch {
+                    m.leader = Some(runid.clone());
+                    m.leader_epoch = epoch;
+                    m.failover_start = Some(self.clock.now());
+                    let payload = format!("{} {}", runid, epoch);
+                    self.publish("+vote-for-leader", payload);
+                }
+                let leader = match runid.as_str() {
+                    "*" => "*".to_string(),
+                    _ => m.leader.clone().unwrap_or_else(|| "*".to_string()),
+                };
+                Ok(Type::Array(vec![
+                    Type::Integer(u8::from(m.master.sdown).to_string()),
+                    Type::BulkString(leader),
+                    Type::Integer(m.leader_epoch.to_string()),
+                ]))
+            }
+            "hello" => {
+                let [ip, port, _runid, current_epoch, name, master_ip, master_port, config_epoch] =
+                    &args[1..]
+                else {
+                    bail!("malformed SENTINEL HELLO");
+                };
+                let sender: SocketAddr = format!("{}:{}", ip, port).parse()?;
+                ensure!(
+                    sender.ip() == from,
+                    "SENTINEL HELLO announces {} but comes from {}",
+                    sender.ip(),
+                    from
+                );
+                let current_epoch: u64 = current_epoch.parse().context("invalid epoch")?;
+                let config_epoch: u64 = config_epoch.parse().context("invalid epoch")?;
+                let master: SocketAddr = format!("{}:{}", master_ip, master_port).parse()?;
+                if state.peers.contains(&sender) {
+                    state.current_epoch = state.current_epoch.max(current_epoch);
+                    if let Ok(m) = state.master(name) {
+                        let pending = m.proposed.map_or(0, |(_, epoch)| epoch);
+                        if master == m.master.addr {
+                            m.config_epoch = m.config_epoch.max(config_epoch);
+                        } else if config_epoch > m.config_epoch.max(pending) {
+                            m.proposed = Some((master, config_epoch));
+                        }
+                    }
+                } else {
+                    state.add_candidate(sender);
+                }
+                let known = state.peers.iter().chain([&state.addr]);
+                let known = known.map(|addr| Type::BulkString(addr.to_string()));
+                Ok(Type::Array(known.collect()))
+            }
+            sub => bail!("Unknown sentinel subcommand '{}'", sub),
+        }
+    }
+}
+
+fn fields(pairs: &[(&str, String)]) -> Type {
+    Type::Array(
+        pairs
+            .iter()
+            .flat_map(|(k, v)| [Type::BulkString(k.to_string()), Type::BulkString(v.clone())])
+            .collect(),
+    )
+}
+
+fn master_fields(m: &Monitored, peers: usize) -> Type {
+    let mut flags = "master".to_string();
+    if m.master.sdown {
+        flags.push_str(",s_down");
+    }
+    if m.odown.is_some() {
+        flags.push_str(",o_down");
+    }
+    fields(&[
+        ("name", m.name.clone()),
+        ("ip", m.master.addr.ip().to_string()),
+        ("port", m.master.addr.port().to_string()),
+        ("flags", flags),
+        ("num-slaves", m.replicas.len().to_string()),
+        ("num-other-sentinels", peers.to_string()),
+        ("quorum", m.quorum.to_string()),
+        ("config-epoch", m.config_epoch.to_string()),
+    ])
+}
+
+/// The `field:value` lines of an `INFO` reply.
+fn parse_info(reply: &Type) -> HashMap<String, String> {
+    let Type::BulkString(info) = reply else {
+        return HashMap::new();
+    };
+    info.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Replica addresses from the `slaveN:ip=...,port=...` lines of a master.
+fn info_replicas(info: &HashMap<String, String>) -> Vec<SocketAddr> {
+    let mut replicas: Vec<(usize, SocketAddr)> = info
+        .iter()
+        .filter_map(|(k, v)| {
+            let i = k.strip_prefix("slave")?.parse().ok()?;
+            let fields: HashMap<&str, &str> =
+                v.split(',').filter_map(|f| f.split_once('=')).collect();
+            let addr = format!("{}:{}", fields.get("ip")?, fields.get("port")?);
+            Some((i, addr.parse().ok()?))
+        })
+        .collect();
+    replicas.sort();
+    replicas.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// How often instances are checked, often enough to notice a failure soon
+/// after `down_after`.
+fn check_period(down_after: Duration) -> Duration {
+    (down_after / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))
+}
+
+/// Checks every instance once per period and fails over masters that are
+/// down, until `shutdown` fires.
+async fn monitor(sentinel: Sentinel, mut shutdown: broadcast::Receiver<()>) {
+    let period = check_period(sentinel.down_after);
+    let mut links: HashMap<SocketAddr, Client> = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = sentinel.clock.sleep(period) => {}
+            _ = shutdown.recv() => return,
+        }
+
+        let auth = sentinel.masterauth.as_ref();
+        let info = args(&["INFO", "replication"]);
+        let replies = query(&mut links, sentinel.instances(), info, auth, period).await;
+        for (replica, master) in sentinel.record_info(replies) {
+            let replicaof = args(&[
+                "REPLICAOF",
+                &master.ip().to_string(),
+                &master.port().to_string(),
+            ]);
+            query(&mut links, vec![replica], replicaof, auth, period).await;
+        }
+
+        for (name, addr) in sentinel.sdown_masters() {
+            let ask = |runid: &str, epoch: u64| {
+                let (ip, port) = (addr.ip().to_string(), addr.port().to_string());
+                let epoch = epoch.to_string();
+                args(&[
+                    "SENTINEL",
+                    "IS-MASTER-DOWN-BY-ADDR",
+                    &ip,
+                    &port,
+                    &epoch,
+                    runid,
+                ])
+            };
+            let current_epoch = sentinel.state.lock().unwrap().current_epoch;
+            let replies = query(
+                &mut links,
+                sentinel.peers(),
+                ask("*", current_epoch),
+                None,
+                period,
+            )
+            .await;
+            let agree = replies
+                .into_values()
+                .filter(|r| is_master_down(r).0)
+                .count()
+                + 1;
+            let Some(epoch) = sentinel.odown(&name, agree) else {
+                continue;
+            };
+
+            let myid = sentinel.myid();
+            let replies = query(
+                &mut links,
+                sentinel.peers(),
+                ask(&myid, epoch),
+                None,
+                period,
+            )
+            .await;
+            let votes = replies
+                .into_values()
+                .filter(|r| is_master_down(r).1.as_deref() == Some(myid.as_str()))
+                .count()
+                + 1;
+            let Some(promote) = sentinel.elected(&name, votes) else {
+                continue;
+            };
+            let replicaof = args(&["REPLICAOF", "NO", "ONE"]);
+            let replies = query(&mut links, vec![promote], replicaof, auth, period).await;
+            match replies.get(&promote) {
+                Some(Type::SimpleString(_)) => sentinel.promoted(&name, promote, epoch),
+                reply => server_log!(Level::Warning, "Promoting {} failed: {:?}", promote, reply),
+            }
+        }
+
+        for hello in sentinel.hellos() {
+            for (_, reply) in query(&mut links, sentinel.peers(), hello, None, period).await {
+                sentinel.learn_peers(reply);
+            }
+        }
+        let candidates = sentinel.candidates();
+        if !candidates.is_empty() {
+            for name in sentinel.names() {
+                let get = args(&["SENTINEL", "GET-MASTER-ADDR-BY-NAME", &name]);
+                let replies = query(&mut links, candidates.clone(), get, None, period).await;
+                sentinel.verify_peers(&name, replies);
+            }
+        }
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Whether a reply to `IS-MASTER-DOWN-BY-ADDR` says the master is down, and
+/// who the sentinel voted for.
+fn is_master_down(reply: &Type) -> (bool, Option<String>) {
+    let Type::Array(parts) = reply else {
+        return (false, None);
+    };
+    let down = matches!(parts.first(), Some(Type::Integer(i)) if i == "1");
+    let leader = match parts.get(1) {
+        Some(Type::BulkString(leader)) if leader != "*" => Some(leader.clone()),
+        _ => None,
+    };
+    (down, leader)
+}
+
+/// Sends `args` to every target at once and collects the replies that
+/// arrive within `timeout`, keeping a link open to each target. New links
+/// log in with `auth` first.
+async fn query(
+    links: &mut HashMap<SocketAddr, Client>,
+    targets: Vec<SocketAddr>,
+    args: Vec<String>,
+    auth: Option<&MasterAuth>,
+    timeout: Duration,
+) -> HashMap<SocketAddr, Type> {
+    let mut requests = JoinSet::new();
+    for addr in targets {
+        let link = links.remove(&addr);
+        let args = args.clone();
+        let auth = auth.cloned();
+        requests.spawn(async move {
+            let request = async move {
+                let mut link = match link {
+                    Some(link) => link,
+                    None => {
+                        let mut link = Client::connect(addr).await?;
+                        if let Some(auth) = &auth {
+                            auth.login(&mut link).await?;
+                        }
+                        link
+                    }
+                };
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let reply = link.request(&args).await?;
+                Ok::<_, anyhow::Error>((link, reply))
+            };
+            let res = match tokio::time::timeout(timeout, request).await {
+                Ok(res) => res,
+                Err(_) => Err(anyhow!("no reply from {} within {:?}", addr, timeout)),
+            };
+            (addr, res)
+        });
+    }
+    let mut replies = HashMap::new();
+    while let Some(res) = requests.join_next().await {
+        let Ok((addr, res)) = res else {
+            continue;
+        };
+        match res {
+            Ok((link, reply)) => {
+                links.insert(addr, link);
+                replies.insert(addr, reply);
+            }
+            Err(e) => server_log!(Level::Debug, "sentinel request failed: {:#}", e),
+        }
+    }
+    replies
+}
+
+/// The arguments of a command sent as an array of strings.
+fn command_args(value: Type) -> Result<Vec<String>> {
+    let Type::Array(parts) = value else {
+        bail!("expected a command as an array");
+    };
+    parts.into_iter().map(String::try_from).collect()
+}
+
+async fn connection(
+    stream: TcpStream,
+    from: IpAddr,
+    sentinel: Sentinel,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+    let mut client = Client::new(stream);
+    loop {
+        let frame = tokio::select! {
+            frame = client.read_frame() => frame?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+        let Some((request, _)) = frame else {
+            return Ok(());
+        };
+        let args = command_args(request)?;
+        let command = args
+            .first()
+            .map(|arg| arg.to_lowercase())
+            .unwrap_or_default();
+        if command == "subscribe" || command == "psubscribe" {
+            return pubsub(client, sentinel, args, shutdown).await;
+        }
+        let reply = match sentinel.handle(&args, from) {
+            Ok(reply) => reply,
+            Err(e) => Type::SimpleError(format!("ERR {:#}", e)),
+        };
+        client.send(reply).await?;
+    }
+}
+
+/// Serves a client after `SUBSCRIBE` or `PSUBSCRIBE`, forwarding the events
+/// it asked for until it disconnects.
+async fn pubsub(
+    mut client: Client,
+    sentinel: Sentinel,
+    args: Vec<String>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+    let mut events = sentinel.events.subscribe();
+    let mut channels: Vec<String> = Vec::new();
+    let mut patterns: Vec<String> = Vec::new();
+    let mut request = Some(args);
+    loop {
+        if let Some(args) = request.take() {
+            let kind = args[0].to_lowercase();
+            for name in args.into_iter().skip(1) {
+                match kind.as_str() {
+                    "subscribe" => channels.push(name.clone()),
+                    "psubscribe" => patterns.push(name.clone()),
+                    _ => {
+                        let e = format!(
+                            "ERR Can't execute '{}': only (P)SUBSCRIBE / PING are allowed in this context",
+                            kind
+                        );
+                        client.send(Type::SimpleError(e)).await?;
+                        break;
+                    }
+                }
+                let count = channels.len() + patterns.len();
+                client
+                    .send(Type::Array(vec![
+                        Type::BulkString(kind.clone()),
+                        Type::BulkString(name),
+                        Type::Integer(count.to_string()),
+                    ]))
+                    .await?;
+            }
+        }
+
+        tokio::select! {
+            frame = client.read_frame() => {
+                let Some((value, _)) = frame? else {
+                    return Ok(());
+                };
+                let args = command_args(value)?;
+                if args.first().is_some_and(|arg| arg.eq_ignore_ascii_case("ping")) {
+                    let pong = ["pong", ""].map(|s| Type::BulkString(s.to_string()));
+                    client.send(Type::Array(pong.to_vec())).await?;
+                } else if !args.is_empty() {
+                    request = Some(args);
+                }
+            }
+            event = events.recv() => {
+                let (channel, payload) = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                if channels.contains(&channel) {
+                    let message = ["message", &channel, &payload].map(|s| Type::BulkString(s.to_string()));
+                    client.send(Type::Array(message.to_vec())).await?;
+                }
+                for pattern in patterns.iter().filter(|p| glob_match(p, &channel)) {
+                    let message = ["pmessage", pattern, &channel, &payload].map(|s| Type::BulkString(s.to_string()));
+                    client.send(Type::Array(message.to_vec())).await?;
+                }
+            }
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Configures and starts a [`Sentinel`] in the current tokio runtime.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let sentinel = redis_starter_rust::sentinel::Sentinel::builder()
+///     .port(26379)
+///     .monitor("mymaster", "127.0.0.1", 6379, 2)
+///     .peer("127.0.0.1", 26380)
+///     .spawn()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SentinelBuilder {
+    addr: String,
+    port: u16,
+    monitor: Vec<(String, String, u16, usize)>,
+    peers: Vec<(String, u16)>,
+    clock: SharedClock,
+    down_after: Duration,
+    failover_timeout: Duration,
+    masteruser: Option<String>,
+    masterauth: Option<String>,
+}
+
+impl Default for SentinelBuilder {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1".to_string(),
+            port: 26379,
+            monitor: Vec::new(),
+            peers: Vec::new(),
+            clock: Arc::new(SystemClock),
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            masteruser: None,
+            masterauth: None,
+        }
+    }
+}
+
+impl SentinelBuilder {
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Port to listen on, 0 picks a free one.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Watches the master at `host:port` under `name`, `quorum` sentinels
+    /// must agree it is down before it is failed over.
+    pub fn monitor(
+        mut self,
+        name: impl Into<String>,
+        host: impl Into<String>,
+        port: u16,
+        quorum: usize,
+    ) -> Self {
+        self.monitor.push((name.into(), host.into(), port, quorum));
+        self
+    }
+
+    /// Another sentinel to start from, the rest are learned through it.
+    pub fn peer(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.peers.push((host.into(), port));
+        self
+    }
+
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// How long an instance may go without answering before it is
+    /// considered down.
+    pub fn down_after(mut self, down_after: Duration) -> Self {
+        self.down_after = down_after;
+        self
+    }
+
+    /// How long to wait before trying to fail the same master over again.
+    pub fn failover_timeout(mut self, timeout: Duration) -> Self {
+        self.failover_timeout = timeout;
+        self
+    }
+
+    /// Password to `AUTH` with on monitored masters and their replicas.
+    pub fn masterauth(mut self, password: impl Into<String>) -> Self {
+        self.masterauth = Some(password.into());
+        self
+    }
+
+    /// User to log in as with the `masterauth` password, the default user
+    /// if not set.
+    pub fn masteruser(mut self, user: impl Into<String>) -> Self {
+        self.masteruser = Some(user.into());
+        self
+    }
+
+    /// Binds the listener and starts monitoring.
+    pub async fn spawn(self) -> Result<SentinelHandle> {
+        let listener = TcpListener::bind((self.addr.as_str(), self.port))
+            .await
+            .context("binding listener")?;
+        let addr = listener.local_addr()?;
+        let now = self.clock.now();
+
+        let mut masters = Vec::new();
+        for (name, host, port, quorum) in self.monitor {
+            let master = resolve(&host, port).await?;
+            masters.push(Monitored {
+                name,
+                quorum,
+                master: Instance::new(master, now),
+                replicas: Vec::new(),
+                odown: None,
+                config_epoch: 0,
+                leader: None,
+                leader_epoch: 0,
+                failover_start: None,
+                proposed: None,
+            });
+        }
+        let mut peers = Vec::new();
+        for (host, port) in self.peers {
+            peers.push(resolve(&host, port).await?);
+        }
+
+        let (events, _) = broadcast::channel(64);
+        let sentinel = Sentinel {
+            state: Arc::new(Mutex::new(State {
+                myid: random_node_id(),
+                addr,
+                current_epoch: 0,
+                masters,
+                peers,
+                candidates: Vec::new(),
+            })),
+            clock: self.clock,
+            down_after: self.down_after,
+            failover_timeout: self.failover_timeout,
+            masterauth: self.masterauth.map(|password| MasterAuth {
+                user: self.masteruser,
+                password,
+            }),
+            events,
+        };
+
+        let (notify_shutdown, _) = broadcast::channel(1);
+        tokio::spawn(monitor(sentinel.clone(), notify_shutdown.subscribe()));
+        let task = tokio::spawn(serve(listener, sentinel.clone(), notify_shutdown.clone()));
+        Ok(SentinelHandle {
+            addr,
+            sentinel,
+            task,
+            notify_shutdown,
+        })
+    }
+}
+
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    lookup_host((host, port))
+        .await
+        .with_context(|| format!("resolving {}:{}", host, port))?
+        .next()
+        .with_context(|| format!("no address found for {}:{}", host, port))
+}
+
+async fn serve(
+    listener: TcpListener,
+    sentinel: Sentinel,
+    notify_shutdown: broadcast::Sender<()>,
+) -> Result<()> {
+    let mut shutdown = notify_shutdown.subscribe();
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, peer)) => {
+                    let sentinel = sentinel.clone();
+                    let shutdown = notify_shutdown.subscribe();
+                    tokio::spawn(async move {
+                        if let Err(e) = connection(stream, peer.ip(), sentinel, shutdown).await {
+                            server_log!(Level::Verbose, "sentinel connection error: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => server_log!(Level::Warning, "error: {}", e),
+            },
+            _ = shutdown.recv() => return Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SentinelHandle {
+    addr: SocketAddr,
+    sentinel: Sentinel,
+    task: JoinHandle<Result<()>>,
+    notify_shutdown: broadcast::Sender<()>,
+}
+
+impl SentinelHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn sentinel(&self) -> &Sentinel {
+        &self.sentinel
+    }
+
+    /// Stops monitoring and closes every connection.
+    pub async fn shutdown(mut self) -> Result<()> {
+        let _ = self.notify_shutdown.send(());
+        match (&mut self.task).await {
+            Ok(rv) => rv,
+            Err(e) => bail!("sentinel task failed: {}", e),
+        }
+    }
+
+    /// Runs until `signal` resolves and then shuts down.
+    pub async fn run_until<F: Future>(mut self, signal: F) -> Result<()> {
+        tokio::select! {
+            rv = &mut self.task => return rv.map_err(|e| anyhow!("sentinel task failed: {}", e))?,
+            _ = signal => {}
+        }
+        self.shutdown().await
+    }
+}
@@ -1,74 +1,402 @@
+use crate::auth::*;
+use crate::backend::*;
+use crate::clock::*;
+use crate::cluster::*;
 use crate::command::*;
-use crate::flags::*;
+use crate::config::*;
 use crate::frame::*;
+use crate::gossip;
+use crate::info::*;
+use crate::keylock::*;
+use crate::log::*;
+use crate::memcache;
+use crate::raft::{self, Raft};
+use crate::rdb::{RdbEntry, Snapshot};
 use crate::replication::*;
 use crate::response::*;
+use crate::resptype::*;
+use crate::server_log;
+use crate::slowlog::*;
+use crate::tier::{self, ColdStore, ColdValue, Compaction, Location, Spill};
 use anyhow::{bail, Context, Result};
 use itertools::Itertools;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{thread, time};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{lookup_host, TcpListener, TcpStream},
+    sync::{broadcast, mpsc, RwLock},
+    task::JoinHandle,
 };
 
+pub type Db = Arc<Mutex<Database>>;
+
+/// Reply to writes once `maxmemory` is reached, worded like Redis.
+const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
 #[derive(Debug, Clone)]
 pub struct DbEntry {
     value: String,
     expiry: Option<Instant>,
+    flags: u32,
+    version: u64,
+    last_access: Option<Instant>,
+    /// Where the value went when it was moved to cold storage.
+    cold: Option<Location>,
+    /// Set with `OBJECT FREEZE`, only `APPEND` may change the key then.
+    append_only: bool,
 }
 
 impl DbEntry {
-    pub fn new(s: String, ex: Option<Duration>) -> Self {
-        if let Some(dur) = ex {
-            return Self {
-                value: s,
-                expiry: Some(Instant::now() + dur),
-            };
-        } else {
-            return Self {
-                value: s,
-                expiry: None,
-            };
+    /// `expiry` is a deadline on the database's clock, see [`Database::now`].
+    pub fn new(s: String, expiry: Option<Instant>) -> Self {
+        Self {
+            value: s,
+            expiry,
+            flags: 0,
+            version: 0,
+            last_access: None,
+            cold: None,
+            append_only: false,
         }
     }
-    pub fn value(self) -> String {
+
+    /// Opaque client flags, only set and read by the memcached listener.
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn value(&self) -> String {
         self.value.clone()
     }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Bumped every time the key is written, see `GETVER`.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn is_append_only(&self) -> bool {
+        self.append_only
+    }
+
+    /// Replaces the value, keeping the expiry and flags.
+    pub fn set_value(&mut self, value: String) {
+        self.value = value;
+    }
+
+    /// Time left at `now` before the entry expires, `None` if it never does.
+    pub fn ttl(&self, now: Instant) -> Option<Duration> {
+        self.expiry
+            .map(|expiry| expiry.saturating_duration_since(now))
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        match self.expiry {
+            Some(expiry) => expiry <= now,
+            None => false,
+        }
+    }
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Debug)]
 pub struct Database {
     db: HashMap<String, DbEntry>,
+    clock: SharedClock,
+    used_memory: u64,
+    cold: Option<ColdStore>,
+    /// The version given to the last write, versions are never reused.
+    last_version: u64,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
 }
 
 impl Database {
-    pub fn insert(mut self, key: String, val: DbEntry) -> Result<()> {
-        self.db.insert(key, val);
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            db: HashMap::new(),
+            clock,
+            used_memory: 0,
+            cold: None,
+            last_version: 0,
+        }
+    }
+
+    /// Moves values that go unused for a while to `store`.
+    pub(crate) fn set_cold_store(&mut self, store: ColdStore) {
+        self.cold = Some(store);
+    }
+
+    /// How long values stay in memory without being used, `None` without
+    /// cold storage.
+    pub(crate) fn cold_idle(&self) -> Option<Duration> {
+        self.cold.as_ref().map(ColdStore::idle)
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Current time on the database's clock, expiry deadlines are relative
+    /// to this.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Bytes taken up by keys and values, checked against `maxmemory`. Values
+    /// in cold storage don't count.
+    pub fn used_memory(&self) -> u64 {
+        self.used_memory
+    }
+
+    /// Stores `val` under `key` with a version higher than any before, so a
+    /// key deleted and written again doesn't get an old version back.
+    pub fn insert(&mut self, key: String, mut val: DbEntry) -> Option<DbEntry> {
+        self.last_version += 1;
+        val.version = self.last_version;
+        val.last_access = Some(self.now());
+        self.used_memory += entry_size(&key, &val);
+        let key_len = key.len();
+        let old = self.db.insert(key, val)?;
+        self.used_memory -= (key_len + old.value.len()) as u64;
+        self.free_cold(&old);
+        Some(old)
+    }
+
+    /// Looks `key` up, reading its value back into memory if it was moved
+    /// to cold storage. Fails if it can't be read back.
+    pub fn get(&mut self, key: &str) -> Result<Option<&DbEntry>> {
+        let now = self.now();
+        let Some(entry) = self.db.get_mut(key) else {
+            return Ok(None);
+        };
+        entry.last_access = Some(now);
+        if let (Some(location), Some(store)) = (entry.cold, self.cold.as_mut()) {
+            let value = store
+                .read(location)
+                .with_context(|| format!("reading {} from cold storage", key))?;
+            store.free(location);
+            self.used_memory += value.len() as u64;
+            entry.value = value;
+            entry.cold = None;
+        }
+        Ok(Some(entry))
+    }
+
+    /// Looks `key` up without reading it back from cold storage, entries
+    /// whose value was moved there have an empty value.
+    pub fn peek(&self, key: &str) -> Option<&DbEntry> {
+        self.db.get(key)
+    }
+
+    /// Whether `key` is stored and not expired, without reading it back from
+    /// cold storage.
+    pub fn contains(&self, key: &str) -> bool {
+        self.db
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(self.now()))
+    }
+
+    /// Whether `key` is stored, not expired and frozen with `OBJECT FREEZE`.
+    pub fn is_append_only(&self, key: &str) -> bool {
+        self.db
+            .get(key)
+            .is_some_and(|entry| entry.append_only && !entry.is_expired(self.now()))
+    }
+
+    /// Marks `key` append-only or lifts it, returns whether the key exists.
+    pub fn set_append_only(&mut self, key: &str, append_only: bool) -> bool {
+        let now = self.now();
+        match self.db.get_mut(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.append_only = append_only;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<DbEntry> {
+        let old = self.db.remove(key)?;
+        self.used_memory -= entry_size(key, &old);
+        self.free_cold(&old);
+        Some(old)
+    }
+
+    fn free_cold(&mut self, entry: &DbEntry) {
+        if let (Some(location), Some(store)) = (entry.cold, self.cold.as_mut()) {
+            store.free(location);
+        }
+    }
+
+    /// Copies the values that went unused for longer than the cold storage
+    /// threshold and sets aside room for them on disk, `None` if there are
+    /// none. See [`tier::spill_loop`].
+    pub(crate) fn plan_spill(&mut self) -> Option<Spill> {
+        let store = self.cold.as_mut()?;
+        let now = self.clock.now();
+        let idle: Vec<(String, u64, String)> = self
+            .db
+            .iter()
+            .filter(|(_, entry)| {
+                let recent = entry
+                    .last_access
+                    .is_some_and(|at| now.saturating_duration_since(at) < store.idle());
+                entry.cold.is_none() && !entry.value.is_empty() && !recent && !entry.is_expired(now)
+            })
+            .map(|(key, entry)| (key.clone(), entry.version, entry.value.clone()))
+            .collect();
+        if idle.is_empty() {
+            return None;
+        }
+        Some(store.reserve(idle))
+    }
+
+    /// Moves the values of a spill out of memory once they were `written`,
+    /// except those whose entry was written or deleted in the meantime.
+    /// Returns how many were moved.
+    pub(crate) fn finish_spill(
+        &mut self,
+        values: Vec<ColdValue>,
+        written: Result<()>,
+    ) -> Result<usize> {
+        let Some(store) = self.cold.as_mut() else {
+            return Ok(0);
+        };
+        if let Err(e) = written {
+            for value in &values {
+                store.free(value.location);
+            }
+            return Err(e);
+        }
+        let mut spilled = 0;
+        for value in values {
+            match self.db.get_mut(&value.key) {
+                Some(entry) if entry.version == value.version && entry.cold.is_none() => {
+                    entry.cold = Some(value.location);
+                    self.used_memory -= entry.value.len() as u64;
+                    entry.value = String::new();
+                    spilled += 1;
+                }
+                _ => store.free(value.location),
+            }
+        }
+        Ok(spilled)
+    }
+
+    /// Starts rewriting the cold file if most of it was freed.
+    pub(crate) fn plan_compaction(&self) -> Result<Option<Compaction>> {
+        let Some(store) = self.cold.as_ref().filter(|store| store.needs_compaction()) else {
+            return Ok(None);
+        };
+        let locations = self
+            .db
+            .iter()
+            .filter_map(|(key, entry)| Some((key.clone(), entry.cold?)))
+            .collect();
+        store.compaction(locations).map(Some)
+    }
+
+    /// Switches to the rewritten cold file, pointing the values that are
+    /// still cold at their new location.
+    pub(crate) fn finish_compaction(&mut self, mut compaction: Compaction) -> Result<()> {
+        let Some(store) = self.cold.as_mut() else {
+            return Ok(());
+        };
+        let moves = std::mem::take(&mut compaction.moves);
+        let mut wasted = 0;
+        let mut moved = Vec::with_capacity(moves.len());
+        for (key, from, to) in moves {
+            let to = to.context("value was not copied")?;
+            match self.db.get_mut(&key) {
+                Some(entry) if entry.cold == Some(from) => moved.push((key, to)),
+                // Read back or deleted while it was copied.
+                _ => wasted += from.len(),
+            }
+        }
+        store.finish_compaction(compaction, wasted)?;
+        for (key, to) in moved {
+            if let Some(entry) = self.db.get_mut(&key) {
+                entry.cold = Some(to);
+            }
+        }
         Ok(())
     }
 
-    pub fn get(self, key: String) -> Result<DbEntry> {
-        if let Some(val) = self.db.get(&key) {
-            return Ok(val.clone());
-        } else {
-            return Ok(DbEntry::new("(nil)".into(), None));
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &DbEntry)> {
+        self.db.iter()
+    }
+
+    /// The keys that haven't expired, with their values and expiries as unix
+    /// time. Values are read back from cold storage, other properties of
+    /// an entry are not kept.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        let now = self.now();
+        let unix_now = self.clock.unix_time();
+        let keys: Vec<String> = self
+            .db
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut snapshot = Snapshot::default();
+        for key in keys {
+            let entry = self.get(&key)?.context("key vanished while snapshotting")?;
+            let expiry = entry
+                .ttl(now)
+                .map(|ttl| (unix_now + ttl).as_millis() as u64);
+            let value = entry.value.clone().into_bytes();
+            snapshot.insert(key, RdbEntry { value, expiry });
+        }
+        Ok(snapshot)
+    }
+
+    /// Replaces every key with those of `snapshot` that haven't expired yet.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let keys: Vec<String> = self.db.keys().cloned().collect();
+        for key in keys {
+            self.remove(&key);
+        }
+        let now = self.now();
+        let unix_now = self.clock.unix_time().as_millis() as u64;
+        for (key, entry) in snapshot.entries() {
+            let expiry = match entry.expiry {
+                Some(at) if at <= unix_now => continue,
+                Some(at) => Some(now + Duration::from_millis(at - unix_now)),
+                None => None,
+            };
+            let value = String::from_utf8_lossy(&entry.value).into_owned();
+            self.insert(key.clone(), DbEntry::new(value, expiry));
         }
     }
 
-    pub fn get_all(self) -> Result<Vec<String>> {
+    pub fn get_all(&self) -> Result<Vec<String>> {
         Ok(self
             .db
-            .clone()
-            .into_iter()
+            .iter()
             .map(|(k, v)| k.to_owned() + ":" + v.value().as_str() + "\n")
             .collect::<Vec<String>>())
     }
 }
-#[derive(Debug)]
+
+fn entry_size(key: &str, entry: &DbEntry) -> u64 {
+    (key.len() + entry.value.len()) as u64
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum Role {
     Master,
     Slave(SocketAddr),
@@ -78,98 +406,1393 @@ pub enum Role {
 pub struct ServerInfo {
     pub role: Role,
     pub addr: SocketAddr,
-    pub replicas: Vec<TcpStream>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Server {
-    redis_db: Arc<Mutex<Database>>,
-    info_db: Arc<Mutex<Database>>,
+    redis_db: Db,
+    info_db: Db,
+    replicas: StreamVec,
     server_info: Arc<Mutex<ServerInfo>>,
+    clock: SharedClock,
+    slowlog: SlowLog,
+    config: Arc<Mutex<Config>>,
+    config_file: Option<Arc<Mutex<ConfigFile>>>,
+    cluster: Option<Cluster>,
+    raft: Option<Raft>,
+    backend: Option<SharedBackend>,
+    /// Held by commands on the same keys while they reach the backend.
+    key_locks: KeyLocks,
+    /// Shared by every command while it runs and is replicated, taken alone
+    /// by `EXEC` so no other client's command runs in between its commands,
+    /// and by `PSYNC` so the snapshot it sends misses no write.
+    exclusive: Arc<RwLock<()>>,
+    /// Set when clients must `AUTH` first.
+    auth: Option<Arc<Authenticator>>,
+    /// Sent in `AUTH` to the master and to `MIGRATE` targets.
+    masterauth: Option<MasterAuth>,
+    /// The task following the master while this is a replica.
+    replication_link: Arc<Mutex<Option<JoinHandle<Result<()>>>>>,
 }
 
 impl Server {
     pub fn new(addr: SocketAddr, role: Role) -> Self {
+        Self::with_options(
+            addr,
+            role,
+            Arc::new(SystemClock),
+            DEFAULT_SLOWER_THAN,
+            Config::default(),
+            None,
+            None,
+        )
+    }
+
+    fn with_options(
+        addr: SocketAddr,
+        role: Role,
+        clock: SharedClock,
+        slowlog_slower_than: Duration,
+        config: Config,
+        config_file: Option<ConfigFile>,
+        cluster: Option<Cluster>,
+    ) -> Self {
+        let info_db = Arc::new(Mutex::new(Database::with_clock(clock.clone())));
+        init_info_db(&info_db, &addr, &role).unwrap();
         Self {
-            server_info: Arc::new(Mutex::new(ServerInfo {
-                replicas: Vec::default(),
-                role,
-                addr,
-            })),
-            redis_db: Arc::new(Mutex::new(Database::default())),
-            info_db: Arc::new(Mutex::new(Database::default())),
+            server_info: Arc::new(Mutex::new(ServerInfo { role, addr })),
+            redis_db: Arc::new(Mutex::new(Database::with_clock(clock.clone()))),
+            replicas: StreamVec::default(),
+            info_db,
+            slowlog: SlowLog::new(clock.clone(), slowlog_slower_than),
+            clock,
+            config: Arc::new(Mutex::new(config)),
+            config_file: config_file.map(|file| Arc::new(Mutex::new(file))),
+            cluster,
+            raft: None,
+            backend: None,
+            key_locks: KeyLocks::default(),
+            exclusive: Arc::default(),
+            auth: None,
+            masterauth: None,
+            replication_link: Arc::default(),
+        }
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn db(&self) -> Db {
+        self.redis_db.clone()
+    }
+
+    /// Cluster state, `None` unless started in cluster mode.
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_ref()
+    }
+
+    /// Raft state, `None` unless started in Raft mode.
+    pub fn raft(&self) -> Option<&Raft> {
+        self.raft.as_ref()
+    }
+
+    pub fn role(&self) -> Role {
+        self.server_info.lock().unwrap().role
+    }
+
+    /// Turns this replica into a master, keeping the data it replicated so
+    /// far. Used when it wins a cluster failover election.
+    pub(crate) fn promote(&self) {
+        if let Some(link) = self.replication_link.lock().unwrap().take() {
+            link.abort();
+        }
+        let mut info = self.server_info.lock().unwrap();
+        info.role = Role::Master;
+        {
+            let mut info_db = self.info_db.lock().unwrap();
+            info_db.remove("master_host");
+            info_db.remove("master_port");
+        }
+        init_info_db(&self.info_db, &info.addr, &info.role).unwrap();
+    }
+
+    /// Starts following the master at `addr`, dropping the current master
+    /// and replicas if any.
+    fn follow(&self, master_addr: SocketAddr) {
+        let mut info = self.server_info.lock().unwrap();
+        info.role = Role::Slave(master_addr);
+        init_info_db(&self.info_db, &info.addr, &info.role).unwrap();
+        let (db, info_db) = (self.redis_db.clone(), self.info_db.clone());
+        let port = info.addr.port();
+        let auth = self.masterauth.clone();
+        let link =
+            tokio::spawn(async move { handshake(master_addr, port, auth, db, info_db).await });
+        if let Some(old) = self.replication_link.lock().unwrap().replace(link) {
+            old.abort();
+        }
+    }
+
+    /// `REPLICAOF host port` starts replicating from another master,
+    /// `REPLICAOF NO ONE` turns a replica into a master. This is how a
+    /// sentinel reconfigures the servers during a failover.
+    pub async fn replicaof(&self, master_addr: Option<SocketAddr>) -> Result<()> {
+        if self.cluster.is_some() {
+            bail!("REPLICAOF not allowed in cluster mode.");
+        }
+        if self.raft.is_some() {
+            bail!("REPLICAOF not allowed in raft mode.");
+        }
+        match master_addr {
+            Some(master_addr) => {
+                server_log!(Level::Notice, "Following new master {}", master_addr);
+                let mut replicas = self.replicas.lock().await;
+                replicas.clear();
+                self.follow(master_addr);
+                record_replicas(&self.info_db, &replicas);
+            }
+            None => {
+                server_log!(Level::Notice, "Turning into a master");
+                self.promote();
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_replicaof(&self, frame: Frame) -> Result<Vec<u8>> {
+        let args = frame.args().unwrap_or_default();
+        let (host, port) = args
+            .iter()
+            .collect_tuple()
+            .context("parsing arguments for replicaof command")?;
+        let master_addr = if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            None
+        } else {
+            let port: u16 = port.parse().context("invalid master port")?;
+            let addr = lookup_host((host.as_str(), port))
+                .await
+                .context("resolving master address")?
+                .next()
+                .context("no address found for master")?;
+            Some(addr)
+        };
+        self.replicaof(master_addr).await?;
+        Ok(Type::SimpleString("OK".to_string()).serialize())
+    }
+
+    /// Re-reads the config file given to [`ServerBuilder::config_file`] and
+    /// applies what changed in it.
+    pub fn reload_config(&self) -> Result<Reload> {
+        match &self.config_file {
+            Some(file) => reload_config_file(file, &self.config),
+            None => bail!("the server was started without a config file"),
         }
     }
 
     pub async fn start(self) -> Result<()> {
-        let bind_addr = self.server_info.lock().unwrap().addr.clone();
-        let listener = TcpListener::bind(&bind_addr).await.unwrap();
-        loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    let db = self.redis_db.clone();
-                    let info_db = self.info_db.clone();
-                    let server_info = self.server_info.clone();
-                    tokio::spawn(
-                        async move { stream_handler(stream, db, info_db, server_info).await },
-                    );
-                    println!("Tokio thread spawned");
+        let bind_addr = self.server_info.lock().unwrap().addr;
+        let listener = TcpListener::bind(&bind_addr)
+            .await
+            .context("binding listener")?;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        self.serve(listener, notify_shutdown, shutdown_complete)
+            .await
+    }
+
+    /// Accepts connections until `notify_shutdown` fires. Every connection
+    /// task holds a clone of `shutdown_complete`, so the matching receiver
+    /// only sees the channel close once all of them have finished.
+    ///
+    /// Subscribes before returning the future, a shutdown sent before the
+    /// task first runs would otherwise be missed.
+    fn serve(
+        self,
+        listener: TcpListener,
+        notify_shutdown: broadcast::Sender<()>,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> impl Future<Output = Result<()>> {
+        let mut shutdown = notify_shutdown.subscribe();
+        async move {
+            loop {
+                tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok((stream, _)) => {
+                            let server = self.clone();
+                            let shutdown = notify_shutdown.subscribe();
+                            let done = shutdown_complete.clone();
+                            tokio::spawn(async move {
+                                let rv = stream_handler(stream, server, shutdown).await;
+                                drop(done);
+                                rv
+                            });
+                            server_log!(Level::Debug, "Tokio thread spawned");
+                        }
+                        Err(e) => {
+                            server_log!(Level::Warning, "error: {}", e);
+                        }
+                    },
+                    _ = shutdown.recv() => {
+                        server_log!(Level::Notice, "No longer accepting connections");
+                        return Ok(());
+                    }
                 }
-                Err(e) => {
-                    println!("error: {}", e);
+            }
+        }
+    }
+}
+
+/// Configures and starts a `Server` in the current tokio runtime.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let master = redis_starter_rust::Server::builder().port(0).spawn().await?;
+/// let replica = redis_starter_rust::Server::builder()
+///     .port(0)
+///     .replicaof("127.0.0.1", master.local_addr().port())
+///     .spawn()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    addr: String,
+    port: u16,
+    replicaof: Option<(String, u16)>,
+    memcached_port: Option<u16>,
+    clock: SharedClock,
+    slowlog_slower_than: Duration,
+    config: Vec<(String, String)>,
+    config_file: Option<ConfigFile>,
+    cluster_slots: Option<RangeInclusive<u16>>,
+    cluster_config_file: Option<PathBuf>,
+    cluster_port: Option<u16>,
+    cluster_node_timeout: Duration,
+    raft_port: Option<u16>,
+    raft_peers: Vec<(String, u16)>,
+    raft_election_timeout: Duration,
+    raft_snapshot_entries: u64,
+    raft_dir: Option<PathBuf>,
+    cold_storage: Option<(PathBuf, Duration)>,
+    backend: Option<SharedBackend>,
+    auth_provider: Option<SharedAuthProvider>,
+    masteruser: Option<String>,
+    masterauth: Option<String>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            addr: String::from("127.0.0.1"),
+            port: 6379,
+            replicaof: None,
+            memcached_port: None,
+            clock: Arc::new(SystemClock),
+            slowlog_slower_than: DEFAULT_SLOWER_THAN,
+            config: Vec::new(),
+            config_file: None,
+            cluster_slots: None,
+            cluster_config_file: None,
+            cluster_port: None,
+            cluster_node_timeout: DEFAULT_NODE_TIMEOUT,
+            raft_port: None,
+            raft_peers: Vec::new(),
+            raft_election_timeout: raft::DEFAULT_ELECTION_TIMEOUT,
+            raft_snapshot_entries: raft::DEFAULT_SNAPSHOT_ENTRIES,
+            raft_dir: None,
+            cold_storage: None,
+            backend: None,
+            auth_provider: None,
+            masteruser: None,
+            masterauth: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Port to listen on, `0` picks an ephemeral port.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Replicates the server at `host:port`. In cluster mode the replica owns
+    /// no slots and takes over its master's if the master fails.
+    pub fn replicaof(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.replicaof = Some((host.into(), port));
+        self
+    }
+
+    /// Also serve the memcached text protocol on `port`, on the same address
+    /// and database. `0` picks an ephemeral port.
+    pub fn memcached_port(mut self, port: u16) -> Self {
+        self.memcached_port = Some(port);
+        self
+    }
+
+    /// Time source for expiry, heartbeats and the slow log, tests can pass a
+    /// [`MockClock`] here.
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Commands that take at least this long end up in `SLOWLOG GET`.
+    pub fn slowlog_slower_than(mut self, threshold: Duration) -> Self {
+        self.slowlog_slower_than = threshold;
+        self
+    }
+
+    /// Sets a `CONFIG` parameter before the server starts, invalid ones make
+    /// `spawn` fail.
+    pub fn config(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config.push((name.into(), value.into()));
+        self
+    }
+
+    /// Loads `CONFIG` parameters from `file`, which [`Server::reload_config`]
+    /// re-reads later. Pairs given to [`ServerBuilder::config`] take precedence
+    /// and startup parameters such as `port` are left to the caller.
+    pub fn config_file(mut self, file: ConfigFile) -> Self {
+        self.config_file = Some(file);
+        self
+    }
+
+    /// Runs in cluster mode, serving only keys that hash to `slots`.
+    pub fn cluster_slots(mut self, slots: RangeInclusive<u16>) -> Self {
+        self.cluster_slots = Some(slots);
+        self
+    }
+
+    /// Runs in cluster mode with the topology in a nodes.conf style file,
+    /// taking precedence over [`ServerBuilder::cluster_slots`].
+    pub fn cluster_config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cluster_config_file = Some(path.into());
+        self
+    }
+
+    /// Port of the cluster bus other nodes gossip with, `0` picks an
+    /// ephemeral port. Defaults to the client port plus 10000, or an
+    /// ephemeral port if that one is too.
+    pub fn cluster_port(mut self, port: u16) -> Self {
+        self.cluster_port = Some(port);
+        self
+    }
+
+    /// How long a node may go without answering on the cluster bus before
+    /// it is flagged as failing.
+    pub fn cluster_node_timeout(mut self, timeout: Duration) -> Self {
+        self.cluster_node_timeout = timeout;
+        self
+    }
+
+    /// Runs in the experimental Raft mode, talking to the other nodes on
+    /// `port`. `0` picks an ephemeral port.
+    pub fn raft_port(mut self, port: u16) -> Self {
+        self.raft_port = Some(port);
+        self
+    }
+
+    /// Adds the node whose Raft port is `host:port` to the group.
+    pub fn raft_peer(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.raft_peers.push((host.into(), port));
+        self
+    }
+
+    /// How long a follower waits to hear from the leader before starting
+    /// an election, randomized up to twice as long.
+    pub fn raft_election_timeout(mut self, timeout: Duration) -> Self {
+        self.raft_election_timeout = timeout;
+        self
+    }
+
+    /// How many applied entries the Raft log keeps before they are replaced
+    /// with a snapshot of the database.
+    pub fn raft_snapshot_entries(mut self, entries: u64) -> Self {
+        self.raft_snapshot_entries = entries;
+        self
+    }
+
+    /// Keeps the Raft term, vote, snapshot and log in `dir` so they survive
+    /// restarts.
+    /// Without it they only live in memory, and a restarted node may vote
+    /// twice in a term or lose entries it acknowledged.
+    pub fn raft_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.raft_dir = Some(dir.into());
+        self
+    }
+
+    /// Moves values nobody used for `idle` to `path` and reads them back
+    /// when they are accessed again. The file is truncated on startup.
+    pub fn cold_storage(mut self, path: impl Into<PathBuf>, idle: Duration) -> Self {
+        self.cold_storage = Some((path.into(), idle));
+        self
+    }
+
+    /// Serves keys missing from memory from `backend` and writes every
+    /// change through to it.
+    pub fn backend(mut self, backend: SharedBackend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Makes clients `AUTH` with a password `provider` accepts before they
+    /// can run other commands.
+    pub fn auth_provider(mut self, provider: SharedAuthProvider) -> Self {
+        self.auth_provider = Some(provider);
+        self
+    }
+
+    /// Password to `AUTH` with on the master and on `MIGRATE` targets.
+    pub fn masterauth(mut self, password: impl Into<String>) -> Self {
+        self.masterauth = Some(password.into());
+        self
+    }
+
+    /// User to log in as with the `masterauth` password, the default user
+    /// if not set.
+    pub fn masteruser(mut self, user: impl Into<String>) -> Self {
+        self.masteruser = Some(user.into());
+        self
+    }
+
+    /// Binds the listener, starts the replication handshake if this is a
+    /// replica and spawns the accept loop onto the runtime.
+    pub async fn spawn(self) -> Result<ServerHandle> {
+        let mut config = Config::default();
+        let from_file = self
+            .config_file
+            .iter()
+            .flat_map(|file| file.entries())
+            .filter(|(name, _)| !is_startup_param(name));
+        let pairs = self
+            .config
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        for (name, value) in from_file.chain(pairs) {
+            config
+                .set(name, value)
+                .with_context(|| format!("setting {} to {:?}", name, value))?;
+        }
+        // Logging is process wide, leave it alone unless asked to change it.
+        if self.config_file.is_some() || !self.config.is_empty() {
+            configure(&config)?;
+        }
+
+        if self.auth_provider.is_some() && self.memcached_port.is_some() {
+            bail!("The memcached listener does not support authentication");
+        }
+        // memcached commands skip the backend and the key locks.
+        if self.backend.is_some() && self.memcached_port.is_some() {
+            bail!("The memcached listener can't be combined with a backend");
+        }
+        let cluster_enabled = self.cluster_config_file.is_some() || self.cluster_slots.is_some();
+        // memcached commands don't check which slots this node serves.
+        if cluster_enabled && self.memcached_port.is_some() {
+            bail!("The memcached listener can't be combined with cluster mode");
+        }
+
+        let listener = TcpListener::bind((self.addr.as_str(), self.port))
+            .await
+            .context("binding listener")?;
+        let addr = listener.local_addr()?;
+        let memcached_listener = match self.memcached_port {
+            Some(port) => Some(
+                TcpListener::bind((self.addr.as_str(), port))
+                    .await
+                    .context("binding memcached listener")?,
+            ),
+            None => None,
+        };
+        let memcached_addr = match &memcached_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+
+        let bus_listener = match (cluster_enabled, self.cluster_port) {
+            (false, _) => None,
+            (true, Some(port)) => Some(port),
+            (true, None) if self.port == 0 => Some(0),
+            (true, None) => Some(default_bus_port(self.port)?),
+        };
+        let bus_listener = match bus_listener {
+            Some(port) => Some(
+                TcpListener::bind((self.addr.as_str(), port))
+                    .await
+                    .context("binding cluster bus listener")?,
+            ),
+            None => None,
+        };
+        let bus_addr = match &bus_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let bus_port = bus_addr.map(|addr| addr.port()).unwrap_or_default();
+        let role = match &self.replicaof {
+            Some((host, port)) => {
+                let master_addr = lookup_host((host.as_str(), *port))
+                    .await
+                    .context("resolving address for --replicaof")?
+                    .next()
+                    .context("no address found for --replicaof")?;
+                Role::Slave(master_addr)
+            }
+            None => Role::Master,
+        };
+
+        let cluster = match (&self.cluster_config_file, self.cluster_slots, role) {
+            (Some(path), _, _) => Some(Cluster::load(path, addr, bus_port)?),
+            (None, Some(_), Role::Slave(master_addr)) => {
+                Some(Cluster::replica(addr, bus_port, master_addr))
+            }
+            (None, Some(slots), Role::Master) => Some(Cluster::new(addr, bus_port, slots)),
+            (None, None, _) => None,
+        }
+        .map(|cluster| cluster.with_node_timeout(self.clock.clone(), self.cluster_node_timeout));
+
+        let raft_listener = match self.raft_port {
+            Some(port) => {
+                if cluster.is_some()
+                    || self.replicaof.is_some()
+                    || self.memcached_port.is_some()
+                    || self.backend.is_some()
+                {
+                    bail!("Raft mode can't be combined with cluster mode, replicaof, memcached or a backend");
                 }
+                Some(
+                    TcpListener::bind((self.addr.as_str(), port))
+                        .await
+                        .context("binding raft listener")?,
+                )
             }
+            None => None,
+        };
+        let mut raft_peers = Vec::new();
+        for (host, port) in &self.raft_peers {
+            let peer = lookup_host((host.as_str(), *port))
+                .await
+                .context("resolving address for --raft-peers")?
+                .next()
+                .context("no address found for --raft-peers")?;
+            raft_peers.push(peer);
+        }
+
+        let mut server = Server::with_options(
+            addr,
+            role,
+            self.clock,
+            self.slowlog_slower_than,
+            config,
+            self.config_file,
+            cluster,
+        );
+        server.backend = self.backend.clone();
+        server.auth = self
+            .auth_provider
+            .map(|provider| Arc::new(Authenticator::new(provider, server.clock.clone())));
+        server.masterauth = self.masterauth.map(|password| MasterAuth {
+            user: self.masteruser,
+            password,
+        });
+        if let Some((path, idle)) = &self.cold_storage {
+            let store = ColdStore::open(path, *idle)?;
+            server.redis_db.lock().unwrap().set_cold_store(store);
+        }
+        if let Some(listener) = &raft_listener {
+            server.raft = Some(Raft::new(
+                listener.local_addr()?,
+                addr,
+                raft_peers,
+                self.raft_dir.as_deref(),
+                server.db(),
+                server.info_db.clone(),
+                server.clock.clone(),
+                self.raft_election_timeout,
+                self.raft_snapshot_entries,
+            )?);
+        }
+        if let Role::Slave(master_addr) = role {
+            server.follow(master_addr);
+        }
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete) = mpsc::channel(1);
+        tokio::spawn(heartbeat(
+            server.replicas.clone(),
+            server.info_db.clone(),
+            server.clock.clone(),
+            notify_shutdown.subscribe(),
+        ));
+        if self.cold_storage.is_some() {
+            tokio::spawn(tier::spill_loop(
+                server.db(),
+                server.clock.clone(),
+                notify_shutdown.subscribe(),
+            ));
+        }
+        if let (Some(listener), Some(cluster)) = (bus_listener, server.cluster()) {
+            tokio::spawn(gossip::serve(
+                listener,
+                cluster.clone(),
+                notify_shutdown.clone(),
+            ));
+            tokio::spawn(gossip::ping_loop(
+                server.clone(),
+                notify_shutdown.subscribe(),
+            ));
+        }
+        if let (Some(listener), Some(raft)) = (raft_listener, server.raft()) {
+            tokio::spawn(raft::serve(listener, raft.clone(), notify_shutdown.clone()));
+            tokio::spawn(raft::run(raft.clone(), notify_shutdown.subscribe()));
         }
+        let serve = server.clone().serve(
+            listener,
+            notify_shutdown.clone(),
+            shutdown_complete_tx.clone(),
+        );
+        let task = match memcached_listener {
+            Some(listener) => {
+                let memcached = memcache::serve(
+                    listener,
+                    server.db(),
+                    server.config.clone(),
+                    server.exclusive.clone(),
+                    notify_shutdown.clone(),
+                    shutdown_complete_tx,
+                );
+                tokio::spawn(async move { tokio::try_join!(serve, memcached).map(|_| ()) })
+            }
+            None => tokio::spawn(serve),
+        };
+
+        Ok(ServerHandle {
+            addr,
+            memcached_addr,
+            bus_addr,
+            server,
+            task,
+            notify_shutdown,
+            shutdown_complete,
+        })
     }
 }
 
-// pub type Db = Arc<Mutex<Database>>;
+#[derive(Debug)]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    memcached_addr: Option<SocketAddr>,
+    bus_addr: Option<SocketAddr>,
+    server: Server,
+    task: JoinHandle<Result<()>>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete: mpsc::Receiver<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn memcached_addr(&self) -> Option<SocketAddr> {
+        self.memcached_addr
+    }
+
+    /// Address of the cluster bus, `None` unless in cluster mode.
+    pub fn cluster_bus_addr(&self) -> Option<SocketAddr> {
+        self.bus_addr
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn db(&self) -> Db {
+        self.server.db()
+    }
+
+    /// Stops accepting new connections.
+    pub fn abort(&self) {
+        self.task.abort();
+    }
+
+    pub async fn wait(self) -> Result<()> {
+        join_result(self.task.await)
+    }
+
+    /// Stops accepting connections and waits up to `drain_timeout` for the
+    /// open ones to finish the command they are currently running.
+    pub async fn shutdown(mut self, drain_timeout: Duration) -> Result<()> {
+        let _ = self.notify_shutdown.send(());
+        let rv = join_result((&mut self.task).await);
+
+        match tokio::time::timeout(drain_timeout, self.shutdown_complete.recv()).await {
+            Ok(_) => rv,
+            Err(_) => bail!(
+                "connections still open after {:?}, shutting down anyway",
+                drain_timeout
+            ),
+        }
+    }
+
+    /// Runs until `signal` resolves and then shuts down gracefully, returns
+    /// early if the accept loop stops on its own.
+    pub async fn run_until<F: Future>(mut self, signal: F, drain_timeout: Duration) -> Result<()> {
+        tokio::select! {
+            rv = &mut self.task => return join_result(rv),
+            _ = signal => {}
+        }
+        self.shutdown(drain_timeout).await
+    }
+}
+
+fn join_result(rv: Result<Result<()>, tokio::task::JoinError>) -> Result<()> {
+    match rv {
+        Ok(rv) => rv,
+        Err(e) if e.is_cancelled() => Ok(()),
+        Err(e) => bail!("server task failed: {}", e),
+    }
+}
+
+/// Whether `key` is stored in `db` and not expired.
+fn key_exists(db: &Db) -> impl Fn(&str) -> bool + '_ {
+    |key| db.lock().unwrap().contains(key)
+}
+
+/// The error to reply with instead of running `frame`, if it must not run.
+/// `asking` is set when the previous command was `ASKING`.
+fn refuse(
+    frame: &Frame,
+    db: &Db,
+    config: &Mutex<Config>,
+    cluster: Option<&Cluster>,
+    asking: bool,
+) -> Option<String> {
+    let args = frame.args().unwrap_or_default();
+    let keys = frame.command().keys(&args);
+    if let Some(e) =
+        cluster.and_then(|cluster| cluster.route(&frame.command(), &keys, asking, key_exists(db)))
+    {
+        return Some(e);
+    }
+    let used = db.lock().unwrap().used_memory();
+    (frame.command().may_grow() && config.lock().unwrap().over_maxmemory(used))
+        .then(|| OOM_ERROR.to_string())
+}
+
+/// Refuses writes other than `APPEND` to keys frozen with `OBJECT FREEZE`.
+/// Checked when the command runs rather than when it is queued, a key may
+/// be frozen or thawed in between.
+fn frozen(frame: &Frame, db: &Db) -> Option<String> {
+    let command = frame.command();
+    if !command.is_write() || matches!(command, Command::Append | Command::Object) {
+        return None;
+    }
+    let args = frame.args().unwrap_or_default();
+    let db = db.lock().unwrap();
+    let key = command
+        .keys(&args)
+        .into_iter()
+        .find(|key| db.is_append_only(key))?;
+    Some(format!(
+        "ERR key '{}' is append-only, only APPEND can change it",
+        key
+    ))
+}
+
+/// Loads the keys `frame` reads and that are not in memory from `backend`.
+async fn read_through(backend: &SharedBackend, frame: &Frame, db: &Db) -> Result<()> {
+    let command = frame.command();
+    let args = frame.args().unwrap_or_default();
+    // Only a conditional SET depends on what it overwrites.
+    let conditional = command == Command::Set
+        && parse_set_options(&args[2..]).is_ok_and(|options| options.if_version.is_some());
+    if !command.touches_existing() && !conditional {
+        return Ok(());
+    }
+    for key in command.keys(&args) {
+        if db.lock().unwrap().contains(key) {
+            continue;
+        }
+        let Some(stored) = backend.load(key).await? else {
+            continue;
+        };
+        let expired = {
+            let mut db = db.lock().unwrap();
+            let unix_now = db.clock().unix_time().as_millis() as u64;
+            // `None` if the stored value has expired, it is then dropped.
+            let expiry = match stored.expires_at {
+                Some(at) if at <= unix_now => None,
+                Some(at) => Some(Some(db.now() + Duration::from_millis(at - unix_now))),
+                None => Some(None),
+            };
+            // Another client may have written it in the meantime.
+            if let (Some(expiry), false) = (expiry, db.contains(key)) {
+                db.insert(key.to_string(), DbEntry::new(stored.value, expiry));
+            }
+            expiry.is_none()
+        };
+        if expired {
+            backend.remove(key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies to `backend` what `frame` changed, given its `reply`. Written
+/// keys are stored as they are in memory, with their expiry.
+async fn write_through(
+    backend: &SharedBackend,
+    frame: &Frame,
+    reply: &[u8],
+    db: &Db,
+) -> Result<()> {
+    let args = frame.args().unwrap_or_default();
+    let command = frame.command();
+    let ok = Type::SimpleString("OK".to_string()).serialize();
+    let deleted = Type::Integer("1".to_string()).serialize();
+    let (written, removed): (Vec<&str>, &[String]) = match command {
+        Command::Set | Command::MSet if reply == ok => (command.keys(&args), &[]),
+        Command::Append if reply.starts_with(b":") => (command.keys(&args), &[]),
+        Command::Del => (Vec::new(), &args),
+        Command::DelIfEq if reply == deleted => (Vec::new(), &args[..1]),
+        _ => return Ok(()),
+    };
+    for key in written {
+        let stored = {
+            let db = db.lock().unwrap();
+            let (now, unix_now) = (db.now(), db.clock().unix_time());
+            db.peek(key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| Stored {
+                    value: entry.value(),
+                    expires_at: entry
+                        .ttl(now)
+                        .map(|ttl| (unix_now + ttl).as_millis() as u64),
+                })
+        };
+        match stored {
+            Some(stored) => backend.store(key, &stored).await?,
+            None => backend.remove(key).await?,
+        }
+    }
+    for key in removed {
+        backend.remove(key).await?;
+    }
+    Ok(())
+}
+
+/// What to send replicas for `frame`, given its replies. A `DELIFEQ` that
+/// deleted its key goes out as a `DEL` and a `SET IFVERSION` that applied
+/// without its condition, so a replica whose value or version differs still
+/// ends up like the master. Conditional writes that did not apply are not
+/// sent at all.
+fn propagated(frame: Frame, responses: &[Vec<u8>]) -> Option<Frame> {
+    let mut args = frame.args().unwrap_or_default();
+    let rewritten: Vec<String> = match frame.command() {
+        Command::DelIfEq => {
+            let deleted = Type::Integer("1".to_string()).serialize();
+            if responses != [deleted] {
+                return None;
+            }
+            args.truncate(1);
+            ["DEL".to_string()].into_iter().chain(args).collect()
+        }
+        Command::Set => {
+            let options = parse_set_options(&args[2..]).ok()?;
+            if responses != [Type::SimpleString("OK".to_string()).serialize()] {
+                return None;
+            }
+            if options.if_version.is_none() {
+                return Some(frame);
+            }
+            args.truncate(2);
+            if let Some(px) = options.px {
+                args.extend(["PX".to_string(), px.to_string()]);
+            }
+            ["SET".to_string()].into_iter().chain(args).collect()
+        }
+        _ => return Some(frame),
+    };
+    frame_of(rewritten).ok()
+}
+
+/// The frame of a command made of `args`, as a client would send it.
+fn frame_of(args: impl IntoIterator<Item = String>) -> Result<Frame> {
+    let bytes = Type::Array(args.into_iter().map(Type::BulkString).collect()).serialize();
+    Frame::new(&bytes, bytes.len())
+}
+
+/// Commands queued after `MULTI`, run together by `EXEC`. Commands refused
+/// while queueing are kept as their error, for `EXEC DRYRUN` to report, and
+/// make `EXEC` fail.
+#[derive(Debug, Default)]
+struct Transaction {
+    queued: Vec<std::result::Result<Frame, String>>,
+}
+
+impl Transaction {
+    fn aborted(&self) -> bool {
+        self.queued.iter().any(|command| command.is_err())
+    }
+}
+
+impl Server {
+    /// Runs `frame` for `client` and returns its replies, replicating and
+    /// auditing it if it is a write.
+    async fn execute(&self, frame: Frame, client: &str, asking: bool) -> Vec<Vec<u8>> {
+        let _shared = self.exclusive.read().await;
+        self.run(frame, client, asking).await
+    }
+
+    /// [`Server::execute`] for callers that already hold `exclusive`.
+    async fn run(&self, frame: Frame, client: &str, asking: bool) -> Vec<Vec<u8>> {
+        let Server {
+            redis_db: db,
+            info_db,
+            slowlog,
+            config,
+            cluster,
+            ..
+        } = self;
+        let frame_c = frame.clone();
+        let mut refused =
+            refuse(&frame, db, config, cluster.as_ref(), asking).or_else(|| frozen(&frame, db));
+        // Keep other commands on these keys from reaching the backend out of
+        // order until this one has been written through, and writes from
+        // slipping in between a MIGRATE copying keys and deleting them.
+        let command = frame.command();
+        let _guard = if self.backend.is_some() || command.is_write() || command == Command::Migrate
+        {
+            let args = frame.args().unwrap_or_default();
+            Some(self.key_locks.lock(&command.keys(&args)).await)
+        } else {
+            None
+        };
+        if let (None, Some(backend)) = (&refused, &self.backend) {
+            if let Err(e) = read_through(backend, &frame, db).await {
+                refused = Some(format!("ERR {:#}", e));
+            }
+        }
+        let mut moved = Vec::new();
+        let responses = match &refused {
+            Some(e) => Ok(vec![Type::SimpleError(e.clone()).serialize()]),
+            None if frame.command() == Command::Migrate => migrate(
+                frame,
+                db,
+                client,
+                cluster.is_some(),
+                self.masterauth.as_ref(),
+            )
+            .await
+            .map(|(rv, keys)| {
+                moved = keys;
+                vec![rv]
+            }),
+            None if frame.command() == Command::ReplicaOf => {
+                self.handle_replicaof(frame).await.map(|rv| vec![rv])
+            }
+            // Raft errors such as NOTLEADER carry their own prefix.
+            None if self.raft.is_some()
+                && (frame.command().is_write() || frame.command().is_read()) =>
+            {
+                let raft = self.raft.as_ref().unwrap();
+                match raft.execute(frame).await {
+                    Ok(responses) => Ok(responses),
+                    Err(e) => Ok(vec![Type::SimpleError(format!("{:#}", e)).serialize()]),
+                }
+            }
+            None => slowlog.time(&frame_c, client, || match frame.command() {
+                Command::SlowLog => slowlog.handle(frame).map(|rv| vec![rv]),
+                Command::Config => handle_config(frame, config, client).map(|rv| vec![rv]),
+                Command::Cluster | Command::Asking => match cluster {
+                    Some(cluster) => cluster.handle(frame, db).map(|rv| vec![rv]),
+                    None => bail!("This instance has cluster support disabled"),
+                },
+                _ => create_response(frame, db, info_db),
+            }),
+        };
+        let mut responses = match responses {
+            Ok(responses) => responses,
+            Err(e) => vec![Type::SimpleError(format!("ERR {:#}", e)).serialize()],
+        };
+        if refused.is_none() {
+            responses = self.written(frame_c, responses, client).await;
+        }
+
+        // Keys MIGRATE moved are deleted like with a DEL, so the delete is
+        // replicated and written through, while their locks still hold.
+        if !moved.is_empty() {
+            let del = frame_of(["DEL".to_string()].into_iter().chain(moved));
+            let replies = match (&self.raft, del) {
+                (_, Err(e)) => Err(e),
+                (Some(raft), Ok(del)) => raft.execute(del.clone()).await.map(|rv| (del, rv)),
+                (None, Ok(del)) => create_response(del.clone(), db, info_db).map(|rv| (del, rv)),
+            };
+            let replies = match replies {
+                Ok((del, replies)) => self.written(del, replies, client).await,
+                Err(e) => vec![Type::SimpleError(format!("ERR {:#}", e)).serialize()],
+            };
+            if replies.iter().any(|reply| reply.starts_with(b"-")) {
+                responses = replies;
+            }
+        }
+        responses
+    }
+
+    /// Writes what `frame` changed through to the backend, then audits and
+    /// replicates it if it is a write. Returns its replies, or the error
+    /// from the backend.
+    async fn written(
+        &self,
+        frame: Frame,
+        mut responses: Vec<Vec<u8>>,
+        client: &str,
+    ) -> Vec<Vec<u8>> {
+        if let (Some(backend), [reply]) = (&self.backend, &responses[..]) {
+            if let Err(e) = write_through(backend, &frame, reply, &self.redis_db).await {
+                let e = format!("ERR {:#}, the change is only cached", e);
+                responses = vec![Type::SimpleError(e).serialize()];
+            }
+        }
+
+        let command = frame.command();
+        if command.is_write() {
+            server_log!(Level::Debug, "Command {}", command.name());
+            let args = frame.args().unwrap_or_default();
+            for key in command.keys(&args) {
+                audit(client, command.name(), key);
+            }
+            // Raft nodes get their writes from the log instead.
+            if let (None, Some(frame)) = (&self.raft, propagated(frame, &responses)) {
+                replicate(frame, &self.replicas).await;
+            }
+        }
+        responses
+    }
+
+    /// Runs the commands of `transaction` one after the other and replies
+    /// with an array of their replies. No command from another client runs
+    /// until they are done. In cluster mode all their keys must be in one
+    /// slot.
+    async fn exec(&self, transaction: Transaction, client: &str) -> Vec<u8> {
+        if let Some(e) = self.refuse_transaction(&transaction) {
+            return Type::SimpleError(e).serialize();
+        }
+        let _exclusive = self.exclusive.write().await;
+        let mut rv = format!("*{}\r\n", transaction.queued.len()).into_bytes();
+        for frame in transaction.queued.into_iter().flatten() {
+            for response in self.run(frame, client, false).await {
+                rv.extend(response);
+            }
+        }
+        rv
+    }
+
+    /// `EXEC DRYRUN`: checks the commands of `transaction` like `EXEC` would
+    /// and replies with the error each one would get or what it would touch,
+    /// without running any of them. Errors include those of commands refused
+    /// while queueing, such as a wrong number of arguments or a missing
+    /// permission, and arguments of the wrong type. Checks are made against
+    /// the current data, not what earlier commands of the transaction would
+    /// leave.
+    fn dry_run(&self, transaction: Transaction) -> Vec<u8> {
+        if let Some(e) = self.cross_slot(&transaction) {
+            return Type::SimpleError(e).serialize();
+        }
+        let db = &self.redis_db;
+        let replies = transaction
+            .queued
+            .iter()
+            .map(|command| {
+                let frame = match command {
+                    Ok(frame) => frame,
+                    Err(e) => return Type::SimpleError(e.clone()),
+                };
+                match refuse(frame, db, &self.config, self.cluster(), false)
+                    .or_else(|| frozen(frame, db))
+                    .or_else(|| check_args(frame, &self.config))
+                {
+                    Some(e) => Type::SimpleError(e),
+                    None => Type::SimpleString(describe(frame)),
+                }
+            })
+            .collect();
+        Type::Array(replies).serialize()
+    }
+
+    /// Why `transaction` can't run as a whole: a command was refused while
+    /// queueing, or in cluster mode its keys span more than one slot.
+    fn refuse_transaction(&self, transaction: &Transaction) -> Option<String> {
+        if transaction.aborted() {
+            let e = "EXECABORT Transaction discarded because of previous errors.";
+            return Some(e.to_string());
+        }
+        self.cross_slot(transaction)
+    }
+
+    /// In cluster mode, the error for a transaction whose keys span more than
+    /// one slot.
+    fn cross_slot(&self, transaction: &Transaction) -> Option<String> {
+        let cluster = self.cluster.as_ref()?;
+        let frames: Vec<&Frame> = transaction.queued.iter().flatten().collect();
+        let args: Vec<Vec<String>> = frames
+            .iter()
+            .map(|frame| frame.args().unwrap_or_default())
+            .collect();
+        let keys: Vec<&str> = frames
+            .iter()
+            .zip(&args)
+            .flat_map(|(frame, args)| frame.command().keys(args))
+            .collect();
+        let exists = key_exists(&self.redis_db);
+        cluster.route(&Command::Exec, &keys, false, exists)
+    }
+}
+
+/// The error `frame` would get for arguments of the wrong type, such as a
+/// `PX` that is not a number or a `CONFIG SET` value the parameter doesn't
+/// take. Values are all strings, so no key holds the wrong type for a
+/// command.
+fn check_args(frame: &Frame, config: &Mutex<Config>) -> Option<String> {
+    let args = frame.args().unwrap_or_default();
+    let checked = match frame.command() {
+        Command::Set => parse_set_options(&args[2..]).map(|_| ()),
+        Command::Object => parse_object_subcommand(&args[0]).map(|_| ()),
+        Command::Config if args[0].eq_ignore_ascii_case("set") => {
+            let config = config.lock().unwrap();
+            config_set(&config, &args[1..]).map(|_| ())
+        }
+        _ => Ok(()),
+    };
+    checked.err().map(|e| format!("ERR {:#}", e))
+}
+
+/// What running `frame` would do, reported by `EXEC DRYRUN`.
+fn describe(frame: &Frame) -> String {
+    let command = frame.command();
+    let args = frame.args().unwrap_or_default();
+    let keys = command.keys(&args).join(" ");
+    if command.is_write() {
+        format!("{} would write {}", command.name(), keys)
+    } else if keys.is_empty() {
+        format!("{} would run", command.name())
+    } else {
+        format!("{} would read {}", command.name(), keys)
+    }
+}
+
+/// `AUTH [user] password` for a client connecting from `addr`, replies
+/// what the user may run if the provider accepted the password.
+async fn authenticate(server: &Server, frame: Frame, addr: IpAddr) -> Result<Option<Acl>> {
+    let Some(auth) = &server.auth else {
+        bail!("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?");
+    };
+    let args = frame.args().unwrap_or_default();
+    let (user, password) = match &args[..] {
+        [password] => (DEFAULT_USER, password),
+        [user, password] => (user.as_str(), password),
+        _ => bail!("wrong number of arguments for AUTH"),
+    };
+    let acl = auth.authenticate(addr, user, password).await?;
+    if acl.is_none() {
+        server_log!(
+            Level::Warning,
+            "Failed AUTH for user {} from {}",
+            user,
+            addr
+        );
+    }
+    Ok(acl)
+}
+
+/// `CLIENT TRACEID <id>` tags the connection's next commands with `id`, an
+/// empty one clears it, and `CLIENT TRACEID` replies with the current one.
+fn handle_client(frame: Frame, trace_id: &mut Option<String>) -> Result<Vec<u8>> {
+    let args = frame.args().unwrap_or_default();
+    let subcommand = args.first().context("getting client subcommand")?;
+    match (
+        subcommand.to_lowercase().as_str(),
+        args.get(1..).unwrap_or_default(),
+    ) {
+        ("traceid", []) => Ok(match trace_id {
+            Some(trace_id) => Type::BulkString(trace_id.clone()),
+            None => Type::NullBulkString,
+        }
+        .serialize()),
+        ("traceid", [id]) => {
+            *trace_id = Some(id.clone()).filter(|id| !id.is_empty());
+            Ok(Type::SimpleString("OK".to_string()).serialize())
+        }
+        _ => bail!("unknown subcommand or wrong number of arguments for CLIENT"),
+    }
+}
 
 async fn stream_handler(
     mut stream: TcpStream,
-    db: Arc<Mutex<Database>>,
-    info_db: Arc<Mutex<Database>>,
-    server_info: Arc<Mutex<ServerInfo>>,
+    server: Server,
+    mut shutdown: broadcast::Receiver<()>,
 ) -> Result<()> {
+    let client = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let client_ip = stream
+        .peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let mut authenticated = server.auth.is_none();
+    // What the client may run, set by AUTH.
+    let mut acl = Acl::default();
     let mut buffer: [u8; 1024] = [0; 1024];
+    let mut asking = false;
+    let mut transaction: Option<Transaction> = None;
+    // Set by a replica during the handshake, it is where it serves clients.
+    let mut listening_port = None;
+    // Set with CLIENT TRACEID, tags the logs and slowlog entries of the
+    // commands that follow.
+    let mut trace_id: Option<String> = None;
     loop {
-        if let Ok(len) = stream.read(&mut buffer).await {
-            if len == 0 {
-                bail!("No bytes read from stream!");
+        // Only wait for shutdown between commands, a command that has already
+        // been read always gets its reply.
+        let idle_timeout = server.config.lock().unwrap().idle_timeout();
+        let len = tokio::select! {
+            len = stream.read(&mut buffer) => len.context("reading from stream")?,
+            _ = shutdown.recv() => return Ok(()),
+            _ = server.clock.sleep(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                server_log!(Level::Verbose, "Closing idle client {}", client);
+                return Ok(());
             }
+        };
+        if len == 0 {
+            bail!("No bytes read from stream!");
+        }
 
-            let frame = Frame::new(&buffer, len)
-                .context("creating frame from buffer")
-                .unwrap();
-
-            let frame_c = frame.clone();
-
-            let responses = create_response(frame, &db, &info_db)
-                .context("getting response from frame")
-                .unwrap();
+        let frame = match Frame::new(&buffer, len) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let e = format!("ERR {:#}", e);
+                stream
+                    .write_all(&Type::SimpleError(e.clone()).serialize())
+                    .await?;
+                if let Some(transaction) = &mut transaction {
+                    transaction.queued.push(Err(e));
+                }
+                continue;
+            }
+        };
 
-            for response in responses.into_iter() {
-                let response_slice = &response[..];
-                stream.write_all(response_slice).await.unwrap();
-                // stream.flush().await.unwrap();
-                let ten_millis = time::Duration::from_millis(10);
-                thread::sleep(ten_millis);
+        if frame.command() == Command::ReplConf {
+            if let Some([key, port]) = frame.args().as_deref() {
+                if key.eq_ignore_ascii_case("listening-port") {
+                    listening_port = port.parse::<u16>().ok();
+                }
             }
-            match frame_c.command() {
-                Command::Set => {
-                    println!("Command SET");
-                    let _ = replicate(frame_c, &server_info).await;
+        }
+
+        // ASKING only applies to the command right after it.
+        let was_asking = std::mem::replace(&mut asking, frame.command() == Command::Asking);
+        let error = |e: &str| vec![Type::SimpleError(e.to_string()).serialize()];
+        let ok = || vec![Type::SimpleString("OK".to_string()).serialize()];
+        let denied = acl.check(&frame.command());
+        let psync = authenticated
+            && denied.is_none()
+            && frame.command() == Command::PSync
+            && transaction.is_none();
+        // Nothing runs from the snapshot until the replica is registered.
+        let _exclusive = match psync {
+            true => Some(server.exclusive.write().await),
+            false => None,
+        };
+        let responses = match (frame.command(), &mut transaction) {
+            (Command::Auth, _) => match authenticate(&server, frame, client_ip).await {
+                Ok(Some(permissions)) => {
+                    authenticated = true;
+                    acl = permissions;
+                    ok()
                 }
-                Command::PSync => {
-                    println!("Command PSYNC");
-                    let mut server_info = server_info.lock().unwrap();
-                    server_info.replicas.push(stream);
-                    return Ok(());
+                Ok(None) => error("WRONGPASS invalid username-password pair or user is disabled."),
+                Err(e) => error(&format!("ERR {:#}", e)),
+            },
+            _ if !authenticated => error("NOAUTH Authentication required."),
+            (_, transaction) if denied.is_some() => {
+                let e = denied.unwrap_or_default();
+                if let Some(transaction) = transaction {
+                    transaction.queued.push(Err(e.clone()));
                 }
-                _ => {
-                    println!("Command PSYNC");
+                error(&e)
+            }
+            (Command::Client, _) => match handle_client(frame, &mut trace_id) {
+                Ok(rv) => vec![rv],
+                Err(e) => error(&format!("ERR {:#}", e)),
+            },
+            (Command::Multi, Some(_)) => error("ERR MULTI calls can not be nested"),
+            (Command::Multi, None) => {
+                transaction = Some(Transaction::default());
+                ok()
+            }
+            (Command::Discard, Some(_)) => {
+                transaction = None;
+                ok()
+            }
+            (Command::Discard, None) => error("ERR DISCARD without MULTI"),
+            // Either way the transaction ends, like after EXEC.
+            (Command::Exec, Some(_)) if frame.args().is_some() => {
+                vec![server.dry_run(transaction.take().unwrap_or_default())]
+            }
+            (Command::Exec, Some(_)) => {
+                let queued = transaction.take().unwrap_or_default();
+                vec![traced(trace_id.clone(), server.exec(queued, &client)).await]
+            }
+            (Command::Exec, None) => error("ERR EXEC without MULTI"),
+            (Command::PSync, Some(transaction)) => {
+                let e = "ERR PSYNC is not allowed inside a transaction";
+                transaction.queued.push(Err(e.to_string()));
+                error(e)
+            }
+            (_, Some(transaction)) => {
+                let db = &server.redis_db;
+                match refuse(&frame, db, &server.config, server.cluster(), was_asking) {
+                    Some(e) => {
+                        transaction.queued.push(Err(e.clone()));
+                        error(&e)
+                    }
+                    None => {
+                        transaction.queued.push(Ok(frame));
+                        vec![Type::SimpleString("QUEUED".to_string()).serialize()]
+                    }
                 }
             }
+            (_, None) if psync => {
+                let run = server.run(frame, &client, was_asking);
+                traced(trace_id.clone(), run).await
+            }
+            (_, None) => {
+                let execute = server.execute(frame, &client, was_asking);
+                traced(trace_id.clone(), execute).await
+            }
+        };
+
+        for response in responses.into_iter() {
+            let response_slice = &response[..];
+            stream.write_all(response_slice).await?;
+            // stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        if psync {
+            server_log!(Level::Debug, "Command PSYNC");
+            let mut addr = stream.peer_addr()?;
+            if let Some(port) = listening_port {
+                addr.set_port(port);
+            }
+            let mut replicas = server.replicas.lock().await;
+            replicas.push(Replica { stream, addr });
+            record_replicas(&server.info_db, &replicas);
+            return Ok(());
         }
     }
 }
@@ -0,0 +1,138 @@
+//! Keeps the most recent commands that took longer than a threshold to run,
+//! as reported by `SLOWLOG GET`.
+use crate::clock::*;
+use crate::frame::*;
+use crate::log::*;
+use crate::resptype::*;
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Default for `slowlog-log-slower-than`.
+pub const DEFAULT_SLOWER_THAN: Duration = Duration::from_millis(10);
+/// Entries kept before the oldest is dropped, `slowlog-max-len` in Redis.
+const MAX_LEN: usize = 128;
+/// Like Redis, only the first few arguments and bytes of each are kept.
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+#[derive(Debug)]
+struct SlowLogEntry {
+    id: u64,
+    timestamp: Duration,
+    duration: Duration,
+    args: Vec<String>,
+    client: String,
+    trace_id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlowLog {
+    entries: Arc<Mutex<Entries>>,
+    slower_than: Duration,
+    clock: SharedClock,
+}
+
+impl SlowLog {
+    pub fn new(clock: SharedClock, slower_than: Duration) -> Self {
+        Self {
+            entries: Arc::default(),
+            slower_than,
+            clock,
+        }
+    }
+
+    /// Runs `f`, logging `frame` if it took `slower_than` or longer.
+    pub fn time<T>(&self, frame: &Frame, client: &str, f: impl FnOnce() -> T) -> T {
+        let start = self.clock.now();
+        let rv = f();
+        let duration = self.clock.now().saturating_duration_since(start);
+        if duration >= self.slower_than {
+            self.record(frame, client, duration);
+        }
+        rv
+    }
+
+    fn record(&self, frame: &Frame, client: &str, duration: Duration) {
+        let mut args = vec![frame.command().name().to_string()];
+        args.extend(frame.args().unwrap_or_default());
+        if args.len() > MAX_ARGS {
+            let more = args.len() - MAX_ARGS + 1;
+            args.truncate(MAX_ARGS - 1);
+            args.push(format!("... ({} more arguments)", more));
+        }
+        for arg in args.iter_mut() {
+            if arg.len() > MAX_ARG_LEN {
+                let mut end = MAX_ARG_LEN;
+                while !arg.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let more = arg.len() - end;
+                arg.truncate(end);
+                arg.push_str(&format!("... ({} more bytes)", more));
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.entries.push_front(SlowLogEntry {
+            id,
+            timestamp: self.clock.unix_time(),
+            duration,
+            args,
+            client: client.to_string(),
+            trace_id: trace_id(),
+        });
+        entries.entries.truncate(MAX_LEN);
+    }
+
+    /// Handles `SLOWLOG GET [count]`, `SLOWLOG LEN` and `SLOWLOG RESET`.
+    /// Entries end with the client name, always empty, and the trace id set
+    /// with `CLIENT TRACEID`.
+    pub fn handle(&self, frame: Frame) -> Result<Vec<u8>> {
+        let args = frame.args().unwrap_or_default();
+        let subcommand = args.first().context("getting slowlog subcommand")?;
+        let mut entries = self.entries.lock().unwrap();
+        match (subcommand.to_lowercase().as_str(), args.get(1)) {
+            ("get", count) => {
+                let count = match count {
+                    Some(count) => count.parse::<i64>().context("parsing slowlog count")?,
+                    None => 10,
+                };
+                // A negative count returns the whole log.
+                let count = usize::try_from(count).unwrap_or(usize::MAX);
+                let rv = entries
+                    .entries
+                    .iter()
+                    .take(count)
+                    .map(|entry| {
+                        Type::Array(vec![
+                            Type::Integer(entry.id.to_string()),
+                            Type::Integer(entry.timestamp.as_secs().to_string()),
+                            Type::Integer(entry.duration.as_micros().to_string()),
+                            Type::Array(entry.args.iter().cloned().map(Type::BulkString).collect()),
+                            Type::BulkString(entry.client.clone()),
+                            Type::BulkString(String::new()),
+                            Type::BulkString(entry.trace_id.clone().unwrap_or_default()),
+                        ])
+                    })
+                    .collect();
+                Ok(Type::Array(rv).serialize())
+            }
+            ("len", None) => Ok(Type::Integer(entries.entries.len().to_string()).serialize()),
+            ("reset", None) => {
+                entries.entries.clear();
+                Ok(Type::SimpleString("OK".to_string()).serialize())
+            }
+            _ => bail!("unknown subcommand or wrong number of arguments for SLOWLOG"),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Cold storage tier: values nobody has read or written for a while are
+//! moved out of memory into an append-only file and read back the next time
+//! their key is accessed. Keys, expiries and flags stay in memory so `KEYS`
+//! and expiry keep working without touching the disk.
+//!
+//! The file is truncated on startup, it only extends memory and does not
+//! persist anything across restarts.
+use crate::clock::*;
+use crate::log::*;
+use crate::server::*;
+use crate::server_log;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Freed space the file may hold before it is rewritten, as long as it
+/// also outweighs the values still stored in it.
+const COMPACT_AFTER: u64 = 1 << 20;
+
+/// Where a value is stored in the cold file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    offset: u64,
+    len: u64,
+}
+
+impl Location {
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// Values on their way to disk, written outside of the database lock.
+#[derive(Debug)]
+pub(crate) struct Spill {
+    file: Arc<File>,
+    pub(crate) values: Vec<ColdValue>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ColdValue {
+    pub(crate) key: String,
+    /// Version of the entry when the value was copied, the value is only
+    /// moved out of memory if the entry is still at it.
+    pub(crate) version: u64,
+    pub(crate) value: String,
+    pub(crate) location: Location,
+}
+
+/// A rewrite of the cold file keeping only the values still in use,
+/// copied outside of the database lock.
+#[derive(Debug)]
+pub(crate) struct Compaction {
+    from: Arc<File>,
+    tmp: PathBuf,
+    file: File,
+    /// Keys of the values to copy and where they are in the old file,
+    /// filled with where they went in the new one.
+    pub(crate) moves: Vec<(String, Location, Option<Location>)>,
+    end: u64,
+}
+
+#[derive(Debug)]
+pub struct ColdStore {
+    path: PathBuf,
+    file: Arc<File>,
+    end: u64,
+    /// Bytes of values that were read back or overwritten since.
+    freed: u64,
+    /// Values are moved here after going unused this long.
+    idle: Duration,
+}
+
+impl ColdStore {
+    pub fn open(path: impl AsRef<Path>, idle: Duration) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = Arc::new(create(&path)?);
+        Ok(Self {
+            path,
+            file,
+            end: 0,
+            freed: 0,
+            idle,
+        })
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    /// Sets aside room at the end of the file for the values of keys at a
+    /// version, they are written with [`Spill::write`].
+    pub(crate) fn reserve(&mut self, values: Vec<(String, u64, String)>) -> Spill {
+        let values = values
+            .into_iter()
+            .map(|(key, version, value)| {
+                let location = Location {
+                    offset: self.end,
+                    len: value.len() as u64,
+                };
+                self.end += location.len;
+                ColdValue {
+                    key,
+                    version,
+                    value,
+                    location,
+                }
+            })
+            .collect();
+        Spill {
+            file: self.file.clone(),
+            values,
+        }
+    }
+
+    pub fn read(&self, location: Location) -> Result<String> {
+        let mut value = vec![0; location.len as usize];
+        self.file.read_exact_at(&mut value, location.offset)?;
+        String::from_utf8(value).context("cold value is not UTF-8")
+    }
+
+    /// Marks the space taken by a value as reusable on the next compaction.
+    pub fn free(&mut self, location: Location) {
+        self.freed += location.len;
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.freed > COMPACT_AFTER && self.freed * 2 > self.end
+    }
+
+    /// Starts rewriting the file with the values of keys at `locations`,
+    /// they are copied with [`Compaction::copy`].
+    pub(crate) fn compaction(&self, locations: Vec<(String, Location)>) -> Result<Compaction> {
+        let tmp = self.path.with_extension("compact");
+        Ok(Compaction {
+            from: self.file.clone(),
+            file: create(&tmp)?,
+            tmp,
+            moves: locations
+                .into_iter()
+                .map(|(key, location)| (key, location, None))
+                .collect(),
+            end: 0,
+        })
+    }
+
+    /// Switches to the file written by `compaction`. `wasted` is how much
+    /// of it holds values that were freed while it was written.
+    pub(crate) fn finish_compaction(&mut self, compaction: Compaction, wasted: u64) -> Result<()> {
+        std::fs::rename(&compaction.tmp, &self.path).context("replacing cold storage file")?;
+        self.file = Arc::new(compaction.file);
+        self.end = compaction.end;
+        self.freed = wasted;
+        Ok(())
+    }
+}
+
+impl Spill {
+    /// Writes the values where they were reserved, blocking.
+    fn write(&self) -> Result<()> {
+        for value in &self.values {
+            self.file
+                .write_all_at(value.value.as_bytes(), value.location.offset)
+                .context("writing to cold storage")?;
+        }
+        Ok(())
+    }
+}
+
+impl Compaction {
+    /// Copies the values into the new file, blocking.
+    fn copy(&mut self) -> Result<()> {
+        for (_, from, to) in &mut self.moves {
+            let mut value = vec![0; from.len as usize];
+            self.from.read_exact_at(&mut value, from.offset)?;
+            self.file.write_all_at(&value, self.end)?;
+            *to = Some(Location {
+                offset: self.end,
+                len: from.len,
+            });
+            self.end += from.len;
+        }
+        Ok(())
+    }
+}
+
+fn create(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("opening cold storage file {}", path.display()))
+}
+
+/// Moves idle values to disk every tenth of the idle threshold.
+pub async fn spill_loop(db: Db, clock: SharedClock, mut shutdown: broadcast::Receiver<()>) {
+    let Some(idle) = db.lock().unwrap().cold_idle() else {
+        return;
+    };
+    let period = (idle / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = clock.sleep(period) => {}
+            _ = shutdown.recv() => return,
+        }
+        match spill_idle(&db).await {
+            Ok(0) => {}
+            Ok(n) => server_log!(Level::Debug, "Moved {} idle values to disk", n),
+            Err(e) => server_log!(Level::Warning, "Moving idle values to disk: {:#}", e),
+        }
+        if let Err(e) = compact(&db).await {
+            server_log!(Level::Warning, "Compacting cold storage: {:#}", e);
+        }
+    }
+}
+
+/// Moves the values that went unused for longer than the cold storage
+/// threshold to disk, returns how many were moved. The database stays
+/// usable while they are written.
+async fn spill_idle(db: &Db) -> Result<usize> {
+    let Some(spill) = db.lock().unwrap().plan_spill() else {
+        return Ok(0);
+    };
+    let (spill, written) = tokio::task::spawn_blocking(move || {
+        let written = spill.write();
+        (spill, written)
+    })
+    .await?;
+    db.lock().unwrap().finish_spill(spill.values, written)
+}
+
+/// Rewrites the cold file without the space freed in it, if that is most
+/// of it.
+async fn compact(db: &Db) -> Result<()> {
+    let Some(compaction) = db.lock().unwrap().plan_compaction()? else {
+        return Ok(());
+    };
+    let (compaction, copied) = tokio::task::spawn_blocking(move || {
+        let mut compaction = compaction;
+        let copied = compaction.copy();
+        (compaction, copied)
+    })
+    .await?;
+    copied?;
+    db.lock().unwrap().finish_compaction(compaction)
+}
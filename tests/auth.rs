@@ -0,0 +1,342 @@
+mod common;
+
+use anyhow::bail;
+use common::*;
+use redis_starter_rust::auth::{
+    hash_password, CallbackProvider, EnvProvider, FileProvider, SharedAuthProvider,
+};
+use redis_starter_rust::clock::MockClock;
+use redis_starter_rust::sentinel::Sentinel;
+use redis_starter_rust::{Server, ServerHandle, Type};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+async fn spawn_with_auth(provider: SharedAuthProvider, clock: Arc<MockClock>) -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .clock(clock)
+        .auth_provider(provider)
+        .spawn()
+        .await
+        .expect("spawning server")
+}
+
+fn error(msg: &str) -> Type {
+    Type::SimpleError(msg.to_string())
+}
+
+fn wrongpass() -> Type {
+    error("WRONGPASS invalid username-password pair or user is disabled.")
+}
+
+#[tokio::test]
+async fn clients_must_auth_first() {
+    let path = std::env::temp_dir().join(format!("kv-store-users-{}", std::process::id()));
+    std::fs::write(
+        &path,
+        format!(
+            "# user sha256\ndefault {}\nalice {}\n",
+            hash_password("secret"),
+            hash_password("wonderland")
+        ),
+    )
+    .unwrap();
+    let provider = Arc::new(FileProvider::open(&path).unwrap());
+    let server = spawn_with_auth(provider, Arc::new(MockClock::new())).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    client
+        .assert_reply(&["GET", "foo"], error("NOAUTH Authentication required."))
+        .await;
+    client
+        .assert_reply(&["MULTI"], error("NOAUTH Authentication required."))
+        .await;
+    client.assert_reply(&["AUTH", "wrong"], wrongpass()).await;
+    client
+        .assert_reply(&["AUTH", "alice", "secret"], wrongpass())
+        .await;
+    client.assert_reply(&["AUTH", "secret"], simple("OK")).await;
+    client
+        .assert_reply(&["SET", "foo", "bar"], simple("OK"))
+        .await;
+
+    let mut other = TestClient::connect(server.local_addr()).await;
+    other
+        .assert_reply(&["GET", "foo"], error("NOAUTH Authentication required."))
+        .await;
+    other
+        .assert_reply(&["AUTH", "alice", "wonderland"], simple("OK"))
+        .await;
+    other.assert_reply(&["GET", "foo"], bulk("bar")).await;
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn reads_passwords_from_the_environment() {
+    let prefix = format!("KV_STORE_TEST_{}_", std::process::id());
+    std::env::set_var(format!("{}BOB", prefix), "builder");
+    let provider = Arc::new(EnvProvider::new(prefix));
+    let server = spawn_with_auth(provider, Arc::new(MockClock::new())).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    client.assert_reply(&["AUTH", "builder"], wrongpass()).await;
+    client
+        .assert_reply(&["AUTH", "bob", "builder"], simple("OK"))
+        .await;
+}
+
+#[tokio::test]
+async fn caches_checks_and_limits_failures() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let provider = Arc::new(CallbackProvider::new(move |user, password| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if password == "down" {
+                bail!("directory unavailable");
+            }
+            Ok(user == "default" && password == "letmein")
+        }
+    }));
+    let clock = Arc::new(MockClock::new());
+    let server = spawn_with_auth(provider, clock.clone()).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    // Accepted passwords are remembered for a while.
+    client
+        .assert_reply(&["AUTH", "letmein"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["AUTH", "letmein"], simple("OK"))
+        .await;
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    clock.advance(Duration::from_secs(61));
+    client
+        .assert_reply(&["AUTH", "letmein"], simple("OK"))
+        .await;
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // A provider that fails is not a wrong password.
+    client
+        .assert_reply(&["AUTH", "down"], error("ERR directory unavailable"))
+        .await;
+
+    for _ in 0..5 {
+        client.assert_reply(&["AUTH", "guess"], wrongpass()).await;
+    }
+    let limited = error("ERR too many failed AUTH attempts, try again later");
+    client
+        .assert_reply(&["AUTH", "letmein"], limited.clone())
+        .await;
+    // The connection stays authenticated from before.
+    client
+        .assert_reply(&["GET", "foo"], Type::NullBulkString)
+        .await;
+
+    // Once the lockout is over, the address gets as many attempts again.
+    clock.advance(Duration::from_secs(61));
+    for _ in 0..5 {
+        client.assert_reply(&["AUTH", "guess"], wrongpass()).await;
+    }
+    client.assert_reply(&["AUTH", "letmein"], limited).await;
+    clock.advance(Duration::from_secs(61));
+    client
+        .assert_reply(&["AUTH", "letmein"], simple("OK"))
+        .await;
+}
+
+#[tokio::test]
+async fn auth_without_a_provider_is_an_error() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(
+            &["AUTH", "secret"],
+            error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"),
+        )
+        .await;
+    client.assert_reply(&["PING"], simple("PONG")).await;
+}
+
+#[tokio::test]
+async fn acl_rules_limit_commands() {
+    let path = std::env::temp_dir().join(format!("kv-store-acl-{}", std::process::id()));
+    std::fs::write(
+        &path,
+        format!("reader {} -@write -keys\n", hash_password("books")),
+    )
+    .unwrap();
+    let provider = Arc::new(FileProvider::open(&path).unwrap());
+    let server = spawn_with_auth(provider, Arc::new(MockClock::new())).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+    let noperm = |command: &str| {
+        error(&format!(
+            "NOPERM User reader has no permissions to run the '{}' command",
+            command
+        ))
+    };
+
+    client
+        .assert_reply(&["AUTH", "reader", "books"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "foo", "bar"], noperm("set"))
+        .await;
+    client
+        .assert_reply(&["GET", "foo"], Type::NullBulkString)
+        .await;
+
+    // Refused commands abort a transaction, and a dry run says why.
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client.assert_reply(&["GET", "foo"], simple("QUEUED")).await;
+    client.assert_reply(&["DEL", "foo"], noperm("del")).await;
+    client.assert_reply(&["KEYS", "*"], noperm("keys")).await;
+    client
+        .assert_reply(
+            &["EXEC", "DRYRUN"],
+            Type::Array(vec![
+                simple("GET would read foo"),
+                noperm("del"),
+                noperm("keys"),
+            ]),
+        )
+        .await;
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client.assert_reply(&["DEL", "foo"], noperm("del")).await;
+    client
+        .assert_reply(
+            &["EXEC"],
+            error("EXECABORT Transaction discarded because of previous errors."),
+        )
+        .await;
+
+    // Rules must be ones the server knows.
+    std::fs::write(
+        &path,
+        format!("reader {} -nosuch\n", hash_password("books")),
+    )
+    .unwrap();
+    assert!(FileProvider::open(&path).is_err());
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn concurrent_attempts_count_against_the_limit() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let provider = Arc::new(CallbackProvider::new(move |_, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(false)
+        }
+    }));
+    let server = spawn_with_auth(provider, Arc::new(MockClock::new())).await;
+
+    let mut attempts = Vec::new();
+    for _ in 0..10 {
+        let addr = server.local_addr();
+        attempts.push(tokio::spawn(async move {
+            let mut client = TestClient::connect(addr).await;
+            client.send(&["AUTH", "guess"]).await
+        }));
+    }
+    let mut limited = 0;
+    for attempt in attempts {
+        match attempt.await.unwrap() {
+            reply if reply == wrongpass() => {}
+            Type::SimpleError(e) if e.contains("too many failed AUTH attempts") => limited += 1,
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+    assert_eq!(limited, 5);
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn servers_log_in_to_each_other() {
+    let prefix = format!("KV_STORE_MASTERAUTH_{}_", std::process::id());
+    std::env::set_var(format!("{}REPL", prefix), "hunter2");
+    let spawn = |replicaof: Option<&ServerHandle>| {
+        let mut builder = Server::builder()
+            .port(0)
+            .auth_provider(Arc::new(EnvProvider::new(prefix.clone())))
+            .masteruser("repl")
+            .masterauth("hunter2");
+        if let Some(master) = replicaof {
+            let addr = master.local_addr();
+            builder = builder.replicaof(addr.ip().to_string(), addr.port());
+        }
+        async move { builder.spawn().await.expect("spawning server") }
+    };
+    let master = spawn(None).await;
+    let replica = spawn(Some(&master)).await;
+    let target = spawn(None).await;
+    let login = |addr| async move {
+        let mut client = TestClient::connect(addr).await;
+        client
+            .assert_reply(&["AUTH", "repl", "hunter2"], simple("OK"))
+            .await;
+        client
+    };
+
+    // The replica logs in before its handshake.
+    let master_addr = master.local_addr();
+    let connected = wait_until(Duration::from_secs(5), || async move {
+        login(master_addr)
+            .await
+            .info_field("replication", "connected_slaves")
+            .await
+            .as_deref()
+            == Some("1")
+    })
+    .await;
+    assert!(connected, "replica never completed the handshake");
+    let mut client = login(master_addr).await;
+    client
+        .assert_reply(&["SET", "foo", "1"], simple("OK"))
+        .await;
+    let replica_addr = replica.local_addr();
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        login(replica_addr).await.send(&["GET", "foo"]).await == bulk("1")
+    })
+    .await;
+    assert!(replicated, "writes were not applied on the replica");
+
+    // So do MIGRATE and sentinels.
+    let target_port = target.local_addr().port().to_string();
+    client
+        .assert_reply(
+            &["MIGRATE", "127.0.0.1", &target_port, "foo", "0", "5000"],
+            simple("OK"),
+        )
+        .await;
+    login(target.local_addr())
+        .await
+        .assert_reply(&["GET", "foo"], bulk("1"))
+        .await;
+
+    let addr = master.local_addr();
+    let sentinel = Sentinel::builder()
+        .port(0)
+        .monitor("mymaster", addr.ip().to_string(), addr.port(), 1)
+        .down_after(Duration::from_millis(300))
+        .masteruser("repl")
+        .masterauth("hunter2")
+        .spawn()
+        .await
+        .expect("spawning sentinel");
+    let sentinel_addr = sentinel.local_addr();
+    let found = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(sentinel_addr).await;
+        matches!(
+            client.send(&["SENTINEL", "REPLICAS", "mymaster"]).await,
+            Type::Array(replicas) if replicas.len() == 1
+        )
+    })
+    .await;
+    assert!(found, "the sentinel never saw the replica");
+}
@@ -0,0 +1,239 @@
+mod common;
+
+use anyhow::bail;
+use common::*;
+use redis_starter_rust::backend::{Backend, BackendFuture, FileBackend, Stored};
+use redis_starter_rust::{Server, ServerHandle, Type};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+async fn value(files: &FileBackend, key: &str) -> Option<String> {
+    files.load(key).await.unwrap().map(|stored| stored.value)
+}
+
+async fn spawn_with_backend(backend: Arc<dyn Backend>) -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .backend(backend)
+        .spawn()
+        .await
+        .expect("spawning server")
+}
+
+#[tokio::test]
+async fn caches_a_file_backend() {
+    let dir = std::env::temp_dir().join(format!("kv-store-backend-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let files = FileBackend::open(&dir).unwrap();
+    files
+        .store("stored", &Stored::new("on disk"))
+        .await
+        .unwrap();
+    let server = spawn_with_backend(Arc::new(files.clone())).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    // Misses are loaded and kept.
+    client
+        .assert_reply(&["GET", "stored"], bulk("on disk"))
+        .await;
+    client
+        .assert_reply(&["GET", "missing"], Type::NullBulkString)
+        .await;
+    files
+        .store("stored", &Stored::new("changed"))
+        .await
+        .unwrap();
+    client
+        .assert_reply(&["GET", "stored"], bulk("on disk"))
+        .await;
+
+    client
+        .assert_reply(&["MSET", "a", "1", "b", "2"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "c", "3", "PX", "100000"], simple("OK"))
+        .await;
+    for (key, value) in [("a", "1"), ("b", "2")] {
+        assert_eq!(files.load(key).await.unwrap(), Some(Stored::new(value)));
+    }
+    // Expiries are kept too.
+    let stored = files.load("c").await.unwrap().unwrap();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expires_in = stored.expires_at.unwrap() - unix_now.as_millis() as u64;
+    assert_eq!(stored.value, "3");
+    assert!((90_000..=100_000).contains(&expires_in), "{}", expires_in);
+    // A conditional write that does not apply is not written through.
+    client
+        .assert_reply(&["SET", "a", "x", "IFVERSION", "7"], Type::NullBulkString)
+        .await;
+    assert_eq!(value(&files, "a").await.as_deref(), Some("1"));
+
+    // Deletes reach the backend too, unless nothing was deleted.
+    client
+        .assert_reply(&["DELIFEQ", "a", "x"], Type::Integer("0".to_string()))
+        .await;
+    assert_eq!(value(&files, "a").await.as_deref(), Some("1"));
+    client
+        .assert_reply(&["DELIFEQ", "a", "1"], Type::Integer("1".to_string()))
+        .await;
+    client
+        .assert_reply(&["DEL", "b", "c"], Type::Integer("2".to_string()))
+        .await;
+    for key in ["a", "b", "c"] {
+        assert_eq!(files.load(key).await.unwrap(), None);
+    }
+
+    // Commands building on a value load it first.
+    files.store("log", &Stored::new("one")).await.unwrap();
+    client
+        .assert_reply(&["APPEND", "log", ",two"], Type::Integer("7".to_string()))
+        .await;
+    assert_eq!(value(&files, "log").await.as_deref(), Some("one,two"));
+    files.store("lock", &Stored::new("owner")).await.unwrap();
+    client
+        .assert_reply(
+            &["DELIFEQ", "lock", "owner"],
+            Type::Integer("1".to_string()),
+        )
+        .await;
+    assert_eq!(files.load("lock").await.unwrap(), None);
+    // Version 0 means there is no such key, but there is one in the backend.
+    files.store("cas", &Stored::new("old")).await.unwrap();
+    client
+        .assert_reply(
+            &["SET", "cas", "new", "IFVERSION", "0"],
+            Type::NullBulkString,
+        )
+        .await;
+    assert_eq!(value(&files, "cas").await.as_deref(), Some("old"));
+    files.store("gone", &Stored::new("soon")).await.unwrap();
+    client
+        .assert_reply(&["DEL", "gone", "never"], Type::Integer("1".to_string()))
+        .await;
+
+    // Keys too long for a file name of their own.
+    let long = "k".repeat(300);
+    client
+        .assert_reply(&["SET", &long, "long"], simple("OK"))
+        .await;
+    assert_eq!(value(&files, &long).await.as_deref(), Some("long"));
+    assert_eq!(value(&files, &long[1..]).await, None);
+
+    // Values that expired in the backend are dropped instead of loaded.
+    let expired = Stored {
+        value: "stale".to_string(),
+        expires_at: Some(1),
+    };
+    files.store("expired", &expired).await.unwrap();
+    client
+        .assert_reply(&["GET", "expired"], Type::NullBulkString)
+        .await;
+    assert_eq!(files.load("expired").await.unwrap(), None);
+
+    drop(client);
+    let _ = server.shutdown(Duration::from_secs(1)).await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// A backend whose service is down.
+#[derive(Debug)]
+struct Unavailable;
+
+impl Backend for Unavailable {
+    fn load<'a>(&'a self, _: &'a str) -> BackendFuture<'a, Option<Stored>> {
+        Box::pin(async { bail!("backend unavailable") })
+    }
+
+    fn store<'a>(&'a self, _: &'a str, _: &'a Stored) -> BackendFuture<'a, ()> {
+        Box::pin(async { bail!("backend unavailable") })
+    }
+
+    fn remove<'a>(&'a self, _: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async { bail!("backend unavailable") })
+    }
+}
+
+#[tokio::test]
+async fn reports_backend_failures() {
+    let server = spawn_with_backend(Arc::new(Unavailable)).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    client
+        .assert_reply(
+            &["GET", "foo"],
+            Type::SimpleError("ERR backend unavailable".to_string()),
+        )
+        .await;
+    client
+        .assert_reply(
+            &["SET", "foo", "bar"],
+            Type::SimpleError("ERR backend unavailable, the change is only cached".to_string()),
+        )
+        .await;
+    // Keys in memory don't need the backend.
+    client.assert_reply(&["GET", "foo"], bulk("bar")).await;
+}
+
+#[tokio::test]
+async fn migrated_keys_are_deleted_everywhere() {
+    let dir = std::env::temp_dir().join(format!("kv-store-migrate-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let files = FileBackend::open(&dir).unwrap();
+    let source = spawn_with_backend(Arc::new(files.clone())).await;
+    let replica = spawn_replica(&source).await;
+    let target = spawn_master().await;
+    let mut client = TestClient::connect(source.local_addr()).await;
+    let mut on_target = TestClient::connect(target.local_addr()).await;
+    let target_port = target.local_addr().port().to_string();
+
+    let source_addr = source.local_addr();
+    let connected = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(source_addr).await;
+        client
+            .info_field("replication", "connected_slaves")
+            .await
+            .as_deref()
+            == Some("1")
+    })
+    .await;
+    assert!(connected, "replica never completed the handshake");
+
+    for key in ["a", "b"] {
+        client.assert_reply(&["SET", key, "1"], simple("OK")).await;
+    }
+    let replica_addr = replica.local_addr();
+    let holds = |expected: Type| async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "a"]).await == expected && client.send(&["GET", "b"]).await == expected
+    };
+    let replicated = wait_until(Duration::from_secs(5), || holds(bulk("1"))).await;
+    assert!(replicated, "writes were not applied on the replica");
+
+    client
+        .assert_reply(
+            &[
+                "MIGRATE",
+                "127.0.0.1",
+                &target_port,
+                "",
+                "0",
+                "5000",
+                "KEYS",
+                "a",
+                "b",
+            ],
+            simple("OK"),
+        )
+        .await;
+    for key in ["a", "b"] {
+        on_target.assert_reply(&["GET", key], bulk("1")).await;
+        client
+            .assert_reply(&["GET", key], Type::NullBulkString)
+            .await;
+        assert_eq!(value(&files, key).await, None);
+    }
+
+    let deleted = wait_until(Duration::from_secs(5), || holds(Type::NullBulkString)).await;
+    assert!(deleted, "the replica still holds migrated keys");
+    let _ = std::fs::remove_dir_all(&dir);
+}
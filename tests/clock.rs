@@ -0,0 +1,164 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::client::Client;
+use redis_starter_rust::clock::MockClock;
+use redis_starter_rust::{Server, ServerHandle, Type};
+use std::sync::Arc;
+use std::time::Duration;
+
+async fn spawn_with_clock(clock: &Arc<MockClock>) -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .clock(clock.clone())
+        .spawn()
+        .await
+        .expect("spawning server")
+}
+
+fn integer(i: i64) -> Type {
+    Type::Integer(i.to_string())
+}
+
+#[tokio::test]
+async fn expiry_follows_the_clock() {
+    let clock = Arc::new(MockClock::new());
+    let server = spawn_with_clock(&clock).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    client
+        .assert_reply(&["SET", "k", "v", "PX", "1000"], simple("OK"))
+        .await;
+    clock.advance(Duration::from_millis(999));
+    client.assert_reply(&["GET", "k"], bulk("v")).await;
+    client.assert_reply(&["PTTL", "k"], integer(1)).await;
+
+    clock.advance(Duration::from_millis(1));
+    client
+        .assert_reply(&["GET", "k"], Type::NullBulkString)
+        .await;
+    client.assert_reply(&["PTTL", "k"], integer(-2)).await;
+}
+
+#[tokio::test]
+async fn time_reports_the_clock() {
+    let clock = Arc::new(MockClock::at(Duration::from_micros(1_700_000_000_250_000)));
+    let server = spawn_with_clock(&clock).await;
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    let time = |secs: &str, micros: &str| Type::Array(vec![bulk(secs), bulk(micros)]);
+    client
+        .assert_reply(&["TIME"], time("1700000000", "250000"))
+        .await;
+    clock.advance(Duration::from_millis(1500));
+    client
+        .assert_reply(&["TIME"], time("1700000001", "750000"))
+        .await;
+}
+
+#[tokio::test]
+async fn slowlog_records_commands_over_the_threshold() {
+    let clock = Arc::new(MockClock::at(Duration::from_secs(1_700_000_000)));
+    let server = Server::builder()
+        .port(0)
+        .clock(clock.clone())
+        .slowlog_slower_than(Duration::ZERO)
+        .spawn()
+        .await
+        .expect("spawning server");
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    client.assert_reply(&["SET", "a", "b"], simple("OK")).await;
+    client.assert_reply(&["PING"], simple("PONG")).await;
+
+    let Type::Array(entries) = client.send(&["SLOWLOG", "GET", "2"]).await else {
+        panic!("SLOWLOG GET did not return an array");
+    };
+    assert_eq!(entries.len(), 2);
+    let Type::Array(newest) = &entries[0] else {
+        panic!("slowlog entry is not an array");
+    };
+    assert_eq!(newest[0], integer(1));
+    assert_eq!(newest[3], Type::Array(vec![bulk("PING")]));
+    let Type::Array(oldest) = &entries[1] else {
+        panic!("slowlog entry is not an array");
+    };
+    assert_eq!(oldest[0], integer(0));
+    assert_eq!(oldest[1], integer(1_700_000_000));
+    assert_eq!(oldest[2], integer(0));
+    assert_eq!(
+        oldest[3],
+        Type::Array(vec![bulk("SET"), bulk("a"), bulk("b")])
+    );
+
+    // SET, PING and the SLOWLOG GET itself.
+    client.assert_reply(&["SLOWLOG", "LEN"], integer(3)).await;
+    client
+        .assert_reply(&["SLOWLOG", "RESET"], simple("OK"))
+        .await;
+    // Only the RESET, which is logged once it has run.
+    client.assert_reply(&["SLOWLOG", "LEN"], integer(1)).await;
+}
+
+#[tokio::test]
+async fn slowlog_entries_carry_the_trace_id() {
+    let server = Server::builder()
+        .port(0)
+        .slowlog_slower_than(Duration::ZERO)
+        .spawn()
+        .await
+        .expect("spawning server");
+    let mut client = TestClient::connect(server.local_addr()).await;
+
+    client
+        .assert_reply(&["CLIENT", "TRACEID"], Type::NullBulkString)
+        .await;
+    client
+        .assert_reply(&["CLIENT", "TRACEID", "req-1"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["CLIENT", "TRACEID"], bulk("req-1"))
+        .await;
+    client.assert_reply(&["SET", "a", "b"], simple("OK")).await;
+    client
+        .assert_reply(&["CLIENT", "TRACEID", ""], simple("OK"))
+        .await;
+    client.assert_reply(&["PING"], simple("PONG")).await;
+
+    let Type::Array(entries) = client.send(&["SLOWLOG", "GET", "2"]).await else {
+        panic!("SLOWLOG GET did not return an array");
+    };
+    let trace_ids: Vec<Type> = entries
+        .into_iter()
+        .map(|entry| match entry {
+            Type::Array(fields) => fields[6].clone(),
+            entry => panic!("slowlog entry is not an array: {:?}", entry),
+        })
+        .collect();
+    assert_eq!(trace_ids, vec![bulk(""), bulk("req-1")]);
+}
+
+#[tokio::test]
+async fn master_pings_replicas_on_heartbeat() {
+    let clock = Arc::new(MockClock::new());
+    let server = spawn_with_clock(&clock).await;
+    let mut replica = Client::connect(server.local_addr()).await.unwrap();
+
+    replica
+        .request(&["REPLCONF", "listening-port", "6380"])
+        .await
+        .unwrap();
+    replica.request(&["PSYNC", "?", "-1"]).await.unwrap();
+    replica.read_rdb().await.unwrap();
+
+    // The heartbeat may not be sleeping yet when we first advance, so keep
+    // nudging the clock until the ping shows up.
+    let ping = loop {
+        clock.advance(Duration::from_secs(10));
+        let frame = tokio::time::timeout(Duration::from_millis(200), replica.read_frame()).await;
+        if let Ok(frame) = frame {
+            break frame.unwrap().expect("master closed the link");
+        }
+    };
+    assert_eq!(ping.0, Type::Array(vec![bulk("PING")]));
+}
@@ -0,0 +1,714 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::cluster::{crc16, key_hash_slot, parse_slot_range};
+use redis_starter_rust::{Server, ServerHandle, Type};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+async fn spawn_node(slots: RangeInclusive<u16>) -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .cluster_slots(slots)
+        .spawn()
+        .await
+        .expect("spawning cluster node")
+}
+
+fn cluster_info(info: &Type, field: &str) -> String {
+    let Type::BulkString(info) = info else {
+        panic!("unexpected CLUSTER INFO reply {:?}", info);
+    };
+    info.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .unwrap_or_else(|| panic!("no {} in {:?}", field, info))
+        .to_string()
+}
+
+#[test]
+fn hashes_keys_like_redis() {
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+    assert_eq!(key_hash_slot("foo"), 12182);
+    assert_eq!(key_hash_slot("bar"), 5061);
+    assert_eq!(
+        key_hash_slot("{user1000}.following"),
+        key_hash_slot("{user1000}.followers")
+    );
+    assert_eq!(
+        key_hash_slot("{user1000}.following"),
+        key_hash_slot("user1000")
+    );
+    // Empty or unterminated tags hash the whole key.
+    assert_eq!(key_hash_slot("{}foo"), crc16(b"{}foo") % 16384);
+    assert_eq!(key_hash_slot("{foo"), crc16(b"{foo") % 16384);
+
+    assert_eq!(parse_slot_range("0-8191").unwrap(), 0..=8191);
+    assert_eq!(parse_slot_range("42").unwrap(), 42..=42);
+    assert!(parse_slot_range("0-16384").is_err());
+    assert!(parse_slot_range("10-1").is_err());
+}
+
+#[tokio::test]
+async fn serves_only_owned_slots() {
+    let node = spawn_node(0..=8191).await;
+    let mut client = TestClient::connect(node.local_addr()).await;
+
+    client
+        .assert_reply(&["SET", "bar", "1"], simple("OK"))
+        .await;
+    client.assert_reply(&["GET", "bar"], bulk("1")).await;
+
+    let not_served = Type::SimpleError("CLUSTERDOWN Hash slot not served".to_string());
+    client
+        .assert_reply(&["SET", "foo", "1"], not_served.clone())
+        .await;
+    client
+        .assert_reply(&["GET", "foo"], not_served.clone())
+        .await;
+    client.assert_reply(&["PTTL", "foo"], not_served).await;
+    client
+        .assert_reply(
+            &["CLUSTER", "KEYSLOT", "foo"],
+            Type::Integer("12182".to_string()),
+        )
+        .await;
+
+    let info = client.send(&["CLUSTER", "INFO"]).await;
+    assert_eq!(cluster_info(&info, "cluster_state"), "fail");
+    assert_eq!(cluster_info(&info, "cluster_slots_assigned"), "8192");
+    assert_eq!(cluster_info(&info, "cluster_known_nodes"), "1");
+}
+
+#[tokio::test]
+async fn reports_cluster_state() {
+    let node = spawn_node(0..=16383).await;
+    let mut client = TestClient::connect(node.local_addr()).await;
+
+    let info = client.send(&["CLUSTER", "INFO"]).await;
+    assert_eq!(cluster_info(&info, "cluster_state"), "ok");
+    assert_eq!(cluster_info(&info, "cluster_slots_assigned"), "16384");
+
+    let myid = node.server().cluster().unwrap().myself();
+    assert_eq!(myid.len(), 40);
+    assert!(myid.chars().all(|c| c.is_ascii_hexdigit()));
+    client.assert_reply(&["CLUSTER", "MYID"], bulk(&myid)).await;
+
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+    client
+        .assert_reply(
+            &["CLUSTER", "INFO"],
+            Type::SimpleError("ERR This instance has cluster support disabled".to_string()),
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn redirects_to_the_slot_owner() {
+    let (a, b) = spawn_pair().await;
+    let (a_cluster, b_cluster) = (a.server().cluster().unwrap(), b.server().cluster().unwrap());
+    let mut client_a = TestClient::connect(a.local_addr()).await;
+    let mut client_b = TestClient::connect(b.local_addr()).await;
+
+    let moved = |slot: u16, node: &ServerHandle| {
+        Type::SimpleError(format!("MOVED {} {}", slot, node.local_addr()))
+    };
+    client_a
+        .assert_reply(&["SET", "foo", "1"], moved(12182, &b))
+        .await;
+    client_b
+        .assert_reply(&["SET", "foo", "1"], simple("OK"))
+        .await;
+    client_b
+        .assert_reply(&["GET", "bar"], moved(5061, &a))
+        .await;
+
+    // Mid-migration, keys that already left get an ASK to the new owner,
+    // which only serves them right after ASKING.
+    client_a
+        .assert_reply(&["SET", "bar", "1"], simple("OK"))
+        .await;
+    a_cluster.set_migrating(5061, &b_cluster.myself()).unwrap();
+    b_cluster.set_importing(5061, &a_cluster.myself()).unwrap();
+    client_a.assert_reply(&["GET", "bar"], bulk("1")).await;
+    client_a
+        .assert_reply(
+            &["GET", "{bar}.new"],
+            Type::SimpleError(format!("ASK 5061 {}", b.local_addr())),
+        )
+        .await;
+    client_b
+        .assert_reply(&["GET", "{bar}.new"], moved(5061, &a))
+        .await;
+    client_b.assert_reply(&["ASKING"], simple("OK")).await;
+    client_b
+        .assert_reply(&["SET", "{bar}.new", "2"], simple("OK"))
+        .await;
+    client_b
+        .assert_reply(&["GET", "{bar}.new"], moved(5061, &a))
+        .await;
+}
+
+const NODES_CONF: &str = "\
+a1 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-5460
+b2 127.0.0.1:7001@17001 master - 0 0 2 connected 5461-10922 [5461->-a1]
+c3 127.0.0.1:7002@17002 master - 0 0 3 connected 10923-16383
+d4 127.0.0.1:7003@17003 slave a1 0 0 1 connected
+vars currentEpoch 3 lastVoteEpoch 0
+";
+
+/// Starts a node whose view of the cluster is `NODES_CONF`, it stands in for
+/// `a1`.
+async fn spawn_from_nodes_conf(name: &str) -> ServerHandle {
+    let path = std::env::temp_dir().join(format!("kv-store-{}-{}.conf", name, std::process::id()));
+    std::fs::write(&path, NODES_CONF).unwrap();
+    let node = Server::builder()
+        .port(0)
+        .cluster_config_file(&path)
+        .spawn()
+        .await
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    node
+}
+
+fn int(i: u16) -> Type {
+    Type::Integer(i.to_string())
+}
+
+#[tokio::test]
+async fn loads_topology_from_a_nodes_file() {
+    let node = spawn_from_nodes_conf("nodes").await;
+    let cluster = node.server().cluster().unwrap();
+    assert_eq!(cluster.myself(), "a1");
+    let nodes = cluster.nodes();
+    assert_eq!(nodes.len(), 4);
+    assert_eq!(nodes[0].addr, node.local_addr());
+    assert_eq!(Some(nodes[0].bus_addr()), node.cluster_bus_addr());
+    assert_eq!(nodes[1].bus_port, 17001);
+    assert_eq!(nodes[1].config_epoch, 2);
+    assert_eq!(nodes[3].replica_of.as_deref(), Some("a1"));
+
+    let mut client = TestClient::connect(node.local_addr()).await;
+    client
+        .assert_reply(
+            &["GET", "foo"],
+            Type::SimpleError("MOVED 12182 127.0.0.1:7002".to_string()),
+        )
+        .await;
+    let info = client.send(&["CLUSTER", "INFO"]).await;
+    assert_eq!(cluster_info(&info, "cluster_state"), "ok");
+    assert_eq!(cluster_info(&info, "cluster_known_nodes"), "4");
+    assert_eq!(cluster_info(&info, "cluster_size"), "3");
+}
+
+#[tokio::test]
+async fn describes_the_topology() {
+    let node = spawn_from_nodes_conf("topology").await;
+    let mut client = TestClient::connect(node.local_addr()).await;
+    let port = node.local_addr().port();
+    let slots_entry =
+        |port: u16, id: &str| Type::Array(vec![bulk("127.0.0.1"), int(port), bulk(id)]);
+
+    client
+        .assert_reply(
+            &["CLUSTER", "SLOTS"],
+            Type::Array(vec![
+                Type::Array(vec![
+                    int(0),
+                    int(5460),
+                    slots_entry(port, "a1"),
+                    slots_entry(7003, "d4"),
+                ]),
+                Type::Array(vec![int(5461), int(10922), slots_entry(7001, "b2")]),
+                Type::Array(vec![int(10923), int(16383), slots_entry(7002, "c3")]),
+            ]),
+        )
+        .await;
+
+    let shards_entry = |port: u16, id: &str, role: &str| {
+        Type::Array(vec![
+            bulk("id"),
+            bulk(id),
+            bulk("port"),
+            int(port),
+            bulk("ip"),
+            bulk("127.0.0.1"),
+            bulk("endpoint"),
+            bulk("127.0.0.1"),
+            bulk("role"),
+            bulk(role),
+            bulk("replication-offset"),
+            int(0),
+            bulk("health"),
+            bulk("online"),
+        ])
+    };
+    let shard = |slots: [u16; 2], nodes: Vec<Type>| {
+        Type::Array(vec![
+            bulk("slots"),
+            Type::Array(slots.into_iter().map(int).collect()),
+            bulk("nodes"),
+            Type::Array(nodes),
+        ])
+    };
+    client
+        .assert_reply(
+            &["CLUSTER", "SHARDS"],
+            Type::Array(vec![
+                shard(
+                    [0, 5460],
+                    vec![
+                        shards_entry(port, "a1", "master"),
+                        shards_entry(7003, "d4", "replica"),
+                    ],
+                ),
+                shard([5461, 10922], vec![shards_entry(7001, "b2", "master")]),
+                shard([10923, 16383], vec![shards_entry(7002, "c3", "master")]),
+            ]),
+        )
+        .await;
+}
+
+/// Two nodes splitting the slots in half, each knowing about the other.
+async fn spawn_pair() -> (ServerHandle, ServerHandle) {
+    let a = spawn_node(0..=8191).await;
+    let b = spawn_node(8192..=16383).await;
+    let (a_cluster, b_cluster) = (a.server().cluster().unwrap(), b.server().cluster().unwrap());
+    let (a_node, b_node) = (a_cluster.nodes().remove(0), b_cluster.nodes().remove(0));
+    a_cluster.add_node(b_node.clone());
+    a_cluster.assign_slots(8192..=16383, &b_node.id).unwrap();
+    b_cluster.add_node(a_node.clone());
+    b_cluster.assign_slots(0..=8191, &a_node.id).unwrap();
+    (a, b)
+}
+
+#[tokio::test]
+async fn migrates_a_slot_online() {
+    let (a, b) = spawn_pair().await;
+    let a_id = a.server().cluster().unwrap().myself();
+    let b_id = b.server().cluster().unwrap().myself();
+    let mut client_a = TestClient::connect(a.local_addr()).await;
+    let mut client_b = TestClient::connect(b.local_addr()).await;
+    let b_port = b.local_addr().port().to_string();
+
+    client_a
+        .assert_reply(&["SET", "bar", "1"], simple("OK"))
+        .await;
+    client_a
+        .assert_reply(&["SET", "{bar}.ttl", "2", "PX", "60000"], simple("OK"))
+        .await;
+    client_a
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "5061", "MIGRATING", &b_id],
+            simple("OK"),
+        )
+        .await;
+    client_b
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "5061", "IMPORTING", &a_id],
+            simple("OK"),
+        )
+        .await;
+
+    client_a
+        .assert_reply(
+            &["CLUSTER", "GETKEYSINSLOT", "5061", "10"],
+            Type::Array(vec![bulk("bar"), bulk("{bar}.ttl")]),
+        )
+        .await;
+    client_a
+        .assert_reply(
+            &["MIGRATE", "127.0.0.1", &b_port, "bar", "0", "5000"],
+            simple("OK"),
+        )
+        .await;
+    // bar already moved, so it is asked for on b while {bar}.ttl is still here.
+    client_a
+        .assert_reply(
+            &["GET", "bar"],
+            Type::SimpleError(format!("ASK 5061 {}", b.local_addr())),
+        )
+        .await;
+    client_a
+        .assert_reply(&["GET", "{bar}.ttl"], bulk("2"))
+        .await;
+    client_b.assert_reply(&["ASKING"], simple("OK")).await;
+    client_b.assert_reply(&["GET", "bar"], bulk("1")).await;
+
+    client_a
+        .assert_reply(
+            &[
+                "MIGRATE",
+                "127.0.0.1",
+                &b_port,
+                "",
+                "0",
+                "5000",
+                "KEYS",
+                "{bar}.ttl",
+            ],
+            simple("OK"),
+        )
+        .await;
+    client_a
+        .assert_reply(
+            &["MIGRATE", "127.0.0.1", &b_port, "bar", "0", "5000"],
+            simple("NOKEY"),
+        )
+        .await;
+    client_a
+        .assert_reply(
+            &["CLUSTER", "COUNTKEYSINSLOT", "5061"],
+            Type::Integer("0".to_string()),
+        )
+        .await;
+
+    for client in [&mut client_b, &mut client_a] {
+        client
+            .assert_reply(&["CLUSTER", "SETSLOT", "5061", "NODE", &b_id], simple("OK"))
+            .await;
+    }
+    client_a
+        .assert_reply(
+            &["GET", "bar"],
+            Type::SimpleError(format!("MOVED 5061 {}", b.local_addr())),
+        )
+        .await;
+    client_b.assert_reply(&["GET", "bar"], bulk("1")).await;
+    let Type::Integer(pttl) = client_b.send(&["PTTL", "{bar}.ttl"]).await else {
+        panic!("PTTL should return an integer");
+    };
+    let pttl: i64 = pttl.parse().unwrap();
+    assert!(pttl > 0 && pttl <= 60000, "{}", pttl);
+}
+
+#[tokio::test]
+async fn migrate_deletes_the_keys_that_moved_before_a_failure() {
+    let (source, target) = (spawn_master().await, spawn_master().await);
+    let mut client = TestClient::connect(source.local_addr()).await;
+    let mut target_client = TestClient::connect(target.local_addr()).await;
+    let target_port = target.local_addr().port().to_string();
+
+    client
+        .assert_reply(&["MSET", "a", "1", "b", "2", "c", "3"], simple("OK"))
+        .await;
+    target_client
+        .assert_reply(&["SET", "b", "taken"], simple("OK"))
+        .await;
+    client
+        .assert_reply(
+            &[
+                "MIGRATE",
+                "127.0.0.1",
+                &target_port,
+                "",
+                "0",
+                "5000",
+                "KEYS",
+                "a",
+                "b",
+                "c",
+            ],
+            Type::SimpleError("BUSYKEY Target key name already exists.".to_string()),
+        )
+        .await;
+
+    // a made it across, b and c stay where they were.
+    target_client.assert_reply(&["GET", "a"], bulk("1")).await;
+    client
+        .assert_reply(&["GET", "a"], Type::NullBulkString)
+        .await;
+    target_client
+        .assert_reply(&["GET", "b"], bulk("taken"))
+        .await;
+    client.assert_reply(&["GET", "b"], bulk("2")).await;
+    client.assert_reply(&["GET", "c"], bulk("3")).await;
+    target_client
+        .assert_reply(&["GET", "c"], Type::NullBulkString)
+        .await;
+}
+
+#[tokio::test]
+async fn setslot_checks_ownership() {
+    let (a, b) = spawn_pair().await;
+    let b_id = b.server().cluster().unwrap().myself();
+    let mut client_a = TestClient::connect(a.local_addr()).await;
+    let b_port = b.local_addr().port().to_string();
+
+    let error = |msg: &str| Type::SimpleError(format!("ERR {}", msg));
+    client_a
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &b_id],
+            error("I'm not the owner of hash slot 12182"),
+        )
+        .await;
+    client_a
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "5061", "IMPORTING", &b_id],
+            error("I'm already the owner of hash slot 5061"),
+        )
+        .await;
+    client_a
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "5061", "MIGRATING", "nobody"],
+            error("Unknown node nobody"),
+        )
+        .await;
+
+    client_a
+        .assert_reply(&["SET", "bar", "1"], simple("OK"))
+        .await;
+    client_a
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "5061", "NODE", &b_id],
+            error("Can't assign hashslot 5061 to a different node while I still hold keys for this hash slot."),
+        )
+        .await;
+
+    // b is not importing the slot, so it refuses the key.
+    client_a
+        .assert_reply(
+            &["CLUSTER", "SETSLOT", "5061", "MIGRATING", &b_id],
+            simple("OK"),
+        )
+        .await;
+    client_a
+        .assert_reply(
+            &["MIGRATE", "127.0.0.1", &b_port, "bar", "0", "5000"],
+            Type::SimpleError(format!(
+                "ERR Target instance replied with error: MOVED 5061 {}",
+                a.local_addr()
+            )),
+        )
+        .await;
+    client_a.assert_reply(&["GET", "bar"], bulk("1")).await;
+    client_a
+        .assert_reply(&["CLUSTER", "SETSLOT", "5061", "STABLE"], simple("OK"))
+        .await;
+}
+
+#[tokio::test]
+async fn multi_key_commands_need_a_single_slot() {
+    let node = spawn_node(0..=16383).await;
+    let mut client = TestClient::connect(node.local_addr()).await;
+    let crossslot =
+        Type::SimpleError("CROSSSLOT Keys in request don't hash to the same slot".to_string());
+
+    client
+        .assert_reply(&["MSET", "foo", "1", "bar", "2"], crossslot.clone())
+        .await;
+    client
+        .assert_reply(&["MGET", "foo", "bar"], crossslot.clone())
+        .await;
+    client
+        .assert_reply(&["MSET", "{user}.a", "1", "{user}.b", "2"], simple("OK"))
+        .await;
+    client
+        .assert_reply(
+            &["MGET", "{user}.a", "{user}.b"],
+            Type::Array(vec![bulk("1"), bulk("2")]),
+        )
+        .await;
+
+    // A transaction is checked as a whole when it runs.
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client
+        .assert_reply(&["SET", "foo", "1"], simple("QUEUED"))
+        .await;
+    client
+        .assert_reply(&["SET", "bar", "1"], simple("QUEUED"))
+        .await;
+    client.assert_reply(&["EXEC"], crossslot.clone()).await;
+    client
+        .assert_reply(&["GET", "foo"], Type::NullBulkString)
+        .await;
+
+    // A command refused while queueing aborts the transaction.
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client
+        .assert_reply(&["SET", "foo", "1"], simple("QUEUED"))
+        .await;
+    client
+        .assert_reply(&["MGET", "foo", "bar"], crossslot)
+        .await;
+    client
+        .assert_reply(
+            &["EXEC"],
+            Type::SimpleError(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            ),
+        )
+        .await;
+    client
+        .assert_reply(&["GET", "foo"], Type::NullBulkString)
+        .await;
+}
+
+/// A node that gives up on others after 300ms without an answer.
+async fn spawn_gossiping_node(slots: RangeInclusive<u16>) -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .cluster_slots(slots)
+        .cluster_node_timeout(Duration::from_millis(300))
+        .spawn()
+        .await
+        .expect("spawning cluster node")
+}
+
+/// Flags of node `id` in `CLUSTER NODES` on `addr`.
+async fn node_flags(addr: SocketAddr, id: &str) -> Option<String> {
+    let mut client = TestClient::connect(addr).await;
+    let Type::BulkString(nodes) = client.send(&["CLUSTER", "NODES"]).await else {
+        panic!("CLUSTER NODES should return a bulk string");
+    };
+    let line = nodes.lines().find(|line| line.starts_with(id))?;
+    line.split_whitespace().nth(2).map(String::from)
+}
+
+#[tokio::test]
+async fn gossips_topology_and_detects_failures() {
+    let a = spawn_gossiping_node(0..=5460).await;
+    let b = spawn_gossiping_node(5461..=10922).await;
+    let c = spawn_gossiping_node(10923..=16383).await;
+    let mut client = TestClient::connect(a.local_addr()).await;
+    for node in [&b, &c] {
+        let port = node.local_addr().port().to_string();
+        let bus_port = node.cluster_bus_addr().unwrap().port().to_string();
+        client
+            .assert_reply(
+                &["CLUSTER", "MEET", "127.0.0.1", &port, &bus_port],
+                simple("OK"),
+            )
+            .await;
+    }
+
+    // b and c only met a, they hear about each other and each other's slots
+    // through gossip.
+    for node in [&a, &b, &c] {
+        let addr = node.local_addr();
+        let converged = wait_until(Duration::from_secs(10), || async move {
+            let info = TestClient::connect(addr)
+                .await
+                .send(&["CLUSTER", "INFO"])
+                .await;
+            cluster_info(&info, "cluster_known_nodes") == "3"
+                && cluster_info(&info, "cluster_state") == "ok"
+        })
+        .await;
+        assert!(converged, "{} never learned the whole topology", addr);
+    }
+    let mut client_b = TestClient::connect(b.local_addr()).await;
+    client_b
+        .assert_reply(
+            &["GET", "foo"],
+            Type::SimpleError(format!("MOVED 12182 {}", c.local_addr())),
+        )
+        .await;
+
+    // Once c stops answering both a and b flag it, which is a majority.
+    let c_id = c.server().cluster().unwrap().myself();
+    c.shutdown(Duration::from_secs(1)).await.unwrap();
+    for node in [&a, &b] {
+        let addr = node.local_addr();
+        let failed = wait_until(Duration::from_secs(10), || {
+            let c_id = c_id.clone();
+            async move { node_flags(addr, &c_id).await.as_deref() == Some("master,fail") }
+        })
+        .await;
+        assert!(failed, "{} never flagged the stopped node as failed", addr);
+    }
+    let info = client.send(&["CLUSTER", "INFO"]).await;
+    assert_eq!(cluster_info(&info, "cluster_state"), "fail");
+    assert_eq!(cluster_info(&info, "cluster_slots_fail"), "5461");
+    assert_eq!(
+        node_flags(a.local_addr(), &a.server().cluster().unwrap().myself())
+            .await
+            .as_deref(),
+        Some("myself,master")
+    );
+}
+
+#[tokio::test]
+async fn promotes_a_replica_when_its_master_fails() {
+    let a = spawn_gossiping_node(0..=5460).await;
+    let b = spawn_gossiping_node(5461..=10922).await;
+    let c = spawn_gossiping_node(10923..=16383).await;
+    let replica = Server::builder()
+        .port(0)
+        .cluster_slots(0..=16383)
+        .replicaof("127.0.0.1", a.local_addr().port())
+        .cluster_node_timeout(Duration::from_millis(300))
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = TestClient::connect(a.local_addr()).await;
+    for node in [&b, &c, &replica] {
+        let port = node.local_addr().port().to_string();
+        let bus_port = node.cluster_bus_addr().unwrap().port().to_string();
+        client
+            .assert_reply(
+                &["CLUSTER", "MEET", "127.0.0.1", &port, &bus_port],
+                simple("OK"),
+            )
+            .await;
+    }
+    let replica_id = replica.server().cluster().unwrap().myself();
+    let (b_addr, replica_addr) = (b.local_addr(), replica.local_addr());
+    let replicating = wait_until(Duration::from_secs(10), || {
+        let replica_id = replica_id.clone();
+        async move { node_flags(b_addr, &replica_id).await.as_deref() == Some("slave") }
+    })
+    .await;
+    assert!(replicating, "the replica never joined as a slave");
+    assert_eq!(
+        node_flags(replica_addr, &replica_id).await.as_deref(),
+        Some("myself,slave")
+    );
+
+    client
+        .assert_reply(&["SET", "bar", "1"], simple("OK"))
+        .await;
+    let replica_db = replica.db();
+    let replicated = wait_until(Duration::from_secs(5), || {
+        let replica_db = replica_db.clone();
+        async move { replica_db.lock().unwrap().contains("bar") }
+    })
+    .await;
+    assert!(replicated, "the write never reached the replica");
+
+    // Once b and c agree a failed they vote the replica in, and it takes
+    // over a's slots with a newer epoch.
+    a.shutdown(Duration::from_secs(1)).await.unwrap();
+    let promoted = wait_until(Duration::from_secs(10), || {
+        let replica_id = replica_id.clone();
+        async move { node_flags(b_addr, &replica_id).await.as_deref() == Some("master") }
+    })
+    .await;
+    assert!(promoted, "the replica was never promoted");
+    let mut client_b = TestClient::connect(b_addr).await;
+    client_b
+        .assert_reply(
+            &["GET", "bar"],
+            Type::SimpleError(format!("MOVED 5061 {}", replica_addr)),
+        )
+        .await;
+    let info = client_b.send(&["CLUSTER", "INFO"]).await;
+    assert_eq!(cluster_info(&info, "cluster_state"), "ok");
+
+    let mut client_replica = TestClient::connect(replica_addr).await;
+    client_replica
+        .assert_reply(&["GET", "bar"], bulk("1"))
+        .await;
+    assert_eq!(
+        client_replica
+            .info_field("replication", "role")
+            .await
+            .as_deref(),
+        Some("master")
+    );
+    let info = client_replica.send(&["CLUSTER", "INFO"]).await;
+    let epoch: u64 = cluster_info(&info, "cluster_my_epoch").parse().unwrap();
+    assert!(epoch > 0, "the new master kept epoch 0");
+}
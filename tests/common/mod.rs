@@ -0,0 +1,100 @@
+//! Helpers for driving in-process servers from `#[tokio::test]` functions.
+#![allow(dead_code)]
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use redis_starter_rust::client::Client;
+use redis_starter_rust::resptype::*;
+use redis_starter_rust::{Server, ServerHandle};
+
+/// Starts a master on an ephemeral port.
+pub async fn spawn_master() -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .spawn()
+        .await
+        .expect("spawning master")
+}
+
+/// Starts a replica of `master` on an ephemeral port.
+pub async fn spawn_replica(master: &ServerHandle) -> ServerHandle {
+    let master_addr = master.local_addr();
+    Server::builder()
+        .port(0)
+        .replicaof(master_addr.ip().to_string(), master_addr.port())
+        .spawn()
+        .await
+        .expect("spawning replica")
+}
+
+pub fn simple(s: &str) -> Type {
+    Type::SimpleString(s.to_string())
+}
+
+pub fn bulk(s: &str) -> Type {
+    Type::BulkString(s.to_string())
+}
+
+pub fn command(args: &[&str]) -> Vec<u8> {
+    redis_starter_rust::client::command(args).serialize()
+}
+
+pub struct TestClient {
+    client: Client,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let client = Client::connect(addr).await.expect("connecting to server");
+        Self { client }
+    }
+
+    /// Writes `bytes` as-is and returns the first reply.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<Type> {
+        self.client.write_raw(bytes).await?;
+        tokio::time::timeout(Duration::from_secs(5), self.client.read_reply())
+            .await
+            .context("waiting for reply")?
+    }
+
+    pub async fn send(&mut self, args: &[&str]) -> Type {
+        self.send_raw(&command(args))
+            .await
+            .unwrap_or_else(|e| panic!("sending {:?}: {}", args, e))
+    }
+
+    pub async fn assert_reply(&mut self, args: &[&str], expected: Type) {
+        let reply = self.send(args).await;
+        assert_eq!(reply, expected, "reply to {:?}", args);
+    }
+
+    /// Runs INFO for `section` and returns the value of `field`.
+    pub async fn info_field(&mut self, section: &str, field: &str) -> Option<String> {
+        let Type::BulkString(info) = self.send(&["INFO", section]).await else {
+            return None;
+        };
+        info.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(k, _)| *k == field)
+            .map(|(_, v)| v.to_string())
+    }
+}
+
+/// Polls `check` until it returns true or the timeout elapses.
+pub async fn wait_until<F, Fut>(timeout: Duration, mut check: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+        if check().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    false
+}
@@ -0,0 +1,182 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::clock::MockClock;
+use redis_starter_rust::config::{parse_config, ConfigFile, Reload};
+use redis_starter_rust::{Server, Type};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn scratch_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("kv-store-{}-{}.conf", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Type {
+    Type::Array(pairs.iter().flat_map(|(k, v)| [bulk(k), bulk(v)]).collect())
+}
+
+#[test]
+fn parses_redis_conf_syntax() {
+    let entries = parse_config(
+        "# comment\n\
+         \n\
+         MaxMemory 64mb\n\
+         logfile \"\"\n\
+         save 900 1\n\
+         save 300 10\n\
+         slaveof 127.0.0.1 6380\n\
+         pidfile '/tmp/kv store.pid'\n",
+    )
+    .unwrap();
+    let entries: Vec<(&str, &str)> = entries
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            ("logfile", ""),
+            ("maxmemory", "64mb"),
+            ("pidfile", "/tmp/kv store.pid"),
+            ("replicaof", "127.0.0.1 6380"),
+            ("save", "900 1 300 10"),
+        ]
+    );
+
+    let err = parse_config("timeout 0\nappendonly yes\n").unwrap_err();
+    assert_eq!(err.to_string(), "line 2: unknown directive 'appendonly'");
+    assert!(parse_config("logfile \"unbalanced\n").is_err());
+}
+
+#[tokio::test]
+async fn reload_applies_what_changed_in_the_file() {
+    let path = scratch_file("reload", "port 6379\nmaxmemory 1mb\ntimeout 0\n");
+    let server = Server::builder()
+        .port(0)
+        .config_file(ConfigFile::read(&path).unwrap())
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await;
+    client
+        .assert_reply(
+            &["CONFIG", "GET", "maxmemory"],
+            pairs(&[("maxmemory", "1048576")]),
+        )
+        .await;
+    client
+        .assert_reply(&["CONFIG", "SET", "timeout", "300"], simple("OK"))
+        .await;
+
+    fs::write(&path, "port 6380\nmaxmemory 2mb\ntimeout 0\nsave \"\"\n").unwrap();
+    let reload = server.server().reload_config().unwrap();
+    assert_eq!(
+        reload,
+        Reload {
+            applied: vec!["maxmemory".to_string(), "save".to_string()],
+            restart_required: vec!["port".to_string()],
+        }
+    );
+    // timeout did not change in the file, so CONFIG SET still wins.
+    client
+        .assert_reply(
+            &["CONFIG", "GET", "maxmemory", "timeout", "save"],
+            pairs(&[("maxmemory", "2097152"), ("timeout", "300"), ("save", "")]),
+        )
+        .await;
+
+    // The port is still the old one, so it keeps being reported.
+    let reload = server.server().reload_config().unwrap();
+    assert!(reload.applied.is_empty());
+    assert_eq!(reload.restart_required, ["port"]);
+
+    // An invalid value leaves everything as it was.
+    fs::write(&path, "port 6380\nmaxmemory 3mb\ntimeout soon\n").unwrap();
+    assert!(server.server().reload_config().is_err());
+    client
+        .assert_reply(
+            &["CONFIG", "GET", "maxmemory"],
+            pairs(&[("maxmemory", "2097152")]),
+        )
+        .await;
+
+    let _ = fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn startup_parameters_are_immutable() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+    client
+        .assert_reply(
+            &["CONFIG", "SET", "port", "6380"],
+            Type::SimpleError("ERR can't set immutable config 'port'".to_string()),
+        )
+        .await;
+    assert!(master.server().reload_config().is_err());
+}
+
+#[tokio::test]
+async fn writes_are_refused_over_maxmemory() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+    client
+        .assert_reply(&["CONFIG", "SET", "maxmemory", "20"], simple("OK"))
+        .await;
+
+    // Keys and values count, a write is refused once the limit is reached.
+    client
+        .assert_reply(&["SET", "a", "0123456789"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "b", "0123456789"], simple("OK"))
+        .await;
+    client
+        .assert_reply(
+            &["SET", "c", "x"],
+            Type::SimpleError(
+                "OOM command not allowed when used memory > 'maxmemory'.".to_string(),
+            ),
+        )
+        .await;
+    client
+        .assert_reply(&["GET", "c"], Type::NullBulkString)
+        .await;
+    client.assert_reply(&["GET", "a"], bulk("0123456789")).await;
+
+    client
+        .assert_reply(&["CONFIG", "SET", "maxmemory", "0"], simple("OK"))
+        .await;
+    client.assert_reply(&["SET", "c", "x"], simple("OK")).await;
+}
+
+#[tokio::test]
+async fn idle_clients_are_disconnected() {
+    let clock = Arc::new(MockClock::new());
+    let server = Server::builder()
+        .port(0)
+        .clock(clock.clone())
+        .config("timeout", "1")
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = TestClient::connect(server.local_addr()).await;
+    client.assert_reply(&["PING"], simple("PONG")).await;
+
+    // The connection may not be waiting for its next command yet, so give it
+    // a moment and keep moving the clock until it notices.
+    let mut closed = false;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        clock.advance(Duration::from_secs(2));
+        if client.send_raw(&command(&["PING"])).await.is_err() {
+            closed = true;
+            break;
+        }
+    }
+    assert!(closed, "idle connection was never closed");
+}
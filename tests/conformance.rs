@@ -0,0 +1,259 @@
+//! Runs the scripts in `tests/scripts` against this server and a real Redis
+//! and compares the raw replies byte for byte.
+//!
+//! The reference instance is picked in this order:
+//!
+//! * `KV_CONFORMANCE_REDIS=host:port`, an already running instance,
+//! * `redis-server` on `PATH`, started on a free port,
+//! * `KV_CONFORMANCE_DOCKER=1`, a `redis` container started with docker.
+//!
+//! Without any of them the test is skipped. A per family report is printed
+//! and written to `target/conformance-report.txt`, set
+//! `KV_CONFORMANCE_STRICT=1` to fail on any difference.
+
+use anyhow::{bail, Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use redis_starter_rust::client::{command, Client};
+use redis_starter_rust::Server;
+
+enum Reference {
+    External(SocketAddr),
+    Process(Child, SocketAddr),
+    Container(String, SocketAddr),
+}
+
+impl Reference {
+    fn start() -> Result<Option<Self>> {
+        if let Ok(addr) = std::env::var("KV_CONFORMANCE_REDIS") {
+            let addr = addr.parse().context("parsing KV_CONFORMANCE_REDIS")?;
+            return Ok(Some(Reference::External(addr)));
+        }
+
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let spawned = Command::new("redis-server")
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--appendonly",
+                "no",
+            ])
+            .stdout(Stdio::null())
+            .spawn();
+        if let Ok(child) = spawned {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            return Ok(Some(Reference::Process(child, addr)));
+        }
+
+        if std::env::var("KV_CONFORMANCE_DOCKER").as_deref() == Ok("1") {
+            let id = docker(&["run", "--rm", "-d", "-p", "127.0.0.1::6379", "redis"])?;
+            let port = docker(&["port", &id, "6379/tcp"])?;
+            let addr = port
+                .lines()
+                .next()
+                .context("docker port printed nothing")?
+                .parse()
+                .context("parsing docker port mapping")?;
+            return Ok(Some(Reference::Container(id, addr)));
+        }
+
+        Ok(None)
+    }
+
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Reference::External(addr) => *addr,
+            Reference::Process(_, addr) => *addr,
+            Reference::Container(_, addr) => *addr,
+        }
+    }
+}
+
+impl Drop for Reference {
+    fn drop(&mut self) {
+        match self {
+            Reference::External(_) => {}
+            Reference::Process(child, _) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            Reference::Container(id, _) => {
+                let _ = docker(&["stop", id]);
+            }
+        }
+    }
+}
+
+fn docker(args: &[&str]) -> Result<String> {
+    let output = Command::new("docker").args(args).output()?;
+    if !output.status.success() {
+        bail!(
+            "docker {:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+async fn connect_with_retry(addr: SocketAddr) -> Result<Client> {
+    for _ in 0..50 {
+        if let Ok(client) = Client::connect(addr).await {
+            return Ok(client);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    bail!("could not connect to reference instance at {}", addr)
+}
+
+/// Splits a script line into arguments, double quotes group words.
+fn split_line(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut in_arg = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+async fn raw_reply(client: &mut Client, args: &[&str]) -> Result<Vec<u8>> {
+    client.send(command(args)).await?;
+    let reply = tokio::time::timeout(Duration::from_secs(5), client.read_frame())
+        .await
+        .context("waiting for reply")??;
+    let (_, raw) = reply.context("connection closed")?;
+    Ok(raw)
+}
+
+struct FamilyReport {
+    family: String,
+    total: usize,
+    diffs: Vec<String>,
+}
+
+async fn run_script(
+    family: &str,
+    script: &str,
+    ours_addr: SocketAddr,
+    reference: SocketAddr,
+) -> Result<FamilyReport> {
+    // Fresh connections per family, so a reply left unread after an error
+    // can't shift every reply after it.
+    let mut ours = Client::connect(ours_addr).await?;
+    let mut theirs = connect_with_retry(reference).await?;
+    let mut report = FamilyReport {
+        family: family.to_string(),
+        total: 0,
+        diffs: Vec::new(),
+    };
+
+    for line in script.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(ms) = line.strip_prefix("!sleep ") {
+            tokio::time::sleep(Duration::from_millis(ms.parse()?)).await;
+            continue;
+        }
+
+        let args = split_line(line);
+        let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+        report.total += 1;
+
+        let expected = raw_reply(&mut theirs, &args).await?;
+        let actual = match raw_reply(&mut ours, &args).await {
+            Ok(actual) => actual,
+            Err(e) => {
+                report.diffs.push(format!("{}\n    error: {:#}", line, e));
+                ours = Client::connect(ours_addr).await?;
+                continue;
+            }
+        };
+        if actual != expected {
+            report.diffs.push(format!(
+                "{}\n    redis: {:?}\n    ours:  {:?}",
+                line,
+                String::from_utf8_lossy(&expected),
+                String::from_utf8_lossy(&actual)
+            ));
+        }
+    }
+    Ok(report)
+}
+
+#[tokio::test]
+async fn conformance_against_real_redis() -> Result<()> {
+    let Some(reference) = Reference::start()? else {
+        println!("skipping: no reference Redis (see tests/conformance.rs)");
+        return Ok(());
+    };
+    let server = Server::builder().port(0).spawn().await?;
+
+    let scripts_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts = fs::read_dir(&scripts_dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    scripts.sort();
+
+    let mut reports = Vec::new();
+    for path in scripts {
+        let family = path
+            .file_stem()
+            .context("script without a name")?
+            .to_string_lossy()
+            .to_string();
+        let script = fs::read_to_string(&path)?;
+        reports.push(run_script(&family, &script, server.local_addr(), reference.addr()).await?);
+    }
+
+    let mut out = String::new();
+    writeln!(out, "Conformance against redis at {}", reference.addr())?;
+    for report in &reports {
+        let passed = report.total - report.diffs.len();
+        writeln!(
+            out,
+            "{:<12} {:>3}/{:<3}",
+            report.family, passed, report.total
+        )?;
+        for diff in &report.diffs {
+            writeln!(out, "  {}", diff)?;
+        }
+    }
+    println!("{}", out);
+    let target_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target");
+    fs::create_dir_all(&target_dir)?;
+    fs::write(target_dir.join("conformance-report.txt"), &out)?;
+
+    let failures: usize = reports.iter().map(|r| r.diffs.len()).sum();
+    if std::env::var("KV_CONFORMANCE_STRICT").as_deref() == Ok("1") && failures > 0 {
+        bail!("{} replies differ from redis", failures);
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+mod common;
+
+use common::*;
+use flate2::read::GzDecoder;
+use redis_starter_rust::log::{RotatingFile, Rotation};
+use redis_starter_rust::Type;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kv-store-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn gunzip(path: &Path) -> String {
+    let mut rv = String::new();
+    GzDecoder::new(fs::File::open(path).unwrap())
+        .read_to_string(&mut rv)
+        .unwrap();
+    rv
+}
+
+fn write_lines(file: &mut RotatingFile, count: usize) {
+    for i in 0..count {
+        // Every line is 10 bytes with its newline.
+        file.write_line(&format!("line {:04}", i)).unwrap();
+    }
+}
+
+#[test]
+fn rotates_and_compresses_old_segments() {
+    let dir = scratch_dir("rotate-gz");
+    let path = dir.join("server.log");
+    let rotation = Rotation {
+        max_size: 30,
+        max_files: 2,
+        compress: true,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+    write_lines(&mut file, 10);
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "line 0009\n");
+    assert_eq!(
+        gunzip(&dir.join("server.log.1.gz")),
+        "line 0006\nline 0007\nline 0008\n"
+    );
+    assert_eq!(
+        gunzip(&dir.join("server.log.2.gz")),
+        "line 0003\nline 0004\nline 0005\n"
+    );
+    assert!(!dir.join("server.log.3.gz").exists());
+    assert!(!dir.join("server.log.1").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rotates_without_compression() {
+    let dir = scratch_dir("rotate-plain");
+    let path = dir.join("audit.log");
+    let rotation = Rotation {
+        max_size: 20,
+        max_files: 1,
+        compress: false,
+    };
+    let mut file = RotatingFile::open(&path, rotation).unwrap();
+    write_lines(&mut file, 5);
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "line 0004\n");
+    assert_eq!(
+        fs::read_to_string(dir.join("audit.log.1")).unwrap(),
+        "line 0002\nline 0003\n"
+    );
+    assert!(!dir.join("audit.log.2").exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// The logger is process wide, so everything touching it lives in one test.
+#[tokio::test]
+async fn config_controls_the_log_files() {
+    let dir = scratch_dir("config");
+    let logfile = dir.join("server.log");
+    let audit_logfile = dir.join("audit.log");
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    let pairs = |pairs: &[(&str, &str)]| {
+        Type::Array(pairs.iter().flat_map(|(k, v)| [bulk(k), bulk(v)]).collect())
+    };
+    client
+        .assert_reply(
+            &["CONFIG", "GET", "log-*"],
+            pairs(&[
+                ("log-max-size", "104857600"),
+                ("log-max-files", "10"),
+                ("log-compress", "yes"),
+            ]),
+        )
+        .await;
+
+    client
+        .assert_reply(
+            &["CONFIG", "SET", "log-max-size", "1kb", "log-compress", "no"],
+            simple("OK"),
+        )
+        .await;
+    client
+        .assert_reply(
+            &["CONFIG", "GET", "log-max-size", "log-compress"],
+            pairs(&[("log-max-size", "1024"), ("log-compress", "no")]),
+        )
+        .await;
+
+    // A bad value rejects the whole call.
+    let reply = client
+        .send(&["CONFIG", "SET", "log-max-files", "3", "loglevel", "loud"])
+        .await;
+    assert!(matches!(reply, Type::SimpleError(_)), "{:?}", reply);
+    client
+        .assert_reply(
+            &["CONFIG", "GET", "log-max-files"],
+            pairs(&[("log-max-files", "10")]),
+        )
+        .await;
+
+    client
+        .assert_reply(
+            &[
+                "CONFIG",
+                "SET",
+                "loglevel",
+                "debug",
+                "logfile",
+                logfile.to_str().unwrap(),
+                "audit-logfile",
+                audit_logfile.to_str().unwrap(),
+            ],
+            simple("OK"),
+        )
+        .await;
+    client
+        .assert_reply(&["SET", "audited", "1"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["CLIENT", "TRACEID", "req-42"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "traced", "1"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["CLIENT", "TRACEID", ""], simple("OK"))
+        .await;
+    client
+        .assert_reply(
+            &["CONFIG", "SET", "logfile", "", "audit-logfile", ""],
+            simple("OK"),
+        )
+        .await;
+    client
+        .assert_reply(&["CONFIG", "SET", "loglevel", "notice"], simple("OK"))
+        .await;
+
+    let log = fs::read_to_string(&logfile).unwrap();
+    assert!(
+        log.lines()
+            .any(|line| line.contains("\"level\":\"debug\"") && line.contains("Command SET")),
+        "{}",
+        log
+    );
+    assert!(
+        log.lines()
+            .any(|line| line.contains("\"trace_id\":\"req-42\"") && line.contains("Command SET")),
+        "{}",
+        log
+    );
+    let audit = fs::read_to_string(&audit_logfile).unwrap();
+    let lines: Vec<&str> = audit.lines().collect();
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains("\"trace_id\":\"req-42\"")
+                && line.contains("\"key\":\"traced\"")),
+        "{}",
+        audit
+    );
+    assert!(
+        lines
+            .iter()
+            .filter(|line| line.contains("\"key\":\"audited\""))
+            .all(|line| !line.contains("trace_id")),
+        "{}",
+        audit
+    );
+    assert!(
+        lines.iter().any(
+            |line| line.contains("\"command\":\"SET\"") && line.contains("\"key\":\"audited\"")
+        ),
+        "{}",
+        audit
+    );
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains("\"command\":\"CONFIG SET\"")
+                && line.contains("\"key\":\"logfile\"")),
+        "{}",
+        audit
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
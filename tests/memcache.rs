@@ -0,0 +1,158 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::backend::FileBackend;
+use redis_starter_rust::{Server, ServerHandle};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn spawn_with_memcached() -> ServerHandle {
+    Server::builder()
+        .port(0)
+        .memcached_port(0)
+        .spawn()
+        .await
+        .expect("spawning server")
+}
+
+struct MemcacheClient {
+    stream: TcpStream,
+}
+
+impl MemcacheClient {
+    async fn connect(server: &ServerHandle) -> Self {
+        let addr = server.memcached_addr().expect("memcached listener");
+        let stream = TcpStream::connect(addr).await.expect("connecting");
+        Self { stream }
+    }
+
+    /// Sends `request` and reads until the reply ends with `terminator`.
+    async fn send(&mut self, request: &str, terminator: &str) -> String {
+        self.stream.write_all(request.as_bytes()).await.unwrap();
+        let mut reply = Vec::new();
+        let mut buffer = [0; 1024];
+        while !reply.ends_with(terminator.as_bytes()) {
+            let len = tokio::time::timeout(Duration::from_secs(5), self.stream.read(&mut buffer))
+                .await
+                .expect("waiting for reply")
+                .unwrap();
+            assert!(len > 0, "connection closed, got {:?}", reply);
+            reply.extend_from_slice(&buffer[..len]);
+        }
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn set_and_get_with_flags() {
+    let server = spawn_with_memcached().await;
+    let mut client = MemcacheClient::connect(&server).await;
+
+    let reply = client
+        .send("set greeting 42 0 5\r\nhello\r\n", "\r\n")
+        .await;
+    assert_eq!(reply, "STORED\r\n");
+    let reply = client.send("get greeting missing\r\n", "END\r\n").await;
+    assert_eq!(reply, "VALUE greeting 42 5\r\nhello\r\nEND\r\n");
+}
+
+#[tokio::test]
+async fn shares_the_database_with_resp() {
+    let server = spawn_with_memcached().await;
+    let mut memcache = MemcacheClient::connect(&server).await;
+    let mut resp = TestClient::connect(server.local_addr()).await;
+
+    memcache
+        .send("set from_memcache 0 0 3\r\nabc\r\n", "\r\n")
+        .await;
+    resp.assert_reply(&["GET", "from_memcache"], bulk("abc"))
+        .await;
+
+    resp.assert_reply(&["SET", "from_resp", "xyz"], simple("OK"))
+        .await;
+    let reply = memcache.send("get from_resp\r\n", "END\r\n").await;
+    assert_eq!(reply, "VALUE from_resp 0 3\r\nxyz\r\nEND\r\n");
+}
+
+#[tokio::test]
+async fn delete() {
+    let server = spawn_with_memcached().await;
+    let mut client = MemcacheClient::connect(&server).await;
+
+    client.send("set k 0 0 1\r\nv\r\n", "\r\n").await;
+    assert_eq!(client.send("delete k\r\n", "\r\n").await, "DELETED\r\n");
+    assert_eq!(client.send("delete k\r\n", "\r\n").await, "NOT_FOUND\r\n");
+    assert_eq!(client.send("get k\r\n", "END\r\n").await, "END\r\n");
+}
+
+#[tokio::test]
+async fn incr_and_decr() {
+    let server = spawn_with_memcached().await;
+    let mut client = MemcacheClient::connect(&server).await;
+
+    assert_eq!(client.send("incr n 1\r\n", "\r\n").await, "NOT_FOUND\r\n");
+    client.send("set n 7 0 2\r\n10\r\n", "\r\n").await;
+    assert_eq!(client.send("incr n 5\r\n", "\r\n").await, "15\r\n");
+    assert_eq!(client.send("decr n 20\r\n", "\r\n").await, "0\r\n");
+    let reply = client.send("get n\r\n", "END\r\n").await;
+    assert_eq!(reply, "VALUE n 7 1\r\n0\r\nEND\r\n");
+
+    client.send("set s 0 0 3\r\nabc\r\n", "\r\n").await;
+    let reply = client.send("incr s 1\r\n", "\r\n").await;
+    assert!(reply.starts_with("CLIENT_ERROR"), "got {:?}", reply);
+}
+
+#[tokio::test]
+async fn exptime_expires_items() {
+    let server = spawn_with_memcached().await;
+    let mut client = MemcacheClient::connect(&server).await;
+
+    client.send("set soon 0 1 1\r\nx\r\n", "\r\n").await;
+    client.send("set gone 0 -1 1\r\nx\r\n", "\r\n").await;
+    assert_eq!(client.send("get gone\r\n", "END\r\n").await, "END\r\n");
+    let reply = client.send("get soon\r\n", "END\r\n").await;
+    assert_eq!(reply, "VALUE soon 0 1\r\nx\r\nEND\r\n");
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(client.send("get soon\r\n", "END\r\n").await, "END\r\n");
+}
+
+#[tokio::test]
+async fn noreply_and_errors() {
+    let server = spawn_with_memcached().await;
+    let mut client = MemcacheClient::connect(&server).await;
+
+    // Nothing comes back for the noreply set, so the next reply is the get's.
+    let reply = client
+        .send("set quiet 0 0 1 noreply\r\nq\r\nget quiet\r\n", "END\r\n")
+        .await;
+    assert_eq!(reply, "VALUE quiet 0 1\r\nq\r\nEND\r\n");
+
+    assert_eq!(client.send("bogus\r\n", "\r\n").await, "ERROR\r\n");
+    let reply = client.send("set k notanumber 0 1\r\n", "\r\n").await;
+    assert!(reply.starts_with("CLIENT_ERROR"), "got {:?}", reply);
+}
+
+#[tokio::test]
+async fn refuses_modes_it_does_not_support() {
+    let dir = std::env::temp_dir().join(format!("kv-store-memcache-{}", std::process::id()));
+    let backend = Arc::new(FileBackend::open(&dir).unwrap());
+    let spawned = Server::builder()
+        .port(0)
+        .memcached_port(0)
+        .backend(backend)
+        .spawn()
+        .await;
+    assert!(spawned.is_err(), "memcached was combined with a backend");
+
+    let spawned = Server::builder()
+        .port(0)
+        .memcached_port(0)
+        .cluster_slots(0..=16383)
+        .spawn()
+        .await;
+    assert!(spawned.is_err(), "memcached was combined with cluster mode");
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,243 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::raft::RaftRole;
+use redis_starter_rust::{Server, ServerBuilder, ServerHandle, Type};
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::Duration;
+
+/// Three Raft ports that were free a moment ago.
+fn free_ports() -> Vec<u16> {
+    (0..3)
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect::<Vec<_>>()
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().port())
+        .collect()
+}
+
+/// Three nodes in one Raft group, on free Raft ports.
+async fn spawn_group() -> Vec<ServerHandle> {
+    spawn_group_on(&free_ports(), None).await
+}
+
+/// The node with Raft port `port` in the group on `ports`.
+fn node(ports: &[u16], port: u16) -> ServerBuilder {
+    let mut builder = Server::builder()
+        .port(0)
+        .raft_port(port)
+        .raft_election_timeout(Duration::from_millis(200));
+    for &peer in ports.iter().filter(|&&peer| peer != port) {
+        builder = builder.raft_peer("127.0.0.1", peer);
+    }
+    builder
+}
+
+/// Three nodes in one Raft group on `ports`, keeping their state in `dir`
+/// if given.
+async fn spawn_group_on(ports: &[u16], dir: Option<&Path>) -> Vec<ServerHandle> {
+    let mut nodes = Vec::new();
+    for &port in ports {
+        let mut builder = node(ports, port).raft_snapshot_entries(5);
+        if let Some(dir) = dir {
+            builder = builder.raft_dir(dir);
+        }
+        nodes.push(builder.spawn().await.expect("spawning raft node"));
+    }
+    nodes
+}
+
+/// Waits for exactly one of `nodes` to lead and returns its index.
+async fn leader(nodes: &[ServerHandle]) -> usize {
+    let elected = wait_until(Duration::from_secs(10), || async move {
+        let leaders = nodes
+            .iter()
+            .filter(|node| node.server().raft().unwrap().role() == RaftRole::Leader)
+            .count();
+        leaders == 1
+    })
+    .await;
+    assert!(elected, "no single raft leader was elected");
+    nodes
+        .iter()
+        .position(|node| node.server().raft().unwrap().role() == RaftRole::Leader)
+        .unwrap()
+}
+
+/// Sets `key` on the leader, retrying while it is still committing the
+/// entry of its own term.
+async fn set_on(node: &ServerHandle, key: &str, value: &str) {
+    let addr = node.local_addr();
+    let written = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(addr).await;
+        client.send(&["SET", key, value]).await == simple("OK")
+    })
+    .await;
+    assert!(written, "SET {} was never committed", key);
+}
+
+#[tokio::test]
+async fn commits_writes_on_a_majority() {
+    let nodes = spawn_group().await;
+    let leader = leader(&nodes).await;
+    set_on(&nodes[leader], "foo", "bar").await;
+
+    let mut client = TestClient::connect(nodes[leader].local_addr()).await;
+    client.assert_reply(&["GET", "foo"], bulk("bar")).await;
+
+    // Followers apply the write but send clients to the leader.
+    let follower = &nodes[(leader + 1) % nodes.len()];
+    let applied = wait_until(Duration::from_secs(5), || async move {
+        let db = follower.db();
+        let db = db.lock().unwrap();
+        db.peek("foo").is_some_and(|entry| entry.value() == "bar")
+    })
+    .await;
+    assert!(applied, "the follower never applied the write");
+    let redirect = Type::SimpleError(format!("NOTLEADER {}", nodes[leader].local_addr()));
+    let mut client = TestClient::connect(follower.local_addr()).await;
+    client.assert_reply(&["GET", "foo"], redirect.clone()).await;
+    client.assert_reply(&["SET", "foo", "baz"], redirect).await;
+    client.assert_reply(&["PING"], simple("PONG")).await;
+}
+
+#[tokio::test]
+async fn elects_a_new_leader_that_keeps_committed_writes() {
+    let mut nodes = spawn_group().await;
+    let old = leader(&nodes).await;
+    set_on(&nodes[old], "foo", "bar").await;
+    let _ = nodes.remove(old).shutdown(Duration::from_secs(1)).await;
+
+    let new = leader(&nodes).await;
+    let read = wait_until(Duration::from_secs(5), || {
+        let addr = nodes[new].local_addr();
+        async move { TestClient::connect(addr).await.send(&["GET", "foo"]).await == bulk("bar") }
+    })
+    .await;
+    assert!(read, "the new leader lost a committed write");
+    set_on(&nodes[new], "foo", "baz").await;
+
+    // A lone node can't reach a majority.
+    let _ = nodes.remove(new).shutdown(Duration::from_secs(1)).await;
+    let mut client = TestClient::connect(nodes[0].local_addr()).await;
+    let reply = client.send(&["SET", "foo", "qux"]).await;
+    assert!(
+        matches!(reply, Type::SimpleError(_)),
+        "a write without a majority succeeded: {:?}",
+        reply
+    );
+    let db = nodes[0].db();
+    assert_ne!(
+        db.lock().unwrap().peek("foo").map(|entry| entry.value()),
+        Some("qux".to_string())
+    );
+}
+
+#[tokio::test]
+async fn restarted_nodes_keep_their_term_and_log() {
+    let dir = std::env::temp_dir().join(format!("kv-store-raft-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let ports = free_ports();
+    let nodes = spawn_group_on(&ports, Some(&dir)).await;
+    let first = leader(&nodes).await;
+    set_on(&nodes[first], "foo", "bar").await;
+    let terms: Vec<u64> = nodes
+        .iter()
+        .map(|node| node.server().raft().unwrap().term())
+        .collect();
+    for node in nodes {
+        let _ = node.shutdown(Duration::from_secs(1)).await;
+    }
+
+    let nodes = spawn_group_on(&ports, Some(&dir)).await;
+    for (node, term) in nodes.iter().zip(terms) {
+        assert!(node.server().raft().unwrap().term() >= term);
+    }
+    let addr = nodes[leader(&nodes).await].local_addr();
+    let read = wait_until(Duration::from_secs(5), || async move {
+        TestClient::connect(addr).await.send(&["GET", "foo"]).await == bulk("bar")
+    })
+    .await;
+    assert!(read, "the group lost a committed write across restarts");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn compacted_logs_survive_restarts() {
+    let dir = std::env::temp_dir().join(format!("kv-store-raft-snap-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let ports = free_ports();
+    let nodes = spawn_group_on(&ports, Some(&dir)).await;
+    let first = leader(&nodes).await;
+    for i in 0..12 {
+        set_on(&nodes[first], &format!("key{}", i), &i.to_string()).await;
+    }
+    let compacted = wait_until(Duration::from_secs(5), || {
+        let (dir, ports) = (dir.clone(), ports.clone());
+        async move {
+            ports
+                .iter()
+                .all(|port| dir.join(format!("raft-{}.snapshot", port)).exists())
+        }
+    })
+    .await;
+    assert!(compacted, "not every node compacted its log");
+    for node in nodes {
+        let _ = node.shutdown(Duration::from_secs(1)).await;
+    }
+
+    // Each node loads its snapshot before hearing from a leader.
+    let nodes = spawn_group_on(&ports, Some(&dir)).await;
+    for node in &nodes {
+        assert!(node.server().raft().unwrap().last_applied() >= 5);
+        let db = node.db();
+        assert!(db.lock().unwrap().peek("key0").is_some());
+    }
+    let addr = nodes[leader(&nodes).await].local_addr();
+    let read = wait_until(Duration::from_secs(5), || async move {
+        TestClient::connect(addr)
+            .await
+            .send(&["GET", "key11"])
+            .await
+            == bulk("11")
+    })
+    .await;
+    assert!(read, "the group lost a committed write across restarts");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn lagging_followers_get_the_snapshot() {
+    let ports = free_ports();
+    let mut nodes = spawn_group_on(&ports, None).await;
+    let first = leader(&nodes).await;
+    let follower = (first + 1) % nodes.len();
+    let _ = nodes
+        .remove(follower)
+        .shutdown(Duration::from_secs(1))
+        .await;
+    let first = if first > follower { first - 1 } else { first };
+    for i in 0..12 {
+        set_on(&nodes[first], &format!("key{}", i), &i.to_string()).await;
+    }
+
+    // Back without any state, it needs entries the leader compacted away.
+    let node = node(&ports, ports[follower])
+        .raft_snapshot_entries(5)
+        .spawn()
+        .await
+        .expect("spawning raft node");
+    let caught_up = wait_until(Duration::from_secs(5), || {
+        let db = node.db();
+        async move {
+            let db = db.lock().unwrap();
+            (0..12).all(|i| {
+                db.peek(&format!("key{}", i))
+                    .is_some_and(|entry| entry.value() == i.to_string())
+            })
+        }
+    })
+    .await;
+    assert!(caught_up, "the follower never caught up from the snapshot");
+}
@@ -0,0 +1,169 @@
+use redis_starter_rust::rdb::{self, diff, Diff, RdbEntry, Snapshot};
+use std::fs;
+use std::process::Command;
+
+/// The empty snapshot the server sends replicas on FULLRESYNC, as saved by
+/// Redis 7.2 with a checksum.
+const EMPTY_RDB: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// An RDB file holding `entries`, saved without a checksum.
+fn rdb(entries: &[(&str, &[u8], Option<u64>)]) -> Vec<u8> {
+    let mut bytes = b"REDIS0011".to_vec();
+    bytes.extend([0xfe, 0x00, 0xfb, entries.len() as u8, 0x00]);
+    for (key, value, expiry) in entries {
+        if let Some(expiry) = expiry {
+            bytes.push(0xfc);
+            bytes.extend(expiry.to_le_bytes());
+        }
+        bytes.extend([0x00, key.len() as u8]);
+        bytes.extend(key.as_bytes());
+        bytes.extend(*value);
+    }
+    bytes.push(0xff);
+    bytes.extend([0; 8]);
+    bytes
+}
+
+#[test]
+fn reads_snapshots_and_checks_their_checksum() {
+    let empty = from_hex(EMPTY_RDB);
+    assert_eq!(Snapshot::parse(&empty).unwrap(), Snapshot::default());
+    let mut corrupt = empty.clone();
+    // A letter of "redis-ver", so only the checksum can tell.
+    corrupt[12] ^= 1;
+    let e = Snapshot::parse(&corrupt).unwrap_err();
+    assert_eq!(e.to_string(), "checksum mismatch");
+
+    let snapshot = Snapshot::parse(&rdb(&[
+        ("plain", b"\x03abc", None),
+        ("int16", b"\xc1\x39\x30", None),
+        // 10 'a's: one literal then a back-reference repeating it 9 times.
+        (
+            "lzf",
+            b"\xc3\x05\x0a\x00a\xe0\x00\x00",
+            Some(1_700_000_000_000),
+        ),
+    ]))
+    .unwrap();
+    let entry = |value: &str, expiry| RdbEntry {
+        value: value.as_bytes().to_vec(),
+        expiry,
+    };
+    let entries: Vec<_> = snapshot.entries().iter().collect();
+    assert_eq!(
+        entries,
+        [
+            (&"int16".to_string(), &entry("12345", None)),
+            (
+                &"lzf".to_string(),
+                &entry("aaaaaaaaaa", Some(1_700_000_000_000))
+            ),
+            (&"plain".to_string(), &entry("abc", None)),
+        ]
+    );
+
+    assert!(Snapshot::parse(b"REDIS0011\x05").is_err());
+    assert!(Snapshot::parse(b"NOTREDIS").is_err());
+}
+
+#[test]
+fn written_snapshots_read_back() {
+    let mut snapshot = Snapshot::default();
+    for (key, len, expiry) in [
+        ("empty", 0, None),
+        ("short", 10, Some(1_700_000_000_000)),
+        ("medium", 1_000, None),
+        ("long", 100_000, Some(1)),
+    ] {
+        let value = "v".repeat(len).into_bytes();
+        snapshot.insert(key.to_string(), RdbEntry { value, expiry });
+    }
+    let bytes = snapshot.serialize();
+    assert_eq!(Snapshot::parse(&bytes).unwrap(), snapshot);
+    assert_eq!(rdb::from_hex(&rdb::to_hex(&bytes)).unwrap(), bytes);
+    assert_eq!(
+        Snapshot::default().serialize(),
+        Snapshot::parse(&Snapshot::default().serialize())
+            .unwrap()
+            .serialize()
+    );
+    assert!(rdb::from_hex("abc").is_err());
+    assert!(rdb::from_hex("zz").is_err());
+}
+
+#[test]
+fn diffs_snapshots() {
+    let a = Snapshot::parse(&rdb(&[
+        ("user:1", b"\x01a", None),
+        ("user:2", b"\x01b", None),
+        ("user:3", b"\x01c", None),
+        ("job:1", b"\x01x", None),
+    ]))
+    .unwrap();
+    let b = Snapshot::parse(&rdb(&[
+        ("user:1", b"\x01a", None),
+        ("user:2", b"\x01B", None),
+        ("user:3", b"\x01c", Some(1)),
+        ("user:4", b"\x01d", None),
+    ]))
+    .unwrap();
+    let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect();
+
+    assert_eq!(
+        diff(&a, &b, None),
+        Diff {
+            added: keys(&["user:4"]),
+            removed: keys(&["job:1"]),
+            changed: keys(&["user:2", "user:3"]),
+        }
+    );
+    assert_eq!(
+        diff(&a, &b, Some("job:*")),
+        Diff {
+            removed: keys(&["job:1"]),
+            ..Diff::default()
+        }
+    );
+    assert!(diff(&a, &a, None).is_empty());
+}
+
+#[test]
+fn diff_rdb_flag_lists_differences() {
+    let dir = std::env::temp_dir().join(format!("kv-store-rdb-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (dir.join("a.rdb"), dir.join("b.rdb"));
+    fs::write(&a, rdb(&[("foo", b"\x011", None), ("bar", b"\x012", None)])).unwrap();
+    fs::write(&b, rdb(&[("foo", b"\x01x", None), ("baz", b"\x013", None)])).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_redis-starter-rust"))
+        .arg("--diff-rdb")
+        .args([&a, &b])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "+ baz\n- bar\n~ foo\n1 added, 1 removed, 1 changed\n"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_redis-starter-rust"))
+        .arg("--diff-rdb")
+        .args([&a, &a])
+        .args(["--diff-pattern", "f*"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "0 added, 0 removed, 0 changed\n"
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,214 @@
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use redis_starter_rust::client::Client;
+use redis_starter_rust::command::Command;
+use redis_starter_rust::frame::Frame;
+use redis_starter_rust::resptype::*;
+
+/// Text that is valid inside a simple string or error, i.e. without CR/LF.
+fn line() -> impl Strategy<Value = String> {
+    "[^\r\n]{0,32}"
+}
+
+fn leaf() -> impl Strategy<Value = Type> {
+    prop_oneof![
+        line().prop_map(Type::SimpleString),
+        line().prop_map(Type::SimpleError),
+        any::<String>().prop_map(Type::BulkString),
+        Just(Type::NullBulkString),
+        any::<i64>().prop_map(|i| Type::Integer(i.to_string())),
+    ]
+}
+
+fn resp_type() -> impl Strategy<Value = Type> {
+    leaf().prop_recursive(4, 64, 8, |inner| vec(inner, 0..8).prop_map(Type::Array))
+}
+
+fn arg() -> impl Strategy<Value = String> {
+    any::<String>()
+}
+
+fn command_frame() -> impl Strategy<Value = (Command, Vec<String>)> {
+    prop_oneof![
+        Just((Command::Ping, vec![])),
+        arg().prop_map(|msg| (Command::Echo, vec![msg])),
+        arg().prop_map(|key| (Command::Get, vec![key])),
+        (arg(), arg()).prop_map(|(key, val)| (Command::Set, vec![key, val])),
+        (arg(), arg(), any::<u32>()).prop_map(|(key, val, px)| {
+            (
+                Command::Set,
+                vec![key, val, "PX".to_string(), px.to_string()],
+            )
+        }),
+        Just((Command::Info, vec![])),
+        arg().prop_map(|section| (Command::Info, vec![section])),
+        (arg(), arg()).prop_map(|(k, v)| (Command::ReplConf, vec![k, v])),
+        (arg(), arg()).prop_map(|(id, offset)| (Command::PSync, vec![id, offset])),
+        arg().prop_map(|pattern| (Command::Keys, vec![pattern])),
+        arg().prop_map(|key| (Command::PTtl, vec![key])),
+        Just((Command::Time, vec![])),
+        arg().prop_map(|sub| (Command::SlowLog, vec![sub])),
+        (arg(), arg()).prop_map(|(sub, count)| (Command::SlowLog, vec![sub, count])),
+        proptest::collection::vec(arg(), 1..4).prop_map(|args| (Command::Config, args)),
+        proptest::collection::vec(arg(), 1..3).prop_map(|args| (Command::Cluster, args)),
+        Just((Command::Asking, vec![])),
+        proptest::collection::vec(arg(), 5..8).prop_map(|args| (Command::Migrate, args)),
+        proptest::collection::vec(arg(), 1..4).prop_map(|keys| (Command::MGet, keys)),
+        proptest::collection::vec((arg(), arg()), 1..4).prop_map(|pairs| {
+            let args = pairs.into_iter().flat_map(|(k, v)| [k, v]).collect();
+            (Command::MSet, args)
+        }),
+        Just((Command::Multi, vec![])),
+        Just((Command::Exec, vec![])),
+        Just((Command::Exec, vec!["DRYRUN".to_string()])),
+        Just((Command::Discard, vec![])),
+        (arg(), arg()).prop_map(|(host, port)| (Command::ReplicaOf, vec![host, port])),
+        arg().prop_map(|key| (Command::GetVer, vec![key])),
+        proptest::collection::vec(arg(), 1..3).prop_map(|args| (Command::Client, args)),
+        proptest::collection::vec(arg(), 1..4).prop_map(|keys| (Command::Del, keys)),
+        (arg(), arg()).prop_map(|(key, val)| (Command::DelIfEq, vec![key, val])),
+        (arg(), arg()).prop_map(|(key, val)| (Command::Append, vec![key, val])),
+        (arg(), arg()).prop_map(|(sub, key)| (Command::Object, vec![sub, key])),
+        proptest::collection::vec(arg(), 1..3).prop_map(|args| (Command::Auth, args)),
+        (arg(), arg(), any::<u64>()).prop_map(|(key, val, version)| {
+            (
+                Command::Set,
+                vec![key, val, "IFVERSION".to_string(), version.to_string()],
+            )
+        }),
+    ]
+}
+
+/// `depth` arrays of one element each, around an integer.
+fn nested(depth: usize) -> Vec<u8> {
+    let mut bytes = b"*1\r\n".repeat(depth);
+    bytes.extend_from_slice(b":1\r\n");
+    bytes
+}
+
+fn encode(command: &Command, args: &[String]) -> Vec<u8> {
+    let mut tokens = vec![Type::BulkString(command.name().to_string())];
+    tokens.extend(args.iter().cloned().map(Type::BulkString));
+    Type::Array(tokens).serialize()
+}
+
+proptest! {
+    #[test]
+    fn type_roundtrips(value in resp_type()) {
+        let bytes = value.clone().serialize();
+        let (parsed, cursor) = parse_resp(&bytes).unwrap();
+        prop_assert_eq!(cursor, bytes.len());
+        prop_assert_eq!(&parsed, &value);
+        prop_assert_eq!(parsed.serialize(), bytes);
+    }
+
+    #[test]
+    fn display_matches_serialize(value in resp_type()) {
+        prop_assert_eq!(value.to_string().into_bytes(), value.serialize());
+    }
+
+    #[test]
+    fn concatenated_values_parse_in_order(values in vec(resp_type(), 1..8)) {
+        let bytes: Vec<u8> = values.iter().cloned().flat_map(Type::serialize).collect();
+        let mut cursor = 0;
+        for value in values {
+            let (parsed, len) = parse_resp(&bytes[cursor..]).unwrap();
+            prop_assert_eq!(parsed, value);
+            cursor += len;
+        }
+        prop_assert_eq!(cursor, bytes.len());
+    }
+
+    #[test]
+    fn truncated_values_are_rejected(value in resp_type(), cut in any::<prop::sample::Index>()) {
+        let bytes = value.serialize();
+        let cut = cut.index(bytes.len());
+        let err = parse_resp(&bytes[..cut]).unwrap_err();
+        prop_assert!(err.is::<Incomplete>(), "{:#}", err);
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_parses(depth in 0..=MAX_DEPTH) {
+        let bytes = nested(depth);
+        let (_, cursor) = parse_resp(&bytes).unwrap();
+        prop_assert_eq!(cursor, bytes.len());
+    }
+
+    #[test]
+    fn deeper_nesting_is_a_protocol_error(depth in MAX_DEPTH + 1..4 * MAX_DEPTH) {
+        let bytes = nested(depth);
+        let err = parse_resp(&bytes).unwrap_err();
+        prop_assert!(!err.is::<Incomplete>(), "{:#}", err);
+        let err = parse_resp(&bytes[..4 * (MAX_DEPTH + 1)]).unwrap_err();
+        prop_assert!(!err.is::<Incomplete>(), "{:#}", err);
+    }
+
+    #[test]
+    fn command_frames_roundtrip((command, args) in command_frame()) {
+        let bytes = encode(&command, &args);
+        let frame = Frame::new(&bytes, bytes.len()).unwrap();
+        prop_assert_eq!(frame.command(), command);
+        prop_assert_eq!(frame.args().unwrap_or_default(), args);
+        prop_assert_eq!(frame.bytes_vec(), bytes.clone());
+        prop_assert_eq!(frame.serialize(), bytes);
+    }
+}
+
+#[test]
+fn very_deep_nesting_does_not_overflow_the_stack() {
+    let err = parse_resp(&nested(300_000)).unwrap_err();
+    assert!(!err.is::<Incomplete>(), "{:#}", err);
+}
+
+#[test]
+fn oversized_bulk_strings_are_rejected() {
+    let bytes = format!("${}\r\n", MAX_FRAME_LEN + 1).into_bytes();
+    let err = parse_resp(&bytes).unwrap_err();
+    assert!(!err.is::<Incomplete>(), "{:#}", err);
+}
+
+/// A client reading from a peer that writes `bytes` a few at a time.
+async fn client_reading(bytes: Vec<u8>, chunk: usize) -> Client {
+    use tokio::io::AsyncWriteExt;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        for piece in bytes.chunks(chunk) {
+            stream.write_all(piece).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::task::yield_now().await;
+        }
+    });
+    Client::connect(addr).await.unwrap()
+}
+
+#[tokio::test]
+async fn client_reads_values_arriving_in_pieces() {
+    let values = vec![
+        Type::SimpleString("OK".to_string()),
+        Type::Array(vec![
+            Type::BulkString("x".repeat(10_000)),
+            Type::Array(vec![]),
+            Type::NullBulkString,
+            Type::Array(vec![Type::Integer("7".to_string())]),
+        ]),
+        Type::BulkString(String::new()),
+        Type::SimpleError("ERR no".to_string()),
+    ];
+    let bytes: Vec<u8> = values.iter().cloned().flat_map(Type::serialize).collect();
+    let mut client = client_reading(bytes, 3).await;
+    for value in values {
+        let (parsed, raw) = client.read_frame().await.unwrap().unwrap();
+        assert_eq!(raw, value.clone().serialize());
+        assert_eq!(parsed, value);
+    }
+    assert!(client.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn client_refuses_deeply_nested_values() {
+    let mut client = client_reading(nested(300_000), 4096).await;
+    assert!(client.read_frame().await.is_err());
+}
@@ -0,0 +1,276 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::client::Client;
+use redis_starter_rust::sentinel::{Sentinel, SentinelHandle};
+use redis_starter_rust::{ServerHandle, Type};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A sentinel watching `master` as `mymaster` that gives up on instances
+/// after 300ms without an answer.
+async fn spawn_sentinel(master: &ServerHandle, peer: Option<&SentinelHandle>) -> SentinelHandle {
+    let addr = master.local_addr();
+    let mut builder = Sentinel::builder()
+        .port(0)
+        .monitor("mymaster", addr.ip().to_string(), addr.port(), 2)
+        .down_after(Duration::from_millis(300))
+        .failover_timeout(Duration::from_secs(2));
+    if let Some(peer) = peer {
+        let peer = peer.local_addr();
+        builder = builder.peer(peer.ip().to_string(), peer.port());
+    }
+    builder.spawn().await.expect("spawning sentinel")
+}
+
+async fn count(addr: SocketAddr, args: &[&str]) -> usize {
+    match TestClient::connect(addr).await.send(args).await {
+        Type::Array(items) => items.len(),
+        reply => panic!("unexpected reply to {:?}: {:?}", args, reply),
+    }
+}
+
+fn addr_reply(addr: SocketAddr) -> Type {
+    Type::Array(vec![
+        bulk(&addr.ip().to_string()),
+        bulk(&addr.port().to_string()),
+    ])
+}
+
+#[tokio::test]
+async fn reports_monitored_masters() {
+    let master = spawn_master().await;
+    let _replica = spawn_replica(&master).await;
+    let sentinel = spawn_sentinel(&master, None).await;
+    let addr = sentinel.local_addr();
+    let mut client = TestClient::connect(addr).await;
+
+    client.assert_reply(&["PING"], simple("PONG")).await;
+    client
+        .assert_reply(
+            &["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"],
+            addr_reply(master.local_addr()),
+        )
+        .await;
+    client
+        .assert_reply(
+            &["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "other"],
+            Type::NullBulkString,
+        )
+        .await;
+    client
+        .assert_reply(
+            &["SENTINEL", "MASTER", "other"],
+            Type::SimpleError("ERR No such master with that name other".to_string()),
+        )
+        .await;
+
+    // Replicas are found through the master's INFO.
+    let found = wait_until(Duration::from_secs(5), || async move {
+        count(addr, &["SENTINEL", "REPLICAS", "mymaster"]).await == 1
+    })
+    .await;
+    assert!(found, "the replica was never discovered");
+    let Type::Array(fields) = client.send(&["SENTINEL", "MASTER", "mymaster"]).await else {
+        panic!("SENTINEL MASTER should return an array");
+    };
+    let fields: Vec<Type> = fields.into_iter().take(8).collect();
+    assert_eq!(fields[6], bulk("flags"));
+    assert_eq!(fields[7], bulk("master"));
+}
+
+#[tokio::test]
+async fn fails_over_to_a_replica() {
+    let master = spawn_master().await;
+    let old_addr = master.local_addr();
+    let replicas = [spawn_replica(&master).await, spawn_replica(&master).await];
+    let first = spawn_sentinel(&master, None).await;
+    let sentinels = [
+        spawn_sentinel(&master, Some(&first)).await,
+        spawn_sentinel(&master, Some(&first)).await,
+        first,
+    ];
+    let addrs: Vec<SocketAddr> = sentinels.iter().map(|s| s.local_addr()).collect();
+
+    // Sentinels find each other through the first one.
+    let ready = wait_until(Duration::from_secs(5), || {
+        let addrs = addrs.clone();
+        async move {
+            for addr in addrs {
+                if count(addr, &["SENTINEL", "SENTINELS", "mymaster"]).await != 2
+                    || count(addr, &["SENTINEL", "REPLICAS", "mymaster"]).await != 2
+                {
+                    return false;
+                }
+            }
+            true
+        }
+    })
+    .await;
+    assert!(
+        ready,
+        "sentinels never discovered each other and the replicas"
+    );
+
+    let mut subscriber = Client::connect(addrs[0]).await.unwrap();
+    let reply = subscriber
+        .request(&["SUBSCRIBE", "+switch-master"])
+        .await
+        .unwrap();
+    assert_eq!(
+        reply,
+        Type::Array(vec![
+            bulk("subscribe"),
+            bulk("+switch-master"),
+            Type::Integer("1".to_string())
+        ])
+    );
+
+    let _ = master.shutdown(Duration::from_secs(1)).await;
+
+    let replica_addrs: Vec<SocketAddr> = replicas.iter().map(|r| r.local_addr()).collect();
+    let switched = wait_until(Duration::from_secs(10), || {
+        let sentinels = &sentinels;
+        let replica_addrs = &replica_addrs;
+        async move {
+            let masters: Vec<_> = sentinels
+                .iter()
+                .map(|s| s.sentinel().master_addr("mymaster"))
+                .collect();
+            masters[0].is_some_and(|addr| replica_addrs.contains(&addr))
+                && masters.iter().all(|m| *m == masters[0])
+        }
+    })
+    .await;
+    assert!(switched, "the sentinels never agreed on a new master");
+    let new_addr = sentinels[0].sentinel().master_addr("mymaster").unwrap();
+
+    let message = tokio::time::timeout(Duration::from_secs(5), subscriber.read_reply())
+        .await
+        .expect("no +switch-master message")
+        .unwrap();
+    let payload = format!(
+        "mymaster {} {} {} {}",
+        old_addr.ip(),
+        old_addr.port(),
+        new_addr.ip(),
+        new_addr.port()
+    );
+    assert_eq!(
+        message,
+        Type::Array(vec![
+            bulk("message"),
+            bulk("+switch-master"),
+            bulk(&payload)
+        ])
+    );
+
+    // The other replica follows the new master.
+    let (promoted, other) = match replica_addrs[0] == new_addr {
+        true => (&replicas[0], &replicas[1]),
+        false => (&replicas[1], &replicas[0]),
+    };
+    let mut client = TestClient::connect(promoted.local_addr()).await;
+    assert_eq!(
+        client.info_field("replication", "role").await.as_deref(),
+        Some("master")
+    );
+    let other_addr = other.local_addr();
+    let following = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(other_addr).await;
+        client.info_field("replication", "master_port").await == Some(new_addr.port().to_string())
+    })
+    .await;
+    assert!(following, "the other replica never followed the new master");
+
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut master = TestClient::connect(new_addr).await;
+        master.send(&["SET", "foo", "1"]).await;
+        let mut client = TestClient::connect(other_addr).await;
+        client.send(&["GET", "foo"]).await == bulk("1")
+    })
+    .await;
+    assert!(
+        replicated,
+        "writes on the new master never reached the replica"
+    );
+}
+
+#[tokio::test]
+async fn only_trusts_hellos_from_checked_sentinels() {
+    let master = spawn_master().await;
+    let replica = spawn_replica(&master).await;
+    let master_addr = master.local_addr();
+    let replica_addr = replica.local_addr();
+    // Stands in for a sentinel given with `--sentinel-peer`, it never answers.
+    let peer = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let sentinel = Sentinel::builder()
+        .port(0)
+        .monitor(
+            "mymaster",
+            master_addr.ip().to_string(),
+            master_addr.port(),
+            1,
+        )
+        .peer(peer_addr.ip().to_string(), peer_addr.port())
+        .down_after(Duration::from_millis(300))
+        .spawn()
+        .await
+        .unwrap();
+    let addr = sentinel.local_addr();
+    let mut client = TestClient::connect(addr).await;
+    // A HELLO from `from` saying `to` is the master in config epoch `epoch`.
+    let hello = |from: SocketAddr, to: SocketAddr, epoch: &str| {
+        let (from_ip, from_port) = (from.ip().to_string(), from.port().to_string());
+        let (ip, port) = (to.ip().to_string(), to.port().to_string());
+        let runid = "0".repeat(40);
+        let args = [
+            "SENTINEL", "HELLO", &from_ip, &from_port, &runid, epoch, "mymaster", &ip, &port, epoch,
+        ];
+        command(&args)
+    };
+
+    // The announced address must be the one the HELLO comes from.
+    let reply = client
+        .send_raw(&hello(
+            "10.1.2.3:26379".parse().unwrap(),
+            replica_addr,
+            "100",
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(reply, Type::SimpleError(_)), "{:?}", reply);
+
+    // The master is not a sentinel, so it never becomes a peer and its
+    // HELLO does not move the master.
+    client
+        .send_raw(&hello(master_addr, replica_addr, "100"))
+        .await
+        .unwrap();
+    // A known peer's HELLO names a replica, which is not a master.
+    client
+        .send_raw(&hello(peer_addr, replica_addr, "100"))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(count(addr, &["SENTINEL", "SENTINELS", "mymaster"]).await, 1);
+    assert_eq!(
+        sentinel.sentinel().master_addr("mymaster"),
+        Some(master_addr)
+    );
+
+    // Naming an instance that is a master is followed.
+    let other = spawn_master().await;
+    let other_addr = other.local_addr();
+    client
+        .send_raw(&hello(peer_addr, other_addr, "101"))
+        .await
+        .unwrap();
+    let switched = wait_until(Duration::from_secs(5), || {
+        let sentinel = &sentinel;
+        async move { sentinel.sentinel().master_addr("mymaster") == Some(other_addr) }
+    })
+    .await;
+    assert!(switched, "the announced master was never switched to");
+}
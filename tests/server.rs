@@ -0,0 +1,711 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::Type;
+use std::time::Duration;
+
+#[tokio::test]
+async fn ping() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client.assert_reply(&["PING"], simple("PONG")).await;
+    let reply = client.send_raw(b"*1\r\n$4\r\nping\r\n").await.unwrap();
+    assert_eq!(reply, simple("PONG"));
+}
+
+#[tokio::test]
+async fn echo() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client.assert_reply(&["ECHO", "Hello"], bulk("Hello")).await;
+}
+
+#[tokio::test]
+async fn set_and_get() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(&["SET", "foo", "Bar"], simple("OK"))
+        .await;
+    client.assert_reply(&["GET", "foo"], bulk("Bar")).await;
+    client
+        .assert_reply(&["SET", "foo", "baz"], simple("OK"))
+        .await;
+    client.assert_reply(&["GET", "foo"], bulk("baz")).await;
+    client
+        .assert_reply(&["GET", "missing"], Type::NullBulkString)
+        .await;
+}
+
+#[tokio::test]
+async fn keys_are_shared_between_connections() {
+    let master = spawn_master().await;
+    let mut first = TestClient::connect(master.local_addr()).await;
+    let mut second = TestClient::connect(master.local_addr()).await;
+
+    first
+        .assert_reply(&["SET", "shared", "1"], simple("OK"))
+        .await;
+    second.assert_reply(&["GET", "shared"], bulk("1")).await;
+}
+
+#[tokio::test]
+async fn set_with_px_expires() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(&["SET", "temp", "value", "PX", "100"], simple("OK"))
+        .await;
+    client.assert_reply(&["GET", "temp"], bulk("value")).await;
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    client
+        .assert_reply(&["GET", "temp"], Type::NullBulkString)
+        .await;
+}
+
+#[tokio::test]
+async fn info_replication_reports_role() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    assert_eq!(
+        client.info_field("replication", "role").await.as_deref(),
+        Some("master")
+    );
+    assert_eq!(
+        client.info_field("replication", "tcp_port").await,
+        Some(master.local_addr().port().to_string())
+    );
+}
+
+#[tokio::test]
+async fn replication_handshake() {
+    let master = spawn_master().await;
+    let replica = spawn_replica(&master).await;
+
+    let mut client = TestClient::connect(replica.local_addr()).await;
+    assert_eq!(
+        client.info_field("replication", "role").await.as_deref(),
+        Some("slave")
+    );
+    assert_eq!(
+        client.info_field("replication", "master_port").await,
+        Some(master.local_addr().port().to_string())
+    );
+
+    let master_addr = master.local_addr();
+    let connected = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(master_addr).await;
+        client
+            .info_field("replication", "connected_slaves")
+            .await
+            .as_deref()
+            == Some("1")
+    })
+    .await;
+    assert!(connected, "replica never completed the handshake");
+}
+
+#[tokio::test]
+async fn writes_on_master_reach_replica() {
+    let master = spawn_master().await;
+    let replica = spawn_replica(&master).await;
+
+    let master_addr = master.local_addr();
+    let connected = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(master_addr).await;
+        client
+            .info_field("replication", "connected_slaves")
+            .await
+            .as_deref()
+            == Some("1")
+    })
+    .await;
+    assert!(connected, "replica never completed the handshake");
+
+    let mut client = TestClient::connect(master_addr).await;
+    client
+        .assert_reply(&["SET", "foo", "1"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "bar", "2"], simple("OK"))
+        .await;
+
+    let replica_addr = replica.local_addr();
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "foo"]).await == bulk("1")
+            && client.send(&["GET", "bar"]).await == bulk("2")
+    })
+    .await;
+    assert!(replicated, "writes were not applied on the replica");
+}
+
+#[tokio::test]
+async fn replicas_start_from_the_masters_keys() {
+    let master = spawn_master().await;
+    let master_addr = master.local_addr();
+    let mut client = TestClient::connect(master_addr).await;
+    client
+        .assert_reply(&["SET", "foo", "1"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "bar", "2", "PX", "100000"], simple("OK"))
+        .await;
+
+    // The keys written before the replica connected come with the RDB file,
+    // later writes are propagated.
+    let replica = spawn_replica(&master).await;
+    let replica_addr = replica.local_addr();
+    let synced = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "foo"]).await == bulk("1")
+    })
+    .await;
+    assert!(synced, "the replica never loaded the master's keys");
+    let mut replica_client = TestClient::connect(replica_addr).await;
+    replica_client
+        .assert_reply(&["GET", "bar"], bulk("2"))
+        .await;
+    let Type::Integer(ttl) = replica_client.send(&["PTTL", "bar"]).await else {
+        panic!("PTTL should reply with an integer");
+    };
+    assert!(ttl.parse::<i64>().unwrap() > 0, "{}", ttl);
+
+    client
+        .assert_reply(&["SET", "foo", "3"], simple("OK"))
+        .await;
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "foo"]).await == bulk("3")
+    })
+    .await;
+    assert!(
+        replicated,
+        "writes after the sync never reached the replica"
+    );
+}
+
+#[tokio::test]
+async fn replicaof_switches_masters_at_runtime() {
+    let master = spawn_master().await;
+    let other = spawn_master().await;
+    let replica = spawn_replica(&master).await;
+    let mut client = TestClient::connect(replica.local_addr()).await;
+
+    // The master lists the replica where it serves clients.
+    let master_addr = master.local_addr();
+    let replica_port = replica.local_addr().port();
+    let listed = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(master_addr).await;
+        client.info_field("replication", "slave0").await
+            == Some(format!(
+                "ip=127.0.0.1,port={},state=online,offset=0,lag=0",
+                replica_port
+            ))
+    })
+    .await;
+    assert!(listed, "the master never listed its replica");
+
+    let other_port = other.local_addr().port().to_string();
+    client
+        .assert_reply(&["REPLICAOF", "127.0.0.1", &other_port], simple("OK"))
+        .await;
+    assert_eq!(
+        client.info_field("replication", "master_port").await,
+        Some(other_port)
+    );
+    // The snapshot sent on PSYNC is empty, so keep writing until the replica
+    // has finished the handshake.
+    let (other_addr, replica_addr) = (other.local_addr(), replica.local_addr());
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut writer = TestClient::connect(other_addr).await;
+        writer.send(&["SET", "foo", "1"]).await;
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "foo"]).await == bulk("1")
+    })
+    .await;
+    assert!(replicated, "writes on the new master never arrived");
+
+    client
+        .assert_reply(&["REPLICAOF", "NO", "ONE"], simple("OK"))
+        .await;
+    assert_eq!(
+        client.info_field("replication", "role").await.as_deref(),
+        Some("master")
+    );
+}
+
+#[tokio::test]
+async fn psync_replies_with_fullresync_and_rdb() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(&["REPLCONF", "listening-port", "6380"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["REPLCONF", "capa", "psync2"], simple("OK"))
+        .await;
+
+    let Type::SimpleString(reply) = client.send(&["PSYNC", "?", "-1"]).await else {
+        panic!("expected a simple string reply to PSYNC");
+    };
+    assert!(reply.starts_with("FULLRESYNC "), "{}", reply);
+}
+
+#[tokio::test]
+async fn mset_and_mget() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(&["MSET", "a", "1", "b", "2"], simple("OK"))
+        .await;
+    client
+        .assert_reply(
+            &["MGET", "a", "missing", "b"],
+            Type::Array(vec![bulk("1"), Type::NullBulkString, bulk("2")]),
+        )
+        .await;
+    let reply = client.send(&["MSET", "a", "1", "b"]).await;
+    assert!(matches!(reply, Type::SimpleError(_)), "{:?}", reply);
+}
+
+#[tokio::test]
+async fn set_ifversion_checks_the_key_version() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+    let version = |n: u64| Type::Integer(n.to_string());
+
+    client.assert_reply(&["GETVER", "foo"], version(0)).await;
+    client
+        .assert_reply(&["SET", "foo", "a", "IFVERSION", "1"], Type::NullBulkString)
+        .await;
+    client
+        .assert_reply(&["SET", "foo", "a", "IFVERSION", "0"], simple("OK"))
+        .await;
+    client.assert_reply(&["GETVER", "foo"], version(1)).await;
+    client
+        .assert_reply(&["SET", "foo", "b"], simple("OK"))
+        .await;
+    client.assert_reply(&["GETVER", "foo"], version(2)).await;
+
+    // A client that read version 1 loses to the write above.
+    client
+        .assert_reply(&["SET", "foo", "c", "IFVERSION", "1"], Type::NullBulkString)
+        .await;
+    client.assert_reply(&["GET", "foo"], bulk("b")).await;
+    client
+        .assert_reply(
+            &["SET", "foo", "c", "PX", "100000", "IFVERSION", "2"],
+            simple("OK"),
+        )
+        .await;
+    client.assert_reply(&["GET", "foo"], bulk("c")).await;
+    client.assert_reply(&["GETVER", "foo"], version(3)).await;
+
+    // Versions are not handed out again once a key is deleted.
+    client
+        .assert_reply(&["DEL", "foo"], Type::Integer("1".to_string()))
+        .await;
+    client
+        .assert_reply(&["SET", "foo", "d", "IFVERSION", "0"], simple("OK"))
+        .await;
+    client.assert_reply(&["GETVER", "foo"], version(4)).await;
+    client
+        .assert_reply(&["SET", "foo", "e", "IFVERSION", "1"], Type::NullBulkString)
+        .await;
+}
+
+#[tokio::test]
+async fn set_ifversion_reaches_replicas_only_when_applied() {
+    let master = spawn_master().await;
+    let master_addr = master.local_addr();
+    let mut client = TestClient::connect(master_addr).await;
+    // Written before the replica connects, so versions differ between them.
+    client
+        .assert_reply(&["SET", "before", "1"], simple("OK"))
+        .await;
+    let replica = spawn_replica(&master).await;
+    let connected = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(master_addr).await;
+        client
+            .info_field("replication", "connected_slaves")
+            .await
+            .as_deref()
+            == Some("1")
+    })
+    .await;
+    assert!(connected, "replica never completed the handshake");
+
+    client
+        .assert_reply(&["SET", "foo", "a"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["GETVER", "foo"], Type::Integer("2".to_string()))
+        .await;
+    client
+        .assert_reply(&["SET", "foo", "b", "IFVERSION", "1"], Type::NullBulkString)
+        .await;
+    client
+        .assert_reply(&["SET", "foo", "c", "IFVERSION", "2"], simple("OK"))
+        .await;
+
+    let replica_addr = replica.local_addr();
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "foo"]).await == bulk("c")
+    })
+    .await;
+    assert!(
+        replicated,
+        "the conditional SET was not applied on the replica"
+    );
+}
+
+#[tokio::test]
+async fn delifeq_deletes_matching_values_on_replicas_too() {
+    let master = spawn_master().await;
+    let replica = spawn_replica(&master).await;
+    let master_addr = master.local_addr();
+    let connected = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(master_addr).await;
+        client
+            .info_field("replication", "connected_slaves")
+            .await
+            .as_deref()
+            == Some("1")
+    })
+    .await;
+    assert!(connected, "replica never completed the handshake");
+
+    let mut client = TestClient::connect(master_addr).await;
+    let deleted = |n: u64| Type::Integer(n.to_string());
+    client
+        .assert_reply(&["DELIFEQ", "lock", "token"], deleted(0))
+        .await;
+    client
+        .assert_reply(&["SET", "lock", "token"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["DELIFEQ", "lock", "other"], deleted(0))
+        .await;
+    client.assert_reply(&["GET", "lock"], bulk("token")).await;
+
+    let replica_addr = replica.local_addr();
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "lock"]).await == bulk("token")
+    })
+    .await;
+    assert!(replicated, "SET was not applied on the replica");
+
+    client
+        .assert_reply(&["DELIFEQ", "lock", "token"], deleted(1))
+        .await;
+    client
+        .assert_reply(&["GET", "lock"], Type::NullBulkString)
+        .await;
+    let replicated = wait_until(Duration::from_secs(5), || async move {
+        let mut client = TestClient::connect(replica_addr).await;
+        client.send(&["GET", "lock"]).await == Type::NullBulkString
+    })
+    .await;
+    assert!(replicated, "the delete was not applied on the replica");
+
+    client
+        .assert_reply(&["MSET", "a", "1", "b", "2"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["DEL", "a", "b", "missing"], deleted(2))
+        .await;
+}
+
+#[tokio::test]
+async fn frozen_keys_only_take_appends() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+    let error = |msg: &str| Type::SimpleError(msg.to_string());
+    let frozen = error("ERR key 'log' is append-only, only APPEND can change it");
+
+    client
+        .assert_reply(&["OBJECT", "FREEZE", "log"], error("ERR no such key"))
+        .await;
+    client
+        .assert_reply(&["APPEND", "log", "a;"], Type::Integer("2".to_string()))
+        .await;
+    client
+        .assert_reply(&["OBJECT", "FREEZE", "log"], simple("OK"))
+        .await;
+
+    client
+        .assert_reply(&["SET", "log", "x"], frozen.clone())
+        .await;
+    client
+        .assert_reply(&["MSET", "other", "1", "log", "x"], frozen.clone())
+        .await;
+    client.assert_reply(&["DEL", "log"], frozen.clone()).await;
+    client
+        .assert_reply(&["DELIFEQ", "log", "a;"], frozen.clone())
+        .await;
+    client
+        .assert_reply(&["APPEND", "log", "b;"], Type::Integer("4".to_string()))
+        .await;
+    client.assert_reply(&["GET", "log"], bulk("a;b;")).await;
+
+    // Queued writes are checked when EXEC runs them.
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client
+        .assert_reply(&["SET", "log", "x"], simple("QUEUED"))
+        .await;
+    client
+        .assert_reply(&["EXEC"], Type::Array(vec![frozen]))
+        .await;
+
+    client
+        .assert_reply(&["OBJECT", "THAW", "log"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "log", "x"], simple("OK"))
+        .await;
+    client.assert_reply(&["GET", "log"], bulk("x")).await;
+}
+
+#[tokio::test]
+async fn multi_queues_commands_until_exec() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+    let error = |msg: &str| Type::SimpleError(msg.to_string());
+
+    client
+        .assert_reply(&["EXEC"], error("ERR EXEC without MULTI"))
+        .await;
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client
+        .assert_reply(&["MULTI"], error("ERR MULTI calls can not be nested"))
+        .await;
+    client
+        .assert_reply(&["SET", "foo", "1"], simple("QUEUED"))
+        .await;
+    client.assert_reply(&["GET", "foo"], simple("QUEUED")).await;
+    // Nothing runs before EXEC.
+    let mut other = TestClient::connect(master.local_addr()).await;
+    other
+        .assert_reply(&["GET", "foo"], Type::NullBulkString)
+        .await;
+    client
+        .assert_reply(&["EXEC"], Type::Array(vec![simple("OK"), bulk("1")]))
+        .await;
+
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    client
+        .assert_reply(&["SET", "foo", "2"], simple("QUEUED"))
+        .await;
+    client.assert_reply(&["DISCARD"], simple("OK")).await;
+    client
+        .assert_reply(&["DISCARD"], error("ERR DISCARD without MULTI"))
+        .await;
+    client.assert_reply(&["GET", "foo"], bulk("1")).await;
+}
+
+#[tokio::test]
+async fn exec_runs_without_other_clients_in_between() {
+    let master = spawn_master().await;
+    let addr = master.local_addr();
+    let mut client = TestClient::connect(addr).await;
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    for _ in 0..200 {
+        client
+            .assert_reply(&["APPEND", "log", "a"], simple("QUEUED"))
+            .await;
+    }
+
+    let mut others = Vec::new();
+    for _ in 0..10 {
+        others.push(tokio::spawn(async move {
+            let mut other = TestClient::connect(addr).await;
+            for _ in 0..20 {
+                other.send(&["APPEND", "log", "b"]).await;
+            }
+        }));
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let Type::Array(replies) = client.send(&["EXEC"]).await else {
+        panic!("EXEC should reply with an array");
+    };
+    assert_eq!(replies.len(), 200);
+    for other in others {
+        other.await.unwrap();
+    }
+
+    let Type::BulkString(log) = client.send(&["GET", "log"]).await else {
+        panic!("GET should reply with a bulk string");
+    };
+    assert_eq!(log.len(), 400);
+    assert!(log.contains(&"a".repeat(200)), "{}", log);
+}
+
+#[tokio::test]
+async fn exec_dryrun_reports_without_running() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(&["SET", "log", "a"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["OBJECT", "FREEZE", "log"], simple("OK"))
+        .await;
+    client.assert_reply(&["MULTI"], simple("OK")).await;
+    for command in [
+        &["SET", "foo", "1"][..],
+        &["GET", "foo"],
+        &["DEL", "log"],
+        &["PING"],
+    ] {
+        client.assert_reply(command, simple("QUEUED")).await;
+    }
+    // Refused right away for its number of arguments, but still reported.
+    let arity = Type::SimpleError(
+        "ERR Set command needs a key, a value and PX or IFVERSION options".to_string(),
+    );
+    client.assert_reply(&["SET", "foo"], arity.clone()).await;
+    // Arguments of the wrong type are only found when the command runs.
+    for command in [
+        &["SET", "foo", "1", "PX", "soon"][..],
+        &["OBJECT", "MELT", "log"],
+    ] {
+        client.assert_reply(command, simple("QUEUED")).await;
+    }
+    client
+        .assert_reply(
+            &["EXEC", "dryrun"],
+            Type::Array(vec![
+                simple("SET would write foo"),
+                simple("GET would read foo"),
+                Type::SimpleError(
+                    "ERR key 'log' is append-only, only APPEND can change it".to_string(),
+                ),
+                simple("PING would run"),
+                arity,
+                Type::SimpleError(
+                    "ERR parsing u64 from string: invalid digit found in string".to_string(),
+                ),
+                Type::SimpleError("ERR unknown subcommand 'MELT' for OBJECT".to_string()),
+            ]),
+        )
+        .await;
+
+    // Nothing ran and the transaction is over.
+    client
+        .assert_reply(&["GET", "foo"], Type::NullBulkString)
+        .await;
+    client.assert_reply(&["GET", "log"], bulk("a")).await;
+    client
+        .assert_reply(
+            &["EXEC", "DRYRUN"],
+            Type::SimpleError("ERR EXEC without MULTI".to_string()),
+        )
+        .await;
+}
+
+#[tokio::test]
+async fn malformed_frames_get_an_error_reply() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    for raw in [
+        &b"*2\r\n$3\r\nGET\r\n$100\r\nfoo\r\n"[..],
+        b"*99999999999\r\n",
+        b"?garbage\r\n",
+    ] {
+        let reply = client.send_raw(raw).await.unwrap();
+        assert!(matches!(reply, Type::SimpleError(_)), "{:?}", reply);
+    }
+    client.assert_reply(&["PING"], simple("PONG")).await;
+}
+
+#[tokio::test]
+async fn shutdown_closes_listener_and_connections() {
+    let master = spawn_master().await;
+    let addr = master.local_addr();
+    let mut client = TestClient::connect(addr).await;
+    client
+        .assert_reply(&["SET", "foo", "bar"], simple("OK"))
+        .await;
+
+    master.shutdown(Duration::from_secs(1)).await.unwrap();
+
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    assert!(client.send_raw(&command(&["PING"])).await.is_err());
+}
+
+async fn sorted_keys(client: &mut TestClient, pattern: &str) -> Vec<String> {
+    let Type::Array(keys) = client.send(&["KEYS", pattern]).await else {
+        panic!("KEYS {} did not return an array", pattern);
+    };
+    let mut keys: Vec<String> = keys
+        .into_iter()
+        .map(|key| key.try_into().unwrap())
+        .collect();
+    keys.sort();
+    keys
+}
+
+#[tokio::test]
+async fn keys_matches_glob_patterns() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    for key in ["hello", "hallo", "hxllo", "heeeello", "world"] {
+        client.assert_reply(&["SET", key, "1"], simple("OK")).await;
+    }
+
+    assert_eq!(sorted_keys(&mut client, "*").await.len(), 5);
+    assert_eq!(
+        sorted_keys(&mut client, "h?llo").await,
+        ["hallo", "hello", "hxllo"]
+    );
+    assert_eq!(
+        sorted_keys(&mut client, "h*llo").await,
+        ["hallo", "heeeello", "hello", "hxllo"]
+    );
+    assert_eq!(
+        sorted_keys(&mut client, "h[ae]llo").await,
+        ["hallo", "hello"]
+    );
+    assert_eq!(
+        sorted_keys(&mut client, "h[^e]llo").await,
+        ["hallo", "hxllo"]
+    );
+    assert_eq!(sorted_keys(&mut client, "h[a-b]llo").await, ["hallo"]);
+    assert!(sorted_keys(&mut client, "nothing*").await.is_empty());
+}
+
+#[tokio::test]
+async fn pttl_reports_remaining_time() {
+    let master = spawn_master().await;
+    let mut client = TestClient::connect(master.local_addr()).await;
+
+    client
+        .assert_reply(&["SET", "forever", "1"], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "brief", "1", "PX", "10000"], simple("OK"))
+        .await;
+
+    client
+        .assert_reply(&["PTTL", "forever"], Type::Integer("-1".to_string()))
+        .await;
+    client
+        .assert_reply(&["PTTL", "missing"], Type::Integer("-2".to_string()))
+        .await;
+    let pttl: i64 = client.send(&["PTTL", "brief"]).await.try_into().unwrap();
+    assert!(0 < pttl && pttl <= 10000, "pttl {}", pttl);
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kv-store-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn recv_state(socket: &UnixDatagram) -> String {
+    let mut buf = [0u8; 256];
+    let len = socket
+        .recv(&mut buf)
+        .expect("waiting for sd_notify message");
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+#[test]
+fn notifies_systemd_and_manages_pidfile() {
+    let dir = scratch_dir("supervised");
+    let notify_path = dir.join("notify.sock");
+    let pidfile = dir.join("server.pid");
+
+    let socket = UnixDatagram::bind(&notify_path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_redis-starter-rust"))
+        .args(["--port", "0", "--supervised", "systemd", "--pidfile"])
+        .arg(&pidfile)
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let ready = recv_state(&socket);
+    assert!(ready.starts_with("READY=1\n"), "{}", ready);
+    assert!(
+        ready.contains(&format!("MAINPID={}", child.id())),
+        "{}",
+        ready
+    );
+    assert_eq!(
+        fs::read_to_string(&pidfile).unwrap().trim(),
+        child.id().to_string()
+    );
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+
+    let stopping = recv_state(&socket);
+    assert!(stopping.starts_with("STOPPING=1\n"), "{}", stopping);
+    assert!(child.wait().unwrap().success());
+    assert!(!pidfile.exists());
+
+    let _ = fs::remove_dir_all(&dir);
+}
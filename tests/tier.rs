@@ -0,0 +1,145 @@
+mod common;
+
+use common::*;
+use redis_starter_rust::clock::MockClock;
+use redis_starter_rust::{DbEntry, Server, Type};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn idle_values_move_to_disk_and_back() {
+    let path = std::env::temp_dir().join(format!("kv-store-cold-{}.log", std::process::id()));
+    let clock = Arc::new(MockClock::new());
+    let server = Server::builder()
+        .port(0)
+        .clock(clock.clone())
+        .cold_storage(&path, Duration::from_secs(1))
+        .spawn()
+        .await
+        .expect("spawning server");
+    let mut client = TestClient::connect(server.local_addr()).await;
+    let value = "v".repeat(500);
+    let db = server.db();
+
+    client
+        .assert_reply(&["SET", "cold", &value], simple("OK"))
+        .await;
+    client
+        .assert_reply(&["SET", "hot", "1"], simple("OK"))
+        .await;
+    assert_eq!(db.lock().unwrap().used_memory(), 500 + 4 + 3 + 1);
+
+    let spilled = wait_until(Duration::from_secs(5), || {
+        clock.advance(Duration::from_millis(200));
+        let db = db.clone();
+        async move { db.lock().unwrap().used_memory() == 4 + 3 }
+    })
+    .await;
+    assert!(spilled, "idle values were never moved to disk");
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 500 + 1);
+
+    // Keys and versions stay in memory.
+    client
+        .assert_reply(&["GETVER", "cold"], Type::Integer("1".to_string()))
+        .await;
+    let Type::Array(keys) = client.send(&["KEYS", "*"]).await else {
+        panic!("KEYS should return an array");
+    };
+    assert_eq!(keys.len(), 2);
+    assert_eq!(db.lock().unwrap().used_memory(), 4 + 3);
+
+    client.assert_reply(&["GET", "cold"], bulk(&value)).await;
+    assert_eq!(db.lock().unwrap().used_memory(), 500 + 4 + 3);
+    client
+        .assert_reply(
+            &["MGET", "hot", "missing"],
+            Type::Array(vec![bulk("1"), Type::NullBulkString]),
+        )
+        .await;
+
+    // Values that can't be read back are an error, not a missing key.
+    client
+        .assert_reply(&["SET", "lost", &value], simple("OK"))
+        .await;
+    let spilled = wait_until(Duration::from_secs(5), || {
+        clock.advance(Duration::from_millis(200));
+        let db = db.clone();
+        async move {
+            let db = db.lock().unwrap();
+            db.peek("lost")
+                .is_some_and(|entry| entry.value().is_empty())
+        }
+    })
+    .await;
+    assert!(spilled, "idle values were never moved to disk");
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(0).unwrap();
+    let reply = client.send(&["GET", "lost"]).await;
+    assert!(
+        matches!(&reply, Type::SimpleError(e) if e.starts_with("ERR reading lost from cold storage")),
+        "{:?}",
+        reply
+    );
+
+    drop(client);
+    let _ = server.shutdown(Duration::from_secs(1)).await;
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn compaction_drops_values_read_back() {
+    let path = std::env::temp_dir().join(format!("kv-store-compact-{}.log", std::process::id()));
+    let clock = Arc::new(MockClock::new());
+    let server = Server::builder()
+        .port(0)
+        .clock(clock.clone())
+        .cold_storage(&path, Duration::from_secs(1))
+        .spawn()
+        .await
+        .expect("spawning server");
+    let mut client = TestClient::connect(server.local_addr()).await;
+    let value = "v".repeat(500);
+    let db = server.db();
+
+    // Enough values to free over a megabyte once they are read back.
+    let keys: Vec<String> = (0..2200).map(|i| format!("k{}", i)).collect();
+    for key in keys.iter().cloned().chain(["kept".to_string()]) {
+        let entry = DbEntry::new(value.clone(), None);
+        db.lock().unwrap().insert(key, entry);
+    }
+    let spilled = wait_until(Duration::from_secs(5), || {
+        clock.advance(Duration::from_millis(200));
+        let db = db.clone();
+        async move {
+            let db = db.lock().unwrap();
+            let all = db.iter().all(|(_, entry)| entry.value().is_empty());
+            all
+        }
+    })
+    .await;
+    assert!(spilled, "idle values were never moved to disk");
+    for key in &keys {
+        let mut db = db.lock().unwrap();
+        assert_eq!(db.get(key).unwrap().unwrap().value(), value);
+    }
+
+    // Only the value still on disk is copied over, the others stay in
+    // memory as long as the clock stays below the idle threshold.
+    let mut advanced = Duration::ZERO;
+    let compacted = wait_until(Duration::from_secs(5), || {
+        if advanced < Duration::from_millis(500) {
+            clock.advance(Duration::from_millis(100));
+            advanced += Duration::from_millis(100);
+        }
+        let path = path.clone();
+        async move { std::fs::metadata(&path).unwrap().len() == 500 }
+    })
+    .await;
+    assert!(compacted, "cold storage was never compacted");
+    client.assert_reply(&["GET", "kept"], bulk(&value)).await;
+    client.assert_reply(&["GET", "k0"], bulk(&value)).await;
+
+    drop(client);
+    let _ = server.shutdown(Duration::from_secs(1)).await;
+    let _ = std::fs::remove_file(path);
+}